@@ -0,0 +1,339 @@
+//  The changes to the public API of Rust library crates that may break semver. The base version of
+//  a changed file is rebuilt from its head version and its patch, both are parsed with syn, and
+//  their public items are compared by signature.
+
+use crate::trace::debug;
+use lazy_static::lazy_static;
+use quote::ToTokens;
+use regex::Regex;
+use std::collections::BTreeMap;
+use syn::spanned::Spanned;
+use syn::{Attribute, Fields, FnArg, ImplItem, Item, Signature, TraitItem, Type, UseTree, Visibility, parse_quote};
+
+lazy_static! {
+    static ref HUNK_HEADER: Regex = Regex::new(r"^@@ -\d+(?:,\d+)? \+(\d+)(?:,(\d+))? @@").unwrap();
+}
+
+//  A public item of a file: the shape that is compared, its signature as shown, and its line
+struct ApiItem {
+    shape: String,
+    display: String,
+    line: usize,
+    //  The item that it is part of, e.g. the struct of a field
+    parent: Option<String>,
+    //  Adding it breaks the users, e.g. a variant of an exhaustive enum
+    breaking_addition: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ApiChange {
+    Removed,
+    Changed(String, String),
+    //  Why adding the item breaks the users
+    Added(&'static str),
+}
+
+#[derive(Debug)]
+pub(crate) struct BreakingChange {
+    pub(crate) path: String,
+    //  The kind and the name of the item, e.g. "fn parse" or "variant Kind::Other"
+    pub(crate) item: String,
+    //  The line in the new version of the file, if the item is still there
+    pub(crate) line: Option<usize>,
+    pub(crate) change: ApiChange,
+}
+
+//  The Rust files of library crates, i.e. not the binaries, build scripts, tests, examples and benches
+pub(crate) fn is_library_source(path: &str) -> bool {
+    let in_src = path.starts_with("src/") || path.contains("/src/");
+    path.ends_with(".rs") && in_src && !path.ends_with("src/main.rs") && !path.contains("src/bin/")
+}
+
+//  The public items of the file at `path` that the patch removes, changes incompatibly, or adds
+//  in a way that breaks the users. `head` is the file after the change, empty if it was deleted.
+pub(crate) fn breaking_changes(path: &str, head: &str, patch: &str) -> Vec<BreakingChange> {
+    let Some(base) = base_text(head, patch) else {
+        debug!("api", "The patch of {} does not apply to its head, skipping", path);
+        return Vec::new();
+    };
+    let (old, new) = match (syn::parse_file(&base), syn::parse_file(head)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(e), _) | (_, Err(e)) => {
+            debug!("api", "Cannot parse {}: {}", path, e);
+            return Vec::new();
+        }
+    };
+    let (mut old_api, mut new_api) = (BTreeMap::new(), BTreeMap::new());
+    collect(&old.items, "", &mut old_api);
+    collect(&new.items, "", &mut new_api);
+
+    let mut changes = Vec::new();
+    for (key, o) in &old_api {
+        // The members of a removed item are not listed on their own
+        match new_api.get(key) {
+            None if o.parent.as_ref().is_some_and(|p| !new_api.contains_key(p)) => {}
+            None => changes.push(BreakingChange { path: path.to_string(), item: key.clone(), line: None, change: ApiChange::Removed }),
+            Some(n) if n.shape != o.shape => changes.push(BreakingChange {
+                path: path.to_string(),
+                item: key.clone(),
+                line: Some(n.line),
+                change: ApiChange::Changed(o.display.clone(), n.display.clone()),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (key, n) in &new_api {
+        let parent_existed = n.parent.as_ref().is_some_and(|p| old_api.contains_key(p));
+        if n.breaking_addition && parent_existed && !old_api.contains_key(key) {
+            let reason = match key.split(' ').next() {
+                Some("field") => "a new public field of a struct that can be built with a literal",
+                Some("variant") => "a new variant of an enum that is not `#[non_exhaustive]`",
+                _ => "a new trait item without a default, which the implementations must add",
+            };
+            changes.push(BreakingChange { path: path.to_string(), item: key.clone(), line: Some(n.line), change: ApiChange::Added(reason) });
+        }
+    }
+    changes
+}
+
+//  The file before the change, by undoing the hunks of its patch on the file after the change.
+//  None when the patch does not match the file, e.g. when it was cut for being too large.
+fn base_text(head: &str, patch: &str) -> Option<String> {
+    let head: Vec<&str> = head.lines().collect();
+    let mut base: Vec<&str> = Vec::new();
+    // The index in head of the next line that is not copied yet
+    let mut next = 0;
+    let mut in_hunk = false;
+    for l in patch.lines() {
+        if let Some(cap) = HUNK_HEADER.captures(l) {
+            let start: usize = cap[1].parse().ok()?;
+            // A hunk without new lines starts after its line number
+            let start = match cap.get(2).map(|count| count.as_str()) {
+                Some("0") => start,
+                _ => start.checked_sub(1)?,
+            };
+            if start < next || start > head.len() {
+                return None;
+            }
+            base.extend(&head[next..start]);
+            next = start;
+            in_hunk = true;
+        } else if !in_hunk || l.starts_with('\\') {
+            continue;
+        } else if let Some(removed) = l.strip_prefix('-') {
+            base.push(removed);
+        } else {
+            let line = *head.get(next)?;
+            if line != l.get(1..).unwrap_or_default() {
+                return None;
+            }
+            if !l.starts_with('+') {
+                base.push(line);
+            }
+            next += 1;
+        }
+    }
+    if !in_hunk {
+        return None;
+    }
+    base.extend(&head[next..]);
+    Some(base.join("\n"))
+}
+
+fn is_public(vis: &Visibility) -> bool {
+    matches!(vis, Visibility::Public(_))
+}
+
+fn has_attr(attrs: &[Attribute], name: &str, arg: Option<&str>) -> bool {
+    attrs.iter().any(|a| {
+        a.path().is_ident(name) && arg.is_none_or(|arg| a.meta.to_token_stream().to_string().contains(arg))
+    })
+}
+
+//  Hidden and test-only items are not part of the API
+fn is_hidden(attrs: &[Attribute]) -> bool {
+    has_attr(attrs, "doc", Some("hidden")) || has_attr(attrs, "cfg", Some("test"))
+}
+
+//  Tokens as code, without the spaces that the token printer puts around all punctuation
+fn tidy(tokens: &dyn ToTokens) -> String {
+    let mut s = tokens.to_token_stream().to_string();
+    for (from, to) in [
+        (" :: ", "::"), (":: ", "::"), (" ,", ","), (" :", ":"), (" ;", ";"), ("& ", "&"), (" < ", "<"), ("< ", "<"),
+        (" >", ">"), ("( ", "("), (" )", ")"), (" (", "("), ("->(", "-> ("), ("[ ", "["), (" ]", "]"), ("' ", "'"), (" ?", "?"),
+    ] {
+        s = s.replace(from, to);
+    }
+    s
+}
+
+//  The signature without the names of the arguments, which the callers do not see
+fn signature_shape(sig: &Signature) -> String {
+    let mut sig = sig.clone();
+    for input in sig.inputs.iter_mut() {
+        if let FnArg::Typed(arg) = input {
+            *arg.pat = parse_quote!(_);
+        }
+    }
+    sig.to_token_stream().to_string()
+}
+
+fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(p) => p.path.segments.last().map(|s| s.ident.to_string()),
+        _ => None,
+    }
+}
+
+fn add(api: &mut BTreeMap<String, ApiItem>, key: String, shape: String, display: String, span: proc_macro2::Span, parent: Option<&str>, breaking_addition: bool) {
+    let item = ApiItem { shape, display, line: span.start().line, parent: parent.map(str::to_string), breaking_addition };
+    api.insert(key, item);
+}
+
+//  The public items of a module, with `prefix` before their names
+fn collect(items: &[Item], prefix: &str, api: &mut BTreeMap<String, ApiItem>) {
+    // Only the impls of the public types of the module are part of its API
+    let public_types: BTreeMap<String, String> = items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Struct(s) if is_public(&s.vis) => Some((s.ident.to_string(), format!("struct {}{}", prefix, s.ident))),
+            Item::Enum(e) if is_public(&e.vis) => Some((e.ident.to_string(), format!("enum {}{}", prefix, e.ident))),
+            _ => None,
+        })
+        .collect();
+    for item in items {
+        match item {
+            Item::Fn(f) if is_public(&f.vis) && !is_hidden(&f.attrs) => {
+                let key = format!("fn {}{}", prefix, f.sig.ident);
+                add(api, key, signature_shape(&f.sig), tidy(&f.sig), f.sig.ident.span(), None, false);
+            }
+            Item::Struct(s) if is_public(&s.vis) && !is_hidden(&s.attrs) => {
+                let name = format!("{}{}", prefix, s.ident);
+                let non_exhaustive = has_attr(&s.attrs, "non_exhaustive", None);
+                let shape = format!("{} {} {}", non_exhaustive, s.generics.to_token_stream(), s.generics.where_clause.to_token_stream());
+                let display = format!("{}struct {}{}", if non_exhaustive { "#[non_exhaustive] " } else { "" }, s.ident, tidy(&s.generics));
+                let key = format!("struct {}", name);
+                add(api, key.clone(), shape, display, s.ident.span(), None, false);
+                // A struct with only public fields can be built with a literal, so a new field breaks it
+                let literal = !non_exhaustive && s.fields.iter().all(|f| is_public(&f.vis));
+                for (i, field) in s.fields.iter().enumerate().filter(|(_, f)| is_public(&f.vis)) {
+                    let field_name = field.ident.as_ref().map_or(i.to_string(), |ident| ident.to_string());
+                    let ty = field.ty.to_token_stream().to_string();
+                    add(api, format!("field {}.{}", name, field_name), ty, tidy(&field.ty), field.span(), Some(&key), literal);
+                }
+            }
+            Item::Enum(e) if is_public(&e.vis) && !is_hidden(&e.attrs) => {
+                let name = format!("{}{}", prefix, e.ident);
+                let non_exhaustive = has_attr(&e.attrs, "non_exhaustive", None);
+                let shape = format!("{} {} {}", non_exhaustive, e.generics.to_token_stream(), e.generics.where_clause.to_token_stream());
+                let display = format!("{}enum {}{}", if non_exhaustive { "#[non_exhaustive] " } else { "" }, e.ident, tidy(&e.generics));
+                let key = format!("enum {}", name);
+                add(api, key.clone(), shape, display, e.ident.span(), None, false);
+                for v in &e.variants {
+                    let fields = match &v.fields {
+                        Fields::Unit => String::new(),
+                        fields => fields.to_token_stream().to_string(),
+                    };
+                    let display = format!("{}{}", v.ident, tidy(&v.fields));
+                    add(api, format!("variant {}::{}", name, v.ident), fields, display, v.ident.span(), Some(&key), !non_exhaustive);
+                }
+            }
+            Item::Trait(t) if is_public(&t.vis) && !is_hidden(&t.attrs) => {
+                let name = format!("{}{}", prefix, t.ident);
+                let shape = format!("{:?} {} {}", t.unsafety.is_some(), t.generics.to_token_stream(), t.supertraits.to_token_stream());
+                let display = format!("trait {}{}{}", t.ident, tidy(&t.generics), if t.supertraits.is_empty() { String::new() } else { format!(": {}", tidy(&t.supertraits)) });
+                let key = format!("trait {}", name);
+                add(api, key.clone(), shape, display, t.ident.span(), None, false);
+                for trait_item in &t.items {
+                    match trait_item {
+                        TraitItem::Fn(f) => {
+                            // Removing the default of a method breaks its implementations too
+                            let shape = format!("{} {}", signature_shape(&f.sig), f.default.is_some());
+                            let item = format!("method {}::{}", name, f.sig.ident);
+                            add(api, item, shape, tidy(&f.sig), f.sig.ident.span(), Some(&key), f.default.is_none());
+                        }
+                        TraitItem::Type(ty) => {
+                            let shape = format!("{} {}", ty.bounds.to_token_stream(), ty.default.is_some());
+                            let item = format!("type {}::{}", name, ty.ident);
+                            add(api, item, shape, tidy(ty), ty.ident.span(), Some(&key), ty.default.is_none());
+                        }
+                        TraitItem::Const(c) => {
+                            let shape = format!("{} {}", c.ty.to_token_stream(), c.default.is_some());
+                            let item = format!("const {}::{}", name, c.ident);
+                            add(api, item, shape, tidy(c), c.ident.span(), Some(&key), c.default.is_none());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Item::Type(t) if is_public(&t.vis) && !is_hidden(&t.attrs) => {
+                let shape = format!("{} {}", t.generics.to_token_stream(), t.ty.to_token_stream());
+                add(api, format!("type {}{}", prefix, t.ident), shape, tidy(&t.ty), t.ident.span(), None, false);
+            }
+            Item::Const(c) if is_public(&c.vis) && !is_hidden(&c.attrs) => {
+                add(api, format!("const {}{}", prefix, c.ident), c.ty.to_token_stream().to_string(), tidy(&c.ty), c.ident.span(), None, false);
+            }
+            Item::Static(s) if is_public(&s.vis) && !is_hidden(&s.attrs) => {
+                let shape = format!("{} {}", s.mutability.to_token_stream(), s.ty.to_token_stream());
+                add(api, format!("static {}{}", prefix, s.ident), shape, tidy(&s.ty), s.ident.span(), None, false);
+            }
+            Item::Mod(m) if is_public(&m.vis) && !is_hidden(&m.attrs) => {
+                if let Some((_, items)) = &m.content {
+                    collect(items, &format!("{}{}::", prefix, m.ident), api);
+                }
+            }
+            Item::Use(u) if is_public(&u.vis) && !is_hidden(&u.attrs) => {
+                let mut paths = Vec::new();
+                use_paths(&u.tree, String::new(), &mut paths);
+                for path in paths {
+                    add(api, format!("use {}", path), String::new(), String::new(), u.span(), None, false);
+                }
+            }
+            Item::Macro(m) if has_attr(&m.attrs, "macro_export", None) && !is_hidden(&m.attrs) => {
+                if let Some(ident) = &m.ident {
+                    add(api, format!("macro {}", ident), String::new(), String::new(), ident.span(), None, false);
+                }
+            }
+            Item::Impl(i) if !is_hidden(&i.attrs) => {
+                let Some((self_name, parent)) = type_name(&i.self_ty).and_then(|name| public_types.get_key_value(&name)) else {
+                    continue;
+                };
+                let type_key = format!("{}{}", prefix, self_name);
+                match &i.trait_ {
+                    // The users may rely on any trait that a type implements
+                    Some((negative, path, _)) => {
+                        let item = format!("impl {}{} for {}{}", if negative.is_some() { "!" } else { "" }, tidy(path), tidy(&i.self_ty), tidy(&i.generics));
+                        add(api, item, String::new(), String::new(), i.span(), Some(parent), false);
+                    }
+                    None => {
+                        for impl_item in &i.items {
+                            match impl_item {
+                                ImplItem::Fn(f) if is_public(&f.vis) && !is_hidden(&f.attrs) => {
+                                    let item = format!("method {}::{}", type_key, f.sig.ident);
+                                    add(api, item, signature_shape(&f.sig), tidy(&f.sig), f.sig.ident.span(), Some(parent), false);
+                                }
+                                ImplItem::Const(c) if is_public(&c.vis) && !is_hidden(&c.attrs) => {
+                                    let item = format!("const {}::{}", type_key, c.ident);
+                                    add(api, item, c.ty.to_token_stream().to_string(), tidy(&c.ty), c.ident.span(), Some(parent), false);
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+//  The paths that a `pub use` re-exports
+fn use_paths(tree: &UseTree, prefix: String, paths: &mut Vec<String>) {
+    match tree {
+        UseTree::Path(p) => use_paths(&p.tree, format!("{}{}::", prefix, p.ident), paths),
+        UseTree::Name(n) => paths.push(format!("{}{}", prefix, n.ident)),
+        UseTree::Rename(r) => paths.push(format!("{}{} as {}", prefix, r.ident, r.rename)),
+        UseTree::Glob(_) => paths.push(format!("{}*", prefix)),
+        UseTree::Group(g) => g.items.iter().for_each(|t| use_paths(t, prefix.clone(), paths)),
+    }
+}
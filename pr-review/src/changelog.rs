@@ -0,0 +1,96 @@
+//  Changelog entries in the Keep a Changelog format (https://keepachangelog.com), and merging them
+//  into the "Unreleased" section of a CHANGELOG.md file.
+
+pub(crate) const CHANGELOG_PATH: &str = "CHANGELOG.md";
+
+//  The kinds of changes of an entry, in the order of the format
+pub(crate) const SECTIONS: &[&str] = &["Added", "Changed", "Deprecated", "Removed", "Fixed", "Security"];
+
+const HEADER: &str = "# Changelog\n\nAll notable changes to this project will be documented in this file.\n\nThe format is based on [Keep a Changelog](https://keepachangelog.com/en/1.1.0/).\n";
+
+//  The kinds of changes of an entry, each with its bullet items
+pub(crate) type Entry = Vec<(&'static str, Vec<String>)>;
+
+//  The entry in an answer of the LLM: the bullet items under the `### <kind>` headings. Other
+//  headings and text are left out.
+pub(crate) fn parse_entry(text: &str) -> Entry {
+    let mut entry: Entry = Vec::new();
+    let mut section: Option<&'static str> = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(heading) = line.strip_prefix('#') {
+            let heading = heading.trim_start_matches('#').trim();
+            section = SECTIONS.iter().copied().find(|s| s.eq_ignore_ascii_case(heading));
+            continue;
+        }
+        let (Some(section), Some(item)) = (section, line.strip_prefix("- ").or_else(|| line.strip_prefix("* "))) else {
+            continue;
+        };
+        if item.trim().is_empty() {
+            continue;
+        }
+        match entry.iter_mut().find(|(s, _)| *s == section) {
+            Some((_, items)) => items.push(item.trim().to_string()),
+            None => entry.push((section, vec![item.trim().to_string()])),
+        }
+    }
+    entry.sort_by_key(|(s, _)| SECTIONS.iter().position(|k| k == s));
+    entry
+}
+
+pub(crate) fn render_entry(entry: &Entry) -> String {
+    entry
+        .iter()
+        .map(|(section, items)| format!("### {}\n\n{}\n", section, items.iter().map(|i| format!("- {}\n", i)).collect::<String>()))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+//  The changelog with the entry added to its "Unreleased" section, under the existing headings of
+//  the same kinds. The section is created above the latest release when there is none.
+pub(crate) fn insert_entry(changelog: &str, entry: &Entry) -> String {
+    let mut lines: Vec<String> = match changelog.trim().is_empty() {
+        true => HEADER.lines().map(str::to_string).collect(),
+        false => changelog.lines().map(str::to_string).collect(),
+    };
+    let is_release = |l: &str| l.starts_with("## ");
+    if !lines.iter().any(|l| is_release(l) && l.to_lowercase().contains("unreleased")) {
+        let at = lines.iter().position(|l| is_release(l)).unwrap_or(lines.len());
+        insert_block(&mut lines, at, vec!["## [Unreleased]".to_string()]);
+    }
+    let start = lines.iter().position(|l| is_release(l) && l.to_lowercase().contains("unreleased")).unwrap_or_default();
+    for (section, items) in entry {
+        let end = (start + 1..lines.len()).find(|&i| is_release(&lines[i])).unwrap_or(lines.len());
+        let heading = format!("### {}", section);
+        let mut block: Vec<String> = items.iter().map(|i| format!("- {}", i)).collect();
+        // After the last item under the heading of the same kind, else at the end of the section
+        let existing = (start + 1..end).find(|&i| lines[i].trim().eq_ignore_ascii_case(&heading));
+        let (from, to) = match existing {
+            Some(h) => (h, (h + 1..end).find(|&i| lines[i].starts_with('#')).unwrap_or(end)),
+            None => {
+                block.splice(0..0, [heading, String::new()]);
+                (start, end)
+            }
+        };
+        let at = (from + 1..to).rev().find(|&i| !lines[i].trim().is_empty()).map_or(from + 1, |i| i + 1);
+        match existing.is_some() && lines[at - 1].starts_with("- ") {
+            true => {
+                lines.splice(at..at, block);
+            }
+            false => insert_block(&mut lines, at, block),
+        }
+    }
+    let mut out = lines.join("\n");
+    out.push('\n');
+    out
+}
+
+//  Insert lines with a blank line before and after them, unless there is one already
+fn insert_block(lines: &mut Vec<String>, at: usize, mut block: Vec<String>) {
+    if at > 0 && !lines[at - 1].trim().is_empty() {
+        block.insert(0, String::new());
+    }
+    if at < lines.len() && !lines[at].trim().is_empty() {
+        block.push(String::new());
+    }
+    lines.splice(at..at, block);
+}
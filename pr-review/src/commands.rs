@@ -0,0 +1,336 @@
+//  The commands of the bot in PR comments: their magic words, how their args are parsed, and the
+//  help that lists them. Each command is one entry of COMMANDS, so the parser and the help stay in
+//  step.
+
+use crate::config::Config;
+
+//  What a PR comment asks the bot to do
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    //  Review the changed files, or only the ones in the given paths, optionally as another persona,
+    //  in another language or in another review mode
+    Review { paths: Vec<String>, persona: Option<String>, language: Option<String>, mode: Option<String> },
+    Summarize,
+    //  Explain the changed files in the given paths, or all of them
+    Explain(Vec<String>),
+    //  Only look for security issues in the changed files in the given paths, or all of them
+    Security(Vec<String>),
+    //  A follow-up question about the review
+    Ask(String),
+    //  Leave files (paths, directories or globs) and findings ("rule:<text>") out of future reviews
+    Ignore(Vec<String>),
+    //  Review again the files whose review failed, keeping the other reviews
+    Retry,
+    //  Review the files left out when the time budget of the last review ran out
+    Continue,
+    //  Start the review over: a new review comment, without any of the cached reviews
+    ReReview,
+    //  Draft a changelog entry of the PR, and commit it to CHANGELOG.md if asked
+    Changelog { commit: bool },
+    //  How to bring the branch of the PR up to date with the base branch
+    RebaseHint,
+    //  Show the settings in effect for the PR, or change one of them for the PR
+    Config(ConfigAction),
+    //  The list of commands
+    Help,
+}
+
+impl Command {
+    //  The name of the command in the config, e.g. for restricted_commands
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Command::Review { .. } => "review",
+            Command::Summarize => "summarize",
+            Command::Explain(_) => "explain",
+            Command::Security(_) => "security",
+            Command::Ask(_) => "ask",
+            Command::Ignore(_) => "ignore",
+            Command::Retry => "retry",
+            Command::Continue => "continue",
+            Command::ReReview => "re-review",
+            Command::Changelog { .. } => "changelog",
+            Command::RebaseHint => "rebase-hint",
+            Command::Config(_) => "config",
+            Command::Help => "help",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ConfigAction {
+    Show,
+    //  A setting and its new value, e.g. "flows config set max_files 20"
+    Set(String, String),
+    //  Go back to the value of the config files, e.g. "flows config reset max_files"
+    Reset(String),
+}
+
+//  A command: its name in the config, the phrases that call it, its args and what it does for the help, and how it is
+//  parsed from the args on the line of the phrase and the whole text after the phrase
+struct Spec {
+    name: &'static str,
+    phrases: fn(&Config) -> Vec<&str>,
+    args: &'static str,
+    help: &'static str,
+    parse: fn(&str, &str) -> Option<Command>,
+}
+
+//  The commands in the order they are matched. The review comes last, since the phrases of the
+//  other commands may start with its phrase.
+const COMMANDS: &[Spec] = &[
+    Spec {
+        name: "summarize",
+        phrases: |c| vec![&c.summarize_phrase],
+        args: "",
+        help: "Post a plain-language summary of the PR",
+        parse: |_, _| Some(Command::Summarize),
+    },
+    Spec {
+        name: "explain",
+        phrases: |c| vec![&c.explain_phrase],
+        args: "[paths]",
+        help: "Walk through the changed files, or the ones in the paths",
+        parse: |args, _| Some(Command::Explain(split_args(args))),
+    },
+    Spec {
+        name: "security",
+        phrases: |c| vec![&c.security_phrase],
+        args: "[paths]",
+        help: "Review the changed files, or the ones in the paths, for security issues only",
+        parse: |args, _| Some(Command::Security(split_args(args))),
+    },
+    Spec {
+        name: "retry",
+        phrases: |c| vec![&c.retry_phrase],
+        args: "",
+        help: "Review again the files whose review failed",
+        parse: |_, _| Some(Command::Retry),
+    },
+    Spec {
+        name: "continue",
+        phrases: |c| vec![&c.continue_phrase],
+        args: "",
+        help: "Review the files left out when the time budget of the last review ran out",
+        parse: |_, _| Some(Command::Continue),
+    },
+    Spec {
+        name: "re-review",
+        phrases: |c| vec![&c.rereview_phrase],
+        args: "",
+        help: "Start the review over, without the cached reviews",
+        parse: |_, _| Some(Command::ReReview),
+    },
+    Spec {
+        name: "rebase-hint",
+        phrases: |c| vec![&c.rebase_hint_phrase],
+        args: "",
+        help: "Show how far the branch is behind and how to rebase it",
+        parse: |_, _| Some(Command::RebaseHint),
+    },
+    Spec {
+        name: "changelog",
+        phrases: |c| vec![&c.changelog_phrase],
+        args: "[commit]",
+        help: "Draft a changelog entry, and commit it to `CHANGELOG.md` with `commit`",
+        parse: |args, _| Some(Command::Changelog { commit: split_args(args).iter().any(|a| a.eq_ignore_ascii_case("commit")) }),
+    },
+    Spec {
+        name: "ignore",
+        phrases: |c| vec![&c.ignore_phrase],
+        args: "<paths or rule:text>",
+        help: "Leave files, or findings that contain the text, out of the next reviews of the PR",
+        parse: |args, _| {
+            let args = split_args(args);
+            (!args.is_empty()).then_some(Command::Ignore(args))
+        },
+    },
+    Spec {
+        name: "ask",
+        phrases: |c| vec![&c.ask_phrase],
+        args: "<question>",
+        help: "Ask a follow-up question about the review. The question can go on over several lines.",
+        // The question goes on after the first line, e.g. a code block
+        parse: |_, rest| {
+            let question = rest.trim();
+            (!question.is_empty()).then(|| Command::Ask(question.to_string()))
+        },
+    },
+    Spec {
+        name: "config",
+        phrases: |c| vec![&c.config_phrase],
+        args: "[set <setting> <value> | reset <setting>]",
+        help: "Show the settings in effect for this PR, or change one of them for this PR",
+        parse: |args, _| {
+            let args = split_args(args);
+            let action = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                ["set", key, ref value @ ..] if !value.is_empty() => ConfigAction::Set(key.to_string(), value.join(" ")),
+                ["reset", key] => ConfigAction::Reset(key.to_string()),
+                _ => ConfigAction::Show,
+            };
+            Some(Command::Config(action))
+        },
+    },
+    Spec {
+        name: "help",
+        phrases: |c| vec![&c.help_phrase],
+        args: "",
+        help: "Show this list of commands",
+        parse: |_, _| Some(Command::Help),
+    },
+    Spec {
+        name: "review",
+        phrases: |c| std::iter::once(&c.trigger_phrase).chain(&c.trigger_aliases).map(String::as_str).collect(),
+        args: "[as:<persona>] [lang:<language>] [mode:<review mode>] [paths]",
+        help: "Review the changed files, or the ones in the paths, optionally as a persona, in another language or in another review mode, e.g. `mode:combined`",
+        // e.g. "flows review as:security lang:zh src/"
+        parse: |args, _| {
+            let (options, paths): (Vec<String>, Vec<String>) = split_args(args)
+                .into_iter()
+                .partition(|a| a.starts_with("as:") || a.starts_with("lang:") || a.starts_with("mode:"));
+            let option = |prefix: &str| options.iter().rev().find_map(|o| o.strip_prefix(prefix)).map(str::to_string);
+            Some(Command::Review {
+                paths,
+                persona: option("as:"),
+                language: option("lang:"),
+                mode: option("mode:"),
+            })
+        },
+    },
+];
+
+//  Parse a PR comment that starts with one of the magic phrases, e.g. "flows review src/parser/ build.rs",
+//  or a mention of the bot, e.g. "@my-bot review src/parser/". With trigger_anywhere, the command
+//  can start at any word of the comment.
+pub(crate) fn parse_command(comment: &str, config: &Config) -> Option<Command> {
+    command_starts(comment, config.trigger_anywhere)
+        .into_iter()
+        .find_map(|start| parse_command_at(&comment[start..], config))
+}
+
+//  The offsets in a comment where a command can start: its first word, or every word outside of
+//  quoted replies and code blocks, so that quoting a command does not run it again
+fn command_starts(comment: &str, anywhere: bool) -> Vec<usize> {
+    let first = comment.len() - comment.trim_start().len();
+    if !anywhere {
+        return vec![first];
+    }
+    let mut starts = Vec::new();
+    let mut in_code = false;
+    let mut offset = 0;
+    for line in comment.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_code = !in_code;
+        } else if !in_code && !trimmed.starts_with('>') {
+            let mut after_space = true;
+            for (i, c) in line.char_indices() {
+                if after_space && !c.is_whitespace() {
+                    starts.push(offset + i);
+                }
+                after_space = c.is_whitespace();
+            }
+        }
+        offset += line.len();
+    }
+    starts
+}
+
+//  The command at the start of the text
+fn parse_command_at(text: &str, config: &Config) -> Option<Command> {
+    let line = text.lines().next().unwrap_or_default().trim();
+    let bot = config.bot_name.as_str();
+    for spec in COMMANDS {
+        for phrase in (spec.phrases)(config) {
+            if let Some(args) = strip_trigger(line, phrase, bot) {
+                let rest = strip_trigger(text.trim(), phrase, bot).unwrap_or(args);
+                return (spec.parse)(args, rest);
+            }
+        }
+    }
+    None
+}
+
+//  The args of a command, split on whitespace except inside quotes, e.g. `"docs/my guide/" src/`
+fn split_args(args: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    for c in args.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '"' | '\'' | '`') => quote = Some(c),
+            (None, c) if c.is_whitespace() => {
+                if !current.is_empty() {
+                    out.push(std::mem::take(&mut current));
+                }
+            }
+            (None, c) => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        out.push(current);
+    }
+    out
+}
+
+//  The rest of the line after the phrase, if the line starts with the phrase as whole words
+fn strip_phrase<'a>(line: &'a str, phrase: &str) -> Option<&'a str> {
+    let head = line.get(..phrase.len())?;
+    let rest = &line[phrase.len()..];
+    let whole = rest.is_empty() || rest.starts_with(char::is_whitespace);
+    (!phrase.is_empty() && head.eq_ignore_ascii_case(phrase) && whole).then_some(rest)
+}
+
+//  The rest of the line after the phrase, or after a mention of the bot and the last word of the
+//  phrase, e.g. "@my-bot summarize" for "flows summarize"
+fn strip_trigger<'a>(line: &'a str, phrase: &str, bot_name: &str) -> Option<&'a str> {
+    if let Some(rest) = strip_phrase(line, phrase) {
+        return Some(rest);
+    }
+    let bot_name = bot_name.trim().trim_start_matches('@');
+    if bot_name.is_empty() {
+        return None;
+    }
+    let rest = strip_phrase(line, &format!("@{}", bot_name))?.trim_start();
+    strip_phrase(rest, phrase.split_whitespace().last()?)
+}
+
+//  The reply to the help command: the commands with the phrases of this repo
+pub(crate) fn render_help(config: &Config) -> String {
+    let mut out = String::from("Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). Here are the commands I understand in PR comments.\n\n| Command | What it does |\n| --- | --- |\n");
+    for spec in COMMANDS {
+        let phrases = (spec.phrases)(config);
+        let Some(phrase) = phrases.first().filter(|p| !p.is_empty()) else {
+            continue;
+        };
+        let usage = match spec.args {
+            "" => phrase.to_string(),
+            args => format!("{} {}", phrase, args),
+        };
+        let mut help = spec.help.to_string();
+        let listed = |names: &[String]| names.iter().any(|n| n.eq_ignore_ascii_case(spec.name));
+        if listed(&config.restricted_commands) {
+            help.push_str(" (maintainers only)");
+        } else if listed(&config.fork_restricted_commands) {
+            help.push_str(" (maintainers only on PRs from forks)");
+        }
+        if phrases.len() > 1 {
+            let aliases: Vec<String> = phrases[1..].iter().map(|p| format!("`{}`", p)).collect();
+            help.push_str(&format!(". Also: {}", aliases.join(", ")));
+        }
+        out.push_str(&format!("| `{}` | {} |\n", usage.replace('|', "\\|"), help));
+    }
+    let bot = config.bot_name.trim().trim_start_matches('@');
+    if !bot.is_empty() {
+        out.push_str(&format!(
+            "\nYou can also mention me with the last word of a command, e.g. `@{} review` or `@{} summarize`.\n",
+            bot, bot
+        ));
+    }
+    out.push_str(match config.trigger_anywhere {
+        true => "\nA command can start at any word of a comment, but not in quoted replies or code blocks.\n",
+        false => "\nA command must start the comment.\n",
+    });
+    out
+}
@@ -0,0 +1,82 @@
+//  The hygiene of the commit messages of a PR: the Conventional Commits format, and the subjects
+//  that are too long or too vague to tell what a commit does.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    pub(crate) static ref CONVENTIONAL_COMMIT: Regex = Regex::new(
+        r"^(?:feat|fix|docs|style|refactor|perf|test|build|ci|chore|revert)(?:\([^)]+\))?!?: \S"
+    ).unwrap();
+    //  Subjects that say nothing about the change, with or without a Conventional Commits type
+    static ref VAGUE_SUBJECT: Regex = Regex::new(
+        r"(?i)^(?:\w+(?:\([^)]*\))?!?:\s*)?(?:wip|fix(?:es|ed)?|update[sd]?|changes?|misc|stuff|tmp|temp|test(?:ing)?|oops|typo|minor|cleanup|more|again|done|\.+)?[.!]*\s*$"
+    ).unwrap();
+    static ref MERGE_COMMIT: Regex = Regex::new(r"^Merge (?:branch|pull request|remote-tracking branch|commit) ").unwrap();
+    //  The start of the suggestion for a commit in the answer of the LLM
+    static ref COMMIT_HEADING: Regex = Regex::new(r"(?m)^\**COMMIT\s+(\d+)\**:?\**\s*$").unwrap();
+}
+
+//  The max length of a subject line, as git and most forges show it
+const MAX_SUBJECT: usize = 72;
+
+//  A commit message that breaks the conventions, and the message that the LLM suggests instead
+pub(crate) struct CommitReview {
+    pub(crate) sha: String,
+    pub(crate) message: String,
+    pub(crate) problems: Vec<&'static str>,
+    pub(crate) suggestion: Option<String>,
+}
+
+//  What is wrong with a commit message. Merge commits are left alone.
+pub(crate) fn commit_problems(message: &str) -> Vec<&'static str> {
+    let mut lines = message.lines();
+    let subject = lines.next().unwrap_or_default().trim();
+    if MERGE_COMMIT.is_match(subject) {
+        return Vec::new();
+    }
+    let mut problems = Vec::new();
+    if !CONVENTIONAL_COMMIT.is_match(subject) {
+        problems.push("not in the Conventional Commits format");
+    }
+    if VAGUE_SUBJECT.is_match(subject) {
+        problems.push("the subject does not say what changed");
+    }
+    if subject.chars().count() > MAX_SUBJECT {
+        problems.push("the subject is longer than 72 characters");
+    }
+    if subject.ends_with('.') {
+        problems.push("the subject ends with a period");
+    }
+    if lines.next().is_some_and(|l| !l.trim().is_empty()) {
+        problems.push("no blank line between the subject and the body");
+    }
+    problems
+}
+
+//  The suggested messages in an answer, by the 1-based number of their commit. Each one follows a
+//  `COMMIT <n>:` line, in a code block or as plain text.
+pub(crate) fn parse_suggestions(answer: &str) -> Vec<(usize, String)> {
+    let headings: Vec<(usize, usize, usize)> = COMMIT_HEADING
+        .captures_iter(answer)
+        .filter_map(|cap| {
+            let m = cap.get(0)?;
+            Some((cap[1].parse().ok()?, m.start(), m.end()))
+        })
+        .collect();
+    let mut suggestions = Vec::new();
+    for (i, &(number, _, end)) in headings.iter().enumerate() {
+        let next = headings.get(i + 1).map_or(answer.len(), |&(_, start, _)| start);
+        let text = answer[end..next].trim();
+        let text = match text.strip_prefix("```") {
+            // The language of the code block, if any, is on the line of the fence
+            Some(block) => block.split_once('\n').map_or("", |(_, rest)| rest).split("```").next().unwrap_or_default(),
+            None => text,
+        };
+        let text = text.trim();
+        if !text.is_empty() {
+            suggestions.push((number, text.to_string()));
+        }
+    }
+    suggestions
+}
@@ -0,0 +1,937 @@
+//  The bot settings, read from the environment and from the config files of the target repo and its owner
+
+use crate::github::{GithubApi, get_content, get_file};
+use crate::llm::truncate_tokens;
+use crate::pipeline::Severity;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+
+lazy_static! {
+    //  Files that are generated by tools rather than written by hand
+    static ref GENERATED_FILES: Vec<glob::Pattern> = parse_globs([
+        "*.lock", "*.min.*", "*.map", "**/generated/**",
+        "*package-lock.json", "*pnpm-lock.yaml", "*go.sum", "*.pb.go", "*_pb2.py", "*.snap",
+    ]);
+    static ref TEST_FILES: Vec<glob::Pattern> = parse_globs([
+        "**/tests/**", "**/test/**", "**/__tests__/**", "**/spec/**",
+        "*_test.*", "*.test.*", "*.spec.*", "*_spec.rb", "**/test_*.py", "*Test.java", "*Tests.cs", "*Test.kt",
+    ]);
+}
+
+//  The per-repo config file, read from the default branch of the target repo
+const CONFIG_PATH: &str = ".github/pr-review.yml";
+
+//  The review guidelines file of the target repo, in the root or in .github
+const GUIDELINES_PATHS: [&str; 2] = ["REVIEW_GUIDELINES.md", ".github/REVIEW_GUIDELINES.md"];
+
+//  The guidelines are cut to this many tokens, to leave room for the code
+const MAX_GUIDELINES_TOKENS: usize = 1000;
+
+//  The contributing guide and the style guide of the target repo: the first of each that is found
+const STYLE_DOCS: [&[&str]; 2] = [
+    &["CONTRIBUTING.md", ".github/CONTRIBUTING.md", "docs/CONTRIBUTING.md"],
+    &["STYLEGUIDE.md", "STYLE_GUIDE.md", "docs/STYLEGUIDE.md"],
+];
+
+//  The database migrations of the common tools: plain SQL and Diesel ones in a migrations
+//  directory, Flyway, Alembic and Flask-Migrate
+const MIGRATION_FILES: &str =
+    "**/migrations/**/*.sql,**/migrate/**/*.sql,**/db/migration/**/*.sql,**/alembic/versions/*.py,**/migrations/versions/*.py";
+
+//  The repo of the owner that holds the org-wide config file
+const ORG_CONFIG_REPO: &str = ".github";
+
+//  How the files are reviewed: each whole file, each patch, each patch with the code around it, or
+//  all patches together
+pub(crate) const REVIEW_MODES: &[&str] = &["full", "diff", "symbols", "combined"];
+
+//  The settings that maintainers can change for one PR with `flows config set`: the ones about
+//  what is reviewed and how, but not the models, the prompts or the access rules
+pub(crate) const RUNTIME_SETTINGS: &[&str] = &[
+    "max_files", "max_bytes", "max_tokens", "fast_path_lines", "max_concurrency", "max_behind", "review_mode", "persona",
+    "output_language", "inline_comments", "suggestions", "summary", "min_confidence", "self_critique", "ensemble_mode", "security_review", "missing_tests",
+    "migration_review", "iac_review", "dockerfile_review", "workflow_review", "api_changes", "secret_scan",
+    "dependency_analysis", "vulnerability_lookup", "review_description", "commit_hygiene", "linked_issues",
+    "related_prs", "group_by_package", "suggest_reviewers", "review_verdict", "request_changes_on", "labels",
+    "size_labels",
+];
+
+//  Files with these extensions are binaries, and have no text to review
+const BINARY_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "bmp", "ico", "webp", "pdf", "zip", "gz", "tgz", "tar", "7z", "rar", "jar",
+    "class", "exe", "dll", "so", "dylib", "a", "o", "wasm", "woff", "woff2", "ttf", "otf", "eot", "mp3", "mp4",
+    "mov", "avi", "bin", "db", "sqlite", "pyc",
+];
+
+//  Settings that maintainers can tune per repo in CONFIG_PATH.
+//  Every setting falls back to the flow env var of the same name, then to a built-in default.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    pub(crate) trigger_phrase: String,
+    //  Other phrases that trigger a review, e.g. ["/review"]
+    pub(crate) trigger_aliases: Vec<String>,
+    //  The GitHub login of the bot, so that "@<bot_name> review" works like the trigger phrase and
+    //  "@<bot_name> <last word of a phrase>" like the other phrases. Empty for no mentions.
+    pub(crate) bot_name: String,
+    //  Find the magic words anywhere in a comment, not only at its start
+    pub(crate) trigger_anywhere: bool,
+    //  The magic words to ask for a plain-language summary of the PR
+    pub(crate) summarize_phrase: String,
+    //  The magic words to ask for a walkthrough of changed files
+    pub(crate) explain_phrase: String,
+    //  The magic words to ask for a security review
+    pub(crate) security_phrase: String,
+    //  The magic words to ask a follow-up question about the review
+    pub(crate) ask_phrase: String,
+    //  The magic words to leave files or findings out of future reviews of the PR
+    pub(crate) ignore_phrase: String,
+    //  The magic words to review again the files whose review failed
+    pub(crate) retry_phrase: String,
+    //  The magic words to review the files left out when the time budget ran out
+    pub(crate) continue_phrase: String,
+    //  The magic words to replace the review with a fresh one
+    pub(crate) rereview_phrase: String,
+    //  The magic words to ask for a changelog entry of the PR
+    pub(crate) changelog_phrase: String,
+    //  The magic words to ask how to bring the branch of the PR up to date
+    pub(crate) rebase_hint_phrase: String,
+    //  The commands that only maintainers can use, by the last word of their phrase, e.g. "config"
+    pub(crate) restricted_commands: Vec<String>,
+    //  The commands that only maintainers can use on the PRs from forks
+    pub(crate) fork_restricted_commands: Vec<String>,
+    //  The magic words to show the settings in effect
+    pub(crate) config_phrase: String,
+    //  The magic words to list the commands
+    pub(crate) help_phrase: String,
+    //  Glob patterns of changed files that are never sent to the LLM
+    #[serde(deserialize_with = "deserialize_globs")]
+    skip_files: Vec<glob::Pattern>,
+    //  Glob patterns of changed files to review, e.g. "src/**/*.rs". Patterns prefixed with "!" exclude files.
+    #[serde(deserialize_with = "deserialize_file_filters")]
+    file_filters: Vec<FileFilter>,
+    //  The LLM backend: "flows", "openai", "anthropic" or "azure"
+    pub(crate) llm_provider: String,
+    //  The API version of Azure OpenAI
+    pub(crate) llm_api_version: String,
+    pub(crate) llm_model_name: String,
+    pub(crate) llm_ctx_size: u32,
+    //  Other models for the files matching their patterns, e.g. a code model for "*.rs"
+    pub(crate) model_routes: Vec<ModelRoute>,
+    //  A second model that also reviews the files matching its patterns, e.g. the high-stakes paths
+    //  of the repo, to weed out the findings that only one of the two models reports
+    pub(crate) ensemble: Option<ModelRoute>,
+    //  What becomes of the findings that only one model of the ensemble reports: "agree" leaves them
+    //  out, "mark" lists them as low confidence
+    pub(crate) ensemble_mode: String,
+    //  "{title}" is replaced with the PR title
+    pub(crate) system_prompt: String,
+    //  The natural language of the review, e.g. "zh" or "Spanish". Empty for English.
+    pub(crate) output_language: String,
+    //  The persona to review as, instead of the system_prompt: a built-in one, or one of `personas`
+    pub(crate) persona: String,
+    personas: HashMap<String, Persona>,
+    //  What to look for in the files of each extension, e.g. "rs". They override the built-in
+    //  templates of the same extension, and an empty one turns the built-in template off.
+    language_prompts: HashMap<String, String>,
+    //  The review guidelines of the repo, appended to the system prompt, e.g. "Never use unwrap in
+    //  src/". A text or a list of guidelines; the REVIEW_GUIDELINES.md of the repo is added to them.
+    #[serde(deserialize_with = "deserialize_guidelines")]
+    guidelines: String,
+    //  Condense the CONTRIBUTING.md and STYLEGUIDE.md of the repo with the LLM, and add them to
+    //  the review prompt. The summary is kept in the review state until the docs change.
+    pub(crate) style_guide: bool,
+    //  Index the definitions of the repo with embeddings, and give each file review the ones that
+    //  are most related to its patch, up to rag_tokens. Only the rag_max_files source files nearest
+    //  to the changed files are indexed. The embedding_model is served by the LLM endpoint.
+    pub(crate) rag: bool,
+    pub(crate) embedding_model: String,
+    pub(crate) rag_max_files: usize,
+    pub(crate) rag_tokens: usize,
+    //  "full" reviews the entire changed file, "diff" reviews only the patch of each file, and
+    //  "symbols" reviews the patch with the definitions that enclose it and the types they use
+    pub(crate) review_mode: String,
+    //  In "diff" mode, also send the full file (after the change) as context for the patch
+    pub(crate) diff_full_context: bool,
+    //  Also post the findings as inline comments on the changed lines via a PR review
+    pub(crate) inline_comments: bool,
+    //  The max number of files that are fetched and reviewed at the same time
+    pub(crate) max_concurrency: usize,
+    //  Update the review comment after each file instead of only once at the end
+    pub(crate) progress_updates: bool,
+    //  The max number of chunks (and LLM calls) that a large file is split into
+    pub(crate) max_chunks: usize,
+    //  Add a summary of the changes and an overall risk assessment to the top of the review
+    pub(crate) summary: bool,
+    //  How many times a failed LLM or GitHub call is tried before giving up
+    pub(crate) max_attempts: u32,
+    //  The max number of files to review in one PR, and the max total size and LLM tokens
+    //  of their review. 0 means no limit for the size and the tokens.
+    pub(crate) max_files: usize,
+    pub(crate) max_bytes: usize,
+    pub(crate) max_tokens: usize,
+    //  The seconds that a review may take, for runtimes that kill long executions. The review stops
+    //  with time to spare and can be resumed with continue_phrase. 0 means no limit.
+    pub(crate) time_budget: u64,
+    //  Review the PRs with fewer changed lines than this in one prompt, instead of one per file. 0
+    //  turns it off.
+    pub(crate) fast_path_lines: u64,
+    //  On new commits, only review again the files changed since the last review
+    pub(crate) incremental: bool,
+    //  The GitHub API calls to leave for other work. A review stops, or is not started, when fewer
+    //  calls are left and the rate limit doesn't reset within rate_limit_wait seconds. 0 turns it off.
+    pub(crate) github_reserve: usize,
+    pub(crate) rate_limit_wait: u64,
+    //  How long to wait for more commits before reviewing a push. Rapid pushes are reviewed once,
+    //  at the last commit. 0 reviews every push.
+    pub(crate) debounce_secs: u64,
+    //  Also publish the review as a check run of the head commit
+    pub(crate) check_run: bool,
+    //  Fail the check run when there are blocker findings
+    pub(crate) fail_on_blockers: bool,
+    //  Also publish the findings as JSON: "gist", "check_run", or "" for none
+    pub(crate) findings_json: String,
+    //  Upload the findings as code scanning alerts
+    pub(crate) sarif_upload: bool,
+    //  Let the inline comments carry fixes that can be applied with one click
+    pub(crate) suggestions: bool,
+    //  Flag credentials committed in the changed files
+    pub(crate) secret_scan: bool,
+    //  Don't review lockfiles, minified bundles, generated code and binaries
+    pub(crate) skip_generated: bool,
+    //  List the dependencies added, updated and removed in the manifests and lockfiles, instead of
+    //  reviewing them with the LLM. Major version bumps, the built-in risky packages and the
+    //  risky_dependencies are flagged.
+    pub(crate) dependency_analysis: bool,
+    pub(crate) risky_dependencies: Vec<String>,
+    //  Look up the added and updated dependency versions in the OSV database of advisories
+    pub(crate) vulnerability_lookup: bool,
+    //  The SPDX ids of the licenses that new dependencies may have, e.g. ["MIT", "Apache-2.0"].
+    //  The other licenses are flagged. Empty for no license check.
+    pub(crate) license_allowlist: Vec<String>,
+    //  Also give feedback on the PR title, description and commit messages
+    pub(crate) review_description: bool,
+    //  Check each commit message against Conventional Commits, and suggest better ones
+    pub(crate) commit_hygiene: bool,
+    //  Draft a title and a description for the PRs that are opened without a description: "off",
+    //  "comment" to suggest them in a comment, or "edit" to write the description into the PR
+    pub(crate) draft_description: String,
+    //  Note in the review when the PR has conflicts, or when its branch is more than this many
+    //  commits behind the base branch. 0 for no note.
+    pub(crate) max_behind: u64,
+    //  List the other open PRs that change the same files
+    pub(crate) related_prs: bool,
+    //  Triage the new issues: their kind, labels that fit them, and possible duplicates
+    pub(crate) issue_triage: bool,
+    //  Only review draft PRs on the trigger phrase, and review them when they are ready for review
+    pub(crate) skip_drafts: bool,
+    //  React to the comments with commands: 👀 when the bot gets them, then 👍 or 😕 when it is done
+    pub(crate) reactions: bool,
+    //  Log the review instead of posting it, and don't touch the PR, e.g. to trial prompts and filters
+    pub(crate) dry_run: bool,
+    //  Ask the LLM how confident it is in each finding, from 0 to 100, and tuck the findings below
+    //  this confidence into a collapsed part of the review of the file. 0 turns it off.
+    pub(crate) min_confidence: u8,
+    //  Add a pass that checks the findings of each file against its patch and drops the false
+    //  positives
+    pub(crate) self_critique: bool,
+    //  Add a security pass to the review of each file
+    pub(crate) security_review: bool,
+    //  List the changed behaviors that the changed tests don't cover
+    pub(crate) missing_tests: bool,
+    //  Add a pass to the review of the database migrations, looking for data loss, missing indexes,
+    //  long locks and irreversible changes, and warn of the serious ones at the top of the review
+    pub(crate) migration_review: bool,
+    //  Glob patterns of the database migrations, e.g. plain SQL, Diesel and Alembic ones
+    #[serde(deserialize_with = "deserialize_globs")]
+    migration_files: Vec<glob::Pattern>,
+    //  Review Terraform, CloudFormation and Kubernetes files for public buckets, open security
+    //  groups, missing resource limits and `latest` image tags, and list their findings apart
+    pub(crate) iac_review: bool,
+    //  Check the changed Dockerfiles for a root user, unpinned base images, secrets in build args
+    //  and single-stage builds, as a checklist in their review
+    pub(crate) dockerfile_review: bool,
+    //  Check the changed GitHub Actions workflows for script injection, broad token permissions
+    //  and third-party actions that are not pinned to a commit
+    pub(crate) workflow_review: bool,
+    //  List the changes to the public items of Rust library crates that may break semver
+    pub(crate) api_changes: bool,
+    //  Only review the files that CODEOWNERS assigns to one of these teams or users, e.g. "@org/platform"
+    pub(crate) owned_by: Vec<String>,
+    //  Give the LLM the issues that the PR says it fixes, e.g. "Fixes #123"
+    pub(crate) linked_issues: bool,
+    //  Group the file reviews by the package of the monorepo they belong to (Cargo, npm or Go)
+    pub(crate) group_by_package: bool,
+    //  Suggest reviewers for the changed paths, by CODEOWNERS or the latest committers
+    pub(crate) suggest_reviewers: bool,
+    //  Also request reviews from them when the PR is first reviewed
+    pub(crate) request_reviewers: bool,
+    //  Approve the PR or request changes in the PR review, based on the most serious finding
+    pub(crate) review_verdict: bool,
+    //  Request changes when a finding is at least this serious
+    pub(crate) request_changes_on: Severity,
+    //  Label the PR by the outcome of the review. An empty label name turns that label off.
+    pub(crate) labels: bool,
+    pub(crate) needs_work_label: String,
+    pub(crate) security_label: String,
+    pub(crate) lgtm_label: String,
+    //  Label the PR by the number of changed lines, from size/XS to size/XL
+    pub(crate) size_labels: bool,
+    //  Don't review PRs from bots such as dependabot or renovate
+    skip_bots: bool,
+    //  Only review PRs from forks when a maintainer says the trigger phrase
+    pub(crate) skip_forks: bool,
+    //  Only review PRs from these authors, if not empty
+    allowed_authors: Vec<String>,
+    //  Never review PRs from these authors, unless a maintainer says the trigger phrase
+    denied_authors: Vec<String>,
+    //  Only review the PRs into these base branches, unless a maintainer says the trigger phrase.
+    //  Glob patterns, and "{default}" for the default branch of the repo. Empty means all branches.
+    base_branches: Vec<String>,
+    //  Post the verdict and the top findings of each review to these chat tools: "slack", "discord"
+    //  or "teams", through the incoming webhook of the first matching notify_channels route of the
+    //  tool, or the one in its env var, e.g. slack_webhook_url. slack_notify adds "slack".
+    pub(crate) notify: Vec<String>,
+    slack_notify: bool,
+    #[serde(alias = "slack_channels")]
+    pub(crate) notify_channels: Vec<NotifyChannel>,
+    //  The email addresses that get the daily digest of the reviews of the repo
+    pub(crate) digest_recipients: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let skip_files = env::var("skip_files").unwrap_or("*.md,*.js,*.css,*.html,*.htm".to_string());
+        Config {
+            trigger_phrase: env::var("trigger_phrase").unwrap_or("flows review".to_string()),
+            trigger_aliases: parse_list(&env::var("trigger_aliases").unwrap_or_default()),
+            bot_name: env::var("bot_name").unwrap_or_default(),
+            trigger_anywhere: env::var("trigger_anywhere").unwrap_or("false".to_string()) == "true",
+            summarize_phrase: env::var("summarize_phrase").unwrap_or("flows summarize".to_string()),
+            explain_phrase: env::var("explain_phrase").unwrap_or("flows explain".to_string()),
+            security_phrase: env::var("security_phrase").unwrap_or("flows security".to_string()),
+            ask_phrase: env::var("ask_phrase").unwrap_or("flows ask".to_string()),
+            ignore_phrase: env::var("ignore_phrase").unwrap_or("flows ignore".to_string()),
+            retry_phrase: env::var("retry_phrase").unwrap_or("flows retry".to_string()),
+            continue_phrase: env::var("continue_phrase").unwrap_or("flows continue".to_string()),
+            rereview_phrase: env::var("rereview_phrase").unwrap_or("flows re-review".to_string()),
+            changelog_phrase: env::var("changelog_phrase").unwrap_or("flows changelog".to_string()),
+            rebase_hint_phrase: env::var("rebase_hint_phrase").unwrap_or("flows rebase-hint".to_string()),
+            restricted_commands: parse_list(&env::var("restricted_commands").unwrap_or("config".to_string())),
+            fork_restricted_commands: parse_list(&env::var("fork_restricted_commands").unwrap_or("re-review".to_string())),
+            config_phrase: env::var("config_phrase").unwrap_or("flows config".to_string()),
+            help_phrase: env::var("help_phrase").unwrap_or("flows help".to_string()),
+            skip_files: parse_globs(skip_files.split(',')),
+            file_filters: parse_file_filters(env::var("file_filters").unwrap_or_default().split(',')),
+            llm_provider: env::var("llm_provider").unwrap_or("flows".to_string()),
+            llm_api_version: env::var("llm_api_version").unwrap_or("2024-02-01".to_string()),
+            llm_model_name: env::var("llm_model_name").unwrap_or("yicoder9b".to_string()),
+            llm_ctx_size: env::var("llm_ctx_size").unwrap_or("126000".to_string()).parse::<u32>().unwrap_or(0),
+            model_routes: parse_model_routes(&env::var("model_routes").unwrap_or_default()),
+            ensemble: parse_ensemble(&env::var("ensemble").unwrap_or_default()),
+            ensemble_mode: env::var("ensemble_mode").unwrap_or("agree".to_string()),
+            system_prompt: env::var("system_prompt").unwrap_or("You are an experienced software developer. You will review a source code file and its patch related to the subject of \"{title}\". Please be concise and accurate. Read through all the files mentioned in the PR and generate your responses.".to_string()),
+            output_language: env::var("output_language").unwrap_or_default(),
+            persona: env::var("persona").unwrap_or_default(),
+            personas: HashMap::new(),
+            language_prompts: parse_language_prompts(&env::var("language_prompts").unwrap_or_default()),
+            guidelines: env::var("guidelines").unwrap_or_default(),
+            style_guide: env::var("style_guide").unwrap_or("true".to_string()) == "true",
+            rag: env::var("rag").unwrap_or("false".to_string()) == "true",
+            embedding_model: env::var("embedding_model").unwrap_or("text-embedding-3-small".to_string()),
+            rag_max_files: env::var("rag_max_files").unwrap_or("100".to_string()).parse::<usize>().unwrap_or(100),
+            rag_tokens: env::var("rag_tokens").unwrap_or("1000".to_string()).parse::<usize>().unwrap_or(1000),
+            review_mode: env::var("review_mode").unwrap_or("full".to_string()),
+            diff_full_context: env::var("diff_full_context").unwrap_or("false".to_string()) == "true",
+            inline_comments: env::var("inline_comments").unwrap_or("false".to_string()) == "true",
+            max_concurrency: env::var("max_concurrency").unwrap_or("3".to_string()).parse::<usize>().unwrap_or(3),
+            progress_updates: env::var("progress_updates").unwrap_or("true".to_string()) == "true",
+            max_chunks: env::var("max_chunks").unwrap_or("4".to_string()).parse::<usize>().unwrap_or(4),
+            summary: env::var("summary").unwrap_or("true".to_string()) == "true",
+            max_attempts: env::var("max_attempts").unwrap_or("3".to_string()).parse::<u32>().unwrap_or(3),
+            max_files: env::var("max_files").unwrap_or("50".to_string()).parse::<usize>().unwrap_or(50),
+            max_bytes: env::var("max_bytes").unwrap_or("0".to_string()).parse::<usize>().unwrap_or(0),
+            max_tokens: env::var("max_tokens").unwrap_or("0".to_string()).parse::<usize>().unwrap_or(0),
+            fast_path_lines: env::var("fast_path_lines").unwrap_or("10".to_string()).parse::<u64>().unwrap_or(10),
+            time_budget: env::var("time_budget").unwrap_or("0".to_string()).parse::<u64>().unwrap_or(0),
+            incremental: env::var("incremental").unwrap_or("true".to_string()) == "true",
+            github_reserve: env::var("github_reserve").unwrap_or("100".to_string()).parse::<usize>().unwrap_or(100),
+            rate_limit_wait: env::var("rate_limit_wait").unwrap_or("60".to_string()).parse::<u64>().unwrap_or(60),
+            debounce_secs: env::var("debounce_secs").unwrap_or("30".to_string()).parse::<u64>().unwrap_or(30),
+            check_run: env::var("check_run").unwrap_or("false".to_string()) == "true",
+            fail_on_blockers: env::var("fail_on_blockers").unwrap_or("false".to_string()) == "true",
+            findings_json: env::var("findings_json").unwrap_or_default(),
+            sarif_upload: env::var("sarif_upload").unwrap_or("false".to_string()) == "true",
+            suggestions: env::var("suggestions").unwrap_or("true".to_string()) == "true",
+            secret_scan: env::var("secret_scan").unwrap_or("true".to_string()) == "true",
+            skip_generated: env::var("skip_generated").unwrap_or("true".to_string()) == "true",
+            dependency_analysis: env::var("dependency_analysis").unwrap_or("true".to_string()) == "true",
+            risky_dependencies: parse_list(&env::var("risky_dependencies").unwrap_or_default()),
+            vulnerability_lookup: env::var("vulnerability_lookup").unwrap_or("true".to_string()) == "true",
+            license_allowlist: parse_list(&env::var("license_allowlist").unwrap_or_default()),
+            review_description: env::var("review_description").unwrap_or("false".to_string()) == "true",
+            commit_hygiene: env::var("commit_hygiene").unwrap_or("false".to_string()) == "true",
+            draft_description: env::var("draft_description").unwrap_or("off".to_string()),
+            max_behind: env::var("max_behind").unwrap_or("50".to_string()).parse::<u64>().unwrap_or(50),
+            related_prs: env::var("related_prs").unwrap_or("true".to_string()) == "true",
+            issue_triage: env::var("issue_triage").unwrap_or("false".to_string()) == "true",
+            skip_drafts: env::var("skip_drafts").unwrap_or("true".to_string()) == "true",
+            reactions: env::var("reactions").unwrap_or("true".to_string()) == "true",
+            dry_run: env::var("dry_run").unwrap_or("false".to_string()) == "true",
+            min_confidence: env::var("min_confidence").unwrap_or("0".to_string()).parse::<u8>().unwrap_or(0),
+            self_critique: env::var("self_critique").unwrap_or("false".to_string()) == "true",
+            security_review: env::var("security_review").unwrap_or("false".to_string()) == "true",
+            missing_tests: env::var("missing_tests").unwrap_or("false".to_string()) == "true",
+            migration_review: env::var("migration_review").unwrap_or("true".to_string()) == "true",
+            migration_files: parse_globs(env::var("migration_files").unwrap_or(MIGRATION_FILES.to_string()).split(',')),
+            iac_review: env::var("iac_review").unwrap_or("true".to_string()) == "true",
+            dockerfile_review: env::var("dockerfile_review").unwrap_or("true".to_string()) == "true",
+            workflow_review: env::var("workflow_review").unwrap_or("true".to_string()) == "true",
+            api_changes: env::var("api_changes").unwrap_or("true".to_string()) == "true",
+            owned_by: parse_list(&env::var("owned_by").unwrap_or_default()),
+            linked_issues: env::var("linked_issues").unwrap_or("false".to_string()) == "true",
+            group_by_package: env::var("group_by_package").unwrap_or("false".to_string()) == "true",
+            suggest_reviewers: env::var("suggest_reviewers").unwrap_or("false".to_string()) == "true",
+            request_reviewers: env::var("request_reviewers").unwrap_or("false".to_string()) == "true",
+            review_verdict: env::var("review_verdict").unwrap_or("false".to_string()) == "true",
+            request_changes_on: Severity::parse(&env::var("request_changes_on").unwrap_or_default()).unwrap_or(Severity::Blocker),
+            labels: env::var("labels").unwrap_or("false".to_string()) == "true",
+            needs_work_label: env::var("needs_work_label").unwrap_or("needs-work".to_string()),
+            security_label: env::var("security_label").unwrap_or("security-concern".to_string()),
+            lgtm_label: env::var("lgtm_label").unwrap_or("lgtm-bot".to_string()),
+            size_labels: env::var("size_labels").unwrap_or("false".to_string()) == "true",
+            skip_bots: env::var("skip_bots").unwrap_or("false".to_string()) == "true",
+            skip_forks: env::var("skip_forks").unwrap_or("false".to_string()) == "true",
+            allowed_authors: parse_list(&env::var("allowed_authors").unwrap_or_default()),
+            denied_authors: parse_list(&env::var("denied_authors").unwrap_or_default()),
+            base_branches: parse_list(&env::var("base_branches").unwrap_or("{default},release/*".to_string())),
+            notify: parse_list(&env::var("notify").unwrap_or_default()),
+            slack_notify: env::var("slack_notify").unwrap_or("false".to_string()) == "true",
+            notify_channels: parse_notify_channels(&env::var("notify_channels").or(env::var("slack_channels")).unwrap_or_default()),
+            digest_recipients: parse_list(&env::var("digest_recipients").unwrap_or_default()),
+        }
+    }
+}
+
+impl Config {
+    //  The personas of the config override the built-in ones of the same name
+    pub(crate) fn persona(&self, name: &str) -> Option<Persona> {
+        self.personas.get(name).cloned().or_else(|| Persona::builtin(name))
+    }
+
+    //  What to look for in a file of this language, if anything
+    pub(crate) fn language_prompt(&self, path: &str) -> Option<&str> {
+        let ext = path.rsplit('/').next()?.rsplit_once('.')?.1.to_ascii_lowercase();
+        let prompt = match self.language_prompts.get(&ext) {
+            Some(p) => p.as_str(),
+            None => builtin_language_prompt(&ext)?,
+        };
+        Some(prompt).filter(|p| !p.trim().is_empty())
+    }
+
+    pub(crate) fn is_migration(&self, path: &str) -> bool {
+        self.migration_files.iter().any(|p| p.matches(path))
+    }
+
+    //  The chat tools to notify of finished reviews
+    pub(crate) fn chat_tools(&self) -> Vec<String> {
+        let mut tools: Vec<String> = self.notify.iter().map(|t| t.to_lowercase()).collect();
+        if self.slack_notify && !tools.iter().any(|t| t == "slack") {
+            tools.push("slack".to_string());
+        }
+        tools
+    }
+
+    pub(crate) fn has_sender_policy(&self) -> bool {
+        self.skip_bots || self.skip_forks || !self.allowed_authors.is_empty() || !self.denied_authors.is_empty()
+    }
+
+    //  Why the sender policy skips a PR, if it does
+    pub(crate) fn skip_reason(&self, author: &str, author_is_bot: bool, from_fork: bool) -> Option<&'static str> {
+        let listed = |authors: &[String]| authors.iter().any(|a| a.eq_ignore_ascii_case(author));
+        if listed(&self.denied_authors) {
+            Some("the author is in denied_authors")
+        } else if !self.allowed_authors.is_empty() && !listed(&self.allowed_authors) {
+            Some("the author is not in allowed_authors")
+        } else if self.skip_bots && author_is_bot {
+            Some("the author is a bot")
+        } else if self.skip_forks && from_fork {
+            Some("the PR comes from a fork")
+        } else {
+            None
+        }
+    }
+
+    //  Whether the PRs into the base branch are reviewed. When the default branch of the repo is not
+    //  known, e.g. in some GitLab and Gitea events, "{default}" matches any branch.
+    pub(crate) fn reviews_base(&self, base: &str, default_branch: Option<&str>) -> bool {
+        self.base_branches.is_empty()
+            || self.base_branches.iter().any(|branch| match branch.as_str() {
+                "{default}" => default_branch.is_none_or(|d| d == base),
+                pattern => glob::Pattern::new(pattern).is_ok_and(|p| p.matches(base)),
+            })
+    }
+
+    //  The settings that shape the reviews, for the config command. The prompts, the endpoints and
+    //  the chat webhooks are left out, as they may be long or secret.
+    pub(crate) fn overview(&self) -> Vec<(&'static str, String)> {
+        let list = |items: &[String]| match items.is_empty() {
+            true => "none".to_string(),
+            false => items.join(", "),
+        };
+        let globs = |patterns: &[glob::Pattern]| list(&patterns.iter().map(|p| p.as_str().to_string()).collect::<Vec<_>>());
+        let filters: Vec<String> = self.file_filters.iter().map(|f| format!("{}{}", if f.exclude { "!" } else { "" }, f.pattern.as_str())).collect();
+        let limit = |n: usize| match n {
+            0 => "no limit".to_string(),
+            n => n.to_string(),
+        };
+        let mut settings = vec![
+            ("trigger_phrase", self.trigger_phrase.clone()),
+            ("trigger_aliases", list(&self.trigger_aliases)),
+            ("llm_model_name", format!("{} ({}, {} tokens)", self.llm_model_name, self.llm_provider, self.llm_ctx_size)),
+            ("model_routes", self.model_routes.len().to_string()),
+            ("ensemble", match &self.ensemble {
+                Some(route) => format!("{} on {} ({})", route.llm_model_name, globs(&route.files), self.ensemble_mode),
+                None => "off".to_string(),
+            }),
+            ("persona", if self.persona.is_empty() { "default".to_string() } else { self.persona.clone() }),
+            ("output_language", if self.output_language.is_empty() { "English".to_string() } else { self.output_language.clone() }),
+            ("review_mode", self.review_mode.clone()),
+            ("skip_files", globs(&self.skip_files)),
+            ("file_filters", list(&filters)),
+            ("max_files", self.max_files.to_string()),
+            ("max_bytes", limit(self.max_bytes)),
+            ("max_tokens", limit(self.max_tokens)),
+            ("time_budget", limit(self.time_budget as usize)),
+            ("min_confidence", self.min_confidence.to_string()),
+            ("fast_path_lines", self.fast_path_lines.to_string()),
+            ("inline_comments", self.inline_comments.to_string()),
+            ("check_run", self.check_run.to_string()),
+            ("review_verdict", format!("{} (request changes on {})", self.review_verdict, self.request_changes_on.as_str())),
+            ("labels", self.labels.to_string()),
+            ("skip_drafts", self.skip_drafts.to_string()),
+            ("skip_bots", self.skip_bots.to_string()),
+            ("skip_forks", self.skip_forks.to_string()),
+            ("allowed_authors", list(&self.allowed_authors)),
+            ("denied_authors", list(&self.denied_authors)),
+            ("base_branches", list(&self.base_branches)),
+            ("owned_by", list(&self.owned_by)),
+            ("reactions", self.reactions.to_string()),
+            ("dry_run", self.dry_run.to_string()),
+        ];
+        // The optional passes and sections of the review
+        for (name, on) in [
+            ("self_critique", self.self_critique),
+            ("security_review", self.security_review),
+            ("missing_tests", self.missing_tests),
+            ("migration_review", self.migration_review),
+            ("iac_review", self.iac_review),
+            ("dockerfile_review", self.dockerfile_review),
+            ("workflow_review", self.workflow_review),
+            ("api_changes", self.api_changes),
+            ("secret_scan", self.secret_scan),
+            ("dependency_analysis", self.dependency_analysis),
+            ("vulnerability_lookup", self.vulnerability_lookup),
+            ("review_description", self.review_description),
+            ("commit_hygiene", self.commit_hygiene),
+            ("linked_issues", self.linked_issues),
+            ("related_prs", self.related_prs),
+            ("rag", self.rag),
+        ] {
+            settings.push((name, on.to_string()));
+        }
+        settings
+    }
+
+    //  A file is reviewed when it is not skipped, matches an include pattern (if there are any),
+    //  and matches no exclude pattern.
+    pub(crate) fn should_review(&self, path: &str) -> bool {
+        if self.skip_files.iter().any(|p| p.matches(path)) {
+            return false;
+        }
+        let mut includes = self.file_filters.iter().filter(|f| !f.exclude).peekable();
+        let included = includes.peek().is_none() || includes.any(|f| f.pattern.matches(path));
+        included && !self.file_filters.iter().any(|f| f.exclude && f.pattern.matches(path))
+    }
+}
+
+//  A reviewer persona: how the LLM reviews, and which findings are worth reporting
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Persona {
+    //  "{title}" is replaced with the PR title
+    pub(crate) system_prompt: String,
+    #[serde(default = "Persona::default_min_severity")]
+    pub(crate) min_severity: Severity,
+}
+
+impl Persona {
+    fn default_min_severity() -> Severity {
+        Severity::Nit
+    }
+
+    //  The personas that every repo can use
+    fn builtin(name: &str) -> Option<Persona> {
+        let (system_prompt, min_severity) = match name {
+            "security" => (
+                "You are a strict application security reviewer. You will review a source code file and its patch related to the subject of \"{title}\". Focus on vulnerabilities, input validation, secrets, authentication and access control, and ignore style. Please be concise and accurate.",
+                Severity::Major,
+            ),
+            "mentor" => (
+                "You are a friendly mentor reviewing a PR from a first-time contributor, related to the subject of \"{title}\". Point out what is done well, explain the reason behind each suggestion, and keep a welcoming tone. Please be concise and accurate.",
+                Severity::Minor,
+            ),
+            "performance" => (
+                "You are a performance specialist. You will review a source code file and its patch related to the subject of \"{title}\". Focus on algorithmic complexity, allocations and copies, blocking calls, and database query patterns. Please be concise and accurate.",
+                Severity::Minor,
+            ),
+            _ => return None,
+        };
+        Some(Persona { system_prompt: system_prompt.to_string(), min_severity })
+    }
+}
+
+//  The name of a language given by its ISO 639-1 code, or "" for English. Other names are taken
+//  as they are.
+pub(crate) fn language_name(language: &str) -> &str {
+    match language.to_ascii_lowercase().as_str() {
+        "en" => "",
+        "zh" | "zh-cn" => "Simplified Chinese",
+        "zh-tw" => "Traditional Chinese",
+        "ja" => "Japanese",
+        "ko" => "Korean",
+        "es" => "Spanish",
+        "fr" => "French",
+        "de" => "German",
+        "pt" => "Portuguese",
+        "ru" => "Russian",
+        "it" => "Italian",
+        "hi" => "Hindi",
+        _ => language.trim(),
+    }
+}
+
+//  The built-in templates of what to look for in the files of each language
+fn builtin_language_prompt(ext: &str) -> Option<&'static str> {
+    let prompt = match ext {
+        "rs" => "This is a Rust file. Pay special attention to borrow checker workarounds such as needless clones, `unsafe` blocks and their safety invariants, and code that can panic, such as `unwrap()`, `expect()`, indexing and integer overflow.",
+        "py" => "This is a Python file. Pay special attention to missing or wrong type hints, exception handling that is too broad or swallows errors, mutable default arguments, and resources that are not closed.",
+        "sql" => "This is a SQL file. Pay special attention to SQL injection through string concatenation, queries and joins that would need an index, missing transactions, and migrations that lock large tables.",
+        "go" => "This is a Go file. Pay special attention to ignored errors, goroutine leaks, data races, and deferred calls in loops.",
+        "js" | "jsx" | "ts" | "tsx" | "mjs" | "cjs" => "This is a JavaScript or TypeScript file. Pay special attention to unhandled promise rejections, `any` types, `==` comparisons, and user input that reaches the DOM or a shell.",
+        "c" | "h" | "cc" | "cpp" | "cxx" | "hpp" => "This is a C or C++ file. Pay special attention to memory safety: buffer overflows, use after free, leaks, uninitialized memory, and undefined behavior.",
+        "java" | "kt" => "This is a JVM file. Pay special attention to null handling, resources that are not closed, exceptions that are swallowed, and thread safety.",
+        _ => return None,
+    };
+    Some(prompt)
+}
+
+//  The language_prompts env var holds the templates as a JSON or YAML map from extension to prompt
+fn parse_language_prompts(prompts: &str) -> HashMap<String, String> {
+    if prompts.trim().is_empty() {
+        return HashMap::new();
+    }
+    serde_yaml::from_str(prompts).unwrap_or_else(|e| {
+        log::error!("Cannot parse language_prompts: {}", e);
+        HashMap::new()
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ModelRoute {
+    #[serde(deserialize_with = "deserialize_globs")]
+    pub(crate) files: Vec<glob::Pattern>,
+    pub(crate) llm_model_name: String,
+    //  Defaults to the flow's llm_api_endpoint
+    pub(crate) llm_api_endpoint: Option<String>,
+    //  The name of the flow env var that holds the API key for llm_api_endpoint
+    pub(crate) llm_api_key_env: Option<String>,
+    pub(crate) llm_provider: Option<String>,
+    pub(crate) llm_ctx_size: Option<u32>,
+}
+
+//  The model_routes env var holds the routes as a JSON or YAML list
+fn parse_model_routes(routes: &str) -> Vec<ModelRoute> {
+    if routes.trim().is_empty() {
+        return Vec::new();
+    }
+    serde_yaml::from_str(routes).unwrap_or_else(|e| {
+        log::error!("Cannot parse model_routes: {}", e);
+        Vec::new()
+    })
+}
+
+//  The routes and the ensemble model of the flow config, whose endpoints and API key names can be
+//  trusted, unlike the ones of the repo config files
+pub(crate) fn flow_routes() -> Vec<ModelRoute> {
+    let mut routes = parse_model_routes(&env::var("model_routes").unwrap_or_default());
+    routes.extend(parse_ensemble(&env::var("ensemble").unwrap_or_default()));
+    routes
+}
+
+//  The ensemble env var holds the second model as a JSON or YAML object, like a route
+fn parse_ensemble(ensemble: &str) -> Option<ModelRoute> {
+    if ensemble.trim().is_empty() {
+        return None;
+    }
+    serde_yaml::from_str(ensemble).unwrap_or_else(|e| {
+        log::error!("Cannot parse ensemble: {}", e);
+        None
+    })
+}
+
+//  A chat channel for the PRs with one of the labels, or with a changed file matching one of the patterns
+#[derive(Debug, Deserialize)]
+pub(crate) struct NotifyChannel {
+    //  The chat tool of the channel
+    #[serde(default = "NotifyChannel::default_chat")]
+    pub(crate) chat: String,
+    #[serde(default)]
+    pub(crate) labels: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_globs")]
+    pub(crate) files: Vec<glob::Pattern>,
+    //  The name of the flow env var that holds the incoming webhook URL of the channel
+    pub(crate) webhook_env: String,
+}
+
+impl NotifyChannel {
+    fn default_chat() -> String {
+        "slack".to_string()
+    }
+}
+
+//  The notify_channels env var holds the routes as a JSON or YAML list
+fn parse_notify_channels(channels: &str) -> Vec<NotifyChannel> {
+    if channels.trim().is_empty() {
+        return Vec::new();
+    }
+    serde_yaml::from_str(channels).unwrap_or_else(|e| {
+        log::error!("Cannot parse notify_channels: {}", e);
+        Vec::new()
+    })
+}
+
+#[derive(Debug)]
+struct FileFilter {
+    pattern: glob::Pattern,
+    exclude: bool,
+}
+
+//  Read CONFIG_PATH from the target repo. A missing or broken file leaves the env defaults in place.
+pub(crate) async fn load_config(github: &dyn GithubApi, owner: &str, repo: &str) -> Config {
+    load_config_with(github, owner, repo, &BTreeMap::new()).await
+}
+
+//  The config with the settings that maintainers changed for a PR on top of the config files
+pub(crate) async fn load_config_with(github: &dyn GithubApi, owner: &str, repo: &str, settings: &BTreeMap<String, String>) -> Config {
+    // Org-wide settings from the owner's .github repo, overridden by the target repo's own file
+    let mut merged = serde_yaml::Value::Null;
+    for config_repo in [ORG_CONFIG_REPO, repo] {
+        if let Some(value) = read_config_file(github, owner, config_repo).await {
+            merge_yaml(&mut merged, value);
+        }
+    }
+    if !settings.is_empty() {
+        merge_yaml(&mut merged, settings_yaml(settings.iter().filter(|(key, _)| RUNTIME_SETTINGS.contains(&key.as_str()))));
+    }
+    if merged.is_null() {
+        return Config::default();
+    }
+    match serde_yaml::from_value(merged) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Cannot parse {}: {}", CONFIG_PATH, e);
+            Config::default()
+        }
+    }
+}
+
+async fn read_config_file(github: &dyn GithubApi, owner: &str, repo: &str) -> Option<serde_yaml::Value> {
+    let text = match get_content(github, owner, repo, CONFIG_PATH).await {
+        Ok(text) => text,
+        Err(_) => {
+            log::debug!("No {} found in {}/{}", CONFIG_PATH, owner, repo);
+            return None;
+        }
+    };
+    if text.trim().is_empty() {
+        return None;
+    }
+    match serde_yaml::from_str(&text) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            log::error!("Cannot parse {} in {}/{}: {}", CONFIG_PATH, owner, repo, e);
+            None
+        }
+    }
+}
+
+//  Check a setting of `flows config set`: one of RUNTIME_SETTINGS, with a value of the right type
+pub(crate) fn check_setting(key: &str, value: &str) -> Result<(), String> {
+    if !RUNTIME_SETTINGS.contains(&key) {
+        return Err(format!("`{}` cannot be changed by command", key));
+    }
+    serde_yaml::from_value::<Config>(settings_yaml([(&key.to_string(), &value.to_string())]))
+        .map(|_| ())
+        .map_err(|e| format!("`{}` is not a valid value of `{}`: {}", value, key, e))
+}
+
+//  The settings as a YAML mapping, with the values typed as in the config file, e.g. `20` is a number
+fn settings_yaml<'a>(settings: impl IntoIterator<Item = (&'a String, &'a String)>) -> serde_yaml::Value {
+    let mapping = settings
+        .into_iter()
+        .map(|(key, value)| {
+            let value = serde_yaml::from_str(value).unwrap_or_else(|_| serde_yaml::Value::from(value.as_str()));
+            (serde_yaml::Value::from(key.as_str()), value)
+        })
+        .collect();
+    serde_yaml::Value::Mapping(mapping)
+}
+
+//  Settings in `overrides` replace the ones in `base`, key by key
+fn merge_yaml(base: &mut serde_yaml::Value, overrides: serde_yaml::Value) {
+    match (base, overrides) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overrides)) => {
+            for (key, value) in overrides {
+                base.insert(key, value);
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+//  The guidelines setting, followed by the guidelines file of the target repo. Both are read from the
+//  default branch, so that a PR cannot change how it is reviewed.
+pub(crate) async fn load_guidelines(github: &dyn GithubApi, owner: &str, repo: &str, config: &Config) -> String {
+    let mut guidelines = vec![config.guidelines.trim().to_string()];
+    for path in GUIDELINES_PATHS {
+        if let Ok(text) = get_content(github, owner, repo, path).await {
+            guidelines.push(text.trim().to_string());
+            break;
+        }
+    }
+    guidelines.retain(|g| !g.is_empty());
+    truncate_tokens(&guidelines.join("\n\n"), MAX_GUIDELINES_TOKENS)
+}
+
+//  The style docs of the target repo on the default branch, as their path, blob SHA and text
+pub(crate) async fn load_style_docs(github: &dyn GithubApi, owner: &str, repo: &str) -> Vec<(String, String, String)> {
+    let mut docs = Vec::new();
+    for paths in STYLE_DOCS {
+        for path in paths {
+            if let Ok((sha, text)) = get_file(github, owner, repo, path).await {
+                docs.push((path.to_string(), sha, text));
+                break;
+            }
+        }
+    }
+    docs
+}
+
+//  The files marked as `linguist-generated` in the .gitattributes of the target repo
+pub(crate) async fn load_generated_patterns(github: &dyn GithubApi, owner: &str, repo: &str) -> Vec<glob::Pattern> {
+    match get_content(github, owner, repo, ".gitattributes").await {
+        Ok(text) => parse_generated_attributes(&text),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn parse_generated_attributes(gitattributes: &str) -> Vec<glob::Pattern> {
+    let mut patterns = Vec::new();
+    for line in gitattributes.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let Some(pattern) = fields.next() else {
+            continue;
+        };
+        if !fields.any(|a| a == "linguist-generated" || a == "linguist-generated=true") {
+            continue;
+        }
+        // A pattern without a slash matches at any level, and a directory matches all files in it
+        let mut pattern = pattern.trim_start_matches('/').to_string();
+        if pattern.ends_with('/') {
+            pattern.push_str("**");
+        }
+        if !pattern.contains('/') {
+            patterns.extend(parse_globs([format!("**/{}", pattern).as_str()]));
+        }
+        patterns.extend(parse_globs([pattern.as_str()]));
+    }
+    patterns
+}
+
+//  Lockfiles, minified bundles, generated code, binaries, and files marked as generated
+pub(crate) fn is_generated(path: &str, generated: &[glob::Pattern]) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let extension = name.rsplit_once('.').map(|(_, e)| e.to_lowercase()).unwrap_or_default();
+    GENERATED_FILES.iter().any(|p| p.matches(path))
+        || BINARY_EXTENSIONS.contains(&extension.as_str())
+        || generated.iter().any(|p| p.matches(path))
+}
+
+//  A comma separated list
+fn parse_list(s: &str) -> Vec<String> {
+    s.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect()
+}
+
+pub(crate) fn parse_globs<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Vec<glob::Pattern> {
+    patterns
+        .into_iter()
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .filter_map(|p| match glob::Pattern::new(p) {
+            Ok(g) => Some(g),
+            Err(e) => {
+                log::error!("Invalid glob pattern {}: {}", p, e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn parse_file_filters<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Vec<FileFilter> {
+    patterns
+        .into_iter()
+        .map(str::trim)
+        .filter_map(|p| {
+            let (exclude, p) = match p.strip_prefix('!') {
+                Some(p) => (true, p),
+                None => (false, p),
+            };
+            let pattern = parse_globs([p]).pop()?;
+            Some(FileFilter { pattern, exclude })
+        })
+        .collect()
+}
+
+fn deserialize_file_filters<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<FileFilter>, D::Error> {
+    let patterns = Vec::<String>::deserialize(d)?;
+    Ok(parse_file_filters(patterns.iter().map(String::as_str)))
+}
+
+//  A text, or a list of guidelines that becomes a markdown list
+fn deserialize_guidelines<'de, D: serde::Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Guidelines {
+        Text(String),
+        List(Vec<String>),
+    }
+    Ok(match Guidelines::deserialize(d)? {
+        Guidelines::Text(text) => text,
+        Guidelines::List(items) => items.iter().map(|g| format!("- {}", g)).collect::<Vec<String>>().join("\n"),
+    })
+}
+
+fn deserialize_globs<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<glob::Pattern>, D::Error> {
+    let patterns = Vec::<String>::deserialize(d)?;
+    Ok(parse_globs(patterns.iter().map(String::as_str)))
+}
+
+//  Test files by the naming conventions of common languages and frameworks
+pub(crate) fn is_test_file(path: &str) -> bool {
+    TEST_FILES.iter().any(|p| p.matches(path))
+}
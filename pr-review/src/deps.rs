@@ -0,0 +1,605 @@
+//  The dependency changes of a PR, read from the patches of the manifests and the lockfiles of
+//  Cargo, npm, Yarn, pnpm and Go. The added, updated and removed dependencies are listed in the
+//  review comment, with the major version bumps and the known risky packages, instead of sending
+//  the raw files to the LLM, which is no good at reading lockfiles.
+
+use crate::config::Config;
+use crate::github::url_escape;
+use crate::llm::{is_transient_llm_error, post_json};
+use crate::render::truncate_bytes;
+use crate::retry;
+use crate::trace::{debug, error, warn};
+use futures::{StreamExt, stream};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::env;
+
+lazy_static! {
+    static ref TOML_SECTION: Regex = Regex::new(r"^\s*\[+\s*([^\]]+?)\s*\]+").unwrap();
+    static ref TOML_KEY: Regex = Regex::new(r#"^\s*"?([A-Za-z0-9_-]+)"?(?:\.[A-Za-z0-9_-]+)?\s*=\s*(.*)$"#).unwrap();
+    static ref TOML_VERSION: Regex = Regex::new(r#"\bversion\s*=\s*"([^"]+)""#).unwrap();
+    //  A section of a single dependency, e.g. `[dependencies.serde]`
+    static ref TOML_DEPENDENCY_SECTION: Regex = Regex::new(r"dependencies\.([A-Za-z0-9_-]+)$").unwrap();
+    static ref JSON_OBJECT: Regex = Regex::new(r#"^\s*"([^"]*)"\s*:\s*\{"#).unwrap();
+    static ref JSON_STRING: Regex = Regex::new(r#"^\s*"([^"]+)"\s*:\s*"([^"]*)""#).unwrap();
+    static ref YARN_VERSION: Regex = Regex::new(r#"^\s+version:?\s+"?([^"\s]+)"?"#).unwrap();
+    //  A package of pnpm-lock.yaml, e.g. `/foo@1.2.3:`, `'@scope/foo@1.2.3(react@18.2.0)':` or `/foo/1.2.3:`
+    static ref PNPM_PACKAGE: Regex =
+        Regex::new(r"^\s+'?/?((?:@[^/@\s]+/)?[^/@\s']+)[@/](\d[^(:/'\s]*)[^:]*'?:\s*$").unwrap();
+    static ref GO_REQUIRE: Regex = Regex::new(r"^\s*(?:require\s+)?([A-Za-z0-9._~-]+\.[A-Za-z0-9._~/-]+)\s+(v\d\S*)").unwrap();
+    static ref GO_SUM: Regex = Regex::new(r"^(\S+)\s+(v[^\s/]+)(?:/go\.mod)?\s+h1:").unwrap();
+    //  The major version in the path of a Go module, e.g. `/v2`
+    static ref GO_MAJOR: Regex = Regex::new(r"/v\d+$").unwrap();
+    //  A version requirement rather than any other string value
+    static ref VERSION_LIKE: Regex = Regex::new(r"^(?:[\^~=<>v*]|\d|workspace:|npm:)").unwrap();
+}
+
+//  The OSV API, unless the osv_api_url env var points to a mirror
+const OSV_API: &str = "https://api.osv.dev";
+
+//  The deps.dev API, for the licenses of the packages, unless the deps_dev_api_url env var points
+//  to a mirror
+const DEPS_DEV_API: &str = "https://api.deps.dev";
+
+//  The max number of dependency versions looked up in each database in one review
+const MAX_LOOKUPS: usize = 100;
+
+//  Packages that are deprecated, unmaintained or were compromised, and why
+const RISKY_PACKAGES: &[(&str, &str)] = &[
+    ("request", "deprecated and unmaintained"),
+    ("event-stream", "shipped malware in 3.3.6"),
+    ("flatmap-stream", "malware"),
+    ("node-ipc", "shipped protestware that deletes files"),
+    ("colors", "sabotaged by its author in 1.4.1"),
+    ("faker", "sabotaged by its author in 6.6.6"),
+    ("ua-parser-js", "had compromised releases"),
+    ("coa", "had compromised releases"),
+    ("rc", "had compromised releases"),
+    ("left-pad", "unpublished once, breaking its dependents"),
+    ("atty", "unmaintained and unsound"),
+    ("failure", "deprecated and unmaintained"),
+    ("yaml-rust", "unmaintained"),
+    ("serde_yaml", "deprecated and unmaintained"),
+    ("github.com/dgrijalva/jwt-go", "unmaintained, with a known vulnerability"),
+    ("github.com/satori/go.uuid", "unmaintained, with a known vulnerability"),
+    ("github.com/golang/protobuf", "deprecated in favor of google.golang.org/protobuf"),
+];
+
+//  The keys of a Cargo.toml that have a version-like value without being dependencies
+const CARGO_PACKAGE_KEYS: &[&str] = &["version", "edition", "rust-version", "resolver"];
+
+//  The keys of a package.json that have a version-like value without being dependencies
+const NPM_PACKAGE_KEYS: &[&str] = &["version", "node", "npm", "yarn", "pnpm"];
+
+//  The objects of a package-lock.json that hold packages rather than being one
+const NPM_LOCK_OBJECTS: &[&str] = &[
+    "packages", "dependencies", "devDependencies", "peerDependencies", "optionalDependencies", "requires", "engines", "bin", "funding",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    CargoToml,
+    CargoLock,
+    PackageJson,
+    PackageLock,
+    YarnLock,
+    PnpmLock,
+    GoMod,
+    GoSum,
+}
+
+impl Format {
+    fn of(path: &str) -> Option<Format> {
+        match path.rsplit('/').next().unwrap_or(path) {
+            "Cargo.toml" => Some(Format::CargoToml),
+            "Cargo.lock" => Some(Format::CargoLock),
+            "package.json" => Some(Format::PackageJson),
+            "package-lock.json" | "npm-shrinkwrap.json" => Some(Format::PackageLock),
+            "yarn.lock" => Some(Format::YarnLock),
+            "pnpm-lock.yaml" => Some(Format::PnpmLock),
+            "go.mod" => Some(Format::GoMod),
+            "go.sum" => Some(Format::GoSum),
+            _ => None,
+        }
+    }
+
+    fn is_lockfile(self) -> bool {
+        !matches!(self, Format::CargoToml | Format::PackageJson | Format::GoMod)
+    }
+
+    //  The ecosystem of the packages in the OSV database
+    fn ecosystem(self) -> &'static str {
+        match self {
+            Format::CargoToml | Format::CargoLock => "crates.io",
+            Format::PackageJson | Format::PackageLock | Format::YarnLock | Format::PnpmLock => "npm",
+            Format::GoMod | Format::GoSum => "Go",
+        }
+    }
+}
+
+pub(crate) enum Change {
+    Added(String),
+    Updated(String, String),
+    Removed(String),
+    //  The version is the same, but the other settings of the dependency changed, e.g. its features
+    Changed,
+}
+
+pub(crate) struct DependencyChange {
+    pub(crate) name: String,
+    //  The package at the new version, e.g. the Go module of a new major version
+    package: String,
+    pub(crate) change: Change,
+    pub(crate) major: bool,
+    //  Why the package is risky, when it is added or updated
+    pub(crate) risk: Option<String>,
+}
+
+//  The dependency changes of one manifest or lockfile
+pub(crate) struct DependencyFile {
+    pub(crate) path: String,
+    format: Format,
+    pub(crate) changes: Vec<DependencyChange>,
+    //  Whether every changed line is a dependency, so that the file needs no LLM review
+    pub(crate) complete: bool,
+    //  GitHub leaves out the patch of large diffs
+    pub(crate) too_large: bool,
+}
+
+//  What a changed line is
+enum Line {
+    //  A dependency, and its version when the line has one
+    Dependency(String, Option<String>),
+    //  A section header, a bracket or a blank line
+    Structure,
+    Other,
+}
+
+//  What is known of the lines above in the same hunk
+#[derive(Default)]
+struct Parser {
+    section: Option<String>,
+    name: Option<String>,
+}
+
+impl Parser {
+    fn parse(&mut self, format: Format, line: &str) -> Line {
+        if line.trim().is_empty() {
+            return Line::Structure;
+        }
+        match format {
+            Format::CargoToml => self.cargo_toml(line),
+            Format::CargoLock => {
+                match TOML_KEY.captures(line) {
+                    Some(cap) if &cap[1] == "name" => self.name = Some(unquote(&cap[2])),
+                    Some(cap) if &cap[1] == "version" => {
+                        if let Some(name) = &self.name {
+                            return Line::Dependency(name.clone(), Some(unquote(&cap[2])));
+                        }
+                    }
+                    _ => {}
+                }
+                Line::Structure
+            }
+            Format::PackageJson => self.package_json(line),
+            Format::PackageLock => {
+                if let Some(cap) = JSON_OBJECT.captures(line) {
+                    let key = cap[1].rsplit("node_modules/").next().unwrap_or_default();
+                    self.name = Some(key.to_string()).filter(|k| !k.is_empty() && !NPM_LOCK_OBJECTS.contains(&k.as_str()));
+                } else if let Some(cap) = JSON_STRING.captures(line).filter(|cap| &cap[1] == "version") {
+                    if let Some(name) = &self.name {
+                        return Line::Dependency(name.clone(), Some(cap[2].to_string()));
+                    }
+                }
+                Line::Structure
+            }
+            Format::YarnLock => {
+                if !line.starts_with([' ', '#']) && line.trim_end().ends_with(':') {
+                    let spec = line.split(',').next().unwrap_or_default().trim().trim_end_matches(':').trim_matches('"');
+                    self.name = spec.rfind('@').filter(|&i| i > 0).map(|i| spec[..i].to_string());
+                } else if let Some(cap) = YARN_VERSION.captures(line) {
+                    if let Some(name) = &self.name {
+                        return Line::Dependency(name.clone(), Some(cap[1].to_string()));
+                    }
+                }
+                Line::Structure
+            }
+            Format::PnpmLock => match PNPM_PACKAGE.captures(line) {
+                Some(cap) => Line::Dependency(cap[1].to_string(), Some(cap[2].to_string())),
+                None => Line::Structure,
+            },
+            Format::GoMod => match GO_REQUIRE.captures(line) {
+                Some(cap) if !line.contains("=>") => Line::Dependency(cap[1].to_string(), Some(cap[2].to_string())),
+                _ if matches!(line.trim(), "require (" | ")") => Line::Structure,
+                _ => Line::Other,
+            },
+            Format::GoSum => match GO_SUM.captures(line) {
+                Some(cap) => Line::Dependency(cap[1].to_string(), Some(cap[2].to_string())),
+                None => Line::Structure,
+            },
+        }
+    }
+
+    fn cargo_toml(&mut self, line: &str) -> Line {
+        if let Some(cap) = TOML_SECTION.captures(line) {
+            self.section = Some(cap[1].to_string());
+            return Line::Structure;
+        }
+        let Some(cap) = TOML_KEY.captures(line) else {
+            return Line::Other;
+        };
+        let (key, value) = (&cap[1], cap[2].trim());
+        let section = self.section.as_deref();
+        if let Some(name) = section.and_then(|s| TOML_DEPENDENCY_SECTION.captures(s)).map(|c| c[1].to_string()) {
+            let version = Some(unquote(value)).filter(|_| key == "version");
+            return Line::Dependency(name, version);
+        }
+        let dependency = match section {
+            Some(section) => section.ends_with("dependencies"),
+            // The hunk starts below its section header: only the lines that look like dependencies are
+            None => {
+                !CARGO_PACKAGE_KEYS.contains(&key)
+                    && ((value.starts_with('"') && VERSION_LIKE.is_match(&unquote(value)))
+                        || (value.starts_with('{') && ["version", "path", "git", "workspace"].iter().any(|k| value.contains(k))))
+            }
+        };
+        if !dependency {
+            return Line::Other;
+        }
+        let version = match value.starts_with('{') {
+            true => TOML_VERSION.captures(value).map(|cap| cap[1].to_string()),
+            false => Some(unquote(value)).filter(|v| VERSION_LIKE.is_match(v)),
+        };
+        Line::Dependency(key.to_string(), version)
+    }
+
+    fn package_json(&mut self, line: &str) -> Line {
+        if let Some(cap) = JSON_OBJECT.captures(line) {
+            self.section = Some(cap[1].to_string());
+            return Line::Structure;
+        }
+        if matches!(line.trim(), "{" | "}" | "}," | "]" | "],") {
+            self.section = None;
+            return Line::Structure;
+        }
+        let Some(cap) = JSON_STRING.captures(line) else {
+            return Line::Other;
+        };
+        let dependency = match self.section.as_deref() {
+            Some(section) => section.ends_with("ependencies") || matches!(section, "overrides" | "resolutions"),
+            None => !NPM_PACKAGE_KEYS.contains(&&cap[1]) && VERSION_LIKE.is_match(&cap[2]),
+        };
+        match dependency {
+            true => Line::Dependency(cap[1].to_string(), Some(cap[2].to_string())),
+            false => Line::Other,
+        }
+    }
+}
+
+//  A string value of TOML
+fn unquote(value: &str) -> String {
+    value.trim().trim_end_matches(',').trim_matches('"').to_string()
+}
+
+//  A Go module without its major version, so that a major bump reads as an update
+fn go_module(path: &str) -> String {
+    GO_MAJOR.replace(path, "").into_owned()
+}
+
+//  The dependency versions that are added, and updated too when `updated`, as (OSV ecosystem,
+//  package, version). When a lockfile of an ecosystem changed, its exact versions are used,
+//  otherwise the lowest versions that the manifests allow. The first MAX_LOOKUPS are kept.
+fn new_versions(files: &[DependencyFile], updated: bool) -> Vec<(&'static str, String, String)> {
+    let mut versions: Vec<(&'static str, String, String)> = Vec::new();
+    for f in files {
+        let ecosystem = f.format.ecosystem();
+        if !f.format.is_lockfile() && files.iter().any(|l| l.format.is_lockfile() && l.format.ecosystem() == ecosystem) {
+            continue;
+        }
+        for c in &f.changes {
+            let version = match &c.change {
+                Change::Added(version) => lowest_version(version),
+                Change::Updated(_, version) if updated => lowest_version(version),
+                _ => None,
+            };
+            if let Some(version) = version {
+                let version = (ecosystem, c.package.clone(), version);
+                if !versions.contains(&version) {
+                    versions.push(version);
+                }
+            }
+        }
+    }
+    if versions.len() > MAX_LOOKUPS {
+        warn!("deps", "Looking up {} of {} dependency versions", MAX_LOOKUPS, versions.len());
+        versions.truncate(MAX_LOOKUPS);
+    }
+    versions
+}
+
+//  A known vulnerability of a dependency version that the PR adds
+pub(crate) struct Advisory {
+    pub(crate) package: String,
+    pub(crate) version: String,
+    //  The OSV id, e.g. RUSTSEC-2024-0001 or GHSA-xxxx-xxxx-xxxx, and its CVE aliases
+    pub(crate) id: String,
+    pub(crate) cves: Vec<String>,
+    pub(crate) summary: String,
+    //  The severity given by the advisory database, e.g. HIGH
+    pub(crate) severity: Option<String>,
+    //  The first version that fixes it, after the added one
+    pub(crate) fixed: Option<String>,
+}
+
+//  The known vulnerabilities of the dependency versions that are added or updated, from the OSV
+//  database, which holds the RustSec, GitHub, npm and Go advisories
+pub(crate) async fn advisories(files: &[DependencyFile], config: &Config) -> Vec<Advisory> {
+    let queries = new_versions(files, true);
+    let url = format!("{}/v1/query", env::var("osv_api_url").unwrap_or(OSV_API.to_string()).trim_end_matches('/'));
+    let results: Vec<Vec<Advisory>> = stream::iter(queries)
+        .map(|(ecosystem, package, version)| {
+            let url = &url;
+            async move {
+                let body = serde_json::json!({ "package": { "name": package, "ecosystem": ecosystem }, "version": version });
+                match retry("osv_query", config.max_attempts, |e: &String| is_transient_llm_error(e), || post_json(url, &[], &body)).await {
+                    Ok(res) => parse_advisories(&res, &package, &version),
+                    Err(error) => {
+                        error!("deps", "Cannot look up the advisories of {} {}: {}", package, version, error);
+                        Vec::new()
+                    }
+                }
+            }
+        })
+        .buffered(config.max_concurrency.max(1))
+        .collect()
+        .await;
+    results.into_iter().flatten().collect()
+}
+
+fn parse_advisories(res: &serde_json::Value, package: &str, version: &str) -> Vec<Advisory> {
+    let Some(vulns) = res["vulns"].as_array() else {
+        return Vec::new();
+    };
+    let mut advisories = Vec::new();
+    for vuln in vulns {
+        let fixed = vuln["affected"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|a| a["package"]["name"].as_str() == Some(package))
+            .flat_map(|a| a["ranges"].as_array().into_iter().flatten())
+            .flat_map(|r| r["events"].as_array().into_iter().flatten())
+            .filter_map(|e| e["fixed"].as_str())
+            .filter(|fixed| version_key(fixed) > version_key(version))
+            .min_by_key(|fixed| version_key(fixed))
+            .map(str::to_string);
+        let summary = vuln["summary"].as_str().or_else(|| vuln["details"].as_str().and_then(|d| d.lines().next())).unwrap_or_default();
+        advisories.push(Advisory {
+            package: package.to_string(),
+            version: version.to_string(),
+            id: vuln["id"].as_str().unwrap_or_default().to_string(),
+            cves: vuln["aliases"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|a| a.as_str())
+                .filter(|a| a.starts_with("CVE-"))
+                .map(str::to_string)
+                .collect(),
+            summary: truncate_bytes(summary, 200).to_string(),
+            severity: vuln["database_specific"]["severity"].as_str().map(str::to_string),
+            fixed,
+        });
+    }
+    advisories
+}
+
+//  A new dependency whose license is not in the license_allowlist
+pub(crate) struct LicenseIssue {
+    pub(crate) package: String,
+    pub(crate) version: String,
+    //  The SPDX expressions of its licenses, none when the package declares no license
+    pub(crate) licenses: Vec<String>,
+}
+
+//  The dependencies that are added with licenses that are not in the license_allowlist, by the
+//  licenses that deps.dev gives for their versions. Packages unknown to deps.dev are left out.
+pub(crate) async fn license_issues(files: &[DependencyFile], config: &Config) -> Vec<LicenseIssue> {
+    let base = env::var("deps_dev_api_url").unwrap_or(DEPS_DEV_API.to_string());
+    let base = base.trim_end_matches('/');
+    let results: Vec<Option<LicenseIssue>> = stream::iter(new_versions(files, false))
+        .map(|(ecosystem, package, version)| async move {
+            let (system, version) = match ecosystem {
+                "crates.io" => ("cargo", version),
+                "Go" => ("go", format!("v{}", version)),
+                _ => ("npm", version),
+            };
+            let url = format!("{}/v3/systems/{}/packages/{}/versions/{}", base, system, url_escape(&package), url_escape(&version));
+            let res = match retry("deps_dev_version", config.max_attempts, |e: &String| is_transient_llm_error(e), || get_json(&url)).await {
+                Ok(res) => res,
+                Err(error) if error.starts_with("404") => {
+                    debug!("deps", "No license of {} {}: not in deps.dev", package, version);
+                    return None;
+                }
+                Err(error) => {
+                    error!("deps", "Cannot get the license of {} {}: {}", package, version, error);
+                    return None;
+                }
+            };
+            let licenses: Vec<String> = res["licenses"].as_array().into_iter().flatten().filter_map(|l| l.as_str()).map(str::to_string).collect();
+            let allowed = !licenses.is_empty() && licenses.iter().all(|l| license_allowed(l, &config.license_allowlist));
+            (!allowed).then_some(LicenseIssue { package, version, licenses })
+        })
+        .buffered(config.max_concurrency.max(1))
+        .collect()
+        .await;
+    results.into_iter().flatten().collect()
+}
+
+//  Whether an SPDX license expression, e.g. `(MIT OR Apache-2.0) AND BSD-3-Clause`, can be met
+//  with the licenses of the allowlist. The exceptions of `WITH` are not checked.
+fn license_allowed(expression: &str, allowlist: &[String]) -> bool {
+    let spaced = expression.replace('(', " ( ").replace(')', " ) ");
+    let mut tokens = spaced.split_whitespace().peekable();
+    let allowed = |id: &str| allowlist.iter().any(|a| a.eq_ignore_ascii_case(id));
+    // expression = term (OR term)*, term = factor (AND factor)*, factor = id [WITH exception] | ( expression )
+    fn expr<'a>(tokens: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>, allowed: &dyn Fn(&str) -> bool) -> bool {
+        let mut any = term(tokens, allowed);
+        while tokens.next_if(|t| t.eq_ignore_ascii_case("OR")).is_some() {
+            any |= term(tokens, allowed);
+        }
+        any
+    }
+    fn term<'a>(tokens: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>, allowed: &dyn Fn(&str) -> bool) -> bool {
+        let mut all = factor(tokens, allowed);
+        while tokens.next_if(|t| t.eq_ignore_ascii_case("AND")).is_some() {
+            all &= factor(tokens, allowed);
+        }
+        all
+    }
+    fn factor<'a>(tokens: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>, allowed: &dyn Fn(&str) -> bool) -> bool {
+        match tokens.next() {
+            Some("(") => {
+                let ok = expr(tokens, allowed);
+                tokens.next_if_eq(&")");
+                ok
+            }
+            Some(id) => {
+                if tokens.next_if(|t| t.eq_ignore_ascii_case("WITH")).is_some() {
+                    tokens.next();
+                }
+                allowed(id)
+            }
+            None => false,
+        }
+    }
+    expr(&mut tokens, &allowed)
+}
+
+async fn get_json(url: &str) -> Result<serde_json::Value, String> {
+    let res = reqwest::Client::new().get(url).send().await.map_err(|e| e.to_string())?;
+    let status = res.status();
+    let bytes = res.bytes().await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("{}: {}", status, String::from_utf8_lossy(&bytes)));
+    }
+    serde_json::from_slice(&bytes).map_err(|e| e.to_string())
+}
+
+//  The lowest version that a version or a version requirement allows, e.g. `1.2.0` for `^1.2`,
+//  with three parts as the OSV database expects. None for ranges and tags.
+fn lowest_version(version: &str) -> Option<String> {
+    let version = version.trim_start_matches(['=', '^', '~', 'v', ' ']);
+    let (core, suffix) = version.find(['-', '+']).map_or((version, ""), |i| version.split_at(i));
+    let parts: Vec<&str> = core.split('.').collect();
+    if parts.len() > 3 || parts.iter().any(|p| p.is_empty() || !p.chars().all(|c| c.is_ascii_digit())) {
+        return None;
+    }
+    let padding = [".0"; 2][..3 - parts.len()].concat();
+    Some(format!("{}{}{}", core, padding, suffix))
+}
+
+//  The numbers of a version, to compare versions
+fn version_key(version: &str) -> Vec<u64> {
+    version
+        .trim_start_matches(|c: char| !c.is_ascii_digit())
+        .split(['.', '-', '+'])
+        .map_while(|p| p.parse::<u64>().ok())
+        .collect()
+}
+
+//  Whether a changed file is a manifest or a lockfile that `analyze` can read
+pub(crate) fn is_dependency_file(path: &str) -> bool {
+    Format::of(path).is_some()
+}
+
+//  The dependency changes in the patch of a manifest or a lockfile. `risky` are more packages to
+//  flag, on top of the built-in ones.
+pub(crate) fn analyze(path: &str, patch: Option<&str>, risky: &[String]) -> DependencyFile {
+    let Some(format) = Format::of(path) else {
+        return DependencyFile { path: path.to_string(), format: Format::CargoToml, changes: Vec::new(), complete: false, too_large: false };
+    };
+    let Some(patch) = patch.filter(|p| !p.is_empty()) else {
+        return DependencyFile { path: path.to_string(), format, changes: Vec::new(), complete: format.is_lockfile(), too_large: true };
+    };
+    // The versions removed and added of each dependency, and its package at the new version.
+    // The Go modules of several major versions are one dependency.
+    let mut versions: BTreeMap<String, (Vec<String>, Vec<String>, String)> = BTreeMap::new();
+    let mut complete = true;
+    let mut parser = Parser::default();
+    for line in patch.lines() {
+        if let Some(header) = line.strip_prefix("@@") {
+            // Git may show the section of the hunk after its header, e.g. `@@ -1,3 +1,4 @@ [dependencies]`
+            let section = header.split_once("@@").map(|(_, s)| s.trim()).unwrap_or_default();
+            parser = Parser::default();
+            parser.parse(format, section);
+            continue;
+        }
+        let (sign, content) = match line.chars().next() {
+            Some(sign @ ('+' | '-' | ' ')) => (sign, &line[1..]),
+            _ => continue,
+        };
+        let parsed = parser.parse(format, content);
+        if sign == ' ' {
+            continue;
+        }
+        match parsed {
+            Line::Dependency(name, version) => {
+                let key = match format {
+                    Format::GoMod | Format::GoSum => go_module(&name),
+                    _ => name.clone(),
+                };
+                let (removed, added, package) = versions.entry(key).or_insert_with(|| (Vec::new(), Vec::new(), name.clone()));
+                let side = match sign {
+                    '-' => removed,
+                    _ => {
+                        *package = name;
+                        added
+                    }
+                };
+                if let Some(version) = version.filter(|v| !side.contains(v)) {
+                    side.push(version);
+                }
+            }
+            Line::Structure => {}
+            Line::Other => complete = false,
+        }
+    }
+    let mut changes = Vec::new();
+    for (name, (removed, added, package)) in versions {
+        let change = match (removed.last(), added.last()) {
+            (None, Some(new)) => Change::Added(new.clone()),
+            (Some(old), None) => Change::Removed(old.clone()),
+            (Some(old), Some(new)) if old != new => Change::Updated(old.clone(), new.clone()),
+            // The same version on both sides of a lockfile, e.g. a moved entry
+            _ if format.is_lockfile() => continue,
+            _ => Change::Changed,
+        };
+        let major = match &change {
+            Change::Updated(old, new) => matches!((major_version(old), major_version(new)), (Some(old), Some(new)) if new > old),
+            _ => false,
+        };
+        let risk = match change {
+            Change::Removed(_) => None,
+            _ => RISKY_PACKAGES
+                .iter()
+                .find(|(package, _)| *package == name)
+                .map(|(_, why)| why.to_string())
+                .or_else(|| risky.contains(&name).then(|| "listed in risky_dependencies".to_string())),
+        };
+        changes.push(DependencyChange { name, package, change, major, risk });
+    }
+    DependencyFile { path: path.to_string(), format, changes, complete, too_large: false }
+}
+
+//  The part of a version that changes on breaking changes, by semver: the major version, or the
+//  minor one of 0.x versions
+fn major_version(version: &str) -> Option<(u64, u64)> {
+    let version = version.trim_start_matches(|c: char| !c.is_ascii_digit());
+    let mut parts = version.split('.').map(|p| p.chars().take_while(char::is_ascii_digit).collect::<String>().parse::<u64>().ok());
+    match (parts.next().flatten()?, parts.next().flatten()) {
+        (0, minor) => Some((0, minor.unwrap_or(0))),
+        (major, _) => Some((major, 0)),
+    }
+}
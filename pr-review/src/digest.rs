@@ -0,0 +1,219 @@
+//  The daily digest of the reviews of some repos, emailed to their maintainers. The reviews are
+//  read back from the review comments of the PRs, so the digest needs no storage of its own.
+
+use crate::config::load_config;
+use crate::github::{GithubApi, from_json};
+use crate::pipeline::Severity;
+use crate::render::REVIEW_MARKER;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::env;
+
+lazy_static! {
+    //  The heading of a severity group in the findings of a review, see render_findings
+    static ref SEVERITY_COUNT: Regex = Regex::new(r"(?m)^#### (blocker|major|minor|nit) \((\d+)\)$").unwrap();
+}
+
+//  The period of a digest
+pub const DIGEST_HOURS: i64 = 24;
+
+//  The heading of the failed file reviews, see render_failures
+const FAILED_REVIEWS: &str = "### Failed reviews";
+
+const SENDGRID_API: &str = "https://api.sendgrid.com/v3/mail/send";
+
+//  Where the digests are sent
+#[async_trait(?Send)]
+pub trait MailSink {
+    async fn send(&self, to: &[String], subject: &str, body: &str) -> Result<(), String>;
+}
+
+//  The mail send API of SendGrid
+pub struct SendGrid {
+    pub api_key: String,
+    pub from: String,
+}
+
+#[async_trait(?Send)]
+impl MailSink for SendGrid {
+    async fn send(&self, to: &[String], subject: &str, body: &str) -> Result<(), String> {
+        let to: Vec<serde_json::Value> = to.iter().map(|email| serde_json::json!({ "email": email })).collect();
+        let mail = serde_json::json!({
+            "personalizations": [{ "to": to }],
+            "from": { "email": self.from },
+            "subject": subject,
+            "content": [{ "type": "text/plain", "value": body }],
+        });
+        let res = reqwest::Client::new()
+            .post(SENDGRID_API)
+            .bearer_auth(&self.api_key)
+            .header("Content-Type", "application/json")
+            .body(mail.to_string())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = res.status();
+        match status.is_success() {
+            true => Ok(()),
+            false => Err(format!("{}: {}", status, res.text().await.unwrap_or_default())),
+        }
+    }
+}
+
+//  An SMTP server, with STARTTLS
+#[cfg(feature = "smtp")]
+pub struct Smtp {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub from: String,
+}
+
+#[cfg(feature = "smtp")]
+#[async_trait(?Send)]
+impl MailSink for Smtp {
+    async fn send(&self, to: &[String], subject: &str, body: &str) -> Result<(), String> {
+        use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+        let mut mail = Message::builder().from(self.from.parse().map_err(|e| format!("Invalid sender: {}", e))?).subject(subject);
+        for email in to {
+            mail = mail.to(email.parse().map_err(|e| format!("Invalid recipient {}: {}", email, e))?);
+        }
+        let mail = mail.body(body.to_string()).map_err(|e| e.to_string())?;
+        let credentials = lettre::transport::smtp::authentication::Credentials::new(self.username.clone(), self.password.clone());
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.host)
+            .map_err(|e| e.to_string())?
+            .port(self.port)
+            .credentials(credentials)
+            .build();
+        transport.send(mail).await.map(|_| ()).map_err(|e| e.to_string())
+    }
+}
+
+//  The mail sink of the digest_sink env var: "sendgrid", or "smtp" when built with the smtp feature
+pub fn mail_sink() -> Result<Box<dyn MailSink>, String> {
+    let from = env::var("digest_from").map_err(|_| "digest_from is not set".to_string())?;
+    match env::var("digest_sink").unwrap_or("sendgrid".to_string()).as_str() {
+        "sendgrid" => {
+            let api_key = env::var("sendgrid_api_key").map_err(|_| "sendgrid_api_key is not set".to_string())?;
+            Ok(Box::new(SendGrid { api_key, from }))
+        }
+        #[cfg(feature = "smtp")]
+        "smtp" => Ok(Box::new(Smtp {
+            host: env::var("smtp_host").map_err(|_| "smtp_host is not set".to_string())?,
+            port: env::var("smtp_port").unwrap_or("587".to_string()).parse().map_err(|_| "Invalid smtp_port".to_string())?,
+            username: env::var("smtp_username").unwrap_or_default(),
+            password: env::var("smtp_password").unwrap_or_default(),
+            from,
+        })),
+        sink => Err(format!("Unknown digest_sink: {}", sink)),
+    }
+}
+
+//  A PR reviewed in the period of the digest
+struct ReviewedPr {
+    number: u64,
+    title: String,
+    //  The review comment
+    url: String,
+    //  The number of findings of each severity, from the most serious
+    findings: [u64; 4],
+    failed: bool,
+}
+
+//  Email a digest of the reviews of the last DIGEST_HOURS to the digest_recipients of each repo,
+//  "owner/repo". Repos with the same recipients share one email.
+pub async fn send_digest(github: &dyn GithubApi, repos: &[String], sink: &dyn MailSink) -> Result<(), String> {
+    let since = Utc::now() - Duration::hours(DIGEST_HOURS);
+    let mut digests: BTreeMap<Vec<String>, Vec<String>> = BTreeMap::new();
+    for full_name in repos {
+        let Some((owner, repo)) = full_name.split_once('/') else {
+            return Err(format!("Not a repo: {}", full_name));
+        };
+        let config = load_config(github, owner, repo).await;
+        if config.digest_recipients.is_empty() {
+            log::info!("No digest_recipients for {}", full_name);
+            continue;
+        }
+        let reviewed = match reviewed_prs(github, owner, repo, since).await {
+            Ok(reviewed) => reviewed,
+            Err(error) => {
+                log::error!("Cannot get the reviews of {}: {}", full_name, error);
+                continue;
+            }
+        };
+        let mut recipients = config.digest_recipients.clone();
+        recipients.sort();
+        digests.entry(recipients).or_default().push(render_repo(full_name, &reviewed));
+    }
+    for (recipients, sections) in digests {
+        let subject = format!("Code reviews of the last {} hours", DIGEST_HOURS);
+        let body = format!("{}\n\nSent by https://github.com/flows-network/github-pr-review\n", sections.join("\n\n"));
+        sink.send(&recipients, &subject, &body).await.map_err(|e| format!("Cannot send the digest to {}: {}", recipients.join(", "), e))?;
+        log::info!("Sent the digest to {}", recipients.join(", "));
+    }
+    Ok(())
+}
+
+//  The PRs with a review comment updated since `since`
+async fn reviewed_prs(github: &dyn GithubApi, owner: &str, repo: &str, since: DateTime<Utc>) -> octocrab::Result<Vec<ReviewedPr>> {
+    let route = format!("/repos/{}/{}/pulls?state=all&sort=updated&direction=desc&per_page=100", owner, repo);
+    let pulls: Vec<serde_json::Value> = github.get(&route).await.and_then(from_json)?;
+    let mut reviewed = Vec::new();
+    for pr in pulls.iter().filter(|pr| updated_since(pr, since)) {
+        let number = pr["number"].as_u64().unwrap_or_default();
+        let route = format!("/repos/{}/{}/issues/{}/comments?per_page=100", owner, repo, number);
+        let comments: Vec<serde_json::Value> = github.get(&route).await.and_then(from_json)?;
+        let review = comments.iter().rev().find(|c| c["body"].as_str().is_some_and(|b| b.contains(REVIEW_MARKER)));
+        let Some(review) = review.filter(|c| updated_since(c, since)) else {
+            continue;
+        };
+        let body = review["body"].as_str().unwrap_or_default();
+        let mut findings = [0; 4];
+        for cap in SEVERITY_COUNT.captures_iter(body) {
+            if let Some(i) = Severity::parse(&cap[1]).and_then(|s| Severity::ALL.iter().position(|&a| a == s)) {
+                findings[i] = cap[2].parse().unwrap_or_default();
+            }
+        }
+        reviewed.push(ReviewedPr {
+            number,
+            title: pr["title"].as_str().unwrap_or_default().to_string(),
+            url: review["html_url"].as_str().unwrap_or_default().to_string(),
+            findings,
+            failed: body.contains(FAILED_REVIEWS),
+        });
+    }
+    Ok(reviewed)
+}
+
+//  Whether a PR or a comment was updated since `since`
+fn updated_since(item: &serde_json::Value, since: DateTime<Utc>) -> bool {
+    let updated = item["updated_at"].as_str().and_then(|t| DateTime::parse_from_rfc3339(t).ok());
+    updated.is_some_and(|t| t >= since)
+}
+
+//  The section of a repo in the digest
+fn render_repo(full_name: &str, reviewed: &[ReviewedPr]) -> String {
+    let mut out = format!("{}: {} PRs reviewed\n", full_name, reviewed.len());
+    out.push_str(&"=".repeat(out.len() - 1));
+    out.push('\n');
+    for pr in reviewed {
+        let counts: Vec<String> = Severity::ALL
+            .iter()
+            .zip(pr.findings)
+            .filter(|(_, n)| *n > 0)
+            .map(|(s, n)| format!("{} {}", n, s.as_str()))
+            .collect();
+        let findings = match counts.is_empty() {
+            true => "no findings".to_string(),
+            false => counts.join(", "),
+        };
+        let failed = if pr.failed { ", some files could not be reviewed" } else { "" };
+        out.push_str(&format!("\n#{} {}\n  {}{}\n  {}\n", pr.number, pr.title, findings, failed, pr.url));
+    }
+    out
+}
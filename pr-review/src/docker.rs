@@ -0,0 +1,135 @@
+//  A checklist of the best practices of Dockerfiles, checked on the whole file after the change:
+//  a non-root user, pinned base images, no secrets in build args, and multi-stage builds. The
+//  checks that fail are findings, so they also get inline comments and count for the verdict.
+
+use crate::pipeline::{Finding, Severity};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref INSTRUCTION: Regex = Regex::new(r"^\s*([A-Za-z]+)\s+(.*)$").unwrap();
+    //  The image of a FROM, and the name of its stage
+    static ref FROM: Regex = Regex::new(r"(?i)^(?:--\S+\s+)*(\S+)(?:\s+AS\s+(\S+))?").unwrap();
+    static ref SECRET_NAME: Regex =
+        Regex::new(r"(?i)secret|token|passw(?:or)?d|api_?key|private_?key|credential|access_?key").unwrap();
+    //  The commands that compile or bundle code, which a multi-stage build keeps out of the final image
+    static ref BUILD_COMMAND: Regex = Regex::new(
+        r"\b(?:cargo build|go build|npm run build|yarn build|pnpm build|mvn|gradle|make|gcc|g\+\+|dotnet publish|build-essential)\b"
+    ).unwrap();
+}
+
+//  An instruction of a Dockerfile, with its continuation lines joined, and its first line
+struct Instruction {
+    line: u64,
+    keyword: String,
+    args: String,
+}
+
+pub(crate) fn is_dockerfile(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name == "Dockerfile" || name == "Containerfile" || name.starts_with("Dockerfile.") || name.ends_with(".dockerfile")
+}
+
+fn instructions(text: &str) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut current: Option<(u64, String)> = None;
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if current.is_none() && (trimmed.is_empty() || trimmed.starts_with('#')) {
+            continue;
+        }
+        let (start, mut joined) = current.take().unwrap_or((i as u64 + 1, String::new()));
+        match trimmed.strip_suffix('\\') {
+            Some(part) => {
+                joined.push_str(part);
+                joined.push(' ');
+                current = Some((start, joined));
+            }
+            None => {
+                joined.push_str(trimmed);
+                if let Some(cap) = INSTRUCTION.captures(&joined) {
+                    instructions.push(Instruction { line: start, keyword: cap[1].to_uppercase(), args: cap[2].trim().to_string() });
+                }
+            }
+        }
+    }
+    instructions
+}
+
+//  The checklist of a Dockerfile, in markdown, and the findings of the checks that fail. Each
+//  failed item is followed by its findings in the `[severity] LINE <n>:` form of the reviews.
+pub(crate) fn dockerfile_checklist(path: &str, text: &str) -> (String, Vec<Finding>) {
+    let instructions = instructions(text);
+    let finding = |line: u64, severity: Severity, message: String| Finding {
+        path: path.to_string(),
+        line: Some(line),
+        end_line: None,
+        severity,
+        message,
+        suggestion: None,
+        confidence: None,
+    };
+    let mut stages: Vec<String> = Vec::new();
+    let mut unpinned = Vec::new();
+    for i in instructions.iter().filter(|i| i.keyword == "FROM") {
+        let Some(cap) = FROM.captures(&i.args) else {
+            continue;
+        };
+        let image = &cap[1];
+        // Earlier stages, scratch and images set by build args are not pulled from a registry as is
+        let local = image == "scratch" || image.contains('$') || stages.iter().any(|s| s.eq_ignore_ascii_case(image));
+        let name = image.rsplit('/').next().unwrap_or(image);
+        let tag = name.split_once(':').map(|(_, tag)| tag);
+        if !local && !image.contains('@') && matches!(tag, None | Some("latest")) {
+            unpinned.push(finding(i.line, Severity::Minor, format!("The base image `{}` is not pinned to a version or a digest, so builds are not reproducible.", image)));
+        }
+        if let Some(stage) = cap.get(2) {
+            stages.push(stage.as_str().to_string());
+        }
+    }
+
+    let last_from = instructions.iter().rposition(|i| i.keyword == "FROM");
+    let final_stage = &instructions[last_from.unwrap_or(0)..];
+    let user = final_stage.iter().rev().find(|i| i.keyword == "USER");
+    let root = match user {
+        Some(u) if matches!(u.args.split(':').next(), Some("root" | "0")) => {
+            vec![finding(u.line, Severity::Major, "The final image runs as root. Switch to a non-root user.".to_string())]
+        }
+        Some(_) => Vec::new(),
+        None => match final_stage.first() {
+            Some(from) => vec![finding(from.line, Severity::Major, "The final image runs as root: there is no `USER` instruction with a non-root user.".to_string())],
+            None => Vec::new(),
+        },
+    };
+
+    let mut secrets = Vec::new();
+    for i in instructions.iter().filter(|i| i.keyword == "ARG" || i.keyword == "ENV") {
+        let name = i.args.split(['=', ' ']).next().unwrap_or_default();
+        if SECRET_NAME.is_match(name) {
+            secrets.push(finding(i.line, Severity::Major, format!("`{} {}` is kept in the image history. Pass secrets with `RUN --mount=type=secret` instead.", i.keyword, name)));
+        }
+    }
+
+    let stage_count = instructions.iter().filter(|i| i.keyword == "FROM").count();
+    let build = instructions.iter().find(|i| i.keyword == "RUN" && BUILD_COMMAND.is_match(&i.args));
+    let single_stage = match build {
+        Some(run) if stage_count == 1 => vec![finding(run.line, Severity::Minor, "The image is built in a single stage with build tools. A multi-stage build keeps the compilers and the sources out of the final image.".to_string())],
+        _ => Vec::new(),
+    };
+
+    let mut out = String::new();
+    let mut findings = Vec::new();
+    for (item, failed) in [
+        ("Runs as a non-root user", root),
+        ("Base images are pinned", unpinned),
+        ("No secrets in build args or env vars", secrets),
+        ("Multi-stage build keeps build tools out of the final image", single_stage),
+    ] {
+        out.push_str(&format!("- [{}] {}\n", if failed.is_empty() { "x" } else { " " }, item));
+        for f in &failed {
+            out.push_str(&format!("  [{}] LINE {}: {}\n", f.severity.as_str(), f.line.unwrap_or_default(), f.message));
+        }
+        findings.extend(failed);
+    }
+    (out, findings)
+}
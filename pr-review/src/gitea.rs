@@ -0,0 +1,450 @@
+//  Gitea and Forgejo, behind the same GitHub API abstraction as the rest of the review. Their API
+//  and webhooks are close to GitHub's: the routes of the review mostly go through as they are,
+//  and the answers and events are reshaped where the models of GitHub need more.
+
+use crate::github::{url_escape, url_unescape, GithubApi};
+use async_trait::async_trait;
+use octocrab::models::webhook_events::WebhookEvent;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+//  The page size of the lists of the Gitea API, which caps it at 50 by default
+const PAGE_SIZE: usize = 50;
+
+//  The REST API (v1) of a Gitea or Forgejo server
+pub struct GiteaApi {
+    client: reqwest::Client,
+    //  The API root, e.g. https://gitea.com/api/v1
+    url: String,
+    token: String,
+    //  The repo, commit and context of the statuses created for check runs, by id
+    statuses: RefCell<HashMap<u64, (String, String, String)>>,
+}
+
+impl GiteaApi {
+    pub fn new(url: &str, token: &str) -> Self {
+        GiteaApi {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            statuses: RefCell::new(HashMap::new()),
+        }
+    }
+
+    async fn send(&self, method: reqwest::Method, route: &str, body: Option<&serde_json::Value>) -> octocrab::Result<String> {
+        let mut request = self.client.request(method, format!("{}{}", self.url, route)).header("Authorization", format!("token {}", self.token));
+        if let Some(body) = body {
+            request = request.header("Content-Type", "application/json").body(body.to_string());
+        }
+        let response = request.send().await.map_err(|source| octocrab::Error::Service {
+            source: Box::new(source),
+            backtrace: snafu::GenerateImplicitData::generate(),
+        })?;
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        if status.is_success() {
+            return Ok(text);
+        }
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap_or_default();
+        Err(gitea_error(value["message"].as_str().unwrap_or(status.as_str())))
+    }
+
+    //  A call to the API, with the route relative to the API root
+    pub async fn request(&self, method: reqwest::Method, route: &str, body: Option<&serde_json::Value>) -> octocrab::Result<serde_json::Value> {
+        let text = self.send(method, route, body).await?;
+        Ok(serde_json::from_str(&text).unwrap_or_default())
+    }
+
+    async fn get_json(&self, route: &str) -> octocrab::Result<serde_json::Value> {
+        self.request(reqwest::Method::GET, route, None).await
+    }
+
+    //  The GitHub routes that need more than a change of parameters
+    async fn get_route(&self, repo: &str, route: &str) -> octocrab::Result<serde_json::Value> {
+        let (path, query) = route.split_once('?').unwrap_or((route, ""));
+        let segments: Vec<&str> = path.split('/').collect();
+        match segments[..] {
+            ["pulls", number] => {
+                let pr = self.get_json(&format!("/repos/{}/pulls/{}", repo, number)).await?;
+                Ok(pull_request(&pr))
+            }
+            // All files come on the first page, as the patches come from one diff of the whole PR
+            ["pulls", _, "files"] if query_param(query, "page").is_some_and(|page| page != "1") => Ok(serde_json::json!([])),
+            ["pulls", number, "files"] => self.pull_files(repo, number).await,
+            // The comparison lists the files of each commit rather than of the whole range
+            ["compare", range] => {
+                let comparison = self.get_json(&format!("/repos/{}/compare/{}", repo, range)).await?;
+                let mut files: Vec<&str> = Vec::new();
+                for file in comparison["commits"].as_array().into_iter().flatten().flat_map(|c| c["files"].as_array().into_iter().flatten()) {
+                    if let Some(name) = file["filename"].as_str().filter(|name| !files.contains(name)) {
+                        files.push(name);
+                    }
+                }
+                let files: Vec<serde_json::Value> = files.iter().map(|name| serde_json::json!({ "filename": name })).collect();
+                Ok(serde_json::json!({ "files": files }))
+            }
+            ["collaborators", login, "permission"] => {
+                let permission = self.get_json(&format!("/repos/{}/collaborators/{}/permission", repo, login)).await?;
+                let permission = match permission["permission"].as_str() {
+                    Some("owner" | "admin") => "admin",
+                    Some("write") => "write",
+                    _ => "read",
+                };
+                Ok(serde_json::json!({ "permission": permission }))
+            }
+            _ => self.get_json(&format!("/repos/{}/{}", repo, paged(route))).await,
+        }
+    }
+
+    //  The changed files of a PR in the shape of the PR files API of GitHub. The file list of Gitea
+    //  has no patches and no blob ids, so they come from the diff of the PR and the contents API.
+    async fn pull_files(&self, repo: &str, number: &str) -> octocrab::Result<serde_json::Value> {
+        let pr = self.get_json(&format!("/repos/{}/pulls/{}", repo, number)).await?;
+        let head = pr["head"]["sha"].as_str().unwrap_or_default();
+        let mut changed = Vec::new();
+        for page in 1..=60 {
+            let next = self.get_json(&format!("/repos/{}/pulls/{}/files?limit={}&page={}", repo, number, PAGE_SIZE, page)).await?;
+            let next = next.as_array().cloned().unwrap_or_default();
+            let last = next.len() < PAGE_SIZE;
+            changed.extend(next);
+            if last {
+                break;
+            }
+        }
+        let diff = self.send(reqwest::Method::GET, &format!("/repos/{}/pulls/{}.diff", repo, number), None).await?;
+        let patches = split_diff(&diff);
+        let mut files = Vec::new();
+        for file in changed {
+            let filename = file["filename"].as_str().unwrap_or_default();
+            let status = match file["status"].as_str() {
+                Some("deleted") => "removed",
+                Some(status @ ("added" | "renamed" | "copied")) => status,
+                _ => "modified",
+            };
+            let sha = match status {
+                "removed" => String::new(),
+                _ => {
+                    let path: Vec<String> = filename.split('/').map(url_escape).collect();
+                    let content = self.get_json(&format!("/repos/{}/contents/{}?ref={}", repo, path.join("/"), head)).await?;
+                    content["sha"].as_str().unwrap_or_default().to_string()
+                }
+            };
+            let url = http_url(file["html_url"].as_str().unwrap_or_default());
+            files.push(serde_json::json!({
+                "sha": sha,
+                "filename": filename,
+                "previous_filename": file["previous_filename"],
+                "status": status,
+                "additions": file["additions"],
+                "deletions": file["deletions"],
+                "changes": file["changes"],
+                "blob_url": url,
+                "raw_url": http_url(file["raw_url"].as_str().unwrap_or_default()),
+                "contents_url": http_url(file["contents_url"].as_str().unwrap_or_default()),
+                "patch": patches.get(filename),
+            }));
+        }
+        Ok(serde_json::json!(files))
+    }
+
+    async fn write_route(&self, method: reqwest::Method, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        let Some((repo, rest)) = split_route(route) else {
+            return Err(gitea_error("Not supported on Gitea"));
+        };
+        let segments: Vec<&str> = rest.split('/').collect();
+        match (method.as_str(), &segments[..]) {
+            // Labels are removed by id
+            ("DELETE", ["issues", number, "labels", label]) => {
+                let labels = self.get_json(&format!("/repos/{}/issues/{}/labels", repo, number)).await?;
+                let name = url_unescape(label);
+                let Some(id) = labels.as_array().into_iter().flatten().find(|l| l["name"] == *name).map(|l| l["id"].clone()) else {
+                    return Ok(serde_json::Value::Null);
+                };
+                self.request(method, &format!("/repos/{}/issues/{}/labels/{}", repo, number, id), None).await
+            }
+            ("POST", ["pulls", number, "reviews"]) => {
+                let event = match body["event"].as_str() {
+                    Some("APPROVE") => "APPROVED",
+                    Some("REQUEST_CHANGES") => "REQUEST_CHANGES",
+                    _ => "COMMENT",
+                };
+                let comments: Vec<serde_json::Value> = body["comments"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|c| serde_json::json!({ "path": c["path"], "body": c["body"], "new_position": c["line"], "old_position": 0 }))
+                    .collect();
+                let review = serde_json::json!({ "event": event, "body": body["body"], "comments": comments });
+                self.request(method, &format!("/repos/{}/pulls/{}/reviews", repo, number), Some(&review)).await
+            }
+            ("PUT", ["pulls", number, "reviews", id, "dismissals"]) => {
+                let route = format!("/repos/{}/pulls/{}/reviews/{}/dismissals", repo, number, id);
+                self.request(reqwest::Method::POST, &route, Some(body)).await
+            }
+            ("POST", ["check-runs"]) => {
+                let sha = body["head_sha"].as_str().unwrap_or_default().to_string();
+                let context = body["name"].as_str().unwrap_or_default().to_string();
+                let status = self.set_status(repo, &sha, &context, "pending", "").await?;
+                let id = status["id"].as_u64().unwrap_or_default();
+                self.statuses.borrow_mut().insert(id, (repo.to_string(), sha, context));
+                Ok(serde_json::json!({ "id": id }))
+            }
+            ("PATCH", ["check-runs", id]) => {
+                let Some((repo, sha, context)) = id.parse().ok().and_then(|id: u64| self.statuses.borrow().get(&id).cloned()) else {
+                    return Err(gitea_error("Unknown check run"));
+                };
+                let state = match body["conclusion"].as_str() {
+                    None => "pending",
+                    Some("success" | "neutral" | "skipped") => "success",
+                    Some("cancelled") => "error",
+                    Some(_) => "failure",
+                };
+                self.set_status(&repo, &sha, &context, state, body["output"]["title"].as_str().unwrap_or_default()).await
+            }
+            _ => {
+                let body = Some(body).filter(|b| !b.is_null());
+                self.request(method, &format!("/repos/{}/{}", repo, rest), body).await
+            }
+        }
+    }
+
+    //  A commit status, which shows next to the commits of the PR like a check run on GitHub
+    async fn set_status(&self, repo: &str, sha: &str, context: &str, state: &str, description: &str) -> octocrab::Result<serde_json::Value> {
+        let description: String = description.chars().take(255).collect();
+        let status = serde_json::json!({ "state": state, "context": context, "description": description });
+        self.request(reqwest::Method::POST, &format!("/repos/{}/statuses/{}", repo, sha), Some(&status)).await
+    }
+}
+
+#[async_trait(?Send)]
+impl GithubApi for GiteaApi {
+    async fn get(&self, route: &str) -> octocrab::Result<serde_json::Value> {
+        match split_route(route) {
+            Some((repo, rest)) => self.get_route(repo, rest).await,
+            None => Err(gitea_error("Not supported on Gitea")),
+        }
+    }
+
+    async fn post(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.write_route(reqwest::Method::POST, route, body).await
+    }
+
+    async fn patch(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.write_route(reqwest::Method::PATCH, route, body).await
+    }
+
+    async fn put(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.write_route(reqwest::Method::PUT, route, body).await
+    }
+
+    async fn delete(&self, route: &str) -> octocrab::Result<()> {
+        self.write_route(reqwest::Method::DELETE, route, &serde_json::Value::Null).await.map(|_| ())
+    }
+}
+
+//  Turn the payload of a Gitea or Forgejo webhook, or of a Gitea Actions run, into the GitHub event
+//  that the review handles. `kind` is the X-Gitea-Event header, or the event name of the run.
+//  Events that the review doesn't handle give None.
+pub fn parse_event(kind: &str, payload: &serde_json::Value) -> Result<Option<WebhookEvent>, String> {
+    let (kind, mut event) = match kind {
+        "pull_request" | "pull_request_target" => {
+            let pr = &payload["pull_request"];
+            // Gitea marks drafts with a prefix of the title, so a PR is ready once the prefix is gone
+            let was_draft = payload["changes"]["title"]["from"].as_str().is_some_and(is_wip);
+            let action = match payload["action"].as_str() {
+                Some("opened") => "opened",
+                Some("reopened") => "reopened",
+                Some("synchronized") => "synchronize",
+                Some("edited") if was_draft && !is_wip(pr["title"].as_str().unwrap_or_default()) => "ready_for_review",
+                _ => return Ok(None),
+            };
+            let event = serde_json::json!({
+                "action": action,
+                "number": pr["number"],
+                "pull_request": pull_request(pr),
+            });
+            ("pull_request", event)
+        }
+        "issue_comment" | "pull_request_comment" => {
+            let issue = &payload["issue"];
+            let action = payload["action"].as_str().unwrap_or_default();
+            if !matches!(action, "created" | "edited") || !(payload["is_pull"] == true || issue["pull_request"].is_object()) {
+                return Ok(None);
+            }
+            let comment = &payload["comment"];
+            let url = http_url(comment["html_url"].as_str().unwrap_or_default());
+            let mut event = serde_json::json!({
+                "action": action,
+                "issue": self::issue(issue),
+                "comment": {
+                    "id": comment["id"],
+                    "node_id": "",
+                    "url": url,
+                    "html_url": url,
+                    "body": comment["body"],
+                    "user": user(&comment["user"]),
+                    "created_at": comment["created_at"].as_str().unwrap_or("1970-01-01T00:00:00Z"),
+                },
+            });
+            if let Some(from) = payload["changes"]["body"]["from"].as_str() {
+                event["changes"] = serde_json::json!({ "body": { "from": from } });
+            }
+            ("issue_comment", event)
+        }
+        _ => return Ok(None),
+    };
+    event["sender"] = user(&payload["sender"]);
+    event["repository"] = repository(&payload["repository"]);
+    let event = WebhookEvent::try_from_header_and_body(kind, &event.to_string()).map_err(|e| format!("Invalid {} event: {}", kind, e))?;
+    Ok(Some(event))
+}
+
+//  The repo and the rest of a GitHub route
+fn split_route(route: &str) -> Option<(&str, &str)> {
+    let rest = route.strip_prefix("/repos/")?;
+    let owner_end = rest.find('/')?;
+    let repo_end = rest[owner_end + 1..].find('/')? + owner_end + 1;
+    Some((&rest[..repo_end], &rest[repo_end + 1..]))
+}
+
+//  Gitea pages its lists with `limit` instead of `per_page`
+fn paged(route: &str) -> String {
+    route.replace("per_page=", "limit=")
+}
+
+//  The patch of each file in the diff of a PR, by the name of the file after the change
+fn split_diff(diff: &str) -> HashMap<String, String> {
+    let mut patches = HashMap::new();
+    let mut current: Option<(String, Vec<&str>)> = None;
+    for line in diff.lines() {
+        if let Some(header) = line.strip_prefix("diff --git ") {
+            if let Some((name, lines)) = current.take() {
+                patches.insert(name, lines.join("\n"));
+            }
+            let name = header.rsplit_once(" b/").map_or(header, |(_, name)| name);
+            current = Some((name.to_string(), Vec::new()));
+            continue;
+        }
+        if let Some((_, lines)) = current.as_mut() {
+            // The hunks start at the first @@ line, after the headers of the file
+            if !lines.is_empty() || line.starts_with("@@") {
+                lines.push(line);
+            }
+        }
+    }
+    if let Some((name, lines)) = current {
+        patches.insert(name, lines.join("\n"));
+    }
+    patches
+}
+
+fn is_wip(title: &str) -> bool {
+    let title = title.trim_start().to_lowercase();
+    title.starts_with("wip:") || title.starts_with("[wip]")
+}
+
+//  A Gitea PR in the shape of a GitHub pull request
+fn pull_request(pr: &serde_json::Value) -> serde_json::Value {
+    let url = http_url(pr["html_url"].as_str().unwrap_or_default());
+    let title = pr["title"].as_str().unwrap_or_default();
+    let branch = |side: &serde_json::Value| serde_json::json!({ "ref": side["ref"], "sha": side["sha"], "repo": repository(&side["repo"]) });
+    serde_json::json!({
+        "url": http_url(pr["url"].as_str().unwrap_or_default()),
+        "html_url": url,
+        "id": pr["id"],
+        "number": pr["number"],
+        "state": pr["state"],
+        "title": title,
+        "body": pr["body"],
+        "draft": pr["draft"].as_bool().unwrap_or(is_wip(title)),
+        "user": user(&pr["user"]),
+        "head": branch(&pr["head"]),
+        "base": branch(&pr["base"]),
+    })
+}
+
+//  A Gitea issue of a PR in the shape of a GitHub issue, for comment events
+fn issue(issue: &serde_json::Value) -> serde_json::Value {
+    let url = http_url(issue["html_url"].as_str().unwrap_or_default());
+    serde_json::json!({
+        "id": issue["id"],
+        "node_id": "",
+        "url": http_url(issue["url"].as_str().unwrap_or_default()),
+        "repository_url": url,
+        "labels_url": url,
+        "comments_url": url,
+        "events_url": url,
+        "html_url": url,
+        "number": issue["number"],
+        "state": issue["state"],
+        "title": issue["title"],
+        "body": issue["body"],
+        "user": user(&issue["user"]),
+        "labels": [],
+        "assignees": [],
+        "author_association": "NONE",
+        "locked": false,
+        "comments": issue["comments"].as_u64().unwrap_or_default(),
+        "created_at": issue["created_at"].as_str().unwrap_or("1970-01-01T00:00:00Z"),
+        "updated_at": issue["updated_at"].as_str().unwrap_or("1970-01-01T00:00:00Z"),
+        "pull_request": { "url": url, "html_url": url, "diff_url": url, "patch_url": url },
+    })
+}
+
+//  A Gitea repo in the shape of a GitHub repository
+fn repository(repo: &serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "id": repo["id"].as_u64().unwrap_or_default(),
+        "name": repo["name"],
+        "full_name": repo["full_name"],
+        "url": http_url(repo["html_url"].as_str().unwrap_or_default()),
+        "owner": user(&repo["owner"]),
+    })
+}
+
+//  A Gitea user in the shape of a GitHub user. Gitea has no bot accounts; the users of Actions
+//  runs and the accounts named like bots are taken as bots.
+fn user(user: &serde_json::Value) -> serde_json::Value {
+    let login = user["login"].as_str().unwrap_or_default();
+    let url = http_url(user["html_url"].as_str().unwrap_or_default());
+    let bot = login.ends_with("-actions") || login.ends_with("-bot") || login.ends_with("[bot]");
+    serde_json::json!({
+        "login": login,
+        "id": user["id"].as_u64().unwrap_or_default(),
+        "node_id": "",
+        "avatar_url": http_url(user["avatar_url"].as_str().unwrap_or_default()),
+        "gravatar_id": "",
+        "url": url,
+        "html_url": url,
+        "followers_url": url,
+        "following_url": url,
+        "gists_url": url,
+        "starred_url": url,
+        "subscriptions_url": url,
+        "organizations_url": url,
+        "repos_url": url,
+        "events_url": url,
+        "received_events_url": url,
+        "type": if bot { "Bot" } else { "User" },
+        "site_admin": user["is_admin"].as_bool().unwrap_or(false),
+    })
+}
+
+//  GitHub models require absolute URLs
+fn http_url(url: &str) -> String {
+    match url.starts_with("http") {
+        true => url.to_string(),
+        false => "https://gitea.com/".to_string(),
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn gitea_error(message: &str) -> octocrab::Error {
+    octocrab::Error::GitHub {
+        source: serde_json::from_value(serde_json::json!({ "message": message })).unwrap(),
+        backtrace: snafu::GenerateImplicitData::generate(),
+    }
+}
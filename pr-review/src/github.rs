@@ -0,0 +1,795 @@
+//  The GitHub API calls of the review, behind a trait so they can be replaced in tests
+
+use crate::config::{Config, parse_globs};
+use crate::llm::truncate_tokens;
+use crate::pipeline::{Finding, Severity};
+use crate::render::truncate_bytes;
+use crate::retry;
+use crate::triage::RecentIssue;
+use async_trait::async_trait;
+use base64::Engine;
+use lazy_static::lazy_static;
+use octocrab::models::pulls::FileDiff;
+use octocrab::params::checks::{CheckRunOutput, CheckRunOutputAnnotation, CheckRunOutputAnnotationLevel};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    //  The keywords that close an issue of the same repo when the PR is merged, e.g. "Fixes #123"
+    static ref CLOSING_ISSUE: Regex = Regex::new(r"(?i)\b(?:close[sd]?|fix(?:e[sd])?|resolve[sd]?)\s*:?\s+#(\d+)\b").unwrap();
+}
+
+//  The manifests that make a directory the root of a package of a monorepo
+const PACKAGE_MANIFESTS: &[&str] = &["Cargo.toml", "package.json", "go.mod"];
+
+//  The name of the check run in the checks tab, and the limits of its output
+pub(crate) const CHECK_RUN_NAME: &str = "Code review";
+
+pub(crate) const CHECK_RUN_ANNOTATIONS: usize = 50;
+
+const CHECK_RUN_TEXT_LIMIT: usize = 65535;
+
+//  The owners of the paths of a repo, by the rules of its CODEOWNERS file
+pub(crate) struct CodeOwners {
+    pub(crate) rules: Vec<(Vec<glob::Pattern>, Vec<String>)>,
+}
+
+impl CodeOwners {
+    //  The last matching rule wins, and may have no owners
+    fn owners(&self, path: &str) -> Option<&[String]> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(patterns, _)| patterns.iter().any(|p| p.matches(path)))
+            .map(|(_, owners)| owners.as_slice())
+    }
+
+    //  Whether one of the owners of the path is in `teams`, with or without the leading @
+    pub(crate) fn is_owned_by(&self, path: &str, teams: &[String]) -> bool {
+        let same = |a: &str, b: &str| a.trim_start_matches('@').eq_ignore_ascii_case(b.trim_start_matches('@'));
+        self.owners(path).is_some_and(|owners| owners.iter().any(|o| teams.iter().any(|t| same(o, t))))
+    }
+}
+
+//  The CODEOWNERS file is looked up where GitHub looks for it
+pub(crate) async fn load_codeowners(github: &dyn GithubApi, owner: &str, repo: &str) -> Option<CodeOwners> {
+    for path in [".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"] {
+        if let Ok(text) = get_content(github, owner, repo, path).await {
+            return Some(parse_codeowners(&text));
+        }
+    }
+    log::debug!("No CODEOWNERS found in {}/{}", owner, repo);
+    None
+}
+
+fn parse_codeowners(codeowners: &str) -> CodeOwners {
+    let mut rules = Vec::new();
+    for line in codeowners.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let Some(pattern) = fields.next() else {
+            continue;
+        };
+        let owners = fields.take_while(|f| !f.starts_with('#')).map(str::to_string).collect();
+        // A pattern without a slash matches at any level, and a directory matches all files in it
+        let anchored = pattern.trim_end_matches('/').contains('/');
+        let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+        let mut globs = vec![pattern.to_string(), format!("{}/**", pattern)];
+        if !anchored {
+            globs.extend([format!("**/{}", pattern), format!("**/{}/**", pattern)]);
+        }
+        rules.push((parse_globs(globs.iter().map(String::as_str)), owners));
+    }
+    CodeOwners { rules }
+}
+
+//  The directories of the repo that hold a package manifest, e.g. the members of a Cargo workspace
+pub(crate) async fn list_package_roots(github: &dyn GithubApi, owner: &str, repo: &str, sha: &str, max_attempts: u32) -> Vec<String> {
+    let route = format!("/repos/{}/{}/git/trees/{}?recursive=1", owner, repo, sha);
+    let tree = match retry("get_tree", max_attempts, is_transient_github_error, || github.get(&route)).await
+    {
+        Ok(tree) => tree,
+        Err(error) => {
+            log::error!("Cannot get the tree of {}: {}", sha, error);
+            return Vec::new();
+        }
+    };
+    tree["tree"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry["path"].as_str())
+        .filter(|path| PACKAGE_MANIFESTS.contains(&path.rsplit('/').next().unwrap_or_default()))
+        .map(|path| parent_dir(path).to_string())
+        .collect()
+}
+
+//  The root of the innermost package that holds a path, or "" for the root of the repo
+pub(crate) fn package_of<'a>(path: &str, roots: &'a [String]) -> &'a str {
+    roots
+        .iter()
+        .filter(|root| path.strip_prefix(root.as_str()).is_some_and(|rest| rest.starts_with('/')))
+        .max_by_key(|root| root.len())
+        .map_or("", String::as_str)
+}
+
+//  The directory of a path, or "" for the root
+fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+//  Reviewers for the changed paths, with the directories they are suggested for. The owners come
+//  from CODEOWNERS, and the latest committers to a directory stand in for the paths without owners.
+pub(crate) async fn suggest_reviewers(
+    github: &dyn GithubApi,
+    owner: &str,
+    repo: &str,
+    paths: &[String],
+    author: &str,
+    max_attempts: u32,
+) -> BTreeMap<String, Vec<String>> {
+    let codeowners = load_codeowners(github, owner, repo).await;
+    let mut dirs_by_reviewer: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut unowned: Vec<&str> = Vec::new();
+    let mut add = |reviewer: String, dir: &str| {
+        let dirs = dirs_by_reviewer.entry(reviewer).or_default();
+        if !dirs.iter().any(|d| d == dir) {
+            dirs.push(dir.to_string());
+        }
+    };
+    for path in paths {
+        let dir = parent_dir(path);
+        match codeowners.as_ref().and_then(|c| c.owners(path)).filter(|owners| !owners.is_empty()) {
+            Some(owners) => owners.iter().for_each(|o| add(o.clone(), dir)),
+            None if !unowned.contains(&dir) => unowned.push(dir),
+            None => {}
+        }
+    }
+    // A few directories at most, to keep the number of API calls down
+    for dir in unowned.into_iter().take(3) {
+        let route = format!("/repos/{}/{}/commits?path={}&per_page=10", owner, repo, url_escape(dir));
+        let commits: Vec<serde_json::Value> = match retry("list_commits", max_attempts, is_transient_github_error, || github.get(&route)).await.and_then(from_json) {
+            Ok(commits) => commits,
+            Err(error) => {
+                log::error!("Cannot get the commits of {}: {}", dir, error);
+                continue;
+            }
+        };
+        let mut committers: Vec<&str> = Vec::new();
+        for login in commits.iter().filter_map(|c| c["author"]["login"].as_str()) {
+            if !committers.contains(&login) && !login.ends_with("[bot]") {
+                committers.push(login);
+            }
+        }
+        committers.into_iter().take(2).for_each(|login| add(format!("@{}", login), dir));
+    }
+    dirs_by_reviewer.retain(|reviewer, _| !reviewer.trim_start_matches('@').eq_ignore_ascii_case(author));
+    dirs_by_reviewer
+}
+
+//  An open PR that changes some of the same files as the reviewed one
+pub(crate) struct RelatedPr {
+    pub(crate) number: u64,
+    pub(crate) title: String,
+    pub(crate) author: String,
+    pub(crate) paths: Vec<String>,
+}
+
+//  The other open PRs that change the same paths, the most overlapping first. Only the 30 PRs
+//  updated last are looked at, and the first 100 files of each, to keep the number of API calls down.
+pub(crate) async fn related_prs(
+    github: &dyn GithubApi,
+    owner: &str,
+    repo: &str,
+    pull_number: u64,
+    paths: &[String],
+    max_attempts: u32,
+) -> Vec<RelatedPr> {
+    let route = format!("/repos/{}/{}/pulls?state=open&sort=updated&direction=desc&per_page=30", owner, repo);
+    let pulls: Vec<serde_json::Value> = match retry("list_pulls", max_attempts, is_transient_github_error, || github.get(&route)).await.and_then(from_json) {
+        Ok(pulls) => pulls,
+        Err(error) => {
+            log::error!("Cannot get the open PRs: {}", error);
+            return Vec::new();
+        }
+    };
+    let mut related = Vec::new();
+    for pull in &pulls {
+        let Some(number) = pull["number"].as_u64().filter(|n| *n != pull_number) else {
+            continue;
+        };
+        let route = format!("/repos/{}/{}/pulls/{}/files?per_page=100", owner, repo, number);
+        let files: Vec<FileDiff> = match retry("list_files", max_attempts, is_transient_github_error, || github.get(&route)).await.and_then(from_json) {
+            Ok(files) => files,
+            Err(error) => {
+                log::error!("Cannot get the files of PR #{}: {}", number, error);
+                continue;
+            }
+        };
+        let shared: Vec<String> = files.into_iter().map(|f| f.filename).filter(|f| paths.contains(f)).collect();
+        if !shared.is_empty() {
+            related.push(RelatedPr {
+                number,
+                title: pull["title"].as_str().unwrap_or_default().to_string(),
+                author: pull["user"]["login"].as_str().unwrap_or_default().to_string(),
+                paths: shared,
+            });
+        }
+    }
+    related.sort_by_key(|r| std::cmp::Reverse(r.paths.len()));
+    related
+}
+
+//  Request reviews from the suggested users and teams. Emails in CODEOWNERS can't be requested.
+pub(crate) async fn request_reviewers(
+    github: &dyn GithubApi,
+    owner: &str,
+    repo: &str,
+    pull_number: u64,
+    suggestions: &BTreeMap<String, Vec<String>>,
+    max_attempts: u32,
+) {
+    let (teams, users): (Vec<&str>, Vec<&str>) = suggestions
+        .keys()
+        .filter_map(|r| r.strip_prefix('@'))
+        .partition(|r| r.contains('/'));
+    let teams: Vec<&str> = teams.into_iter().filter_map(|t| t.split_once('/').map(|(_, slug)| slug)).collect();
+    if users.is_empty() && teams.is_empty() {
+        return;
+    }
+    let route = format!("/repos/{}/{}/pulls/{}/requested_reviewers", owner, repo, pull_number);
+    let body = serde_json::json!({ "reviewers": users, "team_reviewers": teams });
+    if let Err(error) = retry("request_reviewers", max_attempts, is_transient_github_error, || github.post(&route, &body)).await {
+        log::error!("Error requesting reviewers: {}", error);
+    }
+}
+
+//  Add the labels to the PR, and remove the other managed labels that no longer apply
+pub(crate) async fn set_labels(
+    issues: &Issues<'_>,
+    pull_number: u64,
+    labels: &[&str],
+    managed: &[&str],
+    max_attempts: u32,
+) {
+    let current: Vec<String> = match retry("list_labels", max_attempts, is_transient_github_error, || issues.list_labels(pull_number)).await {
+        Ok(labels) => labels,
+        Err(error) => {
+            log::error!("Cannot get the labels of the PR: {}", error);
+            return;
+        }
+    };
+    for stale in managed.iter().filter(|m| !labels.contains(m) && current.iter().any(|c| c == *m)) {
+        if let Err(error) = retry("remove_label", max_attempts, is_transient_github_error, || issues.remove_label(pull_number, stale)).await {
+            log::error!("Error removing the label {}: {}", stale, error);
+        }
+    }
+    let missing: Vec<String> = labels.iter().filter(|l| !current.iter().any(|c| c == *l)).map(|l| l.to_string()).collect();
+    if !missing.is_empty() {
+        if let Err(error) = retry("add_labels", max_attempts, is_transient_github_error, || issues.add_labels(pull_number, &missing)).await {
+            log::error!("Error adding the labels {}: {}", missing.join(", "), error);
+        }
+    }
+}
+
+//  The check run output, with one batch of annotations
+pub(crate) fn check_run_output(summary: &str, text: &str, findings: &[&Finding]) -> CheckRunOutput {
+    CheckRunOutput {
+        title: CHECK_RUN_NAME.to_string(),
+        summary: truncate_bytes(summary, CHECK_RUN_TEXT_LIMIT).to_string(),
+        text: Some(truncate_bytes(text, CHECK_RUN_TEXT_LIMIT).to_string()),
+        annotations: findings
+            .iter()
+            .map(|f| CheckRunOutputAnnotation {
+                path: f.path.clone(),
+                start_line: f.line.unwrap_or(1) as u32,
+                end_line: f.end_line.or(f.line).unwrap_or(1) as u32,
+                start_column: None,
+                end_column: None,
+                annotation_level: match f.severity {
+                    Severity::Blocker => CheckRunOutputAnnotationLevel::Failure,
+                    Severity::Major => CheckRunOutputAnnotationLevel::Warning,
+                    Severity::Minor | Severity::Nit => CheckRunOutputAnnotationLevel::Notice,
+                },
+                message: f.message.clone(),
+                title: Some(f.severity.as_str().to_string()),
+                raw_details: None,
+            })
+            .collect(),
+        images: Vec::new(),
+    }
+}
+
+//  The GitHub REST API, as far as the bot uses it. Routes are relative to the API root, and carry
+//  their query string. The flow goes through octocrab; tests replay recorded events against a mock.
+#[async_trait(?Send)]
+pub trait GithubApi {
+    async fn get(&self, route: &str) -> octocrab::Result<serde_json::Value>;
+    async fn post(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value>;
+    async fn patch(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value>;
+    async fn put(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value>;
+    async fn delete(&self, route: &str) -> octocrab::Result<()>;
+}
+
+//  The GitHub API as the flow's GitHub login
+pub struct OctocrabApi(pub &'static octocrab::Octocrab);
+
+#[async_trait(?Send)]
+impl GithubApi for OctocrabApi {
+    async fn get(&self, route: &str) -> octocrab::Result<serde_json::Value> {
+        self.0.get(route, None::<&()>).await
+    }
+
+    async fn post(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.0.post(route, Some(body)).await
+    }
+
+    async fn patch(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.0.patch(route, Some(body)).await
+    }
+
+    async fn put(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.0.put(route, Some(body)).await
+    }
+
+    //  Deletes answer with an empty body
+    async fn delete(&self, route: &str) -> octocrab::Result<()> {
+        let response = self.0._delete(route, None::<&()>).await?;
+        octocrab::map_github_error(response).await.map(|_| ())
+    }
+}
+
+//  Decode an answer of the GitHub API, failing like octocrab does on an unexpected shape
+pub(crate) fn from_json<T: serde::de::DeserializeOwned>(value: serde_json::Value) -> octocrab::Result<T> {
+    serde_json::from_value(value).map_err(|source| octocrab::Error::Serde {
+        source,
+        backtrace: snafu::GenerateImplicitData::generate(),
+    })
+}
+
+//  Percent-encode a path segment or a query value
+pub(crate) fn url_escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+//  Decode a percent-encoded path segment
+pub(crate) fn url_unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], s.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+//  The text of a file on the default branch
+pub(crate) async fn get_content(github: &dyn GithubApi, owner: &str, repo: &str, path: &str) -> octocrab::Result<String> {
+    get_file(github, owner, repo, path).await.map(|(_, text)| text)
+}
+
+//  The blob SHA and the text of a file on the default branch
+pub(crate) async fn get_file(github: &dyn GithubApi, owner: &str, repo: &str, path: &str) -> octocrab::Result<(String, String)> {
+    get_file_at(github, owner, repo, path, None).await
+}
+
+//  The blob SHA and the text of a file on a branch, or on the default branch
+pub(crate) async fn get_file_at(github: &dyn GithubApi, owner: &str, repo: &str, path: &str, branch: Option<&str>) -> octocrab::Result<(String, String)> {
+    let path: Vec<String> = path.split('/').map(url_escape).collect();
+    let query = branch.map(|b| format!("?ref={}", url_escape(b))).unwrap_or_default();
+    let file = github.get(&format!("/repos/{}/{}/contents/{}{}", owner, repo, path.join("/"), query)).await?;
+    let content: String = file["content"].as_str().unwrap_or_default().split_whitespace().collect();
+    let bytes = base64::engine::general_purpose::STANDARD.decode(content).unwrap_or_default();
+    Ok((file["sha"].as_str().unwrap_or_default().to_string(), String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+//  Commit a new version of a file. `commit` has the message and the branch of the commit, and the
+//  blob SHA of the version that it replaces unless the file is new. Returns the SHA of the commit.
+pub(crate) async fn put_file(
+    github: &dyn GithubApi,
+    owner: &str,
+    repo: &str,
+    path: &str,
+    text: &str,
+    mut commit: serde_json::Value,
+) -> octocrab::Result<String> {
+    let route: Vec<String> = path.split('/').map(url_escape).collect();
+    commit["content"] = serde_json::json!(base64::engine::general_purpose::STANDARD.encode(text));
+    let result = github.put(&format!("/repos/{}/{}/contents/{}", owner, repo, route.join("/")), &commit).await?;
+    Ok(result["commit"]["sha"].as_str().unwrap_or_default().to_string())
+}
+
+//  The comments and labels of the issues and PRs of one repo
+pub(crate) struct Issues<'a> {
+    pub(crate) github: &'a dyn GithubApi,
+    pub(crate) owner: &'a str,
+    pub(crate) repo: &'a str,
+}
+
+//  A comment on an issue or a PR
+#[derive(Debug, Deserialize)]
+pub(crate) struct IssueComment {
+    pub(crate) id: u64,
+    pub(crate) body: Option<String>,
+    pub(crate) html_url: String,
+    #[serde(default)]
+    pub(crate) user: Option<CommentAuthor>,
+}
+
+//  The author of a comment
+#[derive(Debug, Deserialize)]
+pub(crate) struct CommentAuthor {
+    pub(crate) login: String,
+    //  "Bot" for GitHub Apps, else "User"
+    #[serde(rename = "type", default)]
+    pub(crate) kind: String,
+}
+
+impl Issues<'_> {
+    fn route(&self, path: &str) -> String {
+        format!("/repos/{}/{}/issues/{}", self.owner, self.repo, path)
+    }
+
+    async fn get(&self, number: u64) -> octocrab::Result<serde_json::Value> {
+        self.github.get(&self.route(&number.to_string())).await
+    }
+
+    //  All comments, oldest first. GitHub returns them in pages of 100.
+    pub(crate) async fn list_comments(&self, number: u64) -> octocrab::Result<Vec<IssueComment>> {
+        let mut comments = Vec::new();
+        for page in 1..=30 {
+            let route = self.route(&format!("{}/comments?per_page=100&page={}", number, page));
+            let mut next: Vec<IssueComment> = self.github.get(&route).await.and_then(from_json)?;
+            let last = next.len() < 100;
+            comments.append(&mut next);
+            if last {
+                break;
+            }
+        }
+        Ok(comments)
+    }
+
+    pub(crate) async fn create_comment(&self, number: u64, body: &str) -> octocrab::Result<IssueComment> {
+        let body = serde_json::json!({ "body": body });
+        self.github.post(&self.route(&format!("{}/comments", number)), &body).await.and_then(from_json)
+    }
+
+    pub(crate) async fn update_comment(&self, id: u64, body: &str) -> octocrab::Result<IssueComment> {
+        let body = serde_json::json!({ "body": body });
+        self.github.patch(&self.route(&format!("comments/{}", id)), &body).await.and_then(from_json)
+    }
+
+    //  e.g. "eyes" or "+1", see https://docs.github.com/en/rest/reactions/reactions#about-reactions
+    pub(crate) async fn add_reaction(&self, id: u64, content: &str) -> octocrab::Result<()> {
+        let body = serde_json::json!({ "content": content });
+        self.github.post(&self.route(&format!("comments/{}/reactions", id)), &body).await.map(|_| ())
+    }
+
+    pub(crate) async fn delete_comment(&self, id: u64) -> octocrab::Result<()> {
+        self.github.delete(&self.route(&format!("comments/{}", id))).await
+    }
+
+    async fn list_labels(&self, number: u64) -> octocrab::Result<Vec<String>> {
+        let labels: Vec<serde_json::Value> = self.github.get(&self.route(&format!("{}/labels?per_page=100", number))).await.and_then(from_json)?;
+        Ok(labels.iter().filter_map(|l| l["name"].as_str().map(str::to_string)).collect())
+    }
+
+    async fn add_labels(&self, number: u64, labels: &[String]) -> octocrab::Result<()> {
+        let body = serde_json::json!({ "labels": labels });
+        self.github.post(&self.route(&format!("{}/labels", number)), &body).await.map(|_| ())
+    }
+
+    async fn remove_label(&self, number: u64, label: &str) -> octocrab::Result<()> {
+        self.github.delete(&self.route(&format!("{}/labels/{}", number, url_escape(label)))).await
+    }
+}
+
+//  The files changed between two commits
+pub(crate) async fn compare_files(
+    github: &dyn GithubApi,
+    owner: &str,
+    repo: &str,
+    base: &str,
+    head: &str,
+    max_attempts: u32,
+) -> octocrab::Result<HashSet<String>> {
+    let route = format!("/repos/{}/{}/compare/{}...{}", owner, repo, base, head);
+    let comparison = retry("compare", max_attempts, is_transient_github_error, || github.get(&route)).await?;
+    let files = comparison["files"].as_array().cloned().unwrap_or_default();
+    Ok(files.iter().filter_map(|f| f["filename"].as_str().map(str::to_string)).collect())
+}
+
+//  All changed files of a PR. GitHub returns them in pages of 100, up to 3000 files in total.
+pub(crate) async fn list_files(
+    github: &dyn GithubApi,
+    owner: &str,
+    repo: &str,
+    pull_number: u64,
+    max_attempts: u32,
+) -> octocrab::Result<Vec<FileDiff>> {
+    let mut files = Vec::new();
+    for page in 1..=30 {
+        let route = format!("/repos/{}/{}/pulls/{}/files?per_page=100&page={}", owner, repo, pull_number, page);
+        let mut next: Vec<FileDiff> = retry("list_files", max_attempts, is_transient_github_error, || github.get(&route)).await.and_then(from_json)?;
+        let last = next.len() < 100;
+        files.append(&mut next);
+        if last {
+            break;
+        }
+    }
+    Ok(files)
+}
+
+pub(crate) fn is_bot(user: &octocrab::models::Author) -> bool {
+    user.r#type == "Bot" || user.login.ends_with("[bot]")
+}
+
+pub(crate) fn is_from_fork(pr: &octocrab::models::pulls::PullRequest) -> bool {
+    let head = pr.head.repo.as_ref().and_then(|r| r.full_name.as_deref());
+    let base = pr.base.repo.as_ref().and_then(|r| r.full_name.as_deref());
+    head.is_some() && head != base
+}
+
+//  The SHA of the latest commit of a PR
+pub(crate) async fn pr_head(github: &dyn GithubApi, owner: &str, repo: &str, pull_number: u64, max_attempts: u32) -> Option<String> {
+    let route = format!("/repos/{}/{}/pulls/{}", owner, repo, pull_number);
+    match retry("get_pull", max_attempts, is_transient_github_error, || github.get(&route)).await {
+        Ok(pr) => pr["head"]["sha"].as_str().map(str::to_string),
+        Err(error) => {
+            log::error!("Cannot get the PR: {}", error);
+            None
+        }
+    }
+}
+
+//  Whether a PR can be merged as is, and how far its branch is behind the base branch
+pub(crate) struct Mergeability {
+    //  GitHub computes this in the background, so it is unknown for a while after each push
+    pub(crate) conflicting: Option<bool>,
+    pub(crate) behind_by: u64,
+    pub(crate) base: String,
+    pub(crate) head: String,
+    //  The "owner/repo" of the head branch, which is another repo for PRs from forks
+    pub(crate) head_repo: String,
+}
+
+pub(crate) async fn mergeability(github: &dyn GithubApi, owner: &str, repo: &str, pull_number: u64, max_attempts: u32) -> Option<Mergeability> {
+    let route = format!("/repos/{}/{}/pulls/{}", owner, repo, pull_number);
+    let pr = match retry("get_pull", max_attempts, is_transient_github_error, || github.get(&route)).await {
+        Ok(pr) => pr,
+        Err(error) => {
+            log::error!("Cannot get the PR: {}", error);
+            return None;
+        }
+    };
+    let base = pr["base"]["ref"].as_str()?.to_string();
+    let head_sha = pr["head"]["sha"].as_str()?;
+    let conflicting = match pr["mergeable_state"].as_str() {
+        Some("dirty") => Some(true),
+        _ => pr["mergeable"].as_bool().map(|mergeable| !mergeable),
+    };
+    let route = format!("/repos/{}/{}/compare/{}...{}", owner, repo, url_escape(&base), head_sha);
+    let behind_by = match retry("compare", max_attempts, is_transient_github_error, || github.get(&route)).await {
+        Ok(comparison) => comparison["behind_by"].as_u64().unwrap_or_default(),
+        Err(error) => {
+            log::error!("Cannot compare the PR with {}: {}", base, error);
+            0
+        }
+    };
+    Some(Mergeability {
+        conflicting,
+        behind_by,
+        base,
+        head: pr["head"]["ref"].as_str().unwrap_or_default().to_string(),
+        head_repo: pr["head"]["repo"]["full_name"].as_str().unwrap_or_default().to_string(),
+    })
+}
+
+//  Maintainers are the users who can push to the repo
+pub(crate) async fn is_maintainer(github: &dyn GithubApi, owner: &str, repo: &str, login: &str, max_attempts: u32) -> bool {
+    let route = format!("/repos/{}/{}/collaborators/{}/permission", owner, repo, login);
+    match retry("get_permission", max_attempts, is_transient_github_error, || github.get(&route)).await {
+        Ok(permission) => matches!(permission["permission"].as_str(), Some("admin" | "maintain" | "write")),
+        Err(error) => {
+            log::debug!("{} is not a collaborator of {}/{}: {}", login, owner, repo, error);
+            false
+        }
+    }
+}
+
+//  The titles and descriptions of the issues of the same repo that a PR description says it fixes,
+//  up to 3 issues, as context for the system prompt
+pub(crate) async fn linked_issues_context(github: &dyn GithubApi, owner: &str, repo: &str, description: &str, max_attempts: u32) -> String {
+    let issues = Issues { github, owner, repo };
+    let mut numbers: Vec<u64> = Vec::new();
+    for cap in CLOSING_ISSUE.captures_iter(description) {
+        match cap[1].parse() {
+            Ok(number) if !numbers.contains(&number) => numbers.push(number),
+            _ => {}
+        }
+    }
+    let mut context = String::new();
+    for number in numbers.into_iter().take(3) {
+        match retry("get_issue", max_attempts, is_transient_github_error, || issues.get(number)).await {
+            Ok(issue) => context.push_str(&format!(
+                "Issue #{}: {}\n{}\n\n",
+                number,
+                issue["title"].as_str().unwrap_or_default(),
+                truncate_tokens(issue["body"].as_str().unwrap_or_default(), 1000)
+            )),
+            Err(error) => log::error!("Cannot get the linked issue #{}: {}", number, error),
+        }
+    }
+    match context.is_empty() {
+        true => context,
+        false => format!(
+            "The PR says that it fixes the following issues. Judge whether the changes actually address the problems they describe.\n\n{}",
+            context.trim_end()
+        ),
+    }
+}
+
+//  The SHAs and the messages of the first 100 commits of a PR, oldest first
+pub(crate) async fn list_commits(
+    github: &dyn GithubApi,
+    owner: &str,
+    repo: &str,
+    pull_number: u64,
+    max_attempts: u32,
+) -> octocrab::Result<Vec<(String, String)>> {
+    let route = format!("/repos/{}/{}/pulls/{}/commits?per_page=100", owner, repo, pull_number);
+    let commits: Vec<serde_json::Value> = retry("list_commits", max_attempts, is_transient_github_error, || github.get(&route)).await.and_then(from_json)?;
+    Ok(commits
+        .iter()
+        .filter_map(|c| Some((c["sha"].as_str()?.to_string(), c["commit"]["message"].as_str()?.to_string())))
+        .collect())
+}
+
+//  The 100 latest issues of a repo, open or closed, without the PRs
+pub(crate) async fn list_recent_issues(github: &dyn GithubApi, owner: &str, repo: &str, max_attempts: u32) -> octocrab::Result<Vec<RecentIssue>> {
+    let route = format!("/repos/{}/{}/issues?state=all&sort=created&direction=desc&per_page=100", owner, repo);
+    let issues: Vec<serde_json::Value> = retry("list_issues", max_attempts, is_transient_github_error, || github.get(&route)).await.and_then(from_json)?;
+    Ok(issues
+        .iter()
+        .filter(|i| i.get("pull_request").is_none())
+        .filter_map(|i| {
+            Some(RecentIssue {
+                number: i["number"].as_u64()?,
+                title: i["title"].as_str()?.to_string(),
+                body: i["body"].as_str().unwrap_or_default().to_string(),
+            })
+        })
+        .collect())
+}
+
+//  The names and the descriptions of the first 100 labels of a repo
+pub(crate) async fn list_repo_labels(github: &dyn GithubApi, owner: &str, repo: &str, max_attempts: u32) -> octocrab::Result<Vec<(String, String)>> {
+    let route = format!("/repos/{}/{}/labels?per_page=100", owner, repo);
+    let labels: Vec<serde_json::Value> = retry("list_labels", max_attempts, is_transient_github_error, || github.get(&route)).await.and_then(from_json)?;
+    Ok(labels
+        .iter()
+        .filter_map(|l| Some((l["name"].as_str()?.to_string(), l["description"].as_str().unwrap_or_default().to_string())))
+        .collect())
+}
+
+//  Whether there are more than `reserve` GitHub API calls left. When there are not, wait up to
+//  `max_wait` seconds for the rate limit to reset. The rate limit API itself costs no calls.
+pub(crate) async fn github_budget(github: &dyn GithubApi, reserve: usize, max_wait: u64) -> bool {
+    if reserve == 0 {
+        return true;
+    }
+    let core: octocrab::models::Rate = match github.get("/rate_limit").await.and_then(|limit| from_json(limit["resources"]["core"].clone())) {
+        Ok(core) => core,
+        Err(error) => {
+            log::debug!("Cannot get the GitHub API rate limit: {}", error);
+            return true;
+        }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let wait = (core.reset as u64).saturating_sub(now);
+    log::debug!("GitHub API budget: {} of {} calls left, resets in {}s", core.remaining, core.limit, wait);
+    if core.remaining > reserve {
+        return true;
+    }
+    if wait > max_wait {
+        return false;
+    }
+    log::info!("The GitHub API rate limit is nearly used up, waiting {}s for it to reset", wait);
+    tokio::time::sleep(Duration::from_secs(wait + 1)).await;
+    true
+}
+
+//  A short reason for a failed GitHub call, for the review comment
+pub(crate) fn github_error_reason(e: &octocrab::Error) -> String {
+    match e {
+        octocrab::Error::GitHub { source, .. } => match source.message.to_lowercase() {
+            m if m.contains("rate limit") => "GitHub rate limit".to_string(),
+            m if m.contains("not found") => "HTTP 404 not found".to_string(),
+            _ => format!("GitHub error: {}", truncate_bytes(&source.message, 100)),
+        },
+        octocrab::Error::Hyper { .. } | octocrab::Error::Service { .. } | octocrab::Error::Http { .. } => "network error".to_string(),
+        _ => truncate_bytes(&e.to_string(), 100).to_string(),
+    }
+}
+
+pub(crate) fn is_not_found(e: &octocrab::Error) -> bool {
+    matches!(e, octocrab::Error::GitHub { source, .. } if source.message.to_lowercase().contains("not found"))
+}
+
+//  Post a comment on the PR. In dry-run mode, the comment is only logged.
+pub(crate) async fn post_comment(issues: &Issues<'_>, pull_number: u64, body: &str, config: &Config) -> Result<(), octocrab::Error> {
+    if config.dry_run {
+        log::info!("Dry run: comment on PR #{}:\n{}", pull_number, body);
+        return Ok(());
+    }
+    retry("create_comment", config.max_attempts, is_transient_github_error, || issues.create_comment(pull_number, body)).await.map(|_| ())
+}
+
+//  Rate limits, server errors and network failures are worth another try
+pub(crate) fn is_transient_github_error(e: &octocrab::Error) -> bool {
+    match e {
+        octocrab::Error::GitHub { source, .. } => {
+            let message = source.message.to_lowercase();
+            ["rate limit", "server error", "bad gateway", "unavailable", "timeout"].iter().any(|m| message.contains(m))
+        }
+        octocrab::Error::Hyper { .. } | octocrab::Error::Service { .. } | octocrab::Error::Http { .. } => true,
+        _ => false,
+    }
+}
+
+//  The "owner/repo" entries of the github_repos config, with "owner/*" expanded to all repos of the owner
+pub async fn expand_repos(octo: &octocrab::Octocrab, repos: &str) -> Vec<(String, String)> {
+    let mut expanded = Vec::new();
+    for entry in repos.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((owner, repo)) = entry.split_once('/') else {
+            log::error!("Invalid repo {}, expected owner/repo", entry);
+            continue;
+        };
+        if repo != "*" {
+            expanded.push((owner.to_string(), repo.to_string()));
+            continue;
+        }
+        match list_repos(octo, owner).await {
+            Ok(names) => expanded.extend(names.into_iter().map(|name| (owner.to_string(), name))),
+            Err(error) => log::error!("Cannot list the repos of {}: {}", owner, error),
+        }
+    }
+    expanded
+}
+
+//  All repos of an org, or the public repos of a user. Archived repos get no new PRs.
+async fn list_repos(octo: &octocrab::Octocrab, owner: &str) -> octocrab::Result<Vec<String>> {
+    let mut page: octocrab::Page<octocrab::models::Repository> =
+        match octo.get(format!("/orgs/{}/repos", owner), Some(&[("per_page", 100)])).await {
+            Ok(page) => page,
+            Err(_) => octo.get(format!("/users/{}/repos", owner), Some(&[("per_page", 100)])).await?,
+        };
+    let mut repos = page.take_items();
+    while let Some(mut next) = octo.get_page::<octocrab::models::Repository>(&page.next).await? {
+        repos.append(&mut next.take_items());
+        page = next;
+    }
+    Ok(repos
+        .into_iter()
+        .filter(|r| !r.archived.unwrap_or(false))
+        .map(|r| r.name)
+        .collect())
+}
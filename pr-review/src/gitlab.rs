@@ -0,0 +1,609 @@
+//  GitLab merge requests, behind the same GitHub API abstraction as the rest of the review. The
+//  webhook events of GitLab are turned into their GitHub counterparts, and the GitHub routes that
+//  the review calls are translated into GitLab API calls.
+
+use crate::github::{url_unescape, GithubApi};
+use async_trait::async_trait;
+use octocrab::models::webhook_events::WebhookEvent;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+//  The access level of a project member that counts as write access, i.e. Developer
+const WRITE_ACCESS: u64 = 30;
+
+//  The GitLab REST API (v4) for one merge request, answering the GitHub routes of the review
+pub struct GitlabApi {
+    client: reqwest::Client,
+    //  The API root, e.g. https://gitlab.com/api/v4
+    url: String,
+    token: String,
+    //  The path of the project, e.g. "group/subgroup/project"
+    project: String,
+    merge_request: u64,
+    //  The commit and name of the statuses created for check runs, by id
+    statuses: RefCell<HashMap<u64, (String, String)>>,
+}
+
+impl GitlabApi {
+    pub fn new(url: &str, token: &str, project: &str, merge_request: u64) -> Self {
+        GitlabApi {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            project: project.to_string(),
+            merge_request,
+            statuses: RefCell::new(HashMap::new()),
+        }
+    }
+
+    //  A call to the GitLab API, with the route relative to the API root
+    pub async fn request(&self, method: reqwest::Method, route: &str, body: Option<&serde_json::Value>) -> octocrab::Result<serde_json::Value> {
+        let mut request = self.client.request(method, format!("{}{}", self.url, route)).header("PRIVATE-TOKEN", &self.token);
+        if let Some(body) = body {
+            request = request.header("Content-Type", "application/json").body(body.to_string());
+        }
+        let response = request.send().await.map_err(|source| octocrab::Error::Service {
+            source: Box::new(source),
+            backtrace: snafu::GenerateImplicitData::generate(),
+        })?;
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap_or_default();
+        if status.is_success() {
+            return Ok(value);
+        }
+        // GitLab errors carry a message or an error, as text or as an object
+        let message = match (&value["message"], &value["error"]) {
+            (serde_json::Value::String(m), _) | (_, serde_json::Value::String(m)) => m.clone(),
+            (serde_json::Value::Null, _) => status.to_string(),
+            (m, _) => m.to_string(),
+        };
+        Err(gitlab_error(&message))
+    }
+
+    async fn get_json(&self, route: &str) -> octocrab::Result<serde_json::Value> {
+        self.request(reqwest::Method::GET, route, None).await
+    }
+
+    //  The route of the project, or of the merge request under review
+    fn project_route(&self, project: &str) -> String {
+        format!("/projects/{}", escape(project))
+    }
+
+    fn mr_route(&self) -> String {
+        format!("{}/merge_requests/{}", self.project_route(&self.project), self.merge_request)
+    }
+
+    //  The project and the rest of a GitHub route. Besides the project under review, only the
+    //  .github project of its group is read, for the org config file.
+    fn split_route<'a>(&self, route: &'a str) -> Option<(String, &'a str)> {
+        let rest = route.strip_prefix("/repos/")?;
+        if let Some(rest) = rest.strip_prefix(&format!("{}/", self.project)) {
+            return Some((self.project.clone(), rest));
+        }
+        let namespace = self.project.rsplit_once('/').map_or("", |(namespace, _)| namespace);
+        let rest = rest.strip_prefix(&format!("{}/.github/", namespace))?;
+        Some((format!("{}/.github", namespace), rest))
+    }
+
+    async fn get_route(&self, project: &str, route: &str) -> octocrab::Result<serde_json::Value> {
+        let (path, query) = route.split_once('?').unwrap_or((route, ""));
+        let page = query_param(query, "page").unwrap_or("1");
+        let segments: Vec<&str> = path.split('/').collect();
+        let base = self.project_route(project);
+        match segments[..] {
+            ["contents", ..] => self.get_json(&format!("{}/repository/files/{}?ref=HEAD", base, escape(&url_unescape(&path["contents/".len()..])))).await,
+            ["git", "blobs", sha] => self.get_json(&format!("{}/repository/blobs/{}", base, sha)).await,
+            ["git", "trees", sha] => {
+                let mut tree = Vec::new();
+                for page in 1..=30 {
+                    let next = self.get_json(&format!("{}/repository/tree?ref={}&recursive=true&per_page=100&page={}", base, sha, page)).await?;
+                    let next = next.as_array().cloned().unwrap_or_default();
+                    let last = next.len() < 100;
+                    tree.extend(next);
+                    if last {
+                        break;
+                    }
+                }
+                Ok(serde_json::json!({ "tree": tree }))
+            }
+            ["compare", range] => {
+                let (from, to) = range.split_once("...").unwrap_or((range, range));
+                let comparison = self.get_json(&format!("{}/repository/compare?from={}&to={}", base, from, to)).await?;
+                let files: Vec<serde_json::Value> = comparison["diffs"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|d| serde_json::json!({ "filename": d["new_path"] }))
+                    .collect();
+                Ok(serde_json::json!({ "files": files }))
+            }
+            // Commits on GitLab have no user names, so there are no reviewers to suggest from the history
+            ["commits"] => Ok(serde_json::json!([])),
+            ["collaborators", login, "permission"] => {
+                let members = self.get_json(&format!("{}/members/all?query={}", base, escape(login))).await?;
+                let level = members
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .find(|m| m["username"] == *login)
+                    .and_then(|m| m["access_level"].as_u64())
+                    .unwrap_or(0);
+                let permission = match level {
+                    50.. => "admin",
+                    40.. => "maintain",
+                    WRITE_ACCESS.. => "write",
+                    _ => "read",
+                };
+                Ok(serde_json::json!({ "permission": permission }))
+            }
+            ["issues", number] => {
+                let issue = self.get_json(&format!("{}/issues/{}", base, number)).await?;
+                Ok(serde_json::json!({ "title": issue["title"], "body": issue["description"], "state": issue["state"] }))
+            }
+            // Only the merge request under review has comments and labels
+            ["issues", number, "comments"] if number == self.merge_request.to_string() => {
+                let notes = self.get_json(&format!("{}/notes?sort=asc&order_by=created_at&per_page=100", self.mr_route())).await?;
+                let comments: Vec<serde_json::Value> = notes
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter(|n| !n["system"].as_bool().unwrap_or(false))
+                    .map(|n| self.comment(n))
+                    .collect();
+                Ok(serde_json::json!(comments))
+            }
+            ["issues", number, "labels"] if number == self.merge_request.to_string() => {
+                let mr = self.get_json(&self.mr_route()).await?;
+                let labels: Vec<serde_json::Value> = mr["labels"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|name| serde_json::json!({ "name": name }))
+                    .collect();
+                Ok(serde_json::json!(labels))
+            }
+            ["pulls", number] => {
+                let mr = self.get_json(&format!("{}/merge_requests/{}", base, number)).await?;
+                Ok(MergeRequest::from_api(&mr, project).to_pull_request())
+            }
+            ["pulls", number, "commits"] => {
+                let commits = self.get_json(&format!("{}/merge_requests/{}/commits?per_page=100", base, number)).await?;
+                // GitLab lists the newest commit first
+                let mut commits: Vec<serde_json::Value> = commits
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|c| serde_json::json!({ "commit": { "message": c["message"] } }))
+                    .collect();
+                commits.reverse();
+                Ok(serde_json::json!(commits))
+            }
+            ["pulls", number, "files"] => {
+                let mr = self.get_json(&format!("{}/merge_requests/{}", base, number)).await?;
+                let head = mr["sha"].as_str().unwrap_or_default().to_string();
+                let diffs = self.get_json(&format!("{}/merge_requests/{}/diffs?per_page=100&page={}", base, number, page)).await?;
+                let mut files = Vec::new();
+                for diff in diffs.as_array().into_iter().flatten() {
+                    files.push(self.file_diff(&base, &head, diff).await?);
+                }
+                Ok(serde_json::json!(files))
+            }
+            _ => Err(gitlab_error("Not supported on GitLab")),
+        }
+    }
+
+    //  A changed file in the shape of the PR files API of GitHub
+    async fn file_diff(&self, base: &str, head: &str, diff: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        let filename = diff["new_path"].as_str().unwrap_or_default();
+        let patch = diff["diff"].as_str().unwrap_or_default();
+        let additions = patch.lines().filter(|l| l.starts_with('+') && !l.starts_with("+++")).count();
+        let deletions = patch.lines().filter(|l| l.starts_with('-') && !l.starts_with("---")).count();
+        let status = match (diff["new_file"].as_bool(), diff["deleted_file"].as_bool(), diff["renamed_file"].as_bool()) {
+            (Some(true), _, _) => "added",
+            (_, Some(true), _) => "removed",
+            (_, _, Some(true)) => "renamed",
+            _ => "modified",
+        };
+        // The merge request diffs have no blob ids, the files API has them in a header of the HEAD request
+        let sha = match status {
+            "removed" => String::new(),
+            _ => {
+                let route = format!("{}{}/repository/files/{}?ref={}", self.url, base, escape(filename), head);
+                let response = self.client.head(route).header("PRIVATE-TOKEN", &self.token).send().await.map_err(|source| octocrab::Error::Service {
+                    source: Box::new(source),
+                    backtrace: snafu::GenerateImplicitData::generate(),
+                })?;
+                let blob = response.headers().get("X-Gitlab-Blob-Id").and_then(|id| id.to_str().ok());
+                blob.unwrap_or_default().to_string()
+            }
+        };
+        let url = format!("{}/-/blob/{}/{}", self.web_url(), head, filename);
+        Ok(serde_json::json!({
+            "sha": sha,
+            "filename": filename,
+            "previous_filename": diff["old_path"],
+            "status": status,
+            "additions": additions,
+            "deletions": deletions,
+            "changes": additions + deletions,
+            "blob_url": url,
+            "raw_url": url.replace("/-/blob/", "/-/raw/"),
+            "contents_url": url,
+            "patch": patch,
+        }))
+    }
+
+    //  The web page of the project
+    fn web_url(&self) -> String {
+        format!("{}/{}", self.url.trim_end_matches("/api/v4"), self.project)
+    }
+
+    //  A note of the merge request in the shape of an issue comment of GitHub
+    fn comment(&self, note: &serde_json::Value) -> serde_json::Value {
+        let url = format!("{}/-/merge_requests/{}#note_{}", self.web_url(), self.merge_request, note["id"]);
+        serde_json::json!({ "id": note["id"], "body": note["body"], "html_url": url })
+    }
+
+    async fn write_route(&self, method: &str, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        let Some((project, rest)) = self.split_route(route) else {
+            return Err(gitlab_error("Not supported on GitLab"));
+        };
+        let segments: Vec<&str> = rest.split('/').collect();
+        let mr = self.mr_route();
+        let this_mr = |number: &str| number == self.merge_request.to_string() && project == self.project;
+        match (method, &segments[..]) {
+            ("POST", ["issues", number, "comments"]) if this_mr(number) => {
+                let note = self.request(reqwest::Method::POST, &format!("{}/notes", mr), Some(&serde_json::json!({ "body": body["body"] }))).await?;
+                Ok(self.comment(&note))
+            }
+            ("PATCH", ["issues", "comments", id]) if project == self.project => {
+                let note = self.request(reqwest::Method::PUT, &format!("{}/notes/{}", mr, id), Some(&serde_json::json!({ "body": body["body"] }))).await?;
+                Ok(self.comment(&note))
+            }
+            ("DELETE", ["issues", "comments", id]) if project == self.project => self.request(reqwest::Method::DELETE, &format!("{}/notes/{}", mr, id), None).await,
+            ("POST", ["issues", number, "labels"]) if this_mr(number) => {
+                let labels: Vec<&str> = body["labels"].as_array().into_iter().flatten().filter_map(|l| l.as_str()).collect();
+                self.request(reqwest::Method::PUT, &mr, Some(&serde_json::json!({ "add_labels": labels.join(",") }))).await
+            }
+            ("DELETE", ["issues", number, "labels", label]) if this_mr(number) => {
+                self.request(reqwest::Method::PUT, &mr, Some(&serde_json::json!({ "remove_labels": url_unescape(label) }))).await
+            }
+            ("POST", ["pulls", number, "reviews"]) if this_mr(number) => self.post_review(body).await,
+            // The approval is the only kind of review that GitLab can take back
+            ("PUT", ["pulls", number, "reviews", _, "dismissals"]) if this_mr(number) => {
+                self.request(reqwest::Method::POST, &format!("{}/unapprove", mr), None).await
+            }
+            ("POST", ["check-runs"]) => {
+                let sha = body["head_sha"].as_str().unwrap_or_default().to_string();
+                let name = body["name"].as_str().unwrap_or_default().to_string();
+                let status = self.set_status(&sha, &name, "running", "").await?;
+                let id = status["id"].as_u64().unwrap_or_default();
+                self.statuses.borrow_mut().insert(id, (sha, name));
+                Ok(serde_json::json!({ "id": id }))
+            }
+            ("PATCH", ["check-runs", id]) => {
+                let Some((sha, name)) = id.parse().ok().and_then(|id: u64| self.statuses.borrow().get(&id).cloned()) else {
+                    return Err(gitlab_error("Unknown check run"));
+                };
+                let state = match body["conclusion"].as_str() {
+                    None => "running",
+                    Some("success" | "neutral" | "skipped") => "success",
+                    Some("cancelled") => "canceled",
+                    Some(_) => "failed",
+                };
+                self.set_status(&sha, &name, state, body["output"]["title"].as_str().unwrap_or_default()).await
+            }
+            _ => Err(gitlab_error("Not supported on GitLab")),
+        }
+    }
+
+    //  A commit status, which shows in the pipelines of the merge request like a check run on GitHub
+    async fn set_status(&self, sha: &str, name: &str, state: &str, description: &str) -> octocrab::Result<serde_json::Value> {
+        let route = format!("{}/statuses/{}", self.project_route(&self.project), sha);
+        let description: String = description.chars().take(255).collect();
+        let status = serde_json::json!({ "state": state, "name": name, "description": description });
+        self.request(reqwest::Method::POST, &route, Some(&status)).await
+    }
+
+    //  A PR review becomes a note with its body, a discussion for each inline comment, and an approval
+    async fn post_review(&self, review: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        let mr_route = self.mr_route();
+        let mr = self.get_json(&mr_route).await?;
+        let refs = &mr["diff_refs"];
+        for comment in review["comments"].as_array().into_iter().flatten() {
+            let mut body = comment["body"].as_str().unwrap_or_default().to_string();
+            // GitLab suggestions name the lines they replace, counted up from the commented line
+            if let (Some(start), Some(end)) = (comment["start_line"].as_u64(), comment["line"].as_u64()) {
+                body = body.replace("```suggestion\n", &format!("```suggestion:-{}+0\n", end.saturating_sub(start)));
+            }
+            let discussion = serde_json::json!({
+                "body": body,
+                "position": {
+                    "position_type": "text",
+                    "base_sha": refs["base_sha"],
+                    "start_sha": refs["start_sha"],
+                    "head_sha": refs["head_sha"],
+                    "old_path": comment["path"],
+                    "new_path": comment["path"],
+                    "new_line": comment["line"],
+                },
+            });
+            if let Err(error) = self.request(reqwest::Method::POST, &format!("{}/discussions", mr_route), Some(&discussion)).await {
+                log::error!("Cannot post the inline comment on {}: {}", comment["path"], error);
+            }
+        }
+        let note = self.request(reqwest::Method::POST, &format!("{}/notes", mr_route), Some(&serde_json::json!({ "body": review["body"] }))).await?;
+        if review["event"] == "APPROVE" {
+            self.request(reqwest::Method::POST, &format!("{}/approve", mr_route), None).await?;
+        }
+        Ok(serde_json::json!({ "id": note["id"] }))
+    }
+}
+
+#[async_trait(?Send)]
+impl GithubApi for GitlabApi {
+    async fn get(&self, route: &str) -> octocrab::Result<serde_json::Value> {
+        match self.split_route(route) {
+            Some((project, rest)) => self.get_route(&project, rest).await,
+            None => Err(gitlab_error("Not supported on GitLab")),
+        }
+    }
+
+    async fn post(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.write_route("POST", route, body).await
+    }
+
+    async fn patch(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.write_route("PATCH", route, body).await
+    }
+
+    async fn put(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.write_route("PUT", route, body).await
+    }
+
+    async fn delete(&self, route: &str) -> octocrab::Result<()> {
+        self.write_route("DELETE", route, &serde_json::Value::Null).await.map(|_| ())
+    }
+}
+
+//  The parts of a merge request that the review needs, from a webhook or from the API
+struct MergeRequest {
+    iid: u64,
+    title: String,
+    description: String,
+    draft: bool,
+    author: String,
+    source_branch: String,
+    target_branch: String,
+    sha: String,
+    //  The paths of the source and target projects, which differ for merge requests from forks
+    source: String,
+    target: String,
+    url: String,
+}
+
+impl MergeRequest {
+    fn from_hook(mr: &serde_json::Value, author: &str) -> Self {
+        MergeRequest {
+            iid: mr["iid"].as_u64().unwrap_or_default(),
+            title: mr["title"].as_str().unwrap_or_default().to_string(),
+            description: mr["description"].as_str().unwrap_or_default().to_string(),
+            draft: mr["draft"].as_bool().or(mr["work_in_progress"].as_bool()).unwrap_or(false),
+            author: author.to_string(),
+            source_branch: mr["source_branch"].as_str().unwrap_or_default().to_string(),
+            target_branch: mr["target_branch"].as_str().unwrap_or_default().to_string(),
+            sha: mr["last_commit"]["id"].as_str().unwrap_or_default().to_string(),
+            source: mr["source"]["path_with_namespace"].as_str().unwrap_or_default().to_string(),
+            target: mr["target"]["path_with_namespace"].as_str().unwrap_or_default().to_string(),
+            url: mr["url"].as_str().unwrap_or_default().to_string(),
+        }
+    }
+
+    fn from_api(mr: &serde_json::Value, project: &str) -> Self {
+        let fork = mr["source_project_id"] != mr["target_project_id"];
+        MergeRequest {
+            iid: mr["iid"].as_u64().unwrap_or_default(),
+            title: mr["title"].as_str().unwrap_or_default().to_string(),
+            description: mr["description"].as_str().unwrap_or_default().to_string(),
+            draft: mr["draft"].as_bool().unwrap_or(false),
+            author: mr["author"]["username"].as_str().unwrap_or_default().to_string(),
+            source_branch: mr["source_branch"].as_str().unwrap_or_default().to_string(),
+            target_branch: mr["target_branch"].as_str().unwrap_or_default().to_string(),
+            sha: mr["sha"].as_str().unwrap_or_default().to_string(),
+            // The API names the source project by id only; the path of a fork doesn't matter, only that it differs
+            source: if fork { format!("{}#{}", project, mr["source_project_id"]) } else { project.to_string() },
+            target: project.to_string(),
+            url: mr["web_url"].as_str().unwrap_or_default().to_string(),
+        }
+    }
+
+    //  The merge request in the shape of a GitHub pull request
+    fn to_pull_request(&self) -> serde_json::Value {
+        let url = http_url(&self.url);
+        serde_json::json!({
+            "url": url,
+            "html_url": url,
+            "id": self.iid,
+            "number": self.iid,
+            "title": self.title,
+            "body": self.description,
+            "draft": self.draft,
+            "user": author(&self.author),
+            "head": { "ref": self.source_branch, "sha": self.sha, "repo": repository(&self.source, &url) },
+            "base": { "ref": self.target_branch, "sha": "", "repo": repository(&self.target, &url) },
+        })
+    }
+
+    //  The merge request in the shape of a GitHub issue, for comment events
+    fn to_issue(&self) -> serde_json::Value {
+        let url = http_url(&self.url);
+        serde_json::json!({
+            "id": self.iid,
+            "node_id": "",
+            "url": url,
+            "repository_url": url,
+            "labels_url": url,
+            "comments_url": url,
+            "events_url": url,
+            "html_url": url,
+            "number": self.iid,
+            "state": "open",
+            "title": self.title,
+            "body": self.description,
+            "user": author(&self.author),
+            "labels": [],
+            "assignees": [],
+            "author_association": "NONE",
+            "locked": false,
+            "comments": 0,
+            "created_at": "1970-01-01T00:00:00Z",
+            "updated_at": "1970-01-01T00:00:00Z",
+            "pull_request": { "url": url, "html_url": url, "diff_url": url, "patch_url": url },
+        })
+    }
+}
+
+//  Turn the payload of a GitLab webhook into the GitHub event that the review handles, with the
+//  path of the project and the merge request it is about. Events that the review doesn't handle
+//  give None.
+pub fn parse_event(payload: &serde_json::Value) -> Result<Option<(WebhookEvent, String, u64)>, String> {
+    let project = payload["project"]["path_with_namespace"].as_str().ok_or("The event has no project")?;
+    let web_url = payload["project"]["web_url"].as_str().unwrap_or_default();
+    let user = payload["user"]["username"].as_str().unwrap_or_default();
+    let attributes = &payload["object_attributes"];
+    let (kind, event, iid) = match payload["object_kind"].as_str() {
+        Some("merge_request") => {
+            // Only pushes change the code: other updates have no old revision
+            let draft_changed = payload["changes"]["draft"]["previous"] == true && payload["changes"]["draft"]["current"] == false;
+            let action = match attributes["action"].as_str() {
+                Some("open") => "opened",
+                Some("reopen") => "reopened",
+                Some("update") if attributes["oldrev"].is_string() => "synchronize",
+                Some("update") if draft_changed => "ready_for_review",
+                _ => return Ok(None),
+            };
+            let mr = MergeRequest::from_hook(attributes, user);
+            let event = serde_json::json!({
+                "action": action,
+                "number": mr.iid,
+                "pull_request": mr.to_pull_request(),
+            });
+            ("pull_request", event, mr.iid)
+        }
+        Some("note") if attributes["noteable_type"] == "MergeRequest" => {
+            let mr = &payload["merge_request"];
+            let mr_author = mr["author"]["username"].as_str().unwrap_or(user);
+            let mr = MergeRequest::from_hook(mr, mr_author);
+            let url = http_url(attributes["url"].as_str().unwrap_or_default());
+            let event = serde_json::json!({
+                "action": "created",
+                "issue": mr.to_issue(),
+                "comment": {
+                    "id": attributes["id"],
+                    "node_id": "",
+                    "url": url,
+                    "html_url": url,
+                    "body": attributes["note"],
+                    "user": author(user),
+                    "created_at": "1970-01-01T00:00:00Z",
+                },
+            });
+            ("issue_comment", event, mr.iid)
+        }
+        _ => return Ok(None),
+    };
+    let (owner, name) = project.rsplit_once('/').unwrap_or(("", project));
+    let mut event = event;
+    event["sender"] = author(user);
+    event["repository"] = serde_json::json!({
+        "id": payload["project"]["id"],
+        "name": name,
+        "full_name": project,
+        "url": http_url(web_url),
+        "owner": author(owner),
+    });
+    let event = WebhookEvent::try_from_header_and_body(kind, &event.to_string()).map_err(|e| format!("Invalid {} event: {}", kind, e))?;
+    Ok(Some((event, project.to_string(), iid)))
+}
+
+//  A merge request of the API in the shape of a merge request webhook, to review it without a webhook
+pub fn merge_request_event(project: &serde_json::Value, mr: &serde_json::Value) -> serde_json::Value {
+    let path = project["path_with_namespace"].as_str().unwrap_or_default();
+    let fork = mr["source_project_id"] != mr["target_project_id"];
+    let mut attributes = mr.clone();
+    attributes["action"] = "open".into();
+    attributes["url"] = mr["web_url"].clone();
+    attributes["last_commit"] = serde_json::json!({ "id": mr["sha"] });
+    attributes["target"] = serde_json::json!({ "path_with_namespace": path });
+    attributes["source"] = match fork {
+        true => serde_json::json!({ "path_with_namespace": format!("{}#{}", path, mr["source_project_id"]) }),
+        false => serde_json::json!({ "path_with_namespace": path }),
+    };
+    serde_json::json!({
+        "object_kind": "merge_request",
+        "user": mr["author"],
+        "project": project,
+        "object_attributes": attributes,
+    })
+}
+
+//  A GitHub repository for a GitLab project path
+fn repository(path: &str, url: &str) -> serde_json::Value {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    serde_json::json!({ "id": 0, "name": name, "full_name": path, "url": url })
+}
+
+//  A GitHub user for a GitLab user name
+fn author(login: &str) -> serde_json::Value {
+    let url = "https://gitlab.com/";
+    serde_json::json!({
+        "login": login,
+        "id": 0,
+        "node_id": "",
+        "avatar_url": url,
+        "gravatar_id": "",
+        "url": url,
+        "html_url": url,
+        "followers_url": url,
+        "following_url": url,
+        "gists_url": url,
+        "starred_url": url,
+        "subscriptions_url": url,
+        "organizations_url": url,
+        "repos_url": url,
+        "events_url": url,
+        "received_events_url": url,
+        "type": if login.ends_with("-bot") || login.starts_with("project_") { "Bot" } else { "User" },
+        "site_admin": false,
+    })
+}
+
+//  GitHub models require absolute URLs
+fn http_url(url: &str) -> String {
+    match url.starts_with("http") {
+        true => url.to_string(),
+        false => "https://gitlab.com/".to_string(),
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
+
+//  Percent-encode a path for GitLab, slashes included
+fn escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn gitlab_error(message: &str) -> octocrab::Error {
+    octocrab::Error::GitHub {
+        source: serde_json::from_value(serde_json::json!({ "message": message })).unwrap(),
+        backtrace: snafu::GenerateImplicitData::generate(),
+    }
+}
@@ -0,0 +1,49 @@
+//  Infrastructure as code: Terraform, CloudFormation and Kubernetes files get a review prompt for
+//  the misconfigurations that expose or break deployments, and their findings are listed apart
+//  from the ones of the application code.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref CLOUDFORMATION: Regex = Regex::new(r#"(?m)AWSTemplateFormatVersion|^\s*"?Type"?\s*:\s*['"]?AWS::"#).unwrap();
+    static ref KUBERNETES_API_VERSION: Regex = Regex::new(r"(?m)^[+ -]?apiVersion:\s*\S").unwrap();
+    static ref KUBERNETES_KIND: Regex = Regex::new(r"(?m)^[+ -]?kind:\s*[A-Z]").unwrap();
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Iac {
+    Terraform,
+    CloudFormation,
+    Kubernetes,
+}
+
+impl Iac {
+    //  The kind of infrastructure file, by its extension, or by its content for YAML and JSON,
+    //  which may be anything else. `text` is the file, or its patch when the file was not fetched.
+    pub(crate) fn detect(path: &str, text: &str) -> Option<Iac> {
+        let name = path.rsplit('/').next().unwrap_or(path).to_ascii_lowercase();
+        if name.ends_with(".tf") || name.ends_with(".tf.json") || name.ends_with(".tfvars") {
+            return Some(Iac::Terraform);
+        }
+        if !name.ends_with(".yaml") && !name.ends_with(".yml") && !name.ends_with(".json") && !name.ends_with(".template") {
+            return None;
+        }
+        if CLOUDFORMATION.is_match(text) {
+            Some(Iac::CloudFormation)
+        } else if KUBERNETES_API_VERSION.is_match(text) && KUBERNETES_KIND.is_match(text) {
+            Some(Iac::Kubernetes)
+        } else {
+            None
+        }
+    }
+
+    //  What to look for, after the system prompt
+    pub(crate) fn prompt(self) -> &'static str {
+        match self {
+            Iac::Terraform => "This is a Terraform file. Pay special attention to S3 buckets that are public (public ACLs, a public access block that is turned off, bucket policies with a `*` principal), security groups and firewall rules that open ports other than 80 and 443 to 0.0.0.0/0 or ::/0, unencrypted storage and databases, IAM policies with `*` actions or resources, container images with the `latest` tag, and secrets in variables or outputs.",
+            Iac::CloudFormation => "This is a CloudFormation template. Pay special attention to S3 buckets that are public (`PublicRead` access control, a `PublicAccessBlockConfiguration` that is turned off, bucket policies with a `*` principal), security group ingress rules that open ports other than 80 and 443 to 0.0.0.0/0 or ::/0, unencrypted storage and databases, IAM policies with `*` actions or resources, container images with the `latest` tag, and secrets in parameters without `NoEcho`.",
+            Iac::Kubernetes => "This is a Kubernetes manifest. Pay special attention to containers without CPU and memory requests and limits, images with the `latest` tag or no tag, privileged containers, containers that run as root or allow privilege escalation, `hostPath` volumes and `hostNetwork`, Services of type LoadBalancer or NodePort that expose internal ports, missing liveness and readiness probes, and secrets in plain environment values.",
+        }
+    }
+}
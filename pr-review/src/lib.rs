@@ -0,0 +1,62 @@
+//  The review engine of the bot. It takes a GitHub webhook event, reviews the PR with an LLM and
+//  posts the review, through the GitHub and LLM backends given by the caller. The flows function
+//  is one entrypoint; a CLI or a CI runner can be another.
+
+mod api;
+mod changelog;
+mod commands;
+mod commits;
+mod config;
+mod deps;
+pub mod digest;
+mod docker;
+pub mod github;
+pub mod gitea;
+pub mod gitlab;
+mod iac;
+pub mod llm;
+mod metrics;
+mod notify;
+pub mod pipeline;
+mod rag;
+pub mod render;
+mod symbols;
+mod trace;
+mod triage;
+mod workflow;
+
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+//  Run an operation until it succeeds, fails for good, or runs out of attempts.
+//  Attempts are spaced by exponential backoff (1s, 2s, 4s ... up to 30s) with jitter.
+pub(crate) async fn retry<T, E, Fut>(
+    what: &str,
+    max_attempts: u32,
+    is_transient: impl Fn(&E) -> bool,
+    mut op: impl FnMut() -> Fut,
+) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if attempt < max_attempts && is_transient(&e) => {
+                let delay = backoff_delay(attempt);
+                trace::warn!("retry", "{} failed (attempt {} of {}), retrying in {:?}: {}", what, attempt, max_attempts, delay, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn backoff_delay(attempt: u32) -> Duration {
+    let base = Duration::from_secs(1 << (attempt - 1).min(5)).min(Duration::from_secs(30));
+    // The clock is random enough to spread out retries, no need for a RNG
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    base.mul_f64(0.5 + (nanos % 1000) as f64 / 2000.0)
+}
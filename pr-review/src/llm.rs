@@ -0,0 +1,284 @@
+//  The LLM providers the review can talk to, and the token counting of prompts
+
+use crate::config::{Config, ModelRoute, flow_routes};
+use crate::pipeline::RESPONSE_TOKENS;
+use crate::render::truncate_bytes;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use std::env;
+use tiktoken_rs::CoreBPE;
+
+lazy_static! {
+    //  The tokenizer used to measure prompts. The exact tokenizer of open source models differs,
+    //  but cl100k_base stays within a few percent for code.
+    static ref BPE: CoreBPE = tiktoken_rs::cl100k_base().unwrap();
+}
+
+//  One question to the LLM, asked in a fresh conversation
+pub struct ChatRequest<'a> {
+    //  Only numbers, letters, underscores, dashes, and pound signs are allowed, up to 50 characters
+    pub conversation_id: &'a str,
+    pub system_prompt: &'a str,
+    pub question: &'a str,
+    pub model: &'a str,
+    //  The context window of the model, in tokens
+    pub ctx_size: u32,
+}
+
+//  An LLM backend that answers review questions
+#[async_trait(?Send)]
+pub trait LlmProvider {
+    async fn chat(&self, req: &ChatRequest<'_>) -> Result<String, String>;
+
+    //  The embedding vectors of some texts, for the retrieval of related code
+    async fn embed(&self, _model: &str, _input: &[String]) -> Result<Vec<Vec<f32>>, String> {
+        Err("This LLM provider has no embeddings API".to_string())
+    }
+}
+
+//  A model to ask, and the backend that serves it
+pub(crate) struct Model {
+    pub(crate) llm: Box<dyn LlmProvider>,
+    pub(crate) name: String,
+    //  The context window of the model, in tokens
+    pub(crate) ctx_size: u32,
+}
+
+//  A model routed to by file patterns: a route of the model_routes setting, or the ensemble model
+pub(crate) type RoutedModel = (Vec<glob::Pattern>, Model);
+
+//  The default model, the models of the model_routes setting, and the second model of the ensemble
+pub(crate) fn build_models(config: &Config, endpoint: &str, api_key: &str, connect_llm: ConnectLlm) -> (Model, Vec<RoutedModel>, Option<RoutedModel>) {
+    let model = Model {
+        llm: connect_llm(&config.llm_provider, &config.llm_api_version, endpoint.to_string(), api_key.to_string()),
+        name: config.llm_model_name.clone(),
+        ctx_size: config.llm_ctx_size,
+    };
+    let trusted = flow_routes();
+    let routed = |r: &ModelRoute| {
+        let route_endpoint = r.llm_api_endpoint.clone().unwrap_or(endpoint.to_string());
+        // The flow's secrets are never sent to an endpoint that the repo config picked: a key is
+        // only read from the env when the flow config sets the route with the same endpoint
+        let from_flow = |name: &str| {
+            trusted.iter().any(|t| t.llm_api_endpoint == r.llm_api_endpoint && t.llm_api_key_env.as_deref() == Some(name))
+        };
+        let route_key = match &r.llm_api_key_env {
+            Some(name) if from_flow(name) => env::var(name).unwrap_or_default(),
+            Some(name) => {
+                log::warn!("Ignore the llm_api_key_env {} of a route that the flow config does not set", name);
+                String::new()
+            }
+            None if route_endpoint == endpoint => api_key.to_string(),
+            None => String::new(),
+        };
+        let provider = r.llm_provider.as_ref().unwrap_or(&config.llm_provider);
+        let llm = connect_llm(provider, &config.llm_api_version, route_endpoint, route_key);
+        let model = Model {
+            llm,
+            name: r.llm_model_name.clone(),
+            ctx_size: r.llm_ctx_size.unwrap_or(config.llm_ctx_size),
+        };
+        (r.files.clone(), model)
+    };
+    let routes = config.model_routes.iter().map(routed).collect();
+    (model, routes, config.ensemble.as_ref().map(routed))
+}
+
+//  Connects to an LLM backend by provider, API version, endpoint and API key
+pub type ConnectLlm = fn(&str, &str, String, String) -> Box<dyn LlmProvider>;
+
+//  Pick the LLM backend by the llm_provider setting. The flows.network LLM service only runs in a
+//  flow, so elsewhere the flows provider calls the OpenAI compatible endpoint that it would proxy.
+pub fn new_llm_provider(provider: &str, api_version: &str, endpoint: String, api_key: String) -> Box<dyn LlmProvider> {
+    match provider {
+        "openai" | "flows" => Box::new(OpenAiLlm { endpoint, api_key }),
+        "anthropic" => Box::new(AnthropicLlm { endpoint, api_key }),
+        "azure" => Box::new(AzureOpenAiLlm {
+            endpoint,
+            api_key,
+            api_version: api_version.to_string(),
+        }),
+        other => {
+            log::error!("Unknown llm_provider {}, using openai", other);
+            Box::new(OpenAiLlm { endpoint, api_key })
+        }
+    }
+}
+
+//  Any OpenAI compatible chat completions API, called directly
+struct OpenAiLlm {
+    endpoint: String,
+    api_key: String,
+}
+
+#[async_trait(?Send)]
+impl LlmProvider for OpenAiLlm {
+    async fn chat(&self, req: &ChatRequest<'_>) -> Result<String, String> {
+        let url = format!("{}/chat/completions", self.endpoint.trim_end_matches('/'));
+        let body = serde_json::json!({
+            "model": req.model,
+            "messages": [
+                {"role": "system", "content": req.system_prompt},
+                {"role": "user", "content": req.question},
+            ],
+        });
+        let auth = ("Authorization", format!("Bearer {}", self.api_key));
+        let res = post_json(&url, &[auth], &body).await?;
+        res["choices"][0]["message"]["content"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or(format!("Unexpected response: {}", res))
+    }
+
+    async fn embed(&self, model: &str, input: &[String]) -> Result<Vec<Vec<f32>>, String> {
+        let url = format!("{}/embeddings", self.endpoint.trim_end_matches('/'));
+        let body = serde_json::json!({ "model": model, "input": input });
+        let auth = ("Authorization", format!("Bearer {}", self.api_key));
+        parse_embeddings(post_json(&url, &[auth], &body).await?)
+    }
+}
+
+//  The Anthropic messages API
+struct AnthropicLlm {
+    endpoint: String,
+    api_key: String,
+}
+
+#[async_trait(?Send)]
+impl LlmProvider for AnthropicLlm {
+    async fn chat(&self, req: &ChatRequest<'_>) -> Result<String, String> {
+        let url = format!("{}/messages", self.endpoint.trim_end_matches('/'));
+        let body = serde_json::json!({
+            "model": req.model,
+            "system": req.system_prompt,
+            "max_tokens": RESPONSE_TOKENS,
+            "messages": [
+                {"role": "user", "content": req.question},
+            ],
+        });
+        let headers = [
+            ("x-api-key", self.api_key.clone()),
+            ("anthropic-version", "2023-06-01".to_string()),
+        ];
+        let res = post_json(&url, &headers, &body).await?;
+        let text: String = res["content"]
+            .as_array()
+            .ok_or(format!("Unexpected response: {}", res))?
+            .iter()
+            .filter_map(|c| c["text"].as_str())
+            .collect();
+        Ok(text)
+    }
+}
+
+//  Azure OpenAI, where the model name is the name of the deployment
+struct AzureOpenAiLlm {
+    endpoint: String,
+    api_key: String,
+    api_version: String,
+}
+
+#[async_trait(?Send)]
+impl LlmProvider for AzureOpenAiLlm {
+    async fn chat(&self, req: &ChatRequest<'_>) -> Result<String, String> {
+        let url = format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint.trim_end_matches('/'),
+            req.model,
+            self.api_version
+        );
+        let body = serde_json::json!({
+            "messages": [
+                {"role": "system", "content": req.system_prompt},
+                {"role": "user", "content": req.question},
+            ],
+        });
+        let res = post_json(&url, &[("api-key", self.api_key.clone())], &body).await?;
+        res["choices"][0]["message"]["content"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or(format!("Unexpected response: {}", res))
+    }
+
+    //  The model is the name of the deployment of an embedding model
+    async fn embed(&self, model: &str, input: &[String]) -> Result<Vec<Vec<f32>>, String> {
+        let url = format!(
+            "{}/openai/deployments/{}/embeddings?api-version={}",
+            self.endpoint.trim_end_matches('/'),
+            model,
+            self.api_version
+        );
+        let body = serde_json::json!({ "input": input });
+        parse_embeddings(post_json(&url, &[("api-key", self.api_key.clone())], &body).await?)
+    }
+}
+
+//  The vectors of an OpenAI compatible embeddings response, in the order of the input
+fn parse_embeddings(res: serde_json::Value) -> Result<Vec<Vec<f32>>, String> {
+    let data = res["data"].as_array().ok_or(format!("Unexpected response: {}", res))?;
+    let mut vectors: Vec<(u64, Vec<f32>)> = data
+        .iter()
+        .map(|d| {
+            let vector = d["embedding"].as_array().map(|v| v.iter().filter_map(|x| x.as_f64()).map(|x| x as f32).collect());
+            Some((d["index"].as_u64().unwrap_or_default(), vector?))
+        })
+        .collect::<Option<_>>()
+        .ok_or(format!("Unexpected response: {}", res))?;
+    vectors.sort_by_key(|(index, _)| *index);
+    Ok(vectors.into_iter().map(|(_, vector)| vector).collect())
+}
+
+pub(crate) async fn post_json(url: &str, headers: &[(&str, String)], body: &serde_json::Value) -> Result<serde_json::Value, String> {
+    let mut req = reqwest::Client::new()
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(body.to_string());
+    for (name, value) in headers {
+        req = req.header(*name, value);
+    }
+    let res = req.send().await.map_err(|e| e.to_string())?;
+    let status = res.status();
+    let bytes = res.bytes().await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("{}: {}", status, String::from_utf8_lossy(&bytes)));
+    }
+    serde_json::from_slice(&bytes).map_err(|e| e.to_string())
+}
+
+//  A short reason for a failed LLM call, for the review comment
+pub(crate) fn llm_error_reason(e: &str) -> String {
+    let lower = e.to_lowercase();
+    if ["context length", "context window", "maximum context", "too many tokens", "too long"].iter().any(|m| lower.contains(m)) {
+        "token overflow".to_string()
+    } else if lower.contains("timed out") || lower.contains("timeout") {
+        "timeout".to_string()
+    } else if let Some(status) = e.get(..3).and_then(|s| s.parse::<u16>().ok()) {
+        format!("HTTP {}", status)
+    } else {
+        truncate_bytes(e, 100).to_string()
+    }
+}
+
+//  LLM errors are strings that start with the HTTP status when there is one
+pub(crate) fn is_transient_llm_error(e: &str) -> bool {
+    let status = e.get(..3).and_then(|s| s.parse::<u16>().ok());
+    if matches!(status, Some(429) | Some(500..=599)) {
+        return true;
+    }
+    let e = e.to_lowercase();
+    ["timed out", "timeout", "rate limit", "overloaded", "connection", "try again"].iter().any(|m| e.contains(m))
+}
+
+pub(crate) fn count_tokens(s: &str) -> usize {
+    BPE.encode_ordinary(s).len()
+}
+
+pub(crate) fn truncate_tokens(s: &str, max_tokens: usize) -> String {
+    let tokens = BPE.encode_ordinary(s);
+    if tokens.len() <= max_tokens {
+        return s.to_string();
+    }
+    // The cut may fall inside a multi-byte char, which is dropped
+    let bytes = BPE._decode_native(&tokens[..max_tokens]);
+    String::from_utf8_lossy(&bytes).trim_end_matches('\u{FFFD}').to_string()
+}
@@ -0,0 +1,433 @@
+//  The command line entrypoint of the review engine, for CI runners and git hooks. It reviews the
+//  changes of a local git checkout, or a GitHub PR, and prints the review as markdown on stdout.
+//  The LLM and the review settings come from the same environment variables as the flow.
+
+use async_trait::async_trait;
+use base64::Engine;
+use octocrab::models::webhook_events::WebhookEvent;
+use pr_review::{digest, gitea, github::GithubApi, gitlab, llm::new_llm_provider, pipeline::review_event};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+const USAGE: &str = "Usage:
+    pr-review diff [<base>]   review the changes of the working tree against <base>, HEAD by default
+    pr-review pr <url>        review a GitHub PR, read with the personal access token in GITHUB_TOKEN
+    pr-review action          handle the event of a GitHub Actions workflow run, and post the review
+    pr-review gitlab [<file>] handle a GitLab webhook event read from <file>, or the merge request of a
+                              GitLab CI pipeline, and post the review with the token in GITLAB_TOKEN
+    pr-review gitea [<event> <file>]
+                              handle a Gitea or Forgejo webhook event of kind <event> read from <file>,
+                              or the event of a Gitea Actions run, with the token in GITEA_TOKEN
+    pr-review digest <owner/repo>...
+                              email a digest of the reviews of the last 24 hours to the maintainers";
+const GITHUB_API: &str = "https://api.github.com";
+const GITLAB_API: &str = "https://gitlab.com/api/v4";
+//  The owner of the repo in a local review
+const LOCAL_OWNER: &str = "local";
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let review = match args[..] {
+        ["diff"] => review_diff("HEAD").await.map(Some),
+        ["diff", base] => review_diff(base).await.map(Some),
+        ["pr", url] => review_pr(url).await.map(Some),
+        ["action"] => run_action().await.map(|_| None),
+        ["gitlab"] => run_gitlab(None).await.map(|_| None),
+        ["gitlab", path] => run_gitlab(Some(path)).await.map(|_| None),
+        ["gitea"] => run_gitea(None).await.map(|_| None),
+        ["gitea", kind, path] => run_gitea(Some((kind, path))).await.map(|_| None),
+        ["digest", ref repos @ ..] if !repos.is_empty() => send_digest(repos).await.map(|_| None),
+        _ => Err(USAGE.to_string()),
+    };
+    match review {
+        Ok(Some(review)) => println!("{}", review),
+        Ok(None) => {}
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+//  The GitHub REST API, with a personal access token or the token of a workflow run
+struct RestApi {
+    client: reqwest::Client,
+    //  The API root, which differs on GitHub Enterprise Server
+    url: String,
+    token: String,
+}
+
+impl RestApi {
+    fn new(token: String) -> Self {
+        let url = env::var("GITHUB_API_URL").unwrap_or(GITHUB_API.to_string());
+        RestApi { client: reqwest::Client::new(), url, token }
+    }
+
+    async fn send(&self, method: reqwest::Method, route: &str, body: Option<&serde_json::Value>) -> octocrab::Result<serde_json::Value> {
+        let mut request = self
+            .client
+            .request(method, format!("{}{}", self.url, route))
+            .header("Accept", "application/vnd.github+json")
+            .header("User-Agent", "pr-review");
+        if !self.token.is_empty() {
+            request = request.bearer_auth(&self.token);
+        }
+        if let Some(body) = body {
+            request = request.header("Content-Type", "application/json").body(body.to_string());
+        }
+        let response = request.send().await.map_err(|source| octocrab::Error::Service {
+            source: Box::new(source),
+            backtrace: snafu::GenerateImplicitData::generate(),
+        })?;
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap_or_default();
+        match status.is_success() {
+            true => Ok(value),
+            false => Err(github_error(value["message"].as_str().map_or(status.to_string(), str::to_string))),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl GithubApi for RestApi {
+    async fn get(&self, route: &str) -> octocrab::Result<serde_json::Value> {
+        self.send(reqwest::Method::GET, route, None).await
+    }
+
+    async fn post(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.send(reqwest::Method::POST, route, Some(body)).await
+    }
+
+    async fn patch(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.send(reqwest::Method::PATCH, route, Some(body)).await
+    }
+
+    async fn put(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.send(reqwest::Method::PUT, route, Some(body)).await
+    }
+
+    async fn delete(&self, route: &str) -> octocrab::Result<()> {
+        self.send(reqwest::Method::DELETE, route, None).await.map(|_| ())
+    }
+}
+
+//  Where the reads of the review are answered from
+enum Source {
+    //  Fixed answers by route, from the local checkout
+    Local(HashMap<String, serde_json::Value>),
+    Remote(RestApi),
+}
+
+//  A GitHub API that keeps the comments of the review instead of posting them. The other writes are dropped.
+struct Capture {
+    source: Source,
+    comments: RefCell<Vec<String>>,
+}
+
+impl Capture {
+    fn new(source: Source) -> Self {
+        Capture { source, comments: RefCell::new(Vec::new()) }
+    }
+
+    //  The review comment and its continuations, without the hidden markers
+    fn review(&self) -> Option<String> {
+        let comments = self.comments.borrow();
+        if comments.is_empty() {
+            return None;
+        }
+        let review = comments.join("\n\n");
+        let review: Vec<&str> = review.lines().filter(|l| !(l.starts_with("<!-- pr-review:") && l.ends_with("-->"))).collect();
+        Some(review.join("\n").trim().to_string())
+    }
+
+    fn write(&self, method: &str, route: &str, body: &serde_json::Value) -> serde_json::Value {
+        let text = body["body"].as_str().unwrap_or_default().to_string();
+        let mut comments = self.comments.borrow_mut();
+        let id = match route.rsplit_once("/comments") {
+            // A new comment on the PR
+            Some((_, "")) if method == "POST" && route.contains("/issues/") => {
+                comments.push(text);
+                comments.len()
+            }
+            // An update of one of the comments above
+            Some((_, id)) if method == "PATCH" => {
+                let id: usize = id.trim_start_matches('/').parse().unwrap_or(0);
+                if let Some(comment) = comments.get_mut(id.wrapping_sub(1)) {
+                    *comment = text;
+                }
+                id
+            }
+            _ => 0,
+        };
+        serde_json::json!({ "id": id, "body": body["body"], "html_url": "" })
+    }
+}
+
+fn github_error(message: String) -> octocrab::Error {
+    octocrab::Error::GitHub {
+        source: serde_json::from_value(serde_json::json!({ "message": message })).unwrap(),
+        backtrace: snafu::GenerateImplicitData::generate(),
+    }
+}
+
+#[async_trait(?Send)]
+impl GithubApi for Capture {
+    async fn get(&self, route: &str) -> octocrab::Result<serde_json::Value> {
+        match &self.source {
+            Source::Local(answers) => answers.get(route).cloned().ok_or_else(|| github_error("Not Found".to_string())),
+            // The earlier reviews of the PR are left out, so that the review starts over
+            Source::Remote(_) if route.contains("/comments?") => Ok(serde_json::json!([])),
+            Source::Remote(github) => github.get(route).await,
+        }
+    }
+
+    async fn post(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        Ok(self.write("POST", route, body))
+    }
+
+    async fn patch(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        Ok(self.write("PATCH", route, body))
+    }
+
+    async fn put(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        Ok(self.write("PUT", route, body))
+    }
+
+    async fn delete(&self, _route: &str) -> octocrab::Result<()> {
+        Ok(())
+    }
+}
+
+//  Handle the event of a GitHub Actions workflow run as the flow handles webhooks, with the token of the run
+async fn run_action() -> Result<(), String> {
+    let (Ok(name), Ok(path)) = (env::var("GITHUB_EVENT_NAME"), env::var("GITHUB_EVENT_PATH")) else {
+        return Err("GITHUB_EVENT_NAME and GITHUB_EVENT_PATH are not set, run this in a GitHub Actions workflow".to_string());
+    };
+    let token = env::var("GITHUB_TOKEN").map_err(|_| "GITHUB_TOKEN is not set".to_string())?;
+    let body = std::fs::read(&path).map_err(|e| format!("Cannot read the event in {}: {}", path, e))?;
+    // pull_request_target events carry the payload of pull_request events
+    let name = match name.as_str() {
+        "pull_request_target" => "pull_request",
+        name => name,
+    };
+    let event = WebhookEvent::try_from_header_and_body(name, &body).map_err(|e| format!("Invalid {} event: {}", name, e))?;
+    // The logs of the review carry the ID of the workflow run
+    let run_id = env::var("GITHUB_RUN_ID").ok();
+    review_event(event, run_id.as_deref(), &RestApi::new(token), new_llm_provider).await;
+    Ok(())
+}
+
+//  Handle a GitLab webhook event, or review the merge request of the GitLab CI pipeline this runs in
+async fn run_gitlab(path: Option<&str>) -> Result<(), String> {
+    let url = env::var("GITLAB_API_URL").or(env::var("CI_API_V4_URL")).unwrap_or(GITLAB_API.to_string());
+    let token = env::var("GITLAB_TOKEN").map_err(|_| "GITLAB_TOKEN is not set".to_string())?;
+    let payload = match path {
+        Some(path) => {
+            let body = std::fs::read(path).map_err(|e| format!("Cannot read the event in {}: {}", path, e))?;
+            serde_json::from_slice(&body).map_err(|e| format!("Invalid event in {}: {}", path, e))?
+        }
+        None => {
+            let (Ok(project), Ok(iid)) = (env::var("CI_PROJECT_PATH"), env::var("CI_MERGE_REQUEST_IID")) else {
+                return Err("CI_PROJECT_PATH and CI_MERGE_REQUEST_IID are not set, run this in a merge request pipeline".to_string());
+            };
+            let api = gitlab::GitlabApi::new(&url, &token, &project, 0);
+            let route = format!("/projects/{}", project.replace('/', "%2F"));
+            let mr = api.request(reqwest::Method::GET, &format!("{}/merge_requests/{}", route, iid), None).await;
+            let mr = mr.map_err(|e| format!("Cannot get the merge request: {}", e))?;
+            let project = api.request(reqwest::Method::GET, &route, None).await.map_err(|e| format!("Cannot get the project: {}", e))?;
+            gitlab::merge_request_event(&project, &mr)
+        }
+    };
+    let Some((event, project, merge_request)) = gitlab::parse_event(&payload)? else {
+        log::info!("Ignore the GitLab event {}", payload["object_kind"]);
+        return Ok(());
+    };
+    let pipeline_id = env::var("CI_PIPELINE_ID").ok();
+    review_event(event, pipeline_id.as_deref(), &gitlab::GitlabApi::new(&url, &token, &project, merge_request), new_llm_provider).await;
+    Ok(())
+}
+
+//  Handle a Gitea or Forgejo webhook event, or the event of the Gitea Actions run this runs in
+async fn run_gitea(event: Option<(&str, &str)>) -> Result<(), String> {
+    // Gitea Actions set the variables of GitHub Actions, with the API of the Gitea server
+    let url = env::var("GITEA_API_URL").or(env::var("GITHUB_API_URL")).map_err(|_| "GITEA_API_URL is not set".to_string())?;
+    let token = env::var("GITEA_TOKEN").or(env::var("GITHUB_TOKEN")).map_err(|_| "GITEA_TOKEN is not set".to_string())?;
+    let (kind, path) = match event {
+        Some((kind, path)) => (kind.to_string(), path.to_string()),
+        None => match (env::var("GITHUB_EVENT_NAME"), env::var("GITHUB_EVENT_PATH")) {
+            (Ok(kind), Ok(path)) => (kind, path),
+            _ => return Err("GITHUB_EVENT_NAME and GITHUB_EVENT_PATH are not set, run this in a Gitea Actions workflow".to_string()),
+        },
+    };
+    let body = std::fs::read(&path).map_err(|e| format!("Cannot read the event in {}: {}", path, e))?;
+    let payload: serde_json::Value = serde_json::from_slice(&body).map_err(|e| format!("Invalid event in {}: {}", path, e))?;
+    let Some(event) = gitea::parse_event(&kind, &payload)? else {
+        log::info!("Ignore the Gitea event {} {}", kind, payload["action"]);
+        return Ok(());
+    };
+    let run_id = env::var("GITHUB_RUN_ID").ok();
+    review_event(event, run_id.as_deref(), &gitea::GiteaApi::new(&url, &token), new_llm_provider).await;
+    Ok(())
+}
+
+//  Email the digest of the reviews of the repos, read with the token in GITHUB_TOKEN
+async fn send_digest(repos: &[&str]) -> Result<(), String> {
+    let sink = digest::mail_sink()?;
+    let repos: Vec<String> = repos.iter().map(|r| r.to_string()).collect();
+    digest::send_digest(&RestApi::new(env::var("GITHUB_TOKEN").unwrap_or_default()), &repos, sink.as_ref()).await
+}
+
+//  Review a PR as if it had just been opened
+async fn review_pr(url: &str) -> Result<String, String> {
+    let path = url.trim_start_matches("https://github.com/");
+    let (owner, repo, number) = match path.split('/').collect::<Vec<_>>()[..] {
+        [owner, repo, "pull", number, ..] => (owner, repo, number.parse::<u64>().map_err(|_| format!("Invalid PR number in {}", url))?),
+        _ => return Err(format!("Invalid PR URL {}, expected https://github.com/<owner>/<repo>/pull/<number>", url)),
+    };
+    let github = Capture::new(Source::Remote(RestApi::new(env::var("GITHUB_TOKEN").unwrap_or_default())));
+
+    let pull_request = github.get(&format!("/repos/{}/{}/pulls/{}", owner, repo, number)).await.map_err(|e| format!("Cannot get the PR: {}", e))?;
+    let repository = github.get(&format!("/repos/{}/{}", owner, repo)).await.map_err(|e| format!("Cannot get the repo: {}", e))?;
+    let payload = serde_json::json!({
+        "action": "opened",
+        "number": number,
+        "sender": pull_request["user"],
+        "pull_request": pull_request,
+        "repository": repository,
+    });
+    review(payload, &github).await
+}
+
+//  Review the changes of the working tree against a commit, as if they were a PR
+async fn review_diff(base: &str) -> Result<String, String> {
+    let root = git(&["rev-parse", "--show-toplevel"])?;
+    let root = Path::new(root.trim());
+    let repo = root.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or("repo".to_string());
+    let route = |path: &str| format!("/repos/{}/{}/{}", LOCAL_OWNER, repo, path);
+    let mut answers = HashMap::new();
+
+    // The changed files in the shape of the PR files API, with their content as blobs
+    let mut files = Vec::new();
+    for line in git(&["diff", "--no-renames", "--numstat", base])?.lines() {
+        let mut fields = line.splitn(3, '\t');
+        let (Some(additions), Some(deletions), Some(filename)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        // Binary files have no line counts and no patch
+        let (Ok(additions), Ok(deletions)) = (additions.parse::<u64>(), deletions.parse::<u64>()) else {
+            continue;
+        };
+        let diff = git(&["diff", "--no-renames", "--no-color", "--no-ext-diff", base, "--", filename])?;
+        let patch = diff.find("\n@@").map_or("", |start| &diff[start + 1..]).trim_end();
+        // Removed files have no content to read
+        let (sha, status) = match std::fs::read(root.join(filename)) {
+            Ok(content) => {
+                let sha = git(&["hash-object", &root.join(filename).to_string_lossy()])?.trim().to_string();
+                let content = base64::engine::general_purpose::STANDARD.encode(content);
+                answers.insert(route(&format!("git/blobs/{}", sha)), serde_json::json!({ "content": content }));
+                (sha, if diff.contains("\nnew file mode") { "added" } else { "modified" })
+            }
+            Err(_) => (String::new(), "removed"),
+        };
+        files.push(serde_json::json!({
+            "sha": sha,
+            "filename": filename,
+            "status": status,
+            "additions": additions,
+            "deletions": deletions,
+            "changes": additions + deletions,
+            "blob_url": "https://github.com/",
+            "raw_url": "https://github.com/",
+            "contents_url": "https://github.com/",
+            "patch": patch,
+        }));
+    }
+    if files.is_empty() {
+        return Err(format!("No changes against {}", base));
+    }
+    for (page, files) in files.chunks(100).enumerate() {
+        answers.insert(route(&format!("pulls/0/files?per_page=100&page={}", page + 1)), serde_json::json!(files));
+    }
+
+    // The config file of the checkout applies, as the one on the default branch would
+    if let Ok(config) = std::fs::read(root.join(".github/pr-review.yml")) {
+        let content = base64::engine::general_purpose::STANDARD.encode(config);
+        answers.insert(route("contents/.github/pr-review.yml"), serde_json::json!({ "content": content }));
+    }
+
+    let user = local_user(&git(&["config", "user.name"]).unwrap_or_default());
+    let message = git(&["log", "-1", "--format=%B"]).unwrap_or_default();
+    let (title, body) = message.trim().split_once('\n').unwrap_or((message.trim(), ""));
+    let pull_request = serde_json::json!({
+        "url": "",
+        "id": 0,
+        "number": 0,
+        "title": title,
+        "body": body.trim(),
+        "draft": false,
+        "user": user,
+        "head": { "ref": "HEAD", "sha": git(&["rev-parse", "HEAD"])?.trim() },
+        "base": { "ref": base, "sha": git(&["rev-parse", base])?.trim() },
+    });
+    answers.insert(route("pulls/0"), pull_request.clone());
+    let payload = serde_json::json!({
+        "action": "opened",
+        "number": 0,
+        "sender": user,
+        "pull_request": pull_request,
+        "repository": { "id": 0, "name": repo, "url": "https://github.com/", "owner": local_user(LOCAL_OWNER) },
+    });
+    review(payload, &Capture::new(Source::Local(answers))).await
+}
+
+async fn review(payload: serde_json::Value, github: &Capture) -> Result<String, String> {
+    let event = WebhookEvent::try_from_header_and_body("pull_request", &payload.to_string()).map_err(|e| format!("Invalid PR: {}", e))?;
+    review_event(event, None, github, new_llm_provider).await;
+    github.review().ok_or("No review was written, see the log with RUST_LOG=info".to_string())
+}
+
+//  A GitHub user, as found in events
+fn local_user(login: &str) -> serde_json::Value {
+    let login = match login.trim() {
+        "" => LOCAL_OWNER,
+        login => login,
+    };
+    let url = "https://github.com/";
+    serde_json::json!({
+        "login": login,
+        "id": 0,
+        "node_id": "",
+        "avatar_url": url,
+        "gravatar_id": "",
+        "url": url,
+        "html_url": url,
+        "followers_url": url,
+        "following_url": url,
+        "gists_url": url,
+        "starred_url": url,
+        "subscriptions_url": url,
+        "organizations_url": url,
+        "repos_url": url,
+        "events_url": url,
+        "received_events_url": url,
+        "type": "User",
+        "site_admin": false,
+    })
+}
+
+//  The output of a git command in the current directory
+fn git(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git").args(args).output().map_err(|e| format!("Cannot run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,175 @@
+//  Counters and histograms of the work of the bot on one event, in the Prometheus text format.
+//  They are pushed to the metrics_push_url env var at the end of the event, e.g. a Pushgateway.
+
+use crate::github::GithubApi;
+use async_trait::async_trait;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+
+pub(crate) const REVIEWS: &str = "pr_review_reviews_total";
+pub(crate) const FILES_REVIEWED: &str = "pr_review_files_reviewed_total";
+pub(crate) const FILES_SKIPPED: &str = "pr_review_files_skipped_total";
+pub(crate) const LLM_LATENCY: &str = "pr_review_llm_latency_seconds";
+pub(crate) const LLM_TOKENS: &str = "pr_review_llm_tokens_total";
+pub(crate) const LLM_ERRORS: &str = "pr_review_llm_errors_total";
+pub(crate) const GITHUB_ERRORS: &str = "pr_review_github_errors_total";
+
+//  The help text of each metric
+const HELP: &[(&str, &str)] = &[
+    (REVIEWS, "Reviews and commands performed, by command"),
+    (FILES_REVIEWED, "Changed files reviewed"),
+    (FILES_SKIPPED, "Changed files not reviewed, by reason"),
+    (LLM_LATENCY, "Duration of the LLM calls, retries included"),
+    (LLM_TOKENS, "Estimated LLM tokens, by type"),
+    (LLM_ERRORS, "LLM calls that failed after all retries"),
+    (GITHUB_ERRORS, "Failed GitHub API calls, by kind"),
+];
+
+//  The upper bounds of the buckets of the latency histogram, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+//  A metric name, and its label name and value if any
+type Series = (&'static str, Option<(&'static str, String)>);
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    counters: RefCell<BTreeMap<Series, f64>>,
+    histograms: RefCell<BTreeMap<&'static str, Histogram>>,
+}
+
+impl Metrics {
+    pub(crate) fn add(&self, name: &'static str, value: f64) {
+        *self.counters.borrow_mut().entry((name, None)).or_default() += value;
+    }
+
+    pub(crate) fn add_labeled(&self, name: &'static str, label: &'static str, label_value: &str, value: f64) {
+        *self.counters.borrow_mut().entry((name, Some((label, label_value.to_string())))).or_default() += value;
+    }
+
+    //  The value of a counter, for all label values but the excluded one
+    pub(crate) fn total(&self, name: &'static str, except: Option<&str>) -> f64 {
+        self.counters
+            .borrow()
+            .iter()
+            .filter(|((n, label), _)| *n == name && (except.is_none() || label.as_ref().map(|(_, v)| v.as_str()) != except))
+            .map(|(_, value)| value)
+            .sum()
+    }
+
+    pub(crate) fn observe(&self, name: &'static str, seconds: f64) {
+        let mut histograms = self.histograms.borrow_mut();
+        let histogram = histograms.entry(name).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    //  The Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut described = Vec::new();
+        let mut describe = |out: &mut String, name: &'static str, kind: &str| {
+            if !described.contains(&name) {
+                let help = HELP.iter().find(|(n, _)| *n == name).map_or("", |(_, help)| help);
+                out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+                described.push(name);
+            }
+        };
+        for ((name, label), value) in self.counters.borrow().iter() {
+            describe(&mut out, name, "counter");
+            match label {
+                Some((label, label_value)) => out.push_str(&format!("{}{{{}=\"{}\"}} {}\n", name, label, escape(label_value), value)),
+                None => out.push_str(&format!("{} {}\n", name, value)),
+            }
+        }
+        for (name, histogram) in self.histograms.borrow().iter() {
+            describe(&mut out, name, "histogram");
+            for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                out.push_str(&format!("{}_bucket{{le=\"{}\"}} {}\n", name, bound, count));
+            }
+            out.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, histogram.count));
+            out.push_str(&format!("{}_sum {}\n{}_count {}\n", name, histogram.sum, name, histogram.count));
+        }
+        out
+    }
+
+    //  Push the metrics of the event to metrics_push_url, or log them when it is not set
+    pub(crate) async fn push(&self) {
+        let text = self.render();
+        if text.is_empty() {
+            return;
+        }
+        let Some(url) = env::var("metrics_push_url").ok().filter(|url| !url.trim().is_empty()) else {
+            log::debug!("Metrics of the event:\n{}", text);
+            return;
+        };
+        let res = reqwest::Client::new().post(&url).header("Content-Type", "text/plain; version=0.0.4").body(text).send().await;
+        match res {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => log::error!("Error pushing the metrics: {}", res.status()),
+            Err(error) => log::error!("Error pushing the metrics: {}", error),
+        }
+    }
+}
+
+//  The GitHub API, counting the failed calls
+pub(crate) struct MeteredGithub<'a> {
+    pub(crate) github: &'a dyn GithubApi,
+    pub(crate) metrics: &'a Metrics,
+}
+
+impl MeteredGithub<'_> {
+    fn count<T>(&self, result: octocrab::Result<T>) -> octocrab::Result<T> {
+        if let Err(error) = &result {
+            let kind = match error {
+                octocrab::Error::GitHub { source, .. } => match source.message.to_lowercase() {
+                    m if m.contains("rate limit") => "rate_limit",
+                    m if m.contains("not found") || m.contains("couldn't be found") => "not_found",
+                    _ => "api",
+                },
+                _ => "network",
+            };
+            self.metrics.add_labeled(GITHUB_ERRORS, "kind", kind, 1.0);
+        }
+        result
+    }
+}
+
+#[async_trait(?Send)]
+impl GithubApi for MeteredGithub<'_> {
+    async fn get(&self, route: &str) -> octocrab::Result<serde_json::Value> {
+        self.count(self.github.get(route).await)
+    }
+
+    async fn post(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.count(self.github.post(route, body).await)
+    }
+
+    async fn patch(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.count(self.github.patch(route, body).await)
+    }
+
+    async fn put(&self, route: &str, body: &serde_json::Value) -> octocrab::Result<serde_json::Value> {
+        self.count(self.github.put(route, body).await)
+    }
+
+    async fn delete(&self, route: &str) -> octocrab::Result<()> {
+        self.count(self.github.delete(route).await)
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
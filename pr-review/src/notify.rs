@@ -0,0 +1,201 @@
+//  Notifications of finished reviews, posted to the chat channels of the team through incoming
+//  webhooks. Each chat tool is a Notifier that formats the message for its webhooks.
+
+use crate::config::Config;
+use crate::pipeline::{Finding, Severity, review_verdict};
+use std::env;
+
+//  The number of findings listed in a notification, the most serious first
+const TOP_FINDINGS: usize = 3;
+
+//  Discord cuts messages at this many chars
+const DISCORD_LIMIT: usize = 2000;
+
+//  What a notification says about a finished review
+pub(crate) struct ReviewOutcome<'a> {
+    pub(crate) repo: String,
+    pub(crate) pull_number: u64,
+    pub(crate) title: &'a str,
+    //  The review comment on the PR
+    pub(crate) url: &'a str,
+    pub(crate) findings: &'a [Finding],
+    //  Some files could not be reviewed
+    pub(crate) failed: bool,
+    pub(crate) request_changes_on: Severity,
+}
+
+impl ReviewOutcome<'_> {
+    fn verdict(&self) -> &'static str {
+        match (self.failed, review_verdict(self.findings.iter(), self.request_changes_on)) {
+            (true, _) => "❌ Some files could not be reviewed",
+            (false, "REQUEST_CHANGES") => "⛔ Changes requested",
+            (false, "COMMENT") => "⚠️ Needs a look",
+            _ => "✅ No serious findings",
+        }
+    }
+
+    fn top_findings(&self) -> Vec<&Finding> {
+        let mut top: Vec<&Finding> = self.findings.iter().collect();
+        top.sort_by_key(|f| f.severity);
+        top.truncate(TOP_FINDINGS);
+        top
+    }
+}
+
+//  A chat tool that can be notified of reviews
+pub(crate) trait Notifier {
+    //  The name of the tool in the notify config, e.g. "slack"
+    fn chat(&self) -> &'static str;
+    //  The body of a webhook call that posts the review
+    fn message(&self, review: &ReviewOutcome) -> serde_json::Value;
+}
+
+pub(crate) struct Slack;
+
+impl Notifier for Slack {
+    fn chat(&self) -> &'static str {
+        "slack"
+    }
+
+    //  A mrkdwn text, where &, < and > are markup
+    fn message(&self, review: &ReviewOutcome) -> serde_json::Value {
+        let escape = |s: &str| s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        let mut text = format!(
+            "*<{}|{}#{}>* {}\n{}, {} findings",
+            review.url,
+            escape(&review.repo),
+            review.pull_number,
+            escape(review.title),
+            review.verdict(),
+            review.findings.len()
+        );
+        for f in review.top_findings() {
+            text.push_str(&format!("\n• *{}* `{}` {}", f.severity.as_str(), escape(&place(f)), escape(&f.message)));
+        }
+        serde_json::json!({ "text": text })
+    }
+}
+
+pub(crate) struct Discord;
+
+impl Notifier for Discord {
+    fn chat(&self) -> &'static str {
+        "discord"
+    }
+
+    //  A markdown text that mentions no one, whatever the PR title or the findings say
+    fn message(&self, review: &ReviewOutcome) -> serde_json::Value {
+        let mut text = format!(
+            "**[{}#{}](<{}>)** {}\n{}, {} findings",
+            review.repo,
+            review.pull_number,
+            review.url,
+            review.title,
+            review.verdict(),
+            review.findings.len()
+        );
+        for f in review.top_findings() {
+            text.push_str(&format!("\n- **{}** `{}` {}", f.severity.as_str(), place(f), f.message));
+        }
+        let text: String = text.chars().take(DISCORD_LIMIT).collect();
+        serde_json::json!({ "content": text, "allowed_mentions": { "parse": [] } })
+    }
+}
+
+pub(crate) struct Teams;
+
+impl Notifier for Teams {
+    fn chat(&self) -> &'static str {
+        "teams"
+    }
+
+    //  An Adaptive Card, which the webhooks of Teams workflows take
+    fn message(&self, review: &ReviewOutcome) -> serde_json::Value {
+        let mut body = vec![
+            serde_json::json!({
+                "type": "TextBlock",
+                "text": format!("[{}#{}]({}) {}", review.repo, review.pull_number, review.url, review.title),
+                "weight": "Bolder",
+                "wrap": true,
+            }),
+            serde_json::json!({
+                "type": "TextBlock",
+                "text": format!("{}, {} findings", review.verdict(), review.findings.len()),
+                "wrap": true,
+            }),
+        ];
+        for f in review.top_findings() {
+            body.push(serde_json::json!({
+                "type": "TextBlock",
+                "text": format!("- **{}** `{}` {}", f.severity.as_str(), place(f), f.message),
+                "wrap": true,
+                "spacing": "None",
+            }));
+        }
+        serde_json::json!({
+            "type": "message",
+            "attachments": [{
+                "contentType": "application/vnd.microsoft.card.adaptive",
+                "content": {
+                    "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                    "type": "AdaptiveCard",
+                    "version": "1.4",
+                    "body": body,
+                },
+            }],
+        })
+    }
+}
+
+//  The notifiers of the chat tools in the config
+pub(crate) fn notifiers(config: &Config) -> Vec<Box<dyn Notifier>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    for chat in config.chat_tools() {
+        match chat.as_str() {
+            "slack" => notifiers.push(Box::new(Slack)),
+            "discord" => notifiers.push(Box::new(Discord)),
+            "teams" => notifiers.push(Box::new(Teams)),
+            _ => log::error!("Unknown chat tool to notify: {}", chat),
+        }
+    }
+    notifiers
+}
+
+//  The incoming webhook of the channel for a PR: the first route of the chat tool that matches one
+//  of its labels or changed files, or the default channel of the tool, e.g. in slack_webhook_url
+pub(crate) fn webhook(config: &Config, chat: &str, labels: &[String], paths: &[String]) -> Option<String> {
+    let route = config.notify_channels.iter().find(|c| {
+        c.chat.eq_ignore_ascii_case(chat)
+            && (c.labels.iter().any(|l| labels.iter().any(|label| label.eq_ignore_ascii_case(l)))
+                || c.files.iter().any(|p| paths.iter().any(|path| p.matches(path))))
+    });
+    let name = route.map_or(format!("{}_webhook_url", chat), |c| c.webhook_env.clone());
+    let url = env::var(&name).ok().filter(|url| !url.trim().is_empty());
+    if url.is_none() {
+        log::error!("No {} webhook in the {} env var", chat, name);
+    }
+    url
+}
+
+pub(crate) async fn post_webhook(url: &str, message: &serde_json::Value) -> Result<(), String> {
+    let res = reqwest::Client::new()
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(message.to_string())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = res.status();
+    match status.is_success() {
+        true => Ok(()),
+        false => Err(format!("{}: {}", status, res.text().await.unwrap_or_default())),
+    }
+}
+
+//  The file and line of a finding
+fn place(f: &Finding) -> String {
+    match f.line {
+        Some(line) => format!("{}:{}", f.path, line),
+        None => f.path.clone(),
+    }
+}
@@ -0,0 +1,3585 @@
+//  The review of a PR, from the webhook event to the review comment
+
+use crate::api::{BreakingChange, breaking_changes, is_library_source};
+use crate::changelog::{CHANGELOG_PATH, Entry, insert_entry, parse_entry, render_entry};
+use crate::commands::{Command, ConfigAction, parse_command, render_help};
+use crate::commits::{CONVENTIONAL_COMMIT, CommitReview, commit_problems, parse_suggestions};
+use crate::config::{Config, REVIEW_MODES, RUNTIME_SETTINGS, check_setting, load_config_with, is_generated, is_test_file, language_name, load_config, load_generated_patterns, load_guidelines, load_style_docs};
+use crate::deps::{Advisory, DependencyFile, LicenseIssue, advisories, analyze, is_dependency_file, license_issues};
+use crate::docker::{dockerfile_checklist, is_dockerfile};
+use crate::iac::Iac;
+use crate::github::{
+    CHECK_RUN_ANNOTATIONS, CHECK_RUN_NAME, CodeOwners, GithubApi, IssueComment, Issues, check_run_output, compare_files,
+    from_json, get_file_at, github_budget, github_error_reason, is_bot, is_from_fork, is_maintainer, is_not_found,
+    is_transient_github_error, linked_issues_context, list_commits, list_files, list_package_roots,
+    list_recent_issues, list_repo_labels, load_codeowners, mergeability, package_of, post_comment, pr_head, put_file,
+    related_prs, request_reviewers, set_labels, suggest_reviewers,
+};
+use crate::llm::{
+    ChatRequest, ConnectLlm, Model, RoutedModel, build_models, count_tokens, is_transient_llm_error, llm_error_reason,
+    truncate_tokens,
+};
+use crate::metrics::{FILES_REVIEWED, FILES_SKIPPED, GITHUB_ERRORS, LLM_ERRORS, LLM_LATENCY, LLM_TOKENS, MeteredGithub, Metrics, REVIEWS};
+use crate::notify::{ReviewOutcome, notifiers, post_webhook, webhook};
+use crate::rag::RepoIndex;
+use crate::render::{
+    COMMENT_LIMIT, COMMENT_RESERVE, CWE_ID, PACKAGE_MARKER, REVIEW_MARKER, SARIF_TOOL, SECTIONS_END_MARKER, crc32,
+    format_paths, format_tokens, gzip, link_cwes, parse_reviewed_head, parse_sections, render_failures, render_findings,
+    render_advisories, render_api_changes, render_commit_hygiene, render_config, render_dependencies, render_license_issues,
+    render_mergeability, render_migration_risks, render_rebase_hint, render_related_prs, render_reviewers,
+    render_secrets, render_section, render_toc, review_comment, split_sections, to_sarif, truncate_bytes,
+};
+use crate::retry;
+use crate::symbols::{enclosing_blocks, referenced_types};
+use crate::trace::{self, debug, error, info, warn};
+use crate::triage::{parse_triage, render_triage, similar_issues};
+use crate::workflow::{is_workflow, workflow_issues};
+use base64::Engine;
+use futures::{StreamExt, stream};
+use lazy_static::lazy_static;
+use octocrab::models::pulls::{FileDiff, FileDiffStatus};
+use octocrab::models::issues::Issue;
+use octocrab::models::webhook_events::payload::{
+    IssueCommentWebhookEventAction, IssuesWebhookEventAction, PullRequestWebhookEventAction,
+};
+use octocrab::models::webhook_events::{WebhookEvent, WebhookEventPayload};
+use octocrab::params::checks::{CheckRunConclusion, CheckRunStatus};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    //  The header of a unified diff hunk, capturing the first line number on the new side
+    static ref HUNK_HEADER: Regex = Regex::new(r"^@@ -\d+(?:,\d+)? \+(\d+)(?:,\d+)? @@").unwrap();
+    //  The confidence that ends a finding, e.g. "(confidence: 80)" or "[confidence 80%]."
+    static ref CONFIDENCE: Regex = Regex::new(r"(?i)\s*[(\[]?\s*confidence\s*[:=]?\s*(\d{1,3})\s*%?\s*[)\]]?\s*\.?\s*$").unwrap();
+    //  A verdict of the self-critique pass on a finding, e.g. "2: DROP - the value is checked"
+    static ref CRITIQUE_VERDICT: Regex = Regex::new(r"(?im)^\W*(\d+)\W+(keep|drop)\b").unwrap();
+    //  A finding emitted by the LLM in the form of "LINE <n>: <message>"
+    static ref FINDING_LINE: Regex = Regex::new(
+        r"(?i)^\s*(?:[-*]|\d+\.)?\s*\**(?:\[(blocker|major|minor|nit)\]\**\s*)?\**(?:LINES?\s+(\d+)(?:\s*-\s*(\d+))?\**\s*:\s*)?\**\s*(.+)$"
+    ).unwrap();
+    //  Well-known credential formats
+    static ref SECRET_PATTERNS: Vec<(&'static str, Regex)> = vec![
+        ("private key", Regex::new(r"-----BEGIN (?:RSA |EC |DSA |OPENSSH |PGP |ENCRYPTED )?PRIVATE KEY( BLOCK)?-----").unwrap()),
+        ("AWS access key", Regex::new(r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b").unwrap()),
+        ("GitHub token", Regex::new(r"\b(?:gh[pousr]_[A-Za-z0-9]{36,}|github_pat_[A-Za-z0-9_]{40,})\b").unwrap()),
+        ("Slack token", Regex::new(r"\bxox[abposr]-[A-Za-z0-9-]{10,}").unwrap()),
+        ("Google API key", Regex::new(r"\bAIza[0-9A-Za-z_-]{35}").unwrap()),
+        ("Stripe key", Regex::new(r"\b(?:sk|rk)_live_[0-9A-Za-z]{16,}").unwrap()),
+        ("Anthropic API key", Regex::new(r"\bsk-ant-[A-Za-z0-9_-]{32,}").unwrap()),
+        ("OpenAI API key", Regex::new(r"\bsk-(?:proj-)?[A-Za-z0-9_-]{32,}").unwrap()),
+    ];
+    //  A long value assigned to a secret-sounding name, in code or config files
+    static ref SECRET_ASSIGNMENT: Regex = Regex::new(
+        r#"(?i)\b([A-Za-z0-9_.-]*(?:api[_-]?key|secret|token|passw(?:or)?d|credentials?)[A-Za-z0-9_.-]*)["']?\s*[:=]\s*["']([A-Za-z0-9_\-/+=.]{16,})["']"#
+    ).unwrap();
+    static ref LINKED_ISSUE: Regex = Regex::new(r"(?:^|[\s(])(?:[\w.-]+/[\w.-]+)?#\d+\b|https?://\S+/issues/\d+").unwrap();
+    //  The comments in the code that silence the bot on their line and the next one
+    static ref SUPPRESSION: Regex = Regex::new(r"(?i)flows-review:\s*ignore|noqa:\s*flows").unwrap();
+    static ref CONTINUED_FROM: Regex = Regex::new(r"<!-- pr-review:continued=(\d+) -->").unwrap();
+    static ref REVIEW_STATE: Regex = Regex::new(r"<!-- pr-review:state=([A-Za-z0-9+/=]+) -->").unwrap();
+    static ref HIDDEN_MARKER: Regex = Regex::new(r"<!-- pr-review:[^>]*-->\n?").unwrap();
+}
+
+//  Random-looking values assigned to secret-sounding names are flagged above these bits per char
+const SECRET_ENTROPY: f64 = 3.5;
+
+//  The tokens reserved in the context window for the LLM answer
+pub(crate) const RESPONSE_TOKENS: usize = 1024;
+
+//  The condensed style guide is cut to this many tokens, to leave room for the code
+const MAX_STYLE_GUIDE_TOKENS: usize = 600;
+
+//  Bump this when the review prompts change, so that cached file reviews are not reused
+const PROMPT_VERSION: u32 = 1;
+
+//  The share of time_budget that the file reviews may use. The rest is left for the other passes
+//  and for posting the review.
+const TIME_BUDGET_SHARE: f64 = 0.8;
+
+//  The confidence of the findings that only one model of the ensemble reports, in the "mark" mode
+const SINGLE_MODEL_CONFIDENCE: u8 = 40;
+
+//  How many lines apart the findings of the two models of the ensemble can be and still be the same
+const ENSEMBLE_LINE_SLACK: u64 = 3;
+
+//  Everything the bot does for one webhook event. The GitHub API and the LLM backends are
+//  passed in, so that recorded events can be replayed against mocks. The logs of the event carry
+//  the GUID of its delivery, e.g. the X-GitHub-Delivery header, when the caller has it.
+pub async fn review_event(payload: WebhookEvent, delivery: Option<&str>, github: &dyn GithubApi, connect_llm: ConnectLlm) {
+    let context = trace::Context::of_event(&payload, delivery);
+    trace::scope(context, async {
+        let metrics = Metrics::default();
+        let github = MeteredGithub { github, metrics: &metrics };
+        let acknowledged = RefCell::new(None);
+        review(payload, &github, connect_llm, &metrics, &acknowledged).await;
+        if let Some(ack) = acknowledged.take() {
+            ack.finish(&github, &metrics).await;
+        }
+        metrics.push().await;
+    })
+    .await;
+}
+
+//  The comment with a command that the bot reacted to when it got it, to react again when the
+//  command is done
+struct Acknowledged {
+    owner: String,
+    repo: String,
+    comment_id: u64,
+    max_attempts: u32,
+    //  e.g. the command was refused
+    failed: bool,
+}
+
+impl Acknowledged {
+    //  👍 when the command went through, 😕 when an LLM call or a GitHub call failed. GitHub has no
+    //  ✅ or ❌ reactions. The files that are not found, e.g. the optional config files, don't count.
+    async fn finish(self, github: &dyn GithubApi, metrics: &Metrics) {
+        let failed = self.failed || metrics.total(LLM_ERRORS, None) > 0.0 || metrics.total(GITHUB_ERRORS, Some("not_found")) > 0.0;
+        let content = if failed { "confused" } else { "+1" };
+        let issues = Issues { github, owner: &self.owner, repo: &self.repo };
+        if let Err(error) = retry("add_reaction", self.max_attempts, is_transient_github_error, || issues.add_reaction(self.comment_id, content)).await {
+            warn!("post", "Error reacting to the comment: {}", error);
+        }
+    }
+}
+
+async fn review(
+    payload: WebhookEvent,
+    github: &dyn GithubApi,
+    connect_llm: ConnectLlm,
+    metrics: &Metrics,
+    acknowledged: &RefCell<Option<Acknowledged>>,
+) {
+    let started = Instant::now();
+    // The event comes from any of the repos the flow listens to
+    let (owner, repo) = match payload.repository.as_ref().and_then(|r| Some((r.owner.as_ref()?.login.clone(), r.name.clone()))) {
+        Some(target) => target,
+        None => (
+            env::var("github_owner").unwrap_or("staru09".to_string()),
+            env::var("github_repo").unwrap_or("LFX_test".to_string()),
+        ),
+    };
+    let llm_api_endpoint = env::var("llm_api_endpoint").unwrap_or("https://yicoder9b.us.gaianet.network/v1".to_string());
+    let llm_api_key = env::var("llm_api_key").unwrap_or("LLAMAEDGE".to_string());
+    let mut config = load_config(github, &owner, &repo).await;
+
+    // New issues are triaged, not reviewed
+    if let WebhookEventPayload::Issues(e) = &payload.specific {
+        if e.action != IssuesWebhookEventAction::Opened || !config.issue_triage {
+            debug!("event", "Not an issue Opened event, or issue triage is off");
+            return;
+        }
+        if is_bot(&e.issue.user) {
+            info!("event", "Skip the issue of {}: a bot", e.issue.user.login);
+            return;
+        }
+        let (model, routes, ensemble) = build_models(&config, &llm_api_endpoint, &llm_api_key, connect_llm);
+        let reviewer = Reviewer {
+            github,
+            metrics,
+            config: &config,
+            owner: &owner,
+            repo: &repo,
+            model,
+            routes,
+            ensemble,
+            system: "",
+            min_severity: Severity::Nit,
+            language: language_name(&config.output_language),
+            linked_issues: false,
+            pull_number: e.issue.number,
+            style_guide: String::new(),
+            repo_index: None,
+            migrations: Vec::new(),
+            usage: RefCell::new(HashMap::new()),
+        };
+        reviewer.triage(&e.issue).await;
+        return;
+    }
+
+    let mut new_commit: bool = false;
+    //  The review is brought up to date at once, or made if there is none, rather than on a push
+    let mut refresh = false;
+    let mut opened = false;
+    let mut command = Command::Review { paths: Vec::new(), persona: None, language: None, mode: None };
+    let mut head_sha = String::new();
+    let author_is_bot;
+    let mut from_fork: Option<bool> = None;
+    let mut commenter: Option<String> = None;
+    let mut trigger_comment: Option<u64> = None;
+    let mut pr_labels: Vec<String>;
+
+    let (title, description, pull_number, contributor) = match payload.specific {
+        WebhookEventPayload::PullRequest(e) => {
+            if e.action == PullRequestWebhookEventAction::Opened {
+                opened = true;
+                debug!("event", "Received payload: PR Opened");
+            } else if e.action == PullRequestWebhookEventAction::Synchronize {
+                new_commit = true;
+                debug!("event", "Received payload: PR Synced");
+            } else if e.action == PullRequestWebhookEventAction::ReadyForReview {
+                // A skipped draft is reviewed now. A reviewed draft, or one reviewed by command, gets
+                // its review brought up to date.
+                new_commit = true;
+                refresh = true;
+                debug!("event", "Received payload: PR Ready for review");
+            } else if e.action == PullRequestWebhookEventAction::Reopened {
+                // The review may be stale: the base branch or the checks of the bot moved on while
+                // the PR was closed
+                new_commit = true;
+                refresh = true;
+                debug!("event", "Received payload: PR Reopened");
+            } else {
+                debug!("event", "Not a PR Opened, Synchronize, Ready for review or Reopened event");
+                return;
+            }
+            let p = e.pull_request;
+            if config.skip_drafts && p.draft.unwrap_or(false) {
+                info!("event", "Skip the draft PR until it is ready for review");
+                return;
+            }
+            // PRs into backport or long-lived feature branches are only reviewed by command
+            let default_branch = p.base.repo.as_ref().and_then(|r| r.default_branch.as_deref());
+            if !config.reviews_base(&p.base.ref_field, default_branch) {
+                info!("event", "Skip the PR into {}: not one of the base_branches", p.base.ref_field);
+                return;
+            }
+            head_sha = p.head.sha.clone();
+            from_fork = Some(is_from_fork(&p));
+            pr_labels = p.labels.iter().flatten().map(|l| l.name.clone()).collect();
+            let user = p.user.unwrap();
+            author_is_bot = is_bot(&user);
+            (
+                p.title.unwrap_or("".to_string()),
+                p.body.unwrap_or_default(),
+                p.number,
+                user.login,
+            )
+        }
+        WebhookEventPayload::IssueComment(e) => {
+            if e.action == IssueCommentWebhookEventAction::Deleted {
+                debug!("event", "Deleted issue comment");
+                return;
+            }
+
+            let body = e.comment.body.unwrap_or_default();
+            if body.starts_with("Hello, I am a code reviewer") || body.starts_with("Hello, I am a [code reviewer]") {
+                info!("event", "Ignore comment via agent");
+                return;
+            }
+
+            command = match parse_command(&body, &config) {
+                Some(command) => command,
+                None => {
+                    info!("event", "Ignore the comment without the magic words");
+                    return;
+                }
+            };
+            // An edit runs the command only if it fixed or changed the magic words, not on every
+            // edit of a comment that already ran it
+            if e.action == IssueCommentWebhookEventAction::Edited {
+                let before = e.changes.as_ref().and_then(|c| parse_command(&c.body.from, &config));
+                if before.as_ref() == Some(&command) {
+                    info!("event", "Ignore the edit of a comment whose command already ran");
+                    return;
+                }
+            }
+
+            // A retry or a continue updates the review comment like a new commit, reusing the reviews
+            // that are done
+            new_commit = command == Command::Retry || command == Command::Continue;
+            author_is_bot = is_bot(&e.issue.user);
+            commenter = Some(e.comment.user.login);
+            trigger_comment = Some(e.comment.id.into_inner());
+            pr_labels = e.issue.labels.iter().map(|l| l.name.clone()).collect();
+            (e.issue.title, e.issue.body.unwrap_or_default(), e.issue.number, e.issue.user.login)
+        }
+        _ => return,
+    };
+
+    let issues = Issues { github, owner: &owner, repo: &repo };
+
+    // Let the commenter know right away that the command was heard, before the review gets going
+    if let Some(id) = trigger_comment.filter(|_| config.reactions && !config.dry_run) {
+        match retry("add_reaction", config.max_attempts, is_transient_github_error, || issues.add_reaction(id, "eyes")).await {
+            Ok(()) => {
+                *acknowledged.borrow_mut() = Some(Acknowledged {
+                    owner: owner.clone(),
+                    repo: repo.clone(),
+                    comment_id: id,
+                    max_attempts: config.max_attempts,
+                    failed: false,
+                })
+            }
+            Err(error) => warn!("post", "Error reacting to the comment: {}", error),
+        }
+    }
+
+    // The settings that maintainers changed for this PR with the config command. A new PR has none.
+    if !opened {
+        let settings = match find_review(&issues, pull_number, &config).await {
+            Ok(review) => review.and_then(|r| ReviewState::of(&r, &config)).map(|s| s.settings).unwrap_or_default(),
+            Err(error) => {
+                warn!("config", "Cannot get the settings of the PR: {}", error);
+                BTreeMap::new()
+            }
+        };
+        if !settings.is_empty() {
+            debug!("config", "Settings of the PR: {:?}", settings);
+            config = load_config_with(github, &owner, &repo, &settings).await;
+        }
+    }
+
+    // The review mode asked for in the command wins over the configured one
+    if let Command::Review { mode: Some(mode), .. } = &command {
+        match REVIEW_MODES.iter().find(|m| m.eq_ignore_ascii_case(mode)) {
+            Some(mode) => config.review_mode = mode.to_string(),
+            None => error!("config", "Unknown review mode {}, using {}", mode, config.review_mode),
+        }
+    }
+
+    // The persona asked for in the command wins over the configured one
+    let persona_name = match &command {
+        Command::Review { persona: Some(name), .. } => name.as_str(),
+        _ => config.persona.as_str(),
+    };
+    let persona = match persona_name {
+        "" => None,
+        name => {
+            let persona = config.persona(name);
+            if persona.is_none() {
+                error!("config", "Unknown persona {}, using the default system prompt", name);
+            }
+            persona
+        }
+    };
+    // The issues that the PR says it fixes tell the LLM what problem the changes should solve
+    let linked_issues = match config.linked_issues {
+        true => linked_issues_context(github, &owner, &repo, &description, config.max_attempts).await,
+        false => String::new(),
+    };
+    let mut system = persona
+        .as_ref()
+        .map_or(config.system_prompt.as_str(), |p| p.system_prompt.as_str())
+        .replace("{title}", &title);
+    let guidelines = load_guidelines(github, &owner, &repo, &config).await;
+    if !guidelines.is_empty() {
+        system = format!("{}\n\nFollow the review guidelines of this repository:\n{}", system, guidelines);
+    }
+    if !linked_issues.is_empty() {
+        system = format!("{}\n\n{}", system, linked_issues);
+    }
+    let system = &system;
+    // The language asked for in the command wins over the configured one
+    let language = match &command {
+        Command::Review { language: Some(language), .. } => language_name(language),
+        _ => language_name(&config.output_language),
+    };
+    let (model, routes, ensemble) = build_models(&config, &llm_api_endpoint, &llm_api_key, connect_llm);
+
+    let mut reviewer = Reviewer {
+        github,
+        metrics,
+        config: &config,
+        owner: &owner,
+        repo: &repo,
+        model,
+        routes,
+        ensemble,
+        system,
+        min_severity: persona.as_ref().map_or(Severity::Nit, |p| p.min_severity),
+        language,
+        linked_issues: !linked_issues.is_empty(),
+        pull_number,
+        style_guide: String::new(),
+        repo_index: None,
+        migrations: Vec::new(),
+        usage: RefCell::new(HashMap::new()),
+    };
+
+    // Only the paths owned by the owned_by teams are reviewed. Without a CODEOWNERS file, nothing is.
+    let codeowners = match config.owned_by.is_empty() {
+        true => None,
+        false => Some(load_codeowners(github, &owner, &repo).await.unwrap_or_else(|| {
+            error!("config", "owned_by is set, but {}/{} has no CODEOWNERS file", owner, repo);
+            CodeOwners { rules: Vec::new() }
+        })),
+    };
+    let owned = |path: &str| match &codeowners {
+        Some(c) => c.is_owned_by(path, &config.owned_by),
+        None => true,
+    };
+
+    // Maintainers are the users with write access. Their commands always go through; the sender
+    // policy applies to everything else.
+    let maintainer_command = match &commenter {
+        Some(login) => is_maintainer(github, &owner, &repo, login, config.max_attempts).await,
+        None => false,
+    };
+    if config.has_sender_policy() && !maintainer_command {
+        if config.skip_forks && from_fork.is_none() {
+            from_fork = pr_from_fork(github, &owner, &repo, pull_number, config.max_attempts).await;
+        }
+        if let Some(reason) = config.skip_reason(&contributor, author_is_bot, from_fork.unwrap_or(false)) {
+            info!("event", "Skip the PR of {}: {}", contributor, reason);
+            return;
+        }
+    }
+
+    // The sensitive commands are only for maintainers, on all PRs or on the PRs from forks
+    if let Some(login) = commenter.as_deref().filter(|_| !maintainer_command) {
+        let listed = |names: &[String]| names.iter().any(|n| n.eq_ignore_ascii_case(command.name()));
+        if listed(&config.fork_restricted_commands) && from_fork.is_none() {
+            from_fork = pr_from_fork(github, &owner, &repo, pull_number, config.max_attempts).await;
+        }
+        if listed(&config.restricted_commands) || (listed(&config.fork_restricted_commands) && from_fork == Some(true)) {
+            info!("event", "Refuse the {} command of {}: not a maintainer", command.name(), login);
+            let on = match listed(&config.restricted_commands) {
+                true => "",
+                false => " on PRs from forks",
+            };
+            let body = format!(
+                "@{} Sorry, only the maintainers of this repo, who have write access to it, can use the `{}` command{}.",
+                login, command.name(), on
+            );
+            if let Err(error) = post_comment(&issues, pull_number, &body, &config).await {
+                error!("post", "Error posting the refusal: {}", error);
+            }
+            metrics.add_labeled(REVIEWS, "command", "refused", 1.0);
+            if let Some(ack) = acknowledged.borrow_mut().as_mut() {
+                ack.failed = true;
+            }
+            return;
+        }
+    }
+
+    // The description is drafted before the review, which goes on as usual
+    if opened && description.trim().is_empty() && matches!(config.draft_description.as_str(), "comment" | "edit") {
+        match list_files(github, &owner, &repo, pull_number, config.max_attempts).await {
+            Ok(files) => {
+                if let Some((draft_title, draft)) = reviewer.draft_description(&title, &files).await {
+                    post_draft(github, &issues, pull_number, &title, &draft_title, &draft, &config).await;
+                    metrics.add_labeled(REVIEWS, "command", "draft_description", 1.0);
+                }
+            }
+            Err(error) => error!("fetch", "Cannot get file list: {}", error),
+        }
+    }
+
+    if command == Command::Summarize {
+        let files = match list_files(github, &owner, &repo, pull_number, config.max_attempts).await {
+            Ok(files) => files,
+            Err(error) => {
+                error!("fetch", "Cannot get file list: {}", error);
+                return;
+            }
+        };
+        let Some(summary) = reviewer.describe(&title, &description, &files).await else {
+            return;
+        };
+        let body = format!("Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). Here is a summary of this PR.\n\n------\n\n{}", summary);
+        if let Err(error) = post_comment(&issues, pull_number, &body, &config).await {
+            error!("post", "Error posting summary: {}", error);
+        }
+        reviewer.log_usage("summarize", &reviewer.total_usage());
+        metrics.add_labeled(REVIEWS, "command", "summarize", 1.0);
+        return;
+    }
+
+    if command == Command::Help {
+        if let Err(error) = post_comment(&issues, pull_number, &render_help(&config), &config).await {
+            error!("post", "Error posting the help: {}", error);
+        }
+        metrics.add_labeled(REVIEWS, "command", "help", 1.0);
+        return;
+    }
+
+    if let Command::Config(action) = &command {
+        let asker = commenter.as_deref().unwrap_or(&contributor);
+        let review = match find_review(&issues, pull_number, &config).await {
+            Ok(review) => review,
+            Err(error) => {
+                error!("fetch", "Error getting comments: {}", error);
+                return;
+            }
+        };
+        let mut state = review.as_ref().and_then(|r| ReviewState::of(r, &config)).unwrap_or_default();
+        let body = match action {
+            ConfigAction::Show => render_config(&config.overview(), &state.settings),
+            ConfigAction::Set(key, value) => match (check_setting(key, value), &review) {
+                (Err(reason), _) => format!(
+                    "@{} {}. These settings can be changed by command: {}.",
+                    asker,
+                    reason,
+                    format_paths(RUNTIME_SETTINGS.iter().copied())
+                ),
+                (Ok(()), None) => format!("@{} I have not reviewed this PR yet. Say `{}` to get a review first.", asker, config.trigger_phrase),
+                (Ok(()), Some(review)) => {
+                    state.settings.insert(key.clone(), value.clone());
+                    if let Err(error) = save_state(&issues, review, &state, &config).await {
+                        error!("post", "Error saving the settings: {}", error);
+                        return;
+                    }
+                    format!("@{} `{}` is now `{}` for the next reviews of this PR.", asker, key, value)
+                }
+            },
+            ConfigAction::Reset(key) => match (state.settings.remove(key), &review) {
+                (Some(_), Some(review)) => {
+                    if let Err(error) = save_state(&issues, review, &state, &config).await {
+                        error!("post", "Error saving the settings: {}", error);
+                        return;
+                    }
+                    format!("@{} `{}` is back to the value of the config for the next reviews of this PR.", asker, key)
+                }
+                _ => format!("@{} `{}` was not changed for this PR.", asker, key),
+            },
+        };
+        if let Err(error) = post_comment(&issues, pull_number, &body, &config).await {
+            error!("post", "Error posting the config reply: {}", error);
+        }
+        metrics.add_labeled(REVIEWS, "command", "config", 1.0);
+        return;
+    }
+
+    if command == Command::RebaseHint {
+        let Some(m) = mergeability(github, &owner, &repo, pull_number, config.max_attempts).await else {
+            return;
+        };
+        if let Err(error) = post_comment(&issues, pull_number, &render_rebase_hint(&m, &owner, &repo), &config).await {
+            error!("post", "Error posting the rebase hint: {}", error);
+        }
+        metrics.add_labeled(REVIEWS, "command", "rebase_hint", 1.0);
+        return;
+    }
+
+    if let Command::Changelog { commit } = command {
+        let files = match list_files(github, &owner, &repo, pull_number, config.max_attempts).await {
+            Ok(files) => files,
+            Err(error) => {
+                error!("fetch", "Cannot get file list: {}", error);
+                return;
+            }
+        };
+        let Some(answer) = reviewer.changelog_entry(&title, &description, &files).await else {
+            return;
+        };
+        let entry = parse_entry(&answer);
+        let body = match entry.is_empty() {
+            true => "Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). I found no changes in this PR that are worth a changelog entry.".to_string(),
+            false => {
+                let mut body = format!(
+                    "Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). Here is a changelog entry for this PR, in the [Keep a Changelog](https://keepachangelog.com) format.\n\n------\n\n```markdown\n{}```\n",
+                    render_entry(&entry)
+                );
+                // Only the author and the maintainers can have the bot push to the branch of the PR
+                let asker = commenter.as_deref().unwrap_or(&contributor);
+                if commit && !asker.eq_ignore_ascii_case(&contributor) && !maintainer_command {
+                    body.push_str("\n_Only the author of the PR and the maintainers can commit the entry._\n");
+                } else if commit && !config.dry_run {
+                    body.push_str(&format!("\n{}\n", commit_changelog(github, &owner, &repo, pull_number, &entry, config.max_attempts).await));
+                }
+                body
+            }
+        };
+        if let Err(error) = post_comment(&issues, pull_number, &body, &config).await {
+            error!("post", "Error posting changelog entry: {}", error);
+        }
+        reviewer.log_usage("changelog", &reviewer.total_usage());
+        metrics.add_labeled(REVIEWS, "command", "changelog", 1.0);
+        return;
+    }
+
+    if let Command::Security(paths) = &command {
+        let files = match list_files(github, &owner, &repo, pull_number, config.max_attempts).await {
+            Ok(files) => files,
+            Err(error) => {
+                error!("fetch", "Cannot get file list: {}", error);
+                return;
+            }
+        };
+        let files: Vec<FileDiff> = files
+            .into_iter()
+            .filter(|f| config.should_review(&f.filename) && owned(&f.filename) && in_scope(&f.filename, paths))
+            .take(config.max_files)
+            .collect();
+        let reviews: Vec<String> = stream::iter(files)
+            .enumerate()
+            .map(|(i, f)| {
+                let reviewer = &reviewer;
+                trace::for_file(f.filename.clone(), async move {
+                    let (text, _) = reviewer.security_pass(i, &f).await?;
+                    Some(format!("## [{}]({})\n\n{}\n\n", f.filename, f.blob_url.as_str(), text))
+                })
+            })
+            .buffered(config.max_concurrency.max(1))
+            .filter_map(|r| async move { r })
+            .collect()
+            .await;
+        let body = match reviews.is_empty() {
+            true => "Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). I found no changed files to review for security issues.".to_string(),
+            false => format!(
+                "Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). Here is a security review of the changes.\n\n------\n\n{}",
+                reviews.concat()
+            ),
+        };
+        if let Err(error) = post_comment(&issues, pull_number, &body, &config).await {
+            error!("post", "Error posting security review: {}", error);
+        }
+        reviewer.log_usage("security", &reviewer.total_usage());
+        metrics.add_labeled(REVIEWS, "command", "security", 1.0);
+        return;
+    }
+
+    if let Command::Ask(question) = &command {
+        // The context of the follow-up is the latest review of the PR
+        let review = match issues.list_comments(pull_number).await {
+            Ok(comments) => comments
+                .into_iter()
+                .filter(|c| is_own_comment(c, &config))
+                .filter_map(|c| c.body)
+                .rfind(|body| body.contains(REVIEW_MARKER)),
+            Err(error) => {
+                error!("fetch", "Error getting comments: {}", error);
+                return;
+            }
+        };
+        let asker = commenter.as_deref().unwrap_or(&contributor);
+        let answer = match review {
+            Some(review) => reviewer.answer(question, &review).await,
+            None => Some(format!(
+                "I have not reviewed this PR yet. Say `{}` to get a review first.",
+                config.trigger_phrase
+            )),
+        };
+        let Some(answer) = answer else {
+            return;
+        };
+        let quote = question.lines().map(|l| format!("> {}", l)).collect::<Vec<_>>().join("\n");
+        let body = format!("{}\n\n@{} {}", quote, asker, answer);
+        if let Err(error) = post_comment(&issues, pull_number, &body, &config).await {
+            error!("post", "Error posting answer: {}", error);
+        }
+        reviewer.log_usage("ask", &reviewer.total_usage());
+        metrics.add_labeled(REVIEWS, "command", "ask", 1.0);
+        return;
+    }
+
+    if let Command::Ignore(args) = &command {
+        // Only the author and the maintainers can silence the bot on a PR
+        let asker = commenter.as_deref().unwrap_or(&contributor);
+        if !asker.eq_ignore_ascii_case(&contributor) && !maintainer_command {
+            info!("event", "Ignore the suppression by {}, who is neither the author nor a maintainer", asker);
+            return;
+        }
+        // The suppressions are kept in the state of the latest review comment
+        let review = match find_review(&issues, pull_number, &config).await {
+            Ok(review) => review,
+            Err(error) => {
+                error!("fetch", "Error getting comments: {}", error);
+                return;
+            }
+        };
+        let reply = match review {
+            Some(review) => {
+                let mut state = ReviewState::of(&review, &config).unwrap_or_default();
+                for arg in args {
+                    let (list, item) = match arg.strip_prefix("rule:") {
+                        Some(rule) => (&mut state.ignored_rules, rule.to_string()),
+                        None => (&mut state.ignored_files, arg.clone()),
+                    };
+                    if !item.is_empty() && !list.contains(&item) {
+                        list.push(item);
+                    }
+                }
+                if let Err(error) = save_state(&issues, &review, &state, &config).await {
+                    error!("post", "Error saving the suppressions: {}", error);
+                    return;
+                }
+                format!(
+                    "@{} I will leave {} out of future reviews of this PR. Say `{}` to review it again now.",
+                    asker,
+                    format_paths(args.iter().map(String::as_str)),
+                    config.trigger_phrase
+                )
+            }
+            None => format!("@{} I have not reviewed this PR yet. Say `{}` to get a review first.", asker, config.trigger_phrase),
+        };
+        if let Err(error) = post_comment(&issues, pull_number, &reply, &config).await {
+            error!("post", "Error posting reply: {}", error);
+        }
+        return;
+    }
+
+    if let Command::Explain(paths) = &command {
+        let files = match list_files(github, &owner, &repo, pull_number, config.max_attempts).await {
+            Ok(files) => files,
+            Err(error) => {
+                error!("fetch", "Cannot get file list: {}", error);
+                return;
+            }
+        };
+        let files: Vec<FileDiff> = files
+            .into_iter()
+            .filter(|f| config.should_review(&f.filename) && owned(&f.filename) && in_scope(&f.filename, paths))
+            .take(config.max_files)
+            .collect();
+        let walkthroughs: Vec<String> = stream::iter(files)
+            .enumerate()
+            .map(|(i, f)| trace::for_file(f.filename.clone(), reviewer.explain_file(i, f)))
+            .buffered(config.max_concurrency.max(1))
+            .filter_map(|w| async move { w })
+            .collect()
+            .await;
+        let body = match walkthroughs.is_empty() {
+            true => "Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). I found no changed files to explain in these paths.".to_string(),
+            false => format!(
+                "Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). Here is a walkthrough of the changes.\n\n------\n\n{}",
+                walkthroughs.concat()
+            ),
+        };
+        if let Err(error) = post_comment(&issues, pull_number, &body, &config).await {
+            error!("post", "Error posting walkthrough: {}", error);
+        }
+        reviewer.log_usage("explain", &reviewer.total_usage());
+        metrics.add_labeled(REVIEWS, "command", "explain", 1.0);
+        return;
+    }
+
+    // A review of some paths only, by another persona or in another language, is a one-off:
+    // it is not updated on new commits
+    let (scope, one_off) = match &command {
+        Command::Review { paths, persona, language, mode } => {
+            (paths.as_slice(), !paths.is_empty() || persona.is_some() || language.is_some() || mode.is_some())
+        }
+        _ => (&[][..], false),
+    };
+    let review_marker = match !one_off {
+        true => REVIEW_MARKER,
+        false => "",
+    };
+
+    // Each push triggers its own event. Wait for more pushes, and leave the review to the event
+    // of the latest one, so that rapid pushes don't race to update the same comment.
+    if new_commit && !refresh && config.debounce_secs > 0 && !head_sha.is_empty() {
+        tokio::time::sleep(Duration::from_secs(config.debounce_secs)).await;
+        match pr_head(github, &owner, &repo, pull_number, config.max_attempts).await {
+            Some(latest) if latest != head_sha => {
+                info!("event", "Skip the review of {}: {} was pushed since", head_sha, latest);
+                return;
+            }
+            _ => {}
+        }
+    }
+
+    // Defer the review rather than run out of GitHub API calls in the middle of it
+    if !github_budget(github, config.github_reserve, config.rate_limit_wait).await {
+        warn!("event", "Defer the review of PR #{}: the GitHub API rate limit is nearly used up", pull_number);
+        if commenter.is_some() {
+            let body = format!(
+                "Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). The GitHub API rate limit of this bot is nearly used up. Please say `{}` again in an hour.",
+                config.trigger_phrase
+            );
+            if let Err(error) = post_comment(&issues, pull_number, &body, &config).await {
+                error!("post", "Error posting comment: {}", error);
+            }
+        }
+        return;
+    }
+
+    let mut comment_id: u64 = 0;
+    let mut previous_review = String::new();
+    //  The comments that the sections of long reviews overflow to, by the id of their review comment
+    let mut continuations: Vec<(u64, u64, String)> = Vec::new();
+
+    let rereview = command == Command::ReReview;
+    // A redelivered PR Opened event, e.g. after the runtime killed the review, resumes the review
+    // comment of the first delivery
+    if new_commit || rereview || opened {
+        // Find the latest review comment to update
+        match issues.list_comments(pull_number).await {
+            Ok(comments) => {
+                for c in comments.into_iter().filter(|c| is_own_comment(c, &config)) {
+                    let body = c.body.unwrap_or_default();
+                    if body.contains(REVIEW_MARKER) {
+                        comment_id = c.id;
+                        previous_review = body;
+                    } else if let Some(cap) = CONTINUED_FROM.captures(&body) {
+                        let review_id = cap[1].parse().unwrap_or_default();
+                        continuations.push((review_id, c.id, body));
+                    }
+                }
+            }
+            // A new PR gets a new review comment anyway
+            Err(error) if opened => warn!("fetch", "Cannot look for a review to resume: {}", error),
+            Err(error) => {
+                error!("fetch", "Error getting comments: {}", error);
+                return;
+            }
+        }
+    }
+
+    let resuming = command == Command::Continue || (opened && comment_id != 0);
+    if opened && comment_id != 0 {
+        info!("event", "Resume the review of PR #{}: the event was delivered again", pull_number);
+    }
+    if command == Command::Continue && ReviewState::load(&previous_review).is_some_and(|s| s.pending.is_empty()) {
+        let asker = commenter.as_deref().unwrap_or(&contributor);
+        let body = format!(
+            "@{} The last review did not leave any files for later. Say `{}` to review the failed files again, or `{}` for a fresh review.",
+            asker, config.retry_phrase, config.rereview_phrase
+        );
+        if let Err(error) = post_comment(&issues, pull_number, &body, &config).await {
+            error!("post", "Error posting comment: {}", error);
+        }
+        return;
+    }
+
+    if rereview && comment_id != 0 && !config.dry_run {
+        // Start over in a new comment. Only the files and findings asked to be ignored, and the
+        // change request to dismiss, are carried over.
+        let previous_state = ReviewState::load(&previous_review).unwrap_or_default();
+        previous_review = ReviewState {
+            change_request: previous_state.change_request,
+            ignored_files: previous_state.ignored_files,
+            ignored_rules: previous_state.ignored_rules,
+            settings: previous_state.settings,
+            ..Default::default()
+        }
+        .store();
+        continuations.retain(|(review_id, _, _)| *review_id == comment_id);
+        for id in std::iter::once(comment_id).chain(continuations.drain(..).map(|(_, id, _)| id)) {
+            if let Err(error) = retry("delete_comment", config.max_attempts, is_transient_github_error, || issues.delete_comment(id)).await {
+                error!("post", "Error deleting the previous review: {}", error);
+            }
+        }
+        comment_id = 0;
+    }
+
+    if comment_id == 0 && (!new_commit || refresh) && !config.dry_run {
+        // PR OPEN, Ready for review, Reopened or Trigger phrase: create a new comment
+        let placeholder = format!("Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/).\n\nIt could take a few minutes for me to analyze this PR. Relax, grab some protein shake and complete 10-15 pushups. Thanks!\n{}", review_marker);
+        match retry("create_comment", config.max_attempts, is_transient_github_error, || issues.create_comment(pull_number, &placeholder)).await {
+            Ok(comment) => {
+                comment_id = comment.id;
+            }
+            Err(error) => {
+                error!("post", "Error posting comment: {}", error);
+                return;
+            }
+        }
+    }
+
+    if comment_id == 0 && !config.dry_run {
+        if command == Command::Retry || command == Command::Continue {
+            info!("event", "Nothing to {}: PR #{} has no review yet", command.name(), pull_number);
+        }
+        return;
+    }
+
+    if head_sha.is_empty() {
+        head_sha = pr_head(github, &owner, &repo, pull_number, config.max_attempts).await.unwrap_or_default();
+    }
+
+    // On new commits, only the files touched since the last reviewed head are reviewed again.
+    // The other files keep their previous review.
+    continuations.retain(|(review_id, _, _)| *review_id == comment_id);
+    let mut previous_sections = parse_sections(&previous_review);
+    for (_, _, body) in &continuations {
+        previous_sections.extend(parse_sections(body));
+    }
+    let previous_state = ReviewState::load(&previous_review).unwrap_or_default();
+    let mut state = ReviewState {
+        head: head_sha.clone(),
+        reviews: previous_state.reviews + 1,
+        ignored_files: previous_state.ignored_files.clone(),
+        ignored_rules: previous_state.ignored_rules.clone(),
+        ..Default::default()
+    };
+    // The conventions of the project are condensed once for each version of its style docs
+    if config.style_guide {
+        state.style_guide = reviewer.condense_style_guide(previous_state.style_guide.as_ref()).await;
+        reviewer.style_guide = state.style_guide.as_ref().map(|g| g.summary.clone()).unwrap_or_default();
+    }
+    let mut changed: Option<HashSet<String>> = None;
+    if config.incremental && !head_sha.is_empty() {
+        // Comments from before the state was kept only record the head
+        let reviewed_head = Some(previous_state.head.clone())
+            .filter(|head| !head.is_empty())
+            .or_else(|| parse_reviewed_head(&previous_review));
+        if let Some(reviewed_head) = reviewed_head {
+            match compare_files(github, &owner, &repo, &reviewed_head, &head_sha, config.max_attempts).await {
+                Ok(files) => changed = Some(files),
+                Err(error) => error!("fetch", "Cannot compare {}...{}, reviewing all files: {}", reviewed_head, head_sha, error),
+            }
+        }
+    }
+
+    // The check run shows the review in the checks tab of the PR, and can gate merges
+    let mut check_run_route = None;
+    if config.check_run && !config.dry_run && !head_sha.is_empty() {
+        let route = format!("/repos/{}/{}/check-runs", owner, repo);
+        let run = serde_json::json!({ "name": CHECK_RUN_NAME, "head_sha": head_sha, "status": CheckRunStatus::InProgress });
+        match retry("create_check_run", config.max_attempts, is_transient_github_error, || github.post(&route, &run)).await {
+            Ok(run) => check_run_route = run["id"].as_u64().map(|id| format!("{}/{}", route, id)),
+            Err(error) => error!("checks", "Cannot create the check run: {}", error),
+        }
+    }
+
+    let mut resp = String::new();
+    match scope.is_empty() {
+        true => resp.push_str("Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). Here are my reviews of changed source code files in this PR.\n\n------\n\n"),
+        false => resp.push_str(&format!(
+            "Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). Here are my reviews of the changed files in {} in this PR.\n\n------\n\n",
+            format_paths(scope.iter().map(String::as_str))
+        )),
+    }
+    let mut sections = String::new();
+    let mut notes = String::new();
+    let mut overview = String::new();
+    let mut review_comments: Vec<serde_json::Value> = Vec::new();
+    let mut findings: Vec<Finding> = Vec::new();
+    let mut secrets: Vec<Finding> = Vec::new();
+    let mut dependencies: Vec<DependencyFile> = Vec::new();
+    let mut vulnerabilities: Vec<Advisory> = Vec::new();
+    let mut licenses: Vec<LicenseIssue> = Vec::new();
+    let mut api_changes: Vec<BreakingChange> = Vec::new();
+    let mut missing_tests = None;
+    let mut reviewed = 0;
+    let mut failed = false;
+    //  The newer head that was pushed during the review, if any
+    let mut superseded = None;
+    //  The files whose review failed, and why
+    let mut failures: Vec<(String, String)> = Vec::new();
+    //  The number of files that were left out by the budgets
+    let mut left_out = 0;
+    //  The infrastructure as code files, whose findings are listed apart
+    let mut infrastructure: Vec<String> = Vec::new();
+    //  The lines added and deleted by the PR, and the paths of all changed files
+    let mut changed_lines = 0;
+    let mut changed_paths: Vec<String> = Vec::new();
+
+    match list_files(github, &owner, &repo, pull_number, config.max_attempts).await {
+        Ok(files) => {
+            changed_lines = files.iter().map(|f| f.additions + f.deletions).sum();
+            changed_paths = files.iter().map(|f| f.filename.clone()).collect();
+            // Secrets are flagged in all changed files, including the ones that are not reviewed
+            if config.secret_scan {
+                for f in &files {
+                    secrets.extend(scan_secrets(&f.filename, f.patch.as_deref().unwrap_or_default()));
+                }
+            }
+            // The test changes count even in files that are not reviewed, e.g. skipped languages
+            let coverage_files: Vec<FileDiff> = match config.missing_tests {
+                true => files.iter().filter(|f| in_scope(&f.filename, scope) && !is_generated(&f.filename, &[])).cloned().collect(),
+                false => Vec::new(),
+            };
+            let mut files: Vec<FileDiff> = files.into_iter().filter(|f| {
+                let ignored = !state.ignored_files.is_empty() && in_scope(&f.filename, &state.ignored_files);
+                let review = config.should_review(&f.filename) && owned(&f.filename) && in_scope(&f.filename, scope) && !ignored;
+                if !review {
+                    debug!("filter", "Skipping {} by file filters", f.filename);
+                    metrics.add_labeled(FILES_SKIPPED, "reason", "filters", 1.0);
+                }
+                review
+            }).collect();
+            if files.is_empty() && !scope.is_empty() {
+                notes.push_str("_No changed files to review in these paths._\n\n");
+            }
+            if !state.ignored_files.is_empty() {
+                notes.push_str(&format!(
+                    "_Files matching {} are ignored on request._\n\n",
+                    format_paths(state.ignored_files.iter().map(String::as_str))
+                ));
+            }
+            if files.is_empty() && codeowners.is_some() {
+                notes.push_str(&format!("_None of the changed files are owned by {}._\n\n", config.owned_by.join(", ")));
+            }
+            // Manifests and lockfiles get the list of their dependency changes instead of an LLM review.
+            // The manifests with other changes, e.g. scripts, are reviewed too.
+            if config.dependency_analysis {
+                for f in files.iter().filter(|f| is_dependency_file(&f.filename)) {
+                    dependencies.push(analyze(&f.filename, f.patch.as_deref(), &config.risky_dependencies));
+                }
+                let total = files.len();
+                files.retain(|f| !dependencies.iter().any(|d| d.path == f.filename && d.complete));
+                if files.len() < total {
+                    metrics.add_labeled(FILES_SKIPPED, "reason", "dependencies", (total - files.len()) as f64);
+                }
+                if config.vulnerability_lookup {
+                    vulnerabilities = advisories(&dependencies, &config).await;
+                }
+                if !config.license_allowlist.is_empty() {
+                    licenses = license_issues(&dependencies, &config).await;
+                }
+            }
+            // Lockfiles, minified bundles, generated code and binaries are not worth the tokens
+            let (mut files, generated): (Vec<FileDiff>, Vec<FileDiff>) = match config.skip_generated {
+                true => {
+                    let generated = load_generated_patterns(github, &owner, &repo).await;
+                    files.into_iter().partition(|f| !is_generated(&f.filename, &generated))
+                }
+                false => (files, Vec::new()),
+            };
+            if !generated.is_empty() {
+                metrics.add_labeled(FILES_SKIPPED, "reason", "generated", generated.len() as f64);
+                notes.push_str(&format!(
+                    "_{} generated or binary files were not reviewed: {}._\n\n",
+                    generated.len(), format_paths(generated.iter().map(|f| f.filename.as_str()))
+                ));
+            }
+            // The public items of library crates are compared before and after the change
+            if config.api_changes {
+                for f in files.iter().filter(|f| f.status != FileDiffStatus::Added && is_library_source(&f.filename)) {
+                    let head = match f.status {
+                        FileDiffStatus::Removed => String::new(),
+                        _ => match reviewer.fetch_file(f).await {
+                            Ok(text) => text,
+                            Err(_) => continue,
+                        },
+                    };
+                    api_changes.extend(breaking_changes(&f.filename, &head, f.patch.as_deref().unwrap_or_default()));
+                }
+            }
+            let skipped: Vec<String> = files.iter().skip(config.max_files).map(|f| f.filename.clone()).collect();
+            files.truncate(config.max_files);
+            if config.migration_review {
+                reviewer.migrations = files.iter().map(|f| f.filename.clone()).filter(|path| config.is_migration(path)).collect();
+            }
+            // The files with passes of their own, the ones reviewed by the ensemble, and the ones
+            // without a patch, are reviewed one by one
+            let combinable = |f: &FileDiff| {
+                f.patch.is_some()
+                    && !config.is_migration(&f.filename)
+                    && !is_dockerfile(&f.filename)
+                    && !is_workflow(&f.filename)
+                    && !reviewer.ensemble.as_ref().is_some_and(|(patterns, _)| patterns.iter().any(|p| p.matches(&f.filename)))
+            };
+            // A small PR is reviewed in one prompt
+            let fast_path = config.fast_path_lines > 0
+                && changed_lines < config.fast_path_lines
+                && !files.is_empty()
+                && files.iter().all(combinable);
+            let combine = fast_path || config.review_mode == "combined";
+            // The definitions of the repo give the file reviews the context of the other files
+            if (config.rag || config.review_mode == "symbols") && !head_sha.is_empty() && !files.is_empty() && !combine {
+                let changed: Vec<(String, String)> = files.iter().map(|f| (f.filename.clone(), f.sha.clone())).collect();
+                match RepoIndex::build(github, &owner, &repo, &head_sha, &changed, &reviewer.model, &config).await {
+                    Ok(index) => reviewer.repo_index = Some(index),
+                    Err(error) => error!("rag", "Cannot index the repo, reviewing the files on their own: {}", error),
+                }
+            }
+            // A review is reused when it was made of the same blob with the same prompts.
+            // Files missing from the state are reused when they are unchanged since the last head.
+            let previous_review = |f: &FileDiff| {
+                let unchanged = match previous_state.files.get(&f.filename) {
+                    Some(file) => (config.incremental || resuming) && file.cache_key == reviewer.cache_key(f),
+                    None if previous_state.failed.contains(&f.filename) => false,
+                    None => changed.as_ref().is_some_and(|changed| !changed.contains(&f.filename)),
+                };
+                previous_sections.get(&f.filename).filter(|_| unchanged).cloned()
+            };
+            // The patches that are reviewed together go first, then the files reviewed one by one
+            let mut combined: Vec<FileReview> = Vec::new();
+            if combine {
+                let candidates: Vec<&FileDiff> = files.iter().filter(|f| combinable(f) && previous_review(f).is_none()).collect();
+                combined = reviewer.review_combined(&candidates).await;
+                // A failed fast path falls back to the reviews of the files
+                if fast_path && config.review_mode != "combined" && combined.iter().any(|r| r.failure.is_some()) {
+                    combined.clear();
+                }
+                if fast_path && !combined.is_empty() {
+                    debug!("review", "Review the {} changed lines of the PR in one prompt", changed_lines);
+                    notes.push_str(&format!("_This PR has fewer than {} changed lines, so its files were reviewed together in one pass._\n\n", config.fast_path_lines));
+                }
+                let (mut first, rest): (Vec<FileDiff>, Vec<FileDiff>) = files.into_iter().partition(|f| combined.iter().any(|r| r.path == f.filename));
+                first.sort_by_key(|f| combined.iter().position(|r| r.path == f.filename));
+                files = first.into_iter().chain(rest).collect();
+            }
+            let precomputed = combined.len();
+            let total = files.len();
+            let names: Vec<String> = files.iter().map(|f| f.filename.clone()).collect();
+            let (mut bytes, mut tokens) = (0, 0);
+            let mut over_budget = None;
+            let mut reused = 0;
+            let mut silenced = 0;
+            let mut refuted = 0;
+            let mut unconfirmed = 0;
+            let mut file_sections: Vec<(String, String)> = Vec::new();
+            // Review several files at a time; `buffered` yields the results in file order
+            let mut reviews = stream::iter(combined.into_iter().map(Some)).chain(
+                stream::iter(files.into_iter().skip(precomputed))
+                .enumerate()
+                .map(|(i, f)| {
+                    let cache_key = reviewer.cache_key(&f);
+                    let previous = previous_review(&f);
+                    let infrastructure = previous_state.files.get(&f.filename).is_some_and(|file| file.infrastructure);
+                    let reviewer = &reviewer;
+                    trace::for_file(f.filename.clone(), async move {
+                        match previous {
+                            Some(section) => {
+                                debug!("cache", "{} is unchanged since the last review", f.filename);
+                                let findings = parse_findings(&f.filename, &section);
+                                Some(FileReview {
+                                    section,
+                                    findings,
+                                    reused: true,
+                                    infrastructure,
+                                    ..FileReview::new(f.filename, cache_key)
+                                })
+                            }
+                            None => reviewer.review_file(i, f).await,
+                        }
+                    })
+                })
+                .buffered(config.max_concurrency.max(1)),
+            );
+            let mut done = 0;
+            while let Some(review) = reviews.next().await {
+                done += 1;
+                if let Some(mut review) = review {
+                    review.suppress(&state.ignored_rules);
+                    if review.reused {
+                        reused += 1;
+                    }
+                    silenced += review.silenced;
+                    refuted += review.refuted;
+                    unconfirmed += review.unconfirmed;
+                    // Failed reviews are not cached, so that they are tried again
+                    match &review.failure {
+                        Some(reason) => {
+                            failures.push((review.path.clone(), reason.clone()));
+                            state.failed.push(review.path.clone());
+                        }
+                        None => {
+                            state.files.insert(review.path.clone(), FileState {
+                                cache_key: review.cache_key.clone(),
+                                verdict: review.findings.iter().map(|f| f.severity).min(),
+                                findings: review.findings.len(),
+                                infrastructure: review.infrastructure,
+                            });
+                            if review.infrastructure {
+                                infrastructure.push(review.path.clone());
+                            }
+                            reviewed += 1;
+                            metrics.add(FILES_REVIEWED, 1.0);
+                        }
+                    }
+                    bytes += review.bytes;
+                    tokens += review.tokens;
+                    sections.push_str(&review.section);
+                    file_sections.push((review.path.clone(), review.section.clone()));
+                    review_comments.extend(review.comments);
+                    findings.extend(review.findings);
+                }
+                // The reviews made together are all done already, no need to check on each of them
+                if done < precomputed {
+                    continue;
+                }
+                // Stop gracefully once a budget is used up; the files in progress are dropped
+                if config.max_bytes > 0 && bytes >= config.max_bytes {
+                    over_budget = Some("max_bytes");
+                } else if config.max_tokens > 0 && tokens >= config.max_tokens {
+                    over_budget = Some("max_tokens");
+                } else if config.time_budget > 0 && started.elapsed() >= Duration::from_secs(config.time_budget).mul_f64(TIME_BUDGET_SHARE) {
+                    over_budget = Some("time_budget");
+                } else if done < total && !github_budget(github, config.github_reserve, config.rate_limit_wait).await {
+                    over_budget = Some("github_reserve");
+                }
+                if over_budget.is_some() && done < total {
+                    break;
+                }
+                // Stop calling the LLM once a newer commit is pushed: the event of that commit reviews it
+                if done < total && !head_sha.is_empty() {
+                    if let Some(latest) = pr_head(github, &owner, &repo, pull_number, config.max_attempts).await.filter(|latest| *latest != head_sha) {
+                        superseded = Some(latest);
+                        break;
+                    }
+                }
+                // Show the partial review instead of the placeholder while the rest is in progress.
+                // Its state is the cursor of the review: if the runtime kills the review, the
+                // continue command picks up the files that are left.
+                if config.progress_updates && !config.dry_run && done < total {
+                    let usage = reviewer.total_usage();
+                    state.pending = names[done..].to_vec();
+                    state.prompt_tokens = previous_state.prompt_tokens + usage.prompt_tokens;
+                    state.completion_tokens = previous_state.completion_tokens + usage.completion_tokens;
+                    let cursor = match one_off {
+                        true => String::new(),
+                        false => state.store(),
+                    };
+                    let partial = format!(
+                        "{}{}_Reviewed {} of {} files. More reviews are coming..._\n{}{}",
+                        resp, sections, done, total, cursor, review_marker
+                    );
+                    let partial = truncate_bytes(&partial, COMMENT_LIMIT);
+                    if let Err(error) = retry("update_comment", config.max_attempts, is_transient_github_error, || issues.update_comment(comment_id, partial)).await {
+                        error!("post", "Error posting partial response: {}", error);
+                    }
+                }
+            }
+            drop(reviews);
+            state.pending.clear();
+            let mut not_reviewed: Vec<(&str, &[String])> = Vec::new();
+            if let Some(budget) = over_budget.filter(|_| done < total) {
+                not_reviewed.push((budget, &names[done..]));
+            }
+            if !skipped.is_empty() {
+                not_reviewed.push(("max_files", &skipped));
+            }
+            for (budget, paths) in not_reviewed {
+                left_out += paths.len();
+                metrics.add_labeled(FILES_SKIPPED, "reason", budget, paths.len() as f64);
+                // The files left out for time are the ones that the continue command reviews
+                if budget == "time_budget" {
+                    state.pending = paths.to_vec();
+                    notes.push_str(&format!(
+                        "_{} files were not reviewed because the review ran out of time: {}. Say `{}` to review them._\n\n",
+                        paths.len(), format_paths(paths.iter().map(String::as_str)), config.continue_phrase,
+                    ));
+                    continue;
+                }
+                notes.push_str(&format!(
+                    "_{} files were not reviewed because of the `{}` budget: {}. To review them, say `{} <path>` in a comment, e.g. `{} {}`._\n\n",
+                    paths.len(), budget, format_paths(paths.iter().map(String::as_str)),
+                    config.trigger_phrase, config.trigger_phrase, paths[0],
+                ));
+            }
+            if silenced > 0 {
+                notes.push_str(&format!("_{} findings were silenced by `flows-review: ignore` comments in the code._\n\n", silenced));
+            }
+            if refuted > 0 {
+                notes.push_str(&format!("_{} findings were dropped as likely false positives by a second look at the changes._\n\n", refuted));
+            }
+            if unconfirmed > 0 {
+                notes.push_str(&match config.ensemble_mode.as_str() {
+                    "mark" => format!("_{} findings were reported by only one of the two models that reviewed the high-stakes files, and are listed as low confidence._\n\n", unconfirmed),
+                    _ => format!("_{} findings were left out because only one of the two models that reviewed the high-stakes files reported them._\n\n", unconfirmed),
+                });
+            }
+            if reused > 0 {
+                notes.push_str(&format!("_{} files are unchanged since the last review, and keep their previous review._\n\n", reused));
+            }
+            // Out of time, the review is posted as it is
+            let out_of_time = over_budget == Some("time_budget") && done < total;
+            if config.group_by_package && !head_sha.is_empty() && !out_of_time {
+                let roots = list_package_roots(github, &owner, &repo, &head_sha, config.max_attempts).await;
+                if let Some(grouped) = reviewer.group_by_package(&file_sections, &roots).await {
+                    sections = grouped;
+                }
+            }
+            if config.missing_tests && !out_of_time {
+                missing_tests = reviewer.missing_tests(&title, &coverage_files).await;
+            }
+        }
+        Err(error) => {
+            error!("fetch", "Cannot get file list: {}", error);
+            failed = true;
+        }
+    }
+
+    if let Some(latest) = superseded {
+        info!("review", "Cancel the review of {}: {} was pushed since", head_sha, latest);
+        reviewer.log_usage("cancelled", &reviewer.total_usage());
+        // The review comment is left to the newer review; a one-off comment says why it stopped
+        if one_off && !config.dry_run {
+            let body = format!(
+                "{}_This review was cancelled because new commits were pushed. Say `{}` again to review them._\n",
+                resp, config.trigger_phrase
+            );
+            if let Err(error) = retry("update_comment", config.max_attempts, is_transient_github_error, || issues.update_comment(comment_id, &body)).await {
+                error!("post", "Error posting response: {}", error);
+            }
+        }
+        if let Some(route) = &check_run_route {
+            let update = serde_json::json!({ "status": CheckRunStatus::Completed, "conclusion": CheckRunConclusion::Cancelled });
+            let result = retry("update_check_run", config.max_attempts, is_transient_github_error, || github.patch(route, &update)).await;
+            if let Err(error) = result {
+                error!("checks", "Error updating the check run: {}", error);
+            }
+        }
+        return;
+    }
+
+    if config.max_behind > 0 && !one_off {
+        if let Some(m) = mergeability(github, &owner, &repo, pull_number, config.max_attempts).await {
+            resp.push_str(&render_mergeability(&m, config.max_behind, &config.rebase_hint_phrase));
+        }
+    }
+    resp.push_str(&render_secrets(&secrets));
+    // The migration pass may repeat the findings of the file review
+    let mut migration_risks: Vec<&Finding> = Vec::new();
+    for f in findings.iter().filter(|f| f.severity <= Severity::Major && config.is_migration(&f.path)) {
+        if !migration_risks.iter().any(|r| r.path == f.path && r.line == f.line && r.message == f.message) {
+            migration_risks.push(f);
+        }
+    }
+    resp.push_str(&render_migration_risks(&migration_risks));
+    resp.push_str(&render_dependencies(&dependencies));
+    resp.push_str(&render_advisories(&vulnerabilities));
+    resp.push_str(&render_license_issues(&licenses, &config.license_allowlist));
+    resp.push_str(&render_api_changes(&api_changes));
+    if config.summary && !sections.is_empty() {
+        if let Some(summary) = reviewer.summarize(&title, &description, &sections).await {
+            resp.push_str(&summary);
+            resp.push_str("\n\n------\n\n");
+            overview = summary;
+        }
+    }
+    if config.related_prs && !one_off && !changed_paths.is_empty() {
+        let related = related_prs(github, &owner, &repo, pull_number, &changed_paths, config.max_attempts).await;
+        resp.push_str(&render_related_prs(&related));
+    }
+    if (config.suggest_reviewers || config.request_reviewers) && !one_off && !changed_paths.is_empty() {
+        let suggestions = suggest_reviewers(github, &owner, &repo, &changed_paths, &contributor, config.max_attempts).await;
+        if config.suggest_reviewers && !suggestions.is_empty() {
+            resp.push_str(&render_reviewers(&suggestions));
+            resp.push_str("------\n\n");
+        }
+        // Reviewers are only requested once, so that the ones who already reviewed are not asked again
+        if config.request_reviewers && !new_commit && !config.dry_run {
+            request_reviewers(github, &owner, &repo, pull_number, &suggestions, config.max_attempts).await;
+        }
+    }
+    let (infrastructure_findings, code_findings): (Vec<&Finding>, Vec<&Finding>) =
+        findings.iter().partition(|f| infrastructure.contains(&f.path));
+    for (title, findings) in [("Findings by severity", &code_findings), ("Infrastructure findings", &infrastructure_findings)] {
+        let grouped = render_findings(title, findings);
+        if !grouped.is_empty() {
+            resp.push_str(&grouped);
+            resp.push_str("------\n\n");
+        }
+    }
+    if !failures.is_empty() {
+        resp.push_str(&render_failures(&failures, &config.retry_phrase));
+        resp.push_str("------\n\n");
+    }
+    if let Some(missing_tests) = missing_tests {
+        resp.push_str("### Missing tests\n\n");
+        resp.push_str(&missing_tests);
+        resp.push_str("\n\n------\n\n");
+    }
+    let commits = match config.review_description || config.commit_hygiene {
+        true => match list_commits(github, &owner, &repo, pull_number, config.max_attempts).await {
+            Ok(commits) => commits,
+            Err(error) => {
+                error!("fetch", "Cannot get the commits: {}", error);
+                Vec::new()
+            }
+        },
+        false => Vec::new(),
+    };
+    if config.review_description && !commits.is_empty() {
+        let messages: Vec<String> = commits.iter().map(|(_, message)| message.clone()).collect();
+        if let Some(review) = reviewer.review_description(&title, &description, &messages).await {
+            resp.push_str("### PR description and commits\n\n");
+            resp.push_str(&review);
+            resp.push_str("\n\n------\n\n");
+        }
+    }
+    if config.commit_hygiene {
+        let mut poor: Vec<CommitReview> = commits
+            .iter()
+            .map(|(sha, message)| CommitReview { sha: sha.clone(), message: message.clone(), problems: commit_problems(message), suggestion: None })
+            .filter(|c| !c.problems.is_empty())
+            .collect();
+        if !poor.is_empty() {
+            reviewer.rewrite_commits(&title, &changed_paths, &mut poor).await;
+            resp.push_str(&render_commit_hygiene(&poor, commits.len()));
+        }
+    }
+    if state.files.len() > 1 {
+        resp.push_str(&render_toc(&state.files));
+    }
+    // The sections that don't fit in the review comment go to continuation comments
+    let room = COMMENT_LIMIT.saturating_sub(resp.len() + notes.len() + state.store().len() + COMMENT_RESERVE);
+    let mut parts = split_sections(&sections, room, COMMENT_LIMIT - COMMENT_RESERVE).into_iter();
+    resp.push_str(&parts.next().unwrap_or_default());
+    resp.push_str(SECTIONS_END_MARKER);
+    resp.push('\n');
+    resp.push_str(&notes);
+    let parts: Vec<String> = parts.collect();
+    let review_url = format!("https://github.com/{}/{}/pull/{}#issuecomment-{}", owner, repo, pull_number, comment_id);
+    if !parts.is_empty() || !continuations.is_empty() {
+        let total = parts.len() + 1;
+        let mut links = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let body = format!(
+                "<!-- pr-review:continued={} -->\n_Part {} of {} of [the code review]({})._\n\n{}{}\n",
+                comment_id, i + 2, total, review_url, part, SECTIONS_END_MARKER
+            );
+            if config.dry_run {
+                info!("post", "Dry run: part {} of the review of PR #{}:\n{}", i + 2, pull_number, body);
+                continue;
+            }
+            // Reuse the continuation comments of the previous review, in order
+            let posted = match continuations.get(i) {
+                Some((_, id, _)) => retry("update_comment", config.max_attempts, is_transient_github_error, || issues.update_comment(*id, &body)).await,
+                None => retry("create_comment", config.max_attempts, is_transient_github_error, || issues.create_comment(pull_number, &body)).await,
+            };
+            match posted {
+                Ok(comment) => links.push(format!("[part {}]({})", i + 2, comment.html_url)),
+                Err(error) => error!("post", "Error posting part {} of the review: {}", i + 2, error),
+            }
+        }
+        for (_, id, _) in continuations.iter().skip(parts.len()).filter(|_| !config.dry_run) {
+            if let Err(error) = retry("delete_comment", config.max_attempts, is_transient_github_error, || issues.delete_comment(*id)).await {
+                error!("post", "Error deleting an outdated part of the review: {}", error);
+            }
+        }
+        if !links.is_empty() {
+            resp.push_str(&format!("_This review is too long for one comment. It goes on in {}._\n", links.join(", ")));
+        }
+    }
+
+    // The PR review can also approve the PR or request changes, to take part in required reviews
+    // Only maintainers can have the bot approve a PR by command, else the author could ask for
+    // reviews until one approves
+    // A review that failed or left out files has not seen the whole PR, so it never approves
+    let complete = !failed && failures.is_empty() && state.pending.is_empty() && left_out == 0;
+    let verdict = match config.review_verdict && !one_off && !failed {
+        true => match review_verdict(findings.iter().chain(&secrets), config.request_changes_on) {
+            "APPROVE" if commenter.is_some() && !maintainer_command => None,
+            "APPROVE" if !complete => Some("COMMENT"),
+            verdict => Some(verdict),
+        },
+        false => None,
+    };
+    // The verdict that GitHub took, if any
+    let mut posted_verdict = None;
+    if !review_comments.is_empty() || verdict.is_some() {
+        // Anchor the findings to the exact lines through a PR review
+        let route = format!("/repos/{}/{}/pulls/{}/reviews", owner, repo, pull_number);
+        let body = match verdict {
+            Some("APPROVE") => format!("The [code review]({}) has no serious findings.", review_url),
+            Some("REQUEST_CHANGES") => format!(
+                "The [code review]({}) has findings of severity `{}` or more serious. Please address them.",
+                review_url,
+                config.request_changes_on.as_str()
+            ),
+            Some("COMMENT") if !complete => format!(
+                "The [code review]({}) is incomplete, as some files were not reviewed, so it does not approve the PR.",
+                review_url
+            ),
+            _ => "Inline findings from the [code reviewer](https://github.com/flows-network/github-pr-review/).".to_string(),
+        };
+        let review = serde_json::json!({
+            "event": verdict.unwrap_or("COMMENT"),
+            "body": body,
+            "comments": review_comments,
+        });
+        let posted = match config.dry_run {
+            true => {
+                info!("post", "Dry run: PR review of PR #{}:\n{}", pull_number, review);
+                Ok(serde_json::Value::Null)
+            }
+            false => retry("create_review", config.max_attempts, is_transient_github_error, || github.post(&route, &review)).await,
+        };
+        match posted {
+            Ok(review) => {
+                if !review_comments.is_empty() {
+                    resp.push_str(&format!("_Posted {} inline review comments._\n", review_comments.len()));
+                }
+                posted_verdict = verdict;
+                if verdict == Some("REQUEST_CHANGES") {
+                    state.change_request = review["id"].as_u64();
+                }
+            }
+            Err(error) => {
+                error!("post", "Error posting review: {}", error);
+            }
+        }
+    }
+    // A request for changes blocks the merge until it is dismissed, which only a complete review
+    // whose verdict was posted and does not request changes again can do
+    let dismiss = posted_verdict.is_some_and(|v| v != "REQUEST_CHANGES") && complete && !config.dry_run;
+    if !dismiss && state.change_request.is_none() {
+        state.change_request = previous_state.change_request;
+    }
+    if let Some(review_id) = previous_state.change_request.filter(|_| dismiss) {
+        let route = format!("/repos/{}/{}/pulls/{}/reviews/{}/dismissals", owner, repo, pull_number, review_id);
+        let dismissal = serde_json::json!({ "message": "The findings were addressed in newer commits." });
+        if let Err(error) = retry("dismiss_review", config.max_attempts, is_transient_github_error, || {
+            github.put(&route, &dismissal)
+        })
+        .await
+        {
+            error!("post", "Error dismissing the request for changes: {}", error);
+        }
+    }
+
+    // The findings as JSON for downstream tooling, in a secret gist or in the check run
+    let leaked_secrets = !secrets.is_empty();
+    findings.extend(secrets);
+    let findings_json = serde_json::to_string_pretty(&serde_json::json!({
+        "repository": format!("{}/{}", owner, repo),
+        "pull_number": pull_number,
+        "head_sha": head_sha,
+        "findings": findings,
+    }))
+    .unwrap_or_default();
+    if config.findings_json == "gist" && !config.dry_run {
+        let gist = serde_json::json!({
+            "description": format!("Code review findings for {}/{}#{}", owner, repo, pull_number),
+            "public": false,
+            "files": { format!("pr-review-{}-{}-{}.json", owner, repo, pull_number): { "content": findings_json } },
+        });
+        match retry("create_gist", config.max_attempts, is_transient_github_error, || {
+            github.post("/gists", &gist)
+        })
+        .await
+        {
+            Ok(gist) => {
+                let url = gist["html_url"].as_str().unwrap_or_default();
+                resp.push_str(&format!("_The findings as JSON are in [this gist]({})._\n", url));
+            }
+            Err(error) => error!("post", "Error creating the findings gist: {}", error),
+        }
+    }
+
+    // Code scanning alerts in the Security tab of the repo
+    if config.sarif_upload && !config.dry_run && !head_sha.is_empty() {
+        let route = format!("/repos/{}/{}/code-scanning/sarifs", owner, repo);
+        let upload = serde_json::json!({
+            "commit_sha": head_sha,
+            "ref": format!("refs/pull/{}/head", pull_number),
+            "sarif": base64::engine::general_purpose::STANDARD.encode(gzip(to_sarif(&findings).to_string().as_bytes())),
+            "tool_name": SARIF_TOOL,
+        });
+        if let Err(error) = retry("upload_sarif", config.max_attempts, is_transient_github_error, || {
+            github.post(&route, &upload)
+        })
+        .await
+        {
+            error!("post", "Error uploading the SARIF findings: {}", error);
+        }
+    }
+
+    // The tokens of the LLM calls made for this review; reused file reviews cost nothing
+    let usage = reviewer.total_usage();
+    reviewer.log_usage("pr", &usage);
+    metrics.add_labeled(REVIEWS, "command", "review", 1.0);
+    state.prompt_tokens = previous_state.prompt_tokens + usage.prompt_tokens;
+    state.completion_tokens = previous_state.completion_tokens + usage.completion_tokens;
+    let spent = state.prompt_tokens + state.completion_tokens;
+    match state.reviews {
+        1 => resp.push_str(&format!("_Reviewed {} files, ~{} tokens._\n", reviewed, format_tokens(usage.total()))),
+        n => resp.push_str(&format!(
+            "_Reviewed {} files, ~{} tokens (~{} tokens in {} reviews of this PR)._\n",
+            reviewed, format_tokens(usage.total()), format_tokens(spent), n
+        )),
+    }
+    resp.push_str(&state.store());
+    resp.push_str(review_marker);
+
+    if config.dry_run {
+        info!("post", "Dry run: review of PR #{}:\n{}", pull_number, resp);
+        debug!("post", "Dry run: findings of PR #{}:\n{}", pull_number, findings_json);
+        return;
+    }
+
+    // Send the entire response to GitHub PR
+    if let Err(error) = retry("update_comment", config.max_attempts, is_transient_github_error, || issues.update_comment(comment_id, &resp)).await {
+        error!("post", "Error posting response: {}", error);
+    }
+
+    if let Some(route) = &check_run_route {
+        let blockers = findings.iter().any(|f| f.severity == Severity::Blocker);
+        let conclusion = if failed || (config.fail_on_blockers && blockers) {
+            CheckRunConclusion::Failure
+        } else if !findings.is_empty() || !complete {
+            // The files that were not reviewed may hide findings, so an incomplete review is no success
+            CheckRunConclusion::Neutral
+        } else {
+            CheckRunConclusion::Success
+        };
+        let mut summary = format!("Reviewed {} files, with {} findings.", reviewed, findings.len());
+        if !failures.is_empty() || left_out > 0 {
+            summary.push_str(&format!(" {} files failed and {} were left out.", failures.len(), left_out));
+        }
+        if !overview.is_empty() {
+            summary.push_str("\n\n");
+            summary.push_str(&overview);
+        }
+        // The JSON goes first, so that it is not cut by the size limit of the output
+        let text = match config.findings_json == "check_run" {
+            true => format!("```json\n{}\n```\n\n{}{}", findings_json, sections, notes),
+            false => format!("{}{}", sections, notes),
+        };
+        // GitHub takes at most 50 annotations per request, and appends the annotations of later updates
+        let annotated: Vec<&Finding> = findings.iter().filter(|f| f.line.is_some()).collect();
+        let mut batches: Vec<&[&Finding]> = annotated.chunks(CHECK_RUN_ANNOTATIONS).collect();
+        if batches.is_empty() {
+            batches.push(&[]);
+        }
+        for (i, batch) in batches.into_iter().enumerate() {
+            let mut update = serde_json::json!({ "output": check_run_output(&summary, &text, batch) });
+            if i == 0 {
+                update["status"] = serde_json::json!(CheckRunStatus::Completed);
+                update["conclusion"] = serde_json::json!(conclusion);
+            }
+            let result = retry("update_check_run", config.max_attempts, is_transient_github_error, || github.patch(route, &update)).await;
+            if let Err(error) = result {
+                error!("checks", "Error updating the check run: {}", error);
+                break;
+            }
+        }
+    }
+
+    // Labels for triage. One-off reviews don't cover the whole PR, so they don't label it.
+    if !one_off && !failed {
+        let mut labels: Vec<&str> = Vec::new();
+        let mut managed: Vec<&str> = Vec::new();
+        if config.labels {
+            let serious = findings.iter().any(|f| f.severity <= Severity::Major);
+            // The security pass tags its findings with CWE ids; vulnerable dependencies count too
+            let security = leaked_secrets || !vulnerabilities.is_empty() || findings.iter().any(|f| f.severity <= Severity::Major && CWE_ID.is_match(&f.message));
+            let outcome = [
+                (config.needs_work_label.as_str(), serious),
+                (config.security_label.as_str(), security),
+                // A review that left out files can't vouch for the PR
+                (config.lgtm_label.as_str(), !serious && !security && complete),
+            ];
+            for (label, applies) in outcome.into_iter().filter(|(label, _)| !label.is_empty()) {
+                managed.push(label);
+                if applies {
+                    labels.push(label);
+                }
+            }
+        }
+        if config.size_labels {
+            managed.extend(SIZE_LABELS.iter().map(|(label, _)| *label));
+            labels.push(size_label(changed_lines));
+        }
+        if !managed.is_empty() {
+            set_labels(&issues, pull_number, &labels, &managed, config.max_attempts).await;
+        }
+        pr_labels.extend(labels.iter().map(|l| l.to_string()));
+    }
+
+    // The verdict and the top findings in the chats of the team
+    if !one_off {
+        let outcome = ReviewOutcome {
+            repo: format!("{}/{}", owner, repo),
+            pull_number,
+            title: &title,
+            url: &review_url,
+            findings: &findings,
+            failed,
+            request_changes_on: config.request_changes_on,
+        };
+        for notifier in notifiers(&config) {
+            let Some(url) = webhook(&config, notifier.chat(), &pr_labels, &changed_paths) else {
+                continue;
+            };
+            if let Err(error) = post_webhook(&url, &notifier.message(&outcome)).await {
+                error!("notify", "Error notifying {}: {}", notifier.chat(), error);
+            }
+        }
+    }
+}
+
+//  The event of the PR review: request changes when a finding is at least as serious as
+//  `request_changes_on`, comment on major findings, and approve otherwise
+pub(crate) fn review_verdict<'a>(findings: impl Iterator<Item = &'a Finding>, request_changes_on: Severity) -> &'static str {
+    match findings.map(|f| f.severity).min() {
+        Some(severity) if severity <= request_changes_on => "REQUEST_CHANGES",
+        Some(severity) if severity <= Severity::Major => "COMMENT",
+        _ => "APPROVE",
+    }
+}
+
+//  The size labels, by the max number of changed lines
+const SIZE_LABELS: [(&str, u64); 5] = [("size/XS", 9), ("size/S", 29), ("size/M", 99), ("size/L", 499), ("size/XL", u64::MAX)];
+
+fn size_label(changed_lines: u64) -> &'static str {
+    SIZE_LABELS.iter().find(|(_, max)| changed_lines <= *max).map_or("size/XL", |(label, _)| label)
+}
+
+//  The latest review comment of the PR, which holds the state of its reviews
+async fn find_review(issues: &Issues<'_>, pull_number: u64, config: &Config) -> octocrab::Result<Option<IssueComment>> {
+    let comments = issues.list_comments(pull_number).await?;
+    Ok(comments.into_iter().rfind(|c| is_own_comment(c, config) && c.body.as_deref().is_some_and(|body| body.contains(REVIEW_MARKER))))
+}
+
+//  Whether the bot wrote a comment. Anyone can paste the markers and the state of a review into a
+//  comment, so only the comments of a GitHub App, with the bot_name login if it is set, are trusted.
+fn is_own_comment(c: &IssueComment, config: &Config) -> bool {
+    let Some(user) = &c.user else {
+        return false;
+    };
+    let name = config.bot_name.trim().trim_start_matches('@').trim_end_matches("[bot]");
+    let login = user.login.trim_end_matches("[bot]");
+    (user.kind == "Bot" || user.login.ends_with("[bot]")) && (name.is_empty() || login.eq_ignore_ascii_case(name))
+}
+
+//  Write the state into the review comment. In dry-run mode, the state is only logged.
+async fn save_state(issues: &Issues<'_>, review: &IssueComment, state: &ReviewState, config: &Config) -> octocrab::Result<()> {
+    let body = review.body.as_deref().unwrap_or_default();
+    let body = match REVIEW_STATE.is_match(body) {
+        true => REVIEW_STATE.replace(body, state.store().trim_end()).into_owned(),
+        false => body.replacen(REVIEW_MARKER, &format!("{}{}", state.store(), REVIEW_MARKER), 1),
+    };
+    if config.dry_run {
+        info!("post", "Dry run: not saving the review state {:?}", state);
+        return Ok(());
+    }
+    retry("update_comment", config.max_attempts, is_transient_github_error, || issues.update_comment(review.id, &body)).await.map(|_| ())
+}
+
+//  Whether a PR comes from a fork, for the events that do not carry the PR
+async fn pr_from_fork(github: &dyn GithubApi, owner: &str, repo: &str, pull_number: u64, max_attempts: u32) -> Option<bool> {
+    let route = format!("/repos/{}/{}/pulls/{}", owner, repo, pull_number);
+    match retry("get_pull", max_attempts, is_transient_github_error, || github.get(&route)).await.and_then(from_json) {
+        Ok(pr) => Some(is_from_fork(&pr)),
+        Err(error) => {
+            error!("fetch", "Cannot get the PR: {}", error);
+            None
+        }
+    }
+}
+
+//  Write the drafted description into the PR in "edit" mode, else, or when the PR cannot be
+//  edited, suggest the title and the description in a comment
+async fn post_draft(github: &dyn GithubApi, issues: &Issues<'_>, pull_number: u64, title: &str, draft_title: &str, draft: &str, config: &Config) {
+    if config.draft_description == "edit" && !config.dry_run {
+        let route = format!("/repos/{}/{}/pulls/{}", issues.owner, issues.repo, pull_number);
+        let body = serde_json::json!({
+            "body": format!("{}\n\n_This description was drafted by a [code reviewer](https://github.com/flows-network/github-pr-review/) from the changes. Please check it._\n", draft),
+        });
+        match github.patch(&route, &body).await {
+            Ok(_) => {
+                info!("post", "Wrote the drafted description into PR #{}", pull_number);
+                return;
+            }
+            Err(error) => warn!("post", "Cannot edit PR #{}, suggesting the description in a comment: {}", pull_number, error),
+        }
+    }
+    let mut body = String::from("Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). This PR has no description, so here is a draft that you can copy into it.\n\n");
+    if !draft_title.is_empty() && draft_title != title {
+        body.push_str(&format!("**Suggested title:** {}\n\n", draft_title));
+    }
+    body.push_str(&format!("------\n\n```markdown\n{}\n```\n", draft));
+    if let Err(error) = post_comment(issues, pull_number, &body, config).await {
+        error!("post", "Error posting the drafted description: {}", error);
+    }
+}
+
+//  Add the entry to the CHANGELOG.md on the head branch of the PR. Returns the note for the reply.
+async fn commit_changelog(github: &dyn GithubApi, owner: &str, repo: &str, pull_number: u64, entry: &Entry, max_attempts: u32) -> String {
+    let route = format!("/repos/{}/{}/pulls/{}", owner, repo, pull_number);
+    let pr = match retry("get_pull", max_attempts, is_transient_github_error, || github.get(&route)).await {
+        Ok(pr) => pr,
+        Err(error) => return format!("_The entry could not be committed: {}._", github_error_reason(&error)),
+    };
+    let branch = pr["head"]["ref"].as_str().unwrap_or_default();
+    let Some((head_owner, head_repo)) = pr["head"]["repo"]["full_name"].as_str().and_then(|name| name.split_once('/')) else {
+        return "_The entry could not be committed: the head repo of the PR is gone._".to_string();
+    };
+    let (sha, changelog) = match retry("get_content", max_attempts, is_transient_github_error, || get_file_at(github, head_owner, head_repo, CHANGELOG_PATH, Some(branch))).await {
+        Ok((sha, text)) => (Some(sha), text),
+        Err(error) if is_not_found(&error) => (None, String::new()),
+        Err(error) => return format!("_The entry could not be committed: {}._", github_error_reason(&error)),
+    };
+    let mut commit = serde_json::json!({ "message": format!("Add the changelog entry of #{}", pull_number), "branch": branch });
+    if let Some(sha) = &sha {
+        commit["sha"] = serde_json::json!(sha);
+    }
+    match put_file(github, head_owner, head_repo, CHANGELOG_PATH, &insert_entry(&changelog, entry), commit).await {
+        Ok(commit) => {
+            info!("post", "Committed the changelog entry to {} in {}", branch, commit);
+            format!("_The entry was added to `{}` in {}._", CHANGELOG_PATH, commit)
+        }
+        Err(error) => {
+            error!("post", "Cannot commit the changelog entry: {}", error);
+            format!("_The entry could not be committed: {}._", github_error_reason(&error))
+        }
+    }
+}
+
+//  A path is in scope if it is one of the paths, in one of the directories, or matches one of the globs
+fn in_scope(path: &str, scope: &[String]) -> bool {
+    scope.is_empty()
+        || scope.iter().any(|s| {
+            let s = s.trim_start_matches("./").trim_end_matches('/');
+            path == s
+                || path.strip_prefix(s).is_some_and(|rest| rest.starts_with('/'))
+                || glob::Pattern::new(s).is_ok_and(|p| p.matches(path))
+        })
+}
+
+//  Everything needed to review the files of one PR
+struct Reviewer<'a> {
+    github: &'a dyn GithubApi,
+    metrics: &'a Metrics,
+    config: &'a Config,
+    owner: &'a str,
+    repo: &'a str,
+    //  The default model, and the models routed to by file patterns
+    model: Model,
+    routes: Vec<RoutedModel>,
+    //  The second model that reviews the files matching its patterns, see review_ensemble
+    ensemble: Option<RoutedModel>,
+    system: &'a str,
+    //  Less serious findings are left out
+    min_severity: Severity,
+    //  The natural language of the answers, or "" for English
+    language: &'a str,
+    //  The system prompt has the issues that the PR says it fixes
+    linked_issues: bool,
+    //  The conventions of the project, added to the system prompt of the file reviews
+    style_guide: String,
+    //  The definitions of the repo, to find the ones related to each patch
+    repo_index: Option<RepoIndex>,
+    //  The database migrations that the PR changes, for the migration reviews
+    migrations: Vec<String>,
+    pull_number: u64,
+    //  The tokens used by each conversation with the LLM
+    usage: RefCell<HashMap<String, Usage>>,
+}
+
+//  Token counts, estimated with the same tokenizer as the prompt budgets
+#[derive(Debug, Default, Clone, Copy)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+impl Usage {
+    fn total(&self) -> usize {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+//  The review of one changed file
+struct FileReview {
+    path: String,
+    //  See Reviewer::cache_key
+    cache_key: String,
+    //  The markdown section for the review comment
+    section: String,
+    //  Inline comments to post in the PR review
+    comments: Vec<serde_json::Value>,
+    //  All findings tagged with a severity or a line number
+    findings: Vec<Finding>,
+    //  The section comes from the previous review of an unchanged file
+    reused: bool,
+    //  Why the file, or a part of it, could not be reviewed
+    failure: Option<String>,
+    //  The findings silenced by suppression comments in the code
+    silenced: usize,
+    //  The findings that the self-critique pass found to be false positives
+    refuted: usize,
+    //  The findings that only one model of the ensemble reported
+    unconfirmed: usize,
+    //  The size of the reviewed code, and the LLM tokens spent on it
+    bytes: usize,
+    tokens: usize,
+    //  The file is infrastructure as code, whose findings are listed apart
+    infrastructure: bool,
+}
+
+//  What the bot remembers of the reviews of a PR. It is kept as base64 JSON in a hidden marker of
+//  the review comment, so that it lives as long as the PR and needs no storage of its own. Only the
+//  state of a comment that the bot wrote is read, see is_own_comment.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ReviewState {
+    //  The last reviewed head commit
+    head: String,
+    //  The files of the last review, by path
+    files: BTreeMap<String, FileState>,
+    //  The PR review of the bot that requests changes, while it is in force
+    change_request: Option<u64>,
+    //  The files whose review failed in the last review
+    failed: Vec<String>,
+    //  The files and the findings that the author asked to leave out, see Command::Ignore
+    ignored_files: Vec<String>,
+    ignored_rules: Vec<String>,
+    //  The conventions of the project, condensed from its style docs
+    style_guide: Option<StyleGuide>,
+    //  The files left to review when the last review stopped early, in review order, see
+    //  Command::Continue
+    pending: Vec<String>,
+    //  The settings that maintainers changed for the PR, see Command::Config
+    settings: BTreeMap<String, String>,
+    //  The number of reviews of the PR, and the tokens that they used in total
+    reviews: usize,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StyleGuide {
+    //  The blob SHAs of the style docs that were condensed
+    key: String,
+    summary: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct FileState {
+    cache_key: String,
+    //  The severity of the most serious finding, if there are any
+    pub(crate) verdict: Option<Severity>,
+    pub(crate) findings: usize,
+    //  The file is infrastructure as code, see `Iac`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    infrastructure: bool,
+}
+
+impl ReviewState {
+    //  The state in a review comment, if the bot wrote the comment
+    fn of(comment: &IssueComment, config: &Config) -> Option<ReviewState> {
+        match is_own_comment(comment, config) {
+            true => ReviewState::load(comment.body.as_deref().unwrap_or_default()),
+            false => None,
+        }
+    }
+
+    fn load(comment: &str) -> Option<ReviewState> {
+        let encoded = REVIEW_STATE.captures(comment)?;
+        let json = base64::engine::general_purpose::STANDARD.decode(&encoded[1]).ok()?;
+        serde_json::from_slice(&json)
+            .map_err(|e| error!("fetch", "Cannot parse the review state: {}", e))
+            .ok()
+    }
+
+    fn store(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        format!("<!-- pr-review:state={} -->\n", base64::engine::general_purpose::STANDARD.encode(json))
+    }
+}
+
+impl FileReview {
+    //  The review of a file, with nothing in it yet
+    fn new(path: String, cache_key: String) -> Self {
+        FileReview {
+            path,
+            cache_key,
+            section: String::new(),
+            comments: Vec::new(),
+            findings: Vec::new(),
+            reused: false,
+            failure: None,
+            silenced: 0,
+            refuted: 0,
+            unconfirmed: 0,
+            bytes: 0,
+            tokens: 0,
+            infrastructure: false,
+        }
+    }
+
+    //  Leave out the findings that mention one of the rules. Returns how many were left out.
+    fn suppress(&mut self, rules: &[String]) -> usize {
+        let rules: Vec<String> = rules.iter().map(|r| r.to_lowercase()).collect();
+        self.drop_findings(|f| {
+            let message = f.message.to_lowercase();
+            rules.iter().any(|r| message.contains(r))
+        })
+    }
+
+    //  Leave out the findings at lines that the code marks with a suppression comment
+    fn silence(&mut self, lines: &HashSet<u64>) -> usize {
+        self.drop_findings(|f| f.line.is_some_and(|l| lines.contains(&l)))
+    }
+
+    //  Move the findings that the LLM is not confident enough about out of the findings and the
+    //  inline comments, into a collapsed part of the section. They are written so that they are
+    //  not read back as findings when the section is reused.
+    fn tuck_low_confidence(&mut self, min_confidence: u8) {
+        let low = |f: &Finding| f.confidence.is_some_and(|c| c < min_confidence);
+        let tucked: String = self
+            .findings
+            .iter()
+            .filter(|f| low(f))
+            .map(|f| match f.line {
+                Some(line) => format!("- Line {} ({}, {}% confidence): {}\n", line, f.severity.as_str(), f.confidence.unwrap_or_default(), f.message),
+                None => format!("- ({}, {}% confidence): {}\n", f.severity.as_str(), f.confidence.unwrap_or_default(), f.message),
+            })
+            .collect();
+        if tucked.is_empty() {
+            return;
+        }
+        self.drop_findings(low);
+        self.section.push_str(&format!("<details>\n<summary>Possible issues (low confidence)</summary>\n\n{}\n</details>\n\n", tucked));
+    }
+
+    //  Leave out findings from the findings, the inline comments and the section. Returns how
+    //  many were left out.
+    fn drop_findings(&mut self, drop: impl Fn(&Finding) -> bool) -> usize {
+        let dropped: Vec<String> = self.findings.iter().filter(|f| drop(f)).map(|f| f.message.clone()).collect();
+        if dropped.is_empty() {
+            return 0;
+        }
+        self.findings.retain(|f| !drop(f));
+        self.comments.retain(|c| !dropped.iter().any(|m| c["body"].as_str().unwrap_or_default().contains(m.as_str())));
+        self.section = self
+            .section
+            .lines()
+            .filter(|l| !parse_findings(&self.path, l).iter().any(&drop))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        dropped.len()
+    }
+}
+
+//  An issue reported by the LLM, at a line of the new version of a file if the LLM gave one
+#[derive(Serialize)]
+pub(crate) struct Finding {
+    pub(crate) path: String,
+    pub(crate) line: Option<u64>,
+    //  The last line, when the finding spans several lines
+    pub(crate) end_line: Option<u64>,
+    pub(crate) severity: Severity,
+    pub(crate) message: String,
+    //  The replacement code for the lines of the finding, if the LLM proposed a fix
+    pub(crate) suggestion: Option<String>,
+    //  How sure the LLM is that the finding is a real problem, from 0 to 100, when asked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) confidence: Option<u8>,
+}
+
+//  How serious a finding is, from the most to the least serious
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Severity {
+    Blocker,
+    Major,
+    Minor,
+    Nit,
+}
+
+impl Severity {
+    pub(crate) const ALL: [Severity; 4] = [Severity::Blocker, Severity::Major, Severity::Minor, Severity::Nit];
+
+    pub(crate) fn parse(s: &str) -> Option<Severity> {
+        match s.to_lowercase().as_str() {
+            "blocker" => Some(Severity::Blocker),
+            "major" => Some(Severity::Major),
+            "minor" => Some(Severity::Minor),
+            "nit" => Some(Severity::Nit),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Blocker => "blocker",
+            Severity::Major => "major",
+            Severity::Minor => "minor",
+            Severity::Nit => "nit",
+        }
+    }
+}
+
+impl Reviewer<'_> {
+    //  Ask a model one question in a fresh conversation
+    async fn chat(&self, model: &Model, conversation_id: &str, system_prompt: &str, question: &str) -> Result<String, String> {
+        let system_prompt = &match self.language {
+            "" => system_prompt.to_string(),
+            language => format!(
+                "{}\n\nWrite your answer in {}. Keep the code, the file names, and the markers such as `[major]` and `LINE 12:` as they are.",
+                system_prompt, language
+            ),
+        };
+        let req = ChatRequest {
+            conversation_id,
+            system_prompt,
+            question,
+            model: &model.name,
+            ctx_size: model.ctx_size,
+        };
+        let start = Instant::now();
+        let answer = retry("LLM chat", self.config.max_attempts, |e: &String| is_transient_llm_error(e), || model.llm.chat(&req)).await;
+        self.metrics.observe(LLM_LATENCY, start.elapsed().as_secs_f64());
+        let answer = answer.inspect_err(|_| self.metrics.add(LLM_ERRORS, 1.0))?;
+        let (prompt_tokens, completion_tokens) = (count_tokens(system_prompt) + count_tokens(question), count_tokens(&answer));
+        self.metrics.add_labeled(LLM_TOKENS, "type", "prompt", prompt_tokens as f64);
+        self.metrics.add_labeled(LLM_TOKENS, "type", "completion", completion_tokens as f64);
+        let mut usage = self.usage.borrow_mut();
+        let usage = usage.entry(conversation_id.to_string()).or_default();
+        usage.prompt_tokens += prompt_tokens;
+        usage.completion_tokens += completion_tokens;
+        Ok(answer)
+    }
+
+    fn usage_of(&self, conversation_id: &str) -> Usage {
+        self.usage.borrow().get(conversation_id).copied().unwrap_or_default()
+    }
+
+    fn total_usage(&self) -> Usage {
+        self.usage.borrow().values().fold(Usage::default(), |total, u| Usage {
+            prompt_tokens: total.prompt_tokens + u.prompt_tokens,
+            completion_tokens: total.completion_tokens + u.completion_tokens,
+        })
+    }
+
+    //  One structured log line per file and per PR, for admins to monitor the LLM spend
+    fn log_usage(&self, scope: &str, usage: &Usage) {
+        log::info!(
+            "llm_usage repo={}/{} pr={} scope={} prompt_tokens={} completion_tokens={}",
+            self.owner, self.repo, self.pull_number, scope, usage.prompt_tokens, usage.completion_tokens
+        );
+    }
+
+    //  The first route matching the file wins
+    fn model_for(&self, path: &str) -> &Model {
+        self.routes
+            .iter()
+            .find(|(patterns, _)| patterns.iter().any(|p| p.matches(path)))
+            .map(|(_, model)| model)
+            .unwrap_or(&self.model)
+    }
+
+    //  The second model of the ensemble, if it reviews the file
+    fn ensemble_for(&self, path: &str) -> Option<&Model> {
+        self.ensemble
+            .as_ref()
+            .filter(|(patterns, _)| patterns.iter().any(|p| p.matches(path)))
+            .map(|(_, model)| model)
+    }
+
+    //  The review of a file can be reused as long as its blob and everything that shapes the prompt
+    //  stay the same. The key goes into the review comment, which is kept per PR and repo.
+    fn cache_key(&self, f: &FileDiff) -> String {
+        let config = self.config;
+        let prompt = format!(
+            "{}\n{}\n{}\n{}\n{}\n{} {} {} {} {} {}",
+            PROMPT_VERSION,
+            self.system,
+            self.style_guide,
+            config.language_prompt(&f.filename).unwrap_or_default(),
+            self.model_for(&f.filename).name,
+            config.review_mode,
+            config.diff_full_context,
+            config.inline_comments,
+            config.suggestions,
+            config.security_review,
+            self.min_severity.as_str(),
+        );
+        let prompt = format!("{}\n{}\n{}\n{}", prompt, config.iac_review, config.dockerfile_review, config.workflow_review);
+        let prompt = match config.migration_review && config.is_migration(&f.filename) {
+            true => format!("{}\nmigration {}", prompt, self.migrations.join(",")),
+            false => prompt,
+        };
+        let mut prompt = format!("{}\n{}", prompt, self.language);
+        // The related definitions change with the whole repo, so only turning them on counts
+        if config.rag {
+            prompt.push_str("\nrag");
+        }
+        if config.self_critique {
+            prompt.push_str("\nself_critique");
+        }
+        if config.min_confidence > 0 {
+            prompt.push_str(&format!("\nmin_confidence {}", config.min_confidence));
+        }
+        if let Some(second) = self.ensemble_for(&f.filename) {
+            prompt.push_str(&format!("\nensemble {} {}", second.name, config.ensemble_mode));
+        }
+        format!("{}-{:08x}", f.sha, crc32(prompt.as_bytes()))
+    }
+
+    //  The content of a changed file after the change. The blob API is authenticated, so it works
+    //  for private repos, and serves files up to 100 MB.
+    async fn fetch_file(&self, f: &FileDiff) -> Result<String, String> {
+        let route = format!("/repos/{}/{}/git/blobs/{}", self.owner, self.repo, f.sha);
+        debug!("fetch", "Fetching blob of {}: {}", f.filename, route);
+        let blob = match retry("get_blob", self.config.max_attempts, is_transient_github_error, || self.github.get(&route)).await
+        {
+            Ok(blob) => blob,
+            Err(e) => {
+                error!("fetch", "Error fetching file {}: {}", f.filename, e);
+                return Err(github_error_reason(&e));
+            }
+        };
+        let content: String = blob["content"].as_str().unwrap_or_default().split_whitespace().collect();
+        match base64::engine::general_purpose::STANDARD.decode(content) {
+            Ok(bytes) => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            Err(e) => {
+                error!("fetch", "Error decoding file {}: {}", f.filename, e);
+                Err(format!("the blob is not valid base64: {}", e))
+            }
+        }
+    }
+
+    async fn review_file(&self, index: usize, f: FileDiff) -> Option<FileReview> {
+        let config = self.config;
+        let filename = &f.filename;
+
+        let symbol_mode = config.review_mode == "symbols";
+        // In combined mode, the patches too large to be reviewed with others are reviewed alone
+        let diff_mode = config.review_mode == "diff" || config.review_mode == "combined" || symbol_mode;
+        let patch = f.patch.clone().unwrap_or_default();
+        if diff_mode && patch.is_empty() {
+            debug!("filter", "No patch available for {}, skipping", filename);
+            return None; // Binary files and very large diffs have no patch
+        }
+
+        let mut file_as_text = String::new();
+        if !diff_mode || config.diff_full_context || symbol_mode {
+            file_as_text = match self.fetch_file(&f).await {
+                Ok(text) => text,
+                Err(reason) => {
+                    return Some(FileReview {
+                        section: render_section(&f, &format!("_The file could not be fetched: {}._\n\n", reason), &[]),
+                        failure: Some(format!("fetching the file: {}", reason)),
+                        ..FileReview::new(filename.clone(), self.cache_key(&f))
+                    });
+                }
+            };
+            if file_as_text.contains('\0') {
+                debug!("filter", "{} has binary content, skipping", filename);
+                return Some(FileReview {
+                    section: render_section(&f, "_This file has binary content and was not reviewed._\n\n", &[]),
+                    ..FileReview::new(filename.clone(), self.cache_key(&f))
+                });
+            }
+        }
+
+        let mut review = FileReview::new(filename.clone(), self.cache_key(&f));
+
+        debug!("llm", "Sending file to LLM: {}", filename);
+        // Each file gets its own conversation so that concurrent reviews don't share a history
+        let chat_id = format!("PR#{}-{}", self.pull_number, index);
+        let ask = |part: &str, chunk: &str, context: &str| {
+            let mut q = if diff_mode {
+                format!(
+                    "Review the following patch{} and report any bugs or issues introduced by the changed lines in 50 to 100 words but please be concise.\n\n```diff\n{}\n```",
+                    part, chunk
+                )
+            } else {
+                format!(
+                    "Review the following source code{} and report any bugs or issues in 50 to 100 words but please be concise.\n\n{}",
+                    part, chunk
+                )
+            };
+            if !context.is_empty() {
+                q.push_str(match symbol_mode {
+                    true => "\n\nFor context only, here is the code that encloses the changed lines after the change:\n\n",
+                    false => "\n\nFor context only, here is the full file after the change:\n\n",
+                });
+                q.push_str(context);
+            }
+            q.push_str(&self.finding_format());
+            q
+        };
+        // The system prompt is followed by the conventions of the project, and what to look for in this language
+        let mut system = self.system.to_string();
+        if !self.style_guide.is_empty() {
+            system = format!("{}\n\nFollow the conventions of this project:\n{}", system, self.style_guide);
+        }
+        if let Some(prompt) = config.language_prompt(filename) {
+            system = format!("{}\n\n{}", system, prompt);
+        }
+        // Kubernetes and CloudFormation YAML are told apart from other YAML by their content
+        let iac = match config.iac_review {
+            true => Iac::detect(filename, if file_as_text.is_empty() { &patch } else { &file_as_text }),
+            false => None,
+        };
+        if let Some(iac) = iac {
+            system = format!("{}\n\n{}", system, iac.prompt());
+            review.infrastructure = true;
+        }
+        if let Some(index) = &self.repo_index {
+            let related = index.related(&self.model, &config.embedding_model, filename, &patch, config.rag_tokens).await;
+            if !related.is_empty() {
+                system = format!("{}\n\nHere are definitions from other files of the repository that the change may use or affect:\n\n{}", system, related);
+            }
+        }
+        // The tokens left for the code in each prompt, after the system prompt, the instructions
+        // and the room reserved for the answer
+        let overhead = count_tokens(&system) + count_tokens(&ask(" (part 99 of 99)", "", " ")) + RESPONSE_TOKENS;
+        let model = self.model_for(filename);
+        let ensemble = self.ensemble_for(filename);
+        // The ensemble gets the same prompts, so they must fit both models
+        let ctx_size = ensemble.map_or(model.ctx_size, |second| model.ctx_size.min(second.ctx_size));
+        let budget = (ctx_size as usize).saturating_sub(overhead);
+
+        // Large files are reviewed in several chunks rather than truncated.
+        // Line numbers let the LLM refer to the exact lines for inline comments.
+        let text = match (diff_mode, config.inline_comments) {
+            (true, true) => number_patch_lines(&patch),
+            (true, false) => patch.clone(),
+            (false, true) => number_lines(&file_as_text, 1),
+            (false, false) => file_as_text.clone(),
+        };
+        let is_boundary = match (diff_mode, config.inline_comments) {
+            (true, _) => is_hunk_start,
+            (false, true) => is_numbered_block_start,
+            (false, false) => is_block_start,
+        };
+        let chunks: Vec<String> = split_chunks(&text, budget, is_boundary)
+            .into_iter()
+            .map(|c| truncate_tokens(c, budget))
+            .collect();
+        let parts = chunks.len().min(config.max_chunks.max(1));
+        let symbol_context = match symbol_mode {
+            true => self.symbol_context(filename, &file_as_text, &patch),
+            false => String::new(),
+        };
+
+        review.section.push_str("#### Potential issues\n\n");
+        let mut questions = Vec::new();
+        for (i, chunk) in chunks.iter().take(parts).enumerate() {
+            let part = match parts {
+                1 => String::new(),
+                _ => format!(" (part {} of {})", i + 1, parts),
+            };
+            // The code under review has priority; the full file only gets whatever budget is left.
+            let remaining = budget.saturating_sub(count_tokens(chunk));
+            let context = match (symbol_mode, diff_mode && config.diff_full_context) {
+                (true, _) => truncate_tokens(&symbol_context, remaining),
+                (false, true) => truncate_tokens(&file_as_text, remaining),
+                (false, false) => String::new(),
+            };
+            let question = ask(&part, chunk, &context);
+
+            if parts > 1 {
+                review.section.push_str(&format!("**Part {} of {}**\n\n", i + 1, parts));
+            }
+            match self.chat(model, &chat_id, &system, &question).await {
+                Ok(r) => {
+                    review.section.push_str(&r);
+                    review.section.push_str("\n\n");
+                    debug!("llm", "Received LLM response for file: {}{}", filename, part);
+
+                    let commentable = commentable_lines(&patch);
+                    for finding in parse_findings(filename, &r).into_iter().filter(|f| f.severity <= self.min_severity) {
+                        if config.inline_comments {
+                            if let Some(comment) = review_comment(&finding, &commentable, config.suggestions) {
+                                review.comments.push(comment);
+                            }
+                        }
+                        review.findings.push(finding);
+                    }
+                }
+                Err(e) => {
+                    let reason = llm_error_reason(&e);
+                    review.section.push_str(&format!("_The review failed: {}._\n\n", reason));
+                    review.failure.get_or_insert(format!("the LLM call{}: {}", part, reason));
+                    error!("llm", "LLM returns error for file review for {}{}: {}", filename, part, e);
+                }
+            }
+            questions.push(question);
+        }
+        if chunks.len() > parts {
+            review.section.push_str(&format!(
+                "_The last {} parts of this file were not reviewed because of the `max_chunks` limit._\n\n",
+                chunks.len() - parts
+            ));
+        }
+        let ensemble_id = format!("PR#{}-ensemble-{}", self.pull_number, index);
+        if let Some(second) = ensemble.filter(|_| review.failure.is_none()) {
+            review.unconfirmed = self.review_ensemble(&ensemble_id, second, &system, &questions, &mut review).await;
+        }
+        if config.security_review {
+            if let Some((text, findings)) = self.security_pass(index, &f).await {
+                self.add_pass(&mut review, "Security", &text, findings, &patch);
+            }
+        }
+        if config.migration_review && config.is_migration(filename) {
+            if let Some((text, findings)) = self.migration_pass(index, &f).await {
+                self.add_pass(&mut review, "Migration safety", &text, findings, &patch);
+            }
+        }
+        let dockerfile = config.dockerfile_review && is_dockerfile(filename);
+        let workflow = config.workflow_review && is_workflow(filename);
+        if dockerfile || workflow {
+            // The checks are on the whole file, e.g. a USER instruction that the patch does not touch
+            let text = match file_as_text.is_empty() {
+                true => self.fetch_file(&f).await.unwrap_or_default(),
+                false => file_as_text.clone(),
+            };
+            if !text.is_empty() {
+                let (heading, (text, findings)) = match dockerfile {
+                    true => ("Dockerfile checklist", dockerfile_checklist(filename, &text)),
+                    false => ("Workflow security", workflow_issues(filename, &text)),
+                };
+                let findings = findings.into_iter().filter(|f| f.severity <= self.min_severity).collect();
+                self.add_pass(&mut review, heading, &text, findings, &patch);
+            }
+        }
+        review.silenced = review.silence(&suppressed_lines(&patch, &file_as_text));
+        let critique_id = format!("PR#{}-critique-{}", self.pull_number, index);
+        if config.self_critique {
+            review.refuted = self.critique(&critique_id, &mut review, &patch).await;
+        }
+        // The findings that only one model of the ensemble reported are tucked away with the others
+        // of low confidence
+        let min_confidence = match review.unconfirmed > 0 && config.ensemble_mode == "mark" {
+            true => config.min_confidence.max(SINGLE_MODEL_CONFIDENCE + 1),
+            false => config.min_confidence,
+        };
+        review.tuck_low_confidence(min_confidence);
+        review.section = render_section(&f, &review.section, &review.findings);
+        let usage = self.usage_of(&chat_id);
+        self.log_usage(filename, &usage);
+        review.bytes = chunks.iter().take(parts).map(String::len).sum();
+        review.tokens = usage.total()
+            + self.usage_of(&format!("PR#{}-security-{}", self.pull_number, index)).total()
+            + self.usage_of(&format!("PR#{}-migration-{}", self.pull_number, index)).total()
+            + self.usage_of(&critique_id).total()
+            + self.usage_of(&ensemble_id).total();
+        Some(review)
+    }
+
+    //  The review of a file by the second model of the ensemble, with the same prompts as the first
+    //  one. The findings that only one of the two models reports are left out, or with the "mark"
+    //  ensemble_mode, get a low confidence. The findings are kept as they are when the second model
+    //  fails. Returns how many findings were left out or marked.
+    async fn review_ensemble(&self, chat_id: &str, model: &Model, system: &str, questions: &[String], review: &mut FileReview) -> usize {
+        let mut others = Vec::new();
+        for question in questions {
+            match self.chat(model, chat_id, system, question).await {
+                Ok(r) => others.extend(parse_findings(&review.path, &r).into_iter().filter(|f| f.severity <= self.min_severity)),
+                Err(e) => {
+                    error!("llm", "LLM returns error for the ensemble review of {}, keeping the findings: {}", review.path, e);
+                    return 0;
+                }
+            }
+        }
+        let confirmed = |finding: &Finding, by: &[Finding]| by.iter().any(|other| same_issue(finding, other));
+        let alone: Vec<String> = review.findings.iter().filter(|f| !confirmed(f, &others)).map(|f| f.message.clone()).collect();
+        let extra: Vec<Finding> = others.into_iter().filter(|f| !confirmed(f, &review.findings)).collect();
+        debug!(
+            "llm", "The ensemble confirms {} of the {} findings of {}, and reports {} others",
+            review.findings.len() - alone.len(), review.findings.len(), review.path, extra.len()
+        );
+        if self.config.ensemble_mode != "mark" {
+            return review.drop_findings(|f| alone.contains(&f.message));
+        }
+        let low = |confidence: Option<u8>| Some(confidence.map_or(SINGLE_MODEL_CONFIDENCE, |c| c.min(SINGLE_MODEL_CONFIDENCE)));
+        for finding in review.findings.iter_mut().filter(|f| alone.contains(&f.message)) {
+            finding.confidence = low(finding.confidence);
+        }
+        let count = alone.len() + extra.len();
+        review.findings.extend(extra.into_iter().map(|f| Finding { confidence: low(f.confidence), ..f }));
+        count
+    }
+
+    //  A second pass that checks the findings of a review against the patch, and drops the ones
+    //  that the LLM itself finds to be false positives. The findings are kept when the call fails
+    //  or the answer says nothing about them. Returns how many were dropped.
+    async fn critique(&self, chat_id: &str, review: &mut FileReview, patch: &str) -> usize {
+        if review.findings.is_empty() || patch.is_empty() {
+            return 0;
+        }
+        let system = "You are a senior software engineer double-checking the findings of a code review before they are posted. You only keep the findings that are real problems of the changed code. Please be concise and precise.";
+        let listed: String = review
+            .findings
+            .iter()
+            .enumerate()
+            .map(|(i, f)| match f.line {
+                Some(line) => format!("{}. [{}] LINE {}: {}\n", i + 1, f.severity.as_str(), line, f.message),
+                None => format!("{}. [{}] {}\n", i + 1, f.severity.as_str(), f.message),
+            })
+            .collect();
+        let ask = |patch: &str| {
+            format!(
+                "A first review of the following patch of {} reported the issues below. For each issue, check against the patch whether it is actually a problem: not a misreading of the code, not already handled in the code that is shown, and not a matter of taste. Answer with one line per issue: its number, then `KEEP` or `DROP`, then a short reason, e.g. `2: DROP - the value is checked on line 10`.\n\nThe issues:\n{}\nThe patch:\n```diff\n{}\n```",
+                review.path, listed, patch
+            )
+        };
+        let model = self.model_for(&review.path);
+        let budget = (model.ctx_size as usize).saturating_sub(count_tokens(system) + count_tokens(&ask("")) + RESPONSE_TOKENS);
+        let patch = truncate_tokens(&number_patch_lines(patch), budget).to_string();
+        let answer = match self.chat(model, chat_id, system, &ask(&patch)).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("llm", "LLM returns error for the self-critique of {}, keeping the findings: {}", review.path, e);
+                return 0;
+            }
+        };
+        let dropped: HashSet<usize> = CRITIQUE_VERDICT
+            .captures_iter(&answer)
+            .filter(|cap| cap[2].eq_ignore_ascii_case("drop"))
+            .filter_map(|cap| cap[1].parse::<usize>().ok())
+            .collect();
+        let refuted: Vec<String> = review
+            .findings
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| dropped.contains(&(i + 1)))
+            .map(|(_, f)| f.message.clone())
+            .collect();
+        debug!("llm", "The self-critique drops {} of the {} findings of {}", refuted.len(), review.findings.len(), review.path);
+        review.drop_findings(|f| refuted.contains(&f.message))
+    }
+
+    //  How the LLM should write each issue, so that its severity and line can be parsed
+    fn finding_format(&self) -> String {
+        let config = self.config;
+        let mut q = String::from("\n\nStart the description of each issue on a new line with its severity in square brackets: `[blocker]` for bugs that must be fixed before merging, `[major]` for serious problems, `[minor]` for small problems, and `[nit]` for style and naming.");
+        if self.min_severity != Severity::Nit {
+            q.push_str(&format!(" Only report issues of severity `[{}]` or more serious.", self.min_severity.as_str()));
+        }
+        if config.inline_comments {
+            q.push_str(" Follow the severity with `LINE <n>:`, where <n> is the line number of the issue in the new version of the file, e.g. `[major] LINE 12: ...`.");
+            if config.suggestions {
+                q.push_str(" When you can propose a concrete fix, use `LINES <n>-<m>:` for the lines to replace, and put the replacement code right after the description in a ```suggestion code block, without line numbers or diff markers.");
+            }
+        }
+        if config.min_confidence > 0 {
+            q.push_str(" End the first line of each issue with how confident you are that it is a real problem, from 0 to 100, e.g. `(confidence: 80)`.");
+        }
+        q
+    }
+
+    //  The review of the patches of several files together, so that the LLM can follow the changes
+    //  across files, e.g. a renamed function and its call sites. The patches are packed into as few
+    //  prompts as fit the context window, and each answer is split back into the reviews of the
+    //  files by their headings. The files of a prompt share their model and their language prompt,
+    //  so that each file gets the model and the system prompt of its review on its own. The patches
+    //  too large for a prompt of their own are left out, to be reviewed file by file.
+    async fn review_combined(&self, files: &[&FileDiff]) -> Vec<FileReview> {
+        let config = self.config;
+        let mut system = self.system.to_string();
+        if !self.style_guide.is_empty() {
+            system = format!("{}\n\nFollow the conventions of this project:\n{}", system, self.style_guide);
+        }
+        let mut question = String::from("Review the following patches of a PR together and report any bugs or issues introduced by the changed lines in 50 to 100 words per file but please be concise. Look for changes that do not fit together across files, such as a renamed or changed function whose callers were not updated. Put the issues of each file under a `### <path>` heading with the path of the file, and leave out the files without issues.");
+        if config.security_review {
+            question.push_str(" Include security issues, with the CWE identifier of the weakness.");
+        }
+        question.push_str(&self.finding_format());
+        let patch_of = |f: &FileDiff| {
+            let patch = f.patch.as_deref().unwrap_or_default();
+            let patch = match config.inline_comments {
+                true => number_patch_lines(patch),
+                false => patch.to_string(),
+            };
+            format!("\n\n### {}\n```diff\n{}\n```", f.filename, patch)
+        };
+        let mut groups: Vec<(&Model, Option<&str>, Vec<&FileDiff>)> = Vec::new();
+        for f in files.iter().copied() {
+            let (model, prompt) = (self.model_for(&f.filename), config.language_prompt(&f.filename));
+            match groups.iter_mut().find(|(m, p, _)| std::ptr::eq(*m, model) && *p == prompt) {
+                Some((_, _, group)) => group.push(f),
+                None => groups.push((model, prompt, vec![f])),
+            }
+        }
+
+        // Pack the patches of each group in file order, as many as fit in each prompt
+        let mut batches: Vec<(&Model, String, Vec<&FileDiff>, String)> = Vec::new();
+        for (model, prompt, group) in groups {
+            let system = match prompt {
+                Some(prompt) => format!("{}\n\n{}", system, prompt),
+                None => system.clone(),
+            };
+            let budget = (model.ctx_size as usize).saturating_sub(count_tokens(&system) + count_tokens(&question) + RESPONSE_TOKENS);
+            let mut packed: Vec<(Vec<&FileDiff>, String)> = Vec::new();
+            let mut used = 0;
+            for f in group {
+                let patch = patch_of(f);
+                let tokens = count_tokens(&patch);
+                if tokens > budget {
+                    continue;
+                }
+                match packed.last_mut() {
+                    Some((batch, text)) if used + tokens <= budget => {
+                        batch.push(f);
+                        text.push_str(&patch);
+                        used += tokens;
+                    }
+                    _ => {
+                        packed.push((vec![f], patch));
+                        used = tokens;
+                    }
+                }
+            }
+            batches.extend(packed.into_iter().map(|(batch, patches)| (model, system.clone(), batch, patches)));
+        }
+        let question = &question;
+        let reviews: Vec<FileReview> = stream::iter(batches.into_iter().enumerate())
+            .map(|(k, (model, system, batch, patches))| async move {
+                let chat_id = format!("PR#{}-combined-{}", self.pull_number, k);
+                let answer = self.chat(model, &chat_id, &system, &format!("{}{}", question, patches)).await;
+                let usage = self.usage_of(&chat_id);
+                self.log_usage(&format!("combined-{}", k), &usage);
+                self.split_combined(&batch, answer, usage.total())
+            })
+            .buffered(config.max_concurrency.max(1))
+            .flat_map(stream::iter)
+            .collect()
+            .await;
+        stream::iter(reviews.into_iter().enumerate())
+            .map(|(i, mut review)| async move {
+                let Some(f) = files.iter().find(|f| f.filename == review.path) else {
+                    return review;
+                };
+                let patch = f.patch.as_deref().unwrap_or_default();
+                if config.self_critique && review.failure.is_none() {
+                    let critique_id = format!("PR#{}-critique-c{}", self.pull_number, i);
+                    review.refuted = self.critique(&critique_id, &mut review, patch).await;
+                    review.tokens += self.usage_of(&critique_id).total();
+                }
+                review.tuck_low_confidence(config.min_confidence);
+                review.section = render_section(f, &review.section, &review.findings);
+                review
+            })
+            .buffered(config.max_concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    //  The reviews of the files of a combined prompt, from the text under their headings. With one
+    //  file, the whole answer is about it. The sections are rendered by the caller.
+    fn split_combined(&self, batch: &[&FileDiff], answer: Result<String, String>, tokens: usize) -> Vec<FileReview> {
+        let config = self.config;
+        let mut texts: HashMap<&str, String> = HashMap::new();
+        let failure = match &answer {
+            Ok(answer) => {
+                let mut current: Option<&str> = (batch.len() == 1).then(|| batch[0].filename.as_str());
+                for line in answer.lines() {
+                    if let Some(heading) = line.trim().strip_prefix("###") {
+                        let heading = heading.trim_start_matches('#').trim().trim_matches(['`', '*']);
+                        if let Some(f) = batch.iter().find(|f| f.filename == heading) {
+                            current = Some(f.filename.as_str());
+                            continue;
+                        }
+                    }
+                    if let Some(path) = current {
+                        let text = texts.entry(path).or_default();
+                        text.push_str(line);
+                        text.push('\n');
+                    }
+                }
+                None
+            }
+            Err(e) => {
+                error!("llm", "LLM returns error for the combined review of {} files: {}", batch.len(), e);
+                Some(llm_error_reason(e))
+            }
+        };
+        let mut reviews = Vec::new();
+        for (i, f) in batch.iter().enumerate() {
+            let patch = f.patch.as_deref().unwrap_or_default();
+            let mut review = FileReview {
+                failure: failure.as_ref().map(|reason| format!("the LLM call: {}", reason)),
+                bytes: patch.len(),
+                // The tokens of the prompt are counted once, with its first file
+                tokens: if i == 0 { tokens } else { 0 },
+                ..FileReview::new(f.filename.clone(), self.cache_key(f))
+            };
+            if let Some(reason) = &failure {
+                review.section = format!("_The review failed: {}._\n\n", reason);
+                reviews.push(review);
+                continue;
+            }
+            let text = texts.get(f.filename.as_str()).map_or("", |t| t.trim());
+            let text = if text.is_empty() { "No issues found." } else { text };
+            let commentable = commentable_lines(patch);
+            for finding in parse_findings(&f.filename, text).into_iter().filter(|f| f.severity <= self.min_severity) {
+                if config.inline_comments {
+                    if let Some(comment) = review_comment(&finding, &commentable, config.suggestions) {
+                        review.comments.push(comment);
+                    }
+                }
+                review.findings.push(finding);
+            }
+            review.silenced = review.silence(&suppressed_lines(patch, ""));
+            review.section = format!("#### Potential issues\n\n{}\n\n", text);
+            reviews.push(review);
+        }
+        reviews
+    }
+
+    //  The definitions that enclose the changed lines of a file, with their line numbers, and the
+    //  definitions of the types that they use from other files when the repo is indexed
+    fn symbol_context(&self, path: &str, file: &str, patch: &str) -> String {
+        let lines: Vec<&str> = file.lines().collect();
+        let mut context = String::new();
+        for (start, end) in enclosing_blocks(file, &commentable_lines(patch)) {
+            context.push_str(&format!("```\n{}```\n\n", number_lines(&lines[start - 1..end].join("\n"), start)));
+        }
+        if let Some(index) = &self.repo_index {
+            let types = index.types(&referenced_types(&context), path, self.config.rag_tokens);
+            if !types.is_empty() {
+                context.push_str("The types that it uses from other files:\n\n");
+                context.push_str(&types);
+            }
+        }
+        context
+    }
+
+    //  A second pass over the patch of a file, only looking for security issues.
+    //  Returns the answer with links to the CWE entries, and the findings in it.
+    async fn security_pass(&self, index: usize, f: &FileDiff) -> Option<(String, Vec<Finding>)> {
+        let patch = f.patch.as_deref().unwrap_or_default();
+        if patch.is_empty() {
+            return None;
+        }
+        let system = "You are an application security expert reviewing code changes. You only report real, exploitable security issues. Please be concise and precise.";
+        let ask = |patch: &str| {
+            format!(
+                "Review the following patch of {} for security issues introduced by the changed lines: injection (SQL, command, path traversal, XSS), authentication or authorization bypass, unsafe deserialization, server-side request forgery (SSRF), secrets handling, and `unsafe` Rust blocks that may be unsound. Start each issue on a new line with its severity in square brackets (`[blocker]`, `[major]`, `[minor]` or `[nit]`), then `LINE <n>:` with the line number in the new version of the file, then the CWE identifier of the weakness (e.g. CWE-89) and a short explanation. Reply with \"No security issues found.\" if there are none.\n\n```diff\n{}\n```",
+                f.filename, patch
+            )
+        };
+        let model = self.model_for(&f.filename);
+        let budget = (model.ctx_size as usize)
+            .saturating_sub(count_tokens(system) + count_tokens(&ask("")) + RESPONSE_TOKENS);
+        let patch = truncate_tokens(&number_patch_lines(patch), budget).to_string();
+
+        let chat_id = format!("PR#{}-security-{}", self.pull_number, index);
+        match self.chat(model, &chat_id, system, &ask(&patch)).await {
+            Ok(r) => {
+                let findings = parse_findings(&f.filename, &r).into_iter().filter(|f| f.severity <= self.min_severity).collect();
+                Some((link_cwes(&r), findings))
+            }
+            Err(e) => {
+                error!("llm", "LLM returns error for the security review of {}: {}", f.filename, e);
+                None
+            }
+        }
+    }
+
+    //  A second pass over the patch of a database migration, looking for the changes that are
+    //  risky to deploy. Returns the answer and the findings in it.
+    async fn migration_pass(&self, index: usize, f: &FileDiff) -> Option<(String, Vec<Finding>)> {
+        let patch = f.patch.as_deref().unwrap_or_default();
+        if patch.is_empty() {
+            return None;
+        }
+        let system = "You are a database administrator reviewing schema migrations before they are deployed to a production database with large tables and live traffic. You only report real risks. Please be concise and precise.";
+        // The other migrations of the PR tell whether a change can be rolled back, e.g. the down.sql of a Diesel migration
+        let others: Vec<&str> = self.migrations.iter().map(String::as_str).filter(|path| *path != f.filename).collect();
+        let others = match others.is_empty() {
+            true => String::new(),
+            false => format!(" The other migration files of this PR are {}.", others.join(", ")),
+        };
+        let ask = |patch: &str| {
+            format!(
+                "Review the following patch of the database migration {} for: destructive operations that lose data (dropping tables or columns, truncating, deleting or updating rows without a WHERE clause, type changes that truncate values); missing indexes on new foreign keys and on the columns that new queries filter on; DDL that locks large tables for a long time (creating an index without CONCURRENTLY, adding a column with a volatile default, adding NOT NULL or foreign key constraints without validating them separately, changing column types, renaming tables or columns that the running code still uses); and changes that cannot be rolled back (no down migration or downgrade, or one that does not restore the data).{} Start each issue on a new line with its severity in square brackets: `[blocker]` for data loss, `[major]` for long locks and irreversible changes, `[minor]` for missing indexes, then `LINE <n>:` with the line number in the new version of the file and a short explanation. Reply with \"No migration issues found.\" if there are none.\n\n```diff\n{}\n```",
+                f.filename, others, patch
+            )
+        };
+        let model = self.model_for(&f.filename);
+        let budget = (model.ctx_size as usize)
+            .saturating_sub(count_tokens(system) + count_tokens(&ask("")) + RESPONSE_TOKENS);
+        let patch = truncate_tokens(&number_patch_lines(patch), budget).to_string();
+
+        let chat_id = format!("PR#{}-migration-{}", self.pull_number, index);
+        match self.chat(model, &chat_id, system, &ask(&patch)).await {
+            Ok(r) => {
+                let findings = parse_findings(&f.filename, &r).into_iter().filter(|f| f.severity <= self.min_severity).collect();
+                Some((r, findings))
+            }
+            Err(e) => {
+                error!("llm", "LLM returns error for the migration review of {}: {}", f.filename, e);
+                None
+            }
+        }
+    }
+
+    //  Add the answer of a second pass over a file to its review, under a heading
+    fn add_pass(&self, review: &mut FileReview, heading: &str, text: &str, findings: Vec<Finding>, patch: &str) {
+        review.section.push_str(&format!("#### {}\n\n{}\n\n", heading, text));
+        let commentable = commentable_lines(patch);
+        for finding in findings {
+            if self.config.inline_comments {
+                if let Some(comment) = review_comment(&finding, &commentable, self.config.suggestions) {
+                    review.comments.push(comment);
+                }
+            }
+            review.findings.push(finding);
+        }
+    }
+
+    //  A walkthrough of a changed file for the explain command, without looking for bugs
+    async fn explain_file(&self, index: usize, f: FileDiff) -> Option<String> {
+        let system = "You are an experienced software developer. You help reviewers who are not familiar with a part of the code base understand the changes to it. Please be clear and accurate.";
+        let patch = f.patch.clone().unwrap_or_default();
+        let content = match f.status {
+            FileDiffStatus::Removed => String::new(),
+            _ => self.fetch_file(&f).await.unwrap_or_default(),
+        };
+        let ask = |content: &str, patch: &str| {
+            format!(
+                "Explain what the file {} does, and how the following patch changes its behavior. Walk through the important parts of the change in order. Do not review the code or look for bugs.\n\nThe patch:\n```diff\n{}\n```\n\nThe full file after the change:\n```\n{}\n```",
+                f.filename, patch, content
+            )
+        };
+        let model = self.model_for(&f.filename);
+        let budget = (model.ctx_size as usize)
+            .saturating_sub(count_tokens(system) + count_tokens(&ask("", "")) + RESPONSE_TOKENS);
+        // The patch has priority; the full file only gets whatever budget is left
+        let patch = truncate_tokens(&patch, budget / 2);
+        let content = truncate_tokens(&content, budget.saturating_sub(count_tokens(&patch)));
+
+        let chat_id = format!("PR#{}-explain-{}", self.pull_number, index);
+        match self.chat(model, &chat_id, system, &ask(&content, &patch)).await {
+            Ok(r) => Some(format!("## [{}]({})\n\n{}\n\n", f.filename, f.blob_url.as_str(), r)),
+            Err(e) => {
+                error!("llm", "LLM returns error for the walkthrough of {}: {}", f.filename, e);
+                None
+            }
+        }
+    }
+
+    //  The behaviors introduced by the changed source files that the changed test files don't cover,
+    //  with suggested test cases
+    async fn missing_tests(&self, title: &str, files: &[FileDiff]) -> Option<String> {
+        let (tests, sources): (Vec<&FileDiff>, Vec<&FileDiff>) = files.iter().partition(|f| is_test_file(&f.filename));
+        if sources.is_empty() {
+            return None;
+        }
+        let patches = |files: &[&FileDiff]| {
+            files
+                .iter()
+                .map(|f| format!("\n### {}\n```diff\n{}\n```\n", f.filename, f.patch.as_deref().unwrap_or_default()))
+                .collect::<String>()
+        };
+        let ask = |sources: &str, tests: &str| {
+            format!(
+                "Here are the changes to the source files and to the test files of the PR \"{}\". List the behaviors introduced or changed by the source changes that are not covered by the test changes or by tests inside the changed source files. For each of them, suggest a concrete test case: its name, the test file it belongs in, its inputs, and what it should assert. Reply with \"All changed behaviors are tested.\" if nothing is missing.\n\nSource changes:\n{}\n\nTest changes:\n{}",
+                title, sources, if tests.is_empty() { "None" } else { tests }
+            )
+        };
+        let budget = (self.model.ctx_size as usize)
+            .saturating_sub(count_tokens(self.system) + count_tokens(&ask("", "")) + RESPONSE_TOKENS);
+        // The source changes are what needs tests; the test changes get a third of the budget
+        let tests = patches(&tests);
+        let tests = truncate_tokens(&tests, budget / 3);
+        let sources = patches(&sources);
+        let sources = truncate_tokens(&sources, budget.saturating_sub(count_tokens(&tests)));
+
+        let chat_id = format!("PR#{}-tests", self.pull_number);
+        match self.chat(&self.model, &chat_id, self.system, &ask(&sources, &tests)).await {
+            Ok(r) => Some(r),
+            Err(e) => {
+                error!("llm", "LLM returns error for the missing tests: {}", e);
+                None
+            }
+        }
+    }
+
+    //  A plain-language summary of the PR for the summarize command, without looking for bugs
+    async fn describe(&self, title: &str, description: &str, files: &[FileDiff]) -> Option<String> {
+        let system = "You are an experienced software developer. You explain pull requests to reviewers in plain language. Please be concise and accurate.";
+        let ask = |description: &str, changes: &str| {
+            format!(
+                "Summarize the following PR in plain language. Explain what changed, why it changed based on the title and the description, and which areas of the code base are affected. Do not review the code or look for bugs.\n\nTitle: {}\n\nDescription:\n{}\n\nChanges:\n{}",
+                title, description, changes
+            )
+        };
+        let budget = (self.model.ctx_size as usize)
+            .saturating_sub(count_tokens(system) + count_tokens(&ask("", "")) + RESPONSE_TOKENS);
+        let description = truncate_tokens(description, budget / 4);
+        let changes = changes_digest(files, budget.saturating_sub(count_tokens(&description)));
+
+        let chat_id = format!("PR#{}-describe", self.pull_number);
+        match self.chat(&self.model, &chat_id, system, &ask(&description, &changes)).await {
+            Ok(r) => Some(r),
+            Err(e) => {
+                error!("llm", "LLM returns error for the PR description: {}", e);
+                None
+            }
+        }
+    }
+
+    //  The answer with a Keep a Changelog entry for the changelog command, to be parsed by parse_entry
+    async fn changelog_entry(&self, title: &str, description: &str, files: &[FileDiff]) -> Option<String> {
+        let system = "You are an experienced software developer who maintains the changelog of a project. You write for its users, not for its developers. Please be concise and accurate.";
+        let ask = |description: &str, changes: &str| {
+            format!(
+                "Write a changelog entry for the following PR in the Keep a Changelog format. Sort the user-facing changes under `### Added` for new features, `### Changed` for changes in existing functionality, `### Deprecated`, `### Removed`, `### Fixed` for bug fixes and `### Security` for vulnerabilities, with one `- ` bullet item per change, each a short sentence that ends with (#{}). Leave out the kinds without changes, and the refactorings, tests and CI changes that the users do not see. Reply with only the headings and the items.\n\nTitle: {}\n\nDescription:\n{}\n\nChanges:\n{}",
+                self.pull_number, title, description, changes
+            )
+        };
+        let budget = (self.model.ctx_size as usize)
+            .saturating_sub(count_tokens(system) + count_tokens(&ask("", "")) + RESPONSE_TOKENS);
+        let description = truncate_tokens(description, budget / 4);
+        let changes = changes_digest(files, budget.saturating_sub(count_tokens(&description)));
+
+        let chat_id = format!("PR#{}-changelog", self.pull_number);
+        match self.chat(&self.model, &chat_id, system, &ask(&description, &changes)).await {
+            Ok(r) => Some(r),
+            Err(e) => {
+                error!("llm", "LLM returns error for the changelog entry: {}", e);
+                None
+            }
+        }
+    }
+
+    //  A title and a description for a PR that was opened without a description
+    async fn draft_description(&self, title: &str, files: &[FileDiff]) -> Option<(String, String)> {
+        let system = "You are an experienced software developer. You write pull request descriptions that help reviewers understand the changes. Please be concise and accurate.";
+        let ask = |changes: &str| {
+            format!(
+                "The following PR was opened without a description. Write one from its changes. On the first line, write `Title: ` and a clear title for the PR. Then write the description in markdown with a `## Summary` section that says what the PR changes, a `## Motivation` section that says why, as far as the changes show it, and a `## Testing` section that says which tests were added or changed and what a reviewer should check by hand. Do not make up facts that the changes do not show.\n\nTitle: {}\n\nChanges:\n{}",
+                title, changes
+            )
+        };
+        let budget = (self.model.ctx_size as usize)
+            .saturating_sub(count_tokens(system) + count_tokens(&ask("")) + RESPONSE_TOKENS);
+        let changes = changes_digest(files, budget);
+
+        let chat_id = format!("PR#{}-draft", self.pull_number);
+        match self.chat(&self.model, &chat_id, system, &ask(&changes)).await {
+            Ok(r) => {
+                let r = r.trim();
+                let (draft_title, draft) = match r.split_once('\n') {
+                    Some((first, rest)) if first.trim_start_matches(['*', '#', ' ']).starts_with("Title:") => {
+                        (first.trim_start_matches(['*', '#', ' '])["Title:".len()..].trim_matches(['*', ' ']).to_string(), rest.trim())
+                    }
+                    _ => (String::new(), r),
+                };
+                (!draft.is_empty()).then(|| (draft_title, draft.to_string()))
+            }
+            Err(e) => {
+                error!("llm", "LLM returns error for the drafted description: {}", e);
+                None
+            }
+        }
+    }
+
+    //  Post the triage of a new issue, from the labels of the repo and the recent issues that look alike
+    async fn triage(&self, issue: &Issue) {
+        let body = issue.body.as_deref().unwrap_or_default();
+        let labels = match list_repo_labels(self.github, self.owner, self.repo, self.config.max_attempts).await {
+            Ok(labels) => labels,
+            Err(error) => {
+                warn!("fetch", "Cannot get the labels of the repo, triaging without them: {}", error);
+                Vec::new()
+            }
+        };
+        let recent = match list_recent_issues(self.github, self.owner, self.repo, self.config.max_attempts).await {
+            Ok(recent) => recent.into_iter().filter(|i| i.number != issue.number).collect(),
+            Err(error) => {
+                warn!("fetch", "Cannot get the recent issues, triaging without duplicates: {}", error);
+                Vec::new()
+            }
+        };
+        let candidates = similar_issues(&issue.title, body, &recent);
+
+        let system = "You are an experienced maintainer of an open source project. You triage new issues: you tell bug reports from feature requests and questions, pick the labels that fit, and spot the issues that were already reported. Please be concise and accurate.";
+        let label_list: String = labels
+            .iter()
+            .map(|(name, description)| match description.is_empty() {
+                true => format!("- {}\n", name),
+                false => format!("- {}: {}\n", name, description),
+            })
+            .collect();
+        let label_list = match label_list.is_empty() {
+            true => "(none)\n".to_string(),
+            false => label_list,
+        };
+        let ask = |issue_text: &str, others: &str| {
+            format!(
+                "Triage the following new issue. Answer with exactly these four lines and nothing else:\nTYPE: bug, feature or question\nLABELS: the labels from the list below that fit the issue, separated by commas, or none\nDUPLICATES: the numbers of the recent issues below that report the same problem or ask for the same thing, like #12, separated by commas, or none\nSUMMARY: one sentence that says what the issue is about and what information is missing, if any\n\nLabels of the repo:\n{}\nRecent issues:\n{}\nNew issue:\n{}",
+                label_list, others, issue_text
+            )
+        };
+        let budget = (self.model.ctx_size as usize)
+            .saturating_sub(count_tokens(system) + count_tokens(&ask("", "")) + RESPONSE_TOKENS);
+        // The new issue gets most of the budget, the others share the rest
+        let issue_text = truncate_tokens(&format!("Title: {}\n\n{}", issue.title, body), budget * 2 / 3);
+        let each = budget.saturating_sub(count_tokens(&issue_text)) / candidates.len().max(1);
+        let others: String = candidates
+            .iter()
+            .map(|c| truncate_tokens(&format!("#{}: {}\n{}\n\n", c.number, c.title, c.body), each))
+            .collect();
+        let others = match others.is_empty() {
+            true => "(none)\n".to_string(),
+            false => others,
+        };
+
+        let chat_id = format!("Issue#{}-triage", issue.number);
+        let answer = match self.chat(&self.model, &chat_id, system, &ask(&issue_text, &others)).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("llm", "LLM returns error for the triage: {}", e);
+                return;
+            }
+        };
+        let names: Vec<String> = labels.into_iter().map(|(name, _)| name).collect();
+        let numbers: Vec<u64> = candidates.iter().map(|c| c.number).collect();
+        let triage = parse_triage(&answer, &names, &numbers);
+        if triage.kind.is_none() && triage.labels.is_empty() && triage.duplicates.is_empty() {
+            warn!("llm", "No triage in the answer of the LLM: {}", answer);
+            return;
+        }
+        let issues = Issues { github: self.github, owner: self.owner, repo: self.repo };
+        match post_comment(&issues, issue.number, &render_triage(&triage, &candidates), self.config).await {
+            Ok(()) => info!("post", "Triaged issue #{}", issue.number),
+            Err(error) => error!("post", "Error posting the triage: {}", error),
+        }
+        self.metrics.add_labeled(REVIEWS, "command", "triage", 1.0);
+        self.log_usage("triage", &self.total_usage());
+    }
+
+    //  A second pass over the findings of all files, for the top of the review comment
+    async fn summarize(&self, title: &str, description: &str, findings: &str) -> Option<String> {
+        let addresses = match self.linked_issues {
+            true => " In the summary, also say whether the changes actually address the issues that the PR says it fixes, and what is missing if they don't.",
+            false => "",
+        };
+        let ask = |description: &str, findings: &str| {
+            format!(
+                "Here are the title, the description and the per-file review findings of a PR. Write a \"### Summary of changes\" section that explains what the PR changes in a few sentences.{} Then write an \"### Overall risk assessment\" section that rates the risk of merging the PR as low, medium or high, with a short justification based on the findings.\n\nTitle: {}\n\nDescription:\n{}\n\nFindings:\n{}",
+                addresses, title, description, findings
+            )
+        };
+        let budget = (self.model.ctx_size as usize)
+            .saturating_sub(count_tokens(self.system) + count_tokens(&ask("", "")) + RESPONSE_TOKENS);
+        // The findings matter more than a long description
+        let description = truncate_tokens(description, budget / 4);
+        let findings = truncate_tokens(findings, budget.saturating_sub(count_tokens(&description)));
+
+        let chat_id = format!("PR#{}-summary", self.pull_number);
+        match self.chat(&self.model, &chat_id, self.system, &ask(&description, &findings)).await {
+            Ok(r) => Some(r),
+            Err(e) => {
+                error!("llm", "LLM returns error for the PR summary: {}", e);
+                None
+            }
+        }
+    }
+
+    //  The style docs of the repo condensed into review rules, or the previous summary while the
+    //  docs are unchanged
+    async fn condense_style_guide(&self, previous: Option<&StyleGuide>) -> Option<StyleGuide> {
+        let docs = load_style_docs(self.github, self.owner, self.repo).await;
+        if docs.is_empty() {
+            return None;
+        }
+        let key = docs.iter().map(|(_, sha, _)| sha.as_str()).collect::<Vec<&str>>().join(",");
+        if let Some(previous) = previous.filter(|p| p.key == key) {
+            return Some(previous.clone());
+        }
+        let system = "You are an experienced open source maintainer. You condense the contribution guidelines of projects into the rules that code reviewers check. Please be concise and accurate.";
+        let ask = |docs: &str| {
+            format!(
+                "Here are the contribution and style guides of a project. List the coding conventions that a reviewer of a code change should check, as at most 15 short markdown bullet points. Leave out the process, e.g. how to open a PR, sign commits or run the tests.\n\n{}",
+                docs
+            )
+        };
+        let budget = (self.model.ctx_size as usize).saturating_sub(count_tokens(system) + count_tokens(&ask("")) + RESPONSE_TOKENS);
+        let docs: String = docs.iter().map(|(path, _, text)| format!("## {}\n\n{}\n\n", path, text)).collect();
+        let docs = truncate_tokens(&docs, budget);
+
+        let chat_id = format!("PR#{}-style-guide", self.pull_number);
+        match self.chat(&self.model, &chat_id, system, &ask(&docs)).await {
+            Ok(summary) => Some(StyleGuide { key, summary: truncate_tokens(summary.trim(), MAX_STYLE_GUIDE_TOKENS) }),
+            Err(e) => {
+                error!("llm", "LLM returns error for the style guide: {}", e);
+                None
+            }
+        }
+    }
+
+    //  The file sections grouped by the package of the monorepo that they belong to, each package
+    //  with a summary of its changes. None when all files belong to the same package.
+    async fn group_by_package(&self, file_sections: &[(String, String)], roots: &[String]) -> Option<String> {
+        let mut packages: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (path, section) in file_sections {
+            packages.entry(package_of(path, roots)).or_default().push(section);
+        }
+        if packages.len() < 2 {
+            return None;
+        }
+        let mut grouped = String::new();
+        for (i, (root, sections)) in packages.into_iter().enumerate() {
+            let name = match root {
+                "" => "(root)",
+                root => root,
+            };
+            grouped.push_str(&format!("{}{} -->\n## Package `{}`\n\n", PACKAGE_MARKER, name, name));
+            if let Some(summary) = self.summarize_package(i, name, &sections.concat()).await {
+                grouped.push_str(&summary);
+                grouped.push_str("\n\n");
+            }
+            grouped.push_str(&sections.concat());
+        }
+        Some(grouped)
+    }
+
+    async fn summarize_package(&self, index: usize, name: &str, findings: &str) -> Option<String> {
+        let ask = |findings: &str| {
+            format!(
+                "Here are the per-file review findings of the changes that a PR makes to the package `{}` of a monorepo. Summarize in 2 or 3 sentences what the PR changes in this package, and its most important findings.\n\nFindings:\n{}",
+                name, findings
+            )
+        };
+        let budget = (self.model.ctx_size as usize)
+            .saturating_sub(count_tokens(self.system) + count_tokens(&ask("")) + RESPONSE_TOKENS);
+        let findings = truncate_tokens(findings, budget);
+
+        let chat_id = format!("PR#{}-package-{}", self.pull_number, index);
+        match self.chat(&self.model, &chat_id, self.system, &ask(&findings)).await {
+            Ok(r) => Some(r),
+            Err(e) => {
+                error!("llm", "LLM returns error for the summary of package {}: {}", name, e);
+                None
+            }
+        }
+    }
+
+    //  Feedback on the title, the description and the commit messages, rather than the code
+    async fn review_description(&self, title: &str, description: &str, commits: &[String]) -> Option<String> {
+        let system = "You are an experienced open source maintainer. You help contributors write PRs and commit messages that reviewers can understand. Please be concise and constructive.";
+        // Facts that the LLM is not good at checking by itself
+        let mut facts = Vec::new();
+        if !LINKED_ISSUE.is_match(description) {
+            facts.push("The description does not link to any issue.".to_string());
+        }
+        let unconventional: Vec<&str> = commits
+            .iter()
+            .map(|c| c.lines().next().unwrap_or_default())
+            .filter(|subject| !CONVENTIONAL_COMMIT.is_match(subject))
+            .collect();
+        if !unconventional.is_empty() {
+            facts.push(format!(
+                "{} of {} commit subjects do not follow the Conventional Commits format.",
+                unconventional.len(), commits.len()
+            ));
+        }
+        let ask = |description: &str, commits: &str| {
+            format!(
+                "Evaluate the title, the description and the commit messages of the following PR, not its code. Point out what is unclear, what context a reviewer would miss (e.g. the motivation, a linked issue, how it was tested), and which commit messages do not follow the Conventional Commits format (`type(scope): subject`). Suggest a better title or commit message where it helps. Reply in a short bullet list.\n\nTitle: {}\n\nDescription:\n{}\n\nCommit messages:\n{}\n\nFacts:\n{}",
+                title, description, commits, facts.join("\n")
+            )
+        };
+        let commits = commits.iter().map(|c| format!("- {}", c.replace('\n', "\n  "))).collect::<Vec<_>>().join("\n");
+        let budget = (self.model.ctx_size as usize)
+            .saturating_sub(count_tokens(system) + count_tokens(&ask("", "")) + RESPONSE_TOKENS);
+        let description = truncate_tokens(description, budget / 2);
+        let commits = truncate_tokens(&commits, budget.saturating_sub(count_tokens(&description)));
+
+        let chat_id = format!("PR#{}-description", self.pull_number);
+        match self.chat(&self.model, &chat_id, system, &ask(&description, &commits)).await {
+            Ok(r) => Some(r),
+            Err(e) => {
+                error!("llm", "LLM returns error for the PR description review: {}", e);
+                None
+            }
+        }
+    }
+
+    //  Suggest better messages for the commits whose messages break the conventions
+    async fn rewrite_commits(&self, title: &str, paths: &[String], commits: &mut [CommitReview]) {
+        let system = "You are an experienced open source maintainer. You help contributors write commit messages that tell reviewers and the readers of the history what changed and why. Please be concise and accurate.";
+        let ask = |paths: &str, commits: &str| {
+            format!(
+                "The following commit messages of a PR break the conventions. For each one, write a better commit message in the Conventional Commits format (`type(scope): subject`), with a subject in the imperative mood of at most 72 characters that says what changed, and a short body after a blank line only if the original message explains why. Keep what the original message says, and do not make up changes. Start each answer with `COMMIT <n>:` on its own line, then the message in a code block.\n\nPR title: {}\n\nChanged files:\n{}\n\nCommit messages:\n{}",
+                title, paths, commits
+            )
+        };
+        let listed: String = commits
+            .iter()
+            .enumerate()
+            .map(|(i, c)| format!("COMMIT {} ({}):\n```\n{}\n```\n\n", i + 1, c.problems.join("; "), c.message.trim()))
+            .collect();
+        let budget = (self.model.ctx_size as usize)
+            .saturating_sub(count_tokens(system) + count_tokens(&ask("", "")) + RESPONSE_TOKENS);
+        let paths = truncate_tokens(&paths.join("\n"), budget / 4).to_string();
+        let listed = truncate_tokens(&listed, budget.saturating_sub(count_tokens(&paths)));
+
+        let chat_id = format!("PR#{}-commits", self.pull_number);
+        match self.chat(&self.model, &chat_id, system, &ask(&paths, &listed)).await {
+            Ok(r) => {
+                for (number, suggestion) in parse_suggestions(&r) {
+                    if let Some(commit) = number.checked_sub(1).and_then(|i| commits.get_mut(i)) {
+                        commit.suggestion = Some(suggestion);
+                    }
+                }
+            }
+            Err(e) => error!("llm", "LLM returns error for the commit messages: {}", e),
+        }
+    }
+
+    //  Answer a follow-up question about the review. Each chat starts a fresh conversation, so the
+    //  review comment is sent along as the context of the question.
+    async fn answer(&self, question: &str, review: &str) -> Option<String> {
+        let ask = |review: &str| {
+            format!(
+                "Here is your earlier review of this PR:\n\n{}\n\n------\n\nThe PR author or a reviewer asks the following follow-up question about the review. Answer it in the context of the review and the PR. If the review does not hold the information needed, say so rather than guess.\n\n{}",
+                review, question
+            )
+        };
+        let review = HIDDEN_MARKER.replace_all(review, "");
+        let budget = (self.model.ctx_size as usize)
+            .saturating_sub(count_tokens(self.system) + count_tokens(&ask("")) + RESPONSE_TOKENS);
+        let review = truncate_tokens(&review, budget);
+
+        let chat_id = format!("PR#{}-ask", self.pull_number);
+        match self.chat(&self.model, &chat_id, self.system, &ask(&review)).await {
+            Ok(r) => Some(r),
+            Err(e) => {
+                error!("llm", "LLM returns error for the follow-up question: {}", e);
+                None
+            }
+        }
+    }
+}
+
+//  Lines on the new side of a patch that can carry a review comment, i.e. added and context lines
+fn commentable_lines(patch: &str) -> HashSet<u64> {
+    let mut lines = HashSet::new();
+    let mut new_line = 0u64;
+    for l in patch.lines() {
+        if let Some(cap) = HUNK_HEADER.captures(l) {
+            new_line = cap[1].parse().unwrap_or(0);
+        } else if l.starts_with('-') || l.starts_with('\\') {
+            continue;
+        } else if new_line > 0 {
+            lines.insert(new_line);
+            new_line += 1;
+        }
+    }
+    lines
+}
+
+//  The lines of the new file that a suppression comment silences: its own line and the next one.
+//  The full file is used when it was fetched, else the lines of the patch.
+fn suppressed_lines(patch: &str, file: &str) -> HashSet<u64> {
+    let mut marked: Vec<u64> = Vec::new();
+    if !file.is_empty() {
+        marked.extend(file.lines().enumerate().filter(|(_, l)| SUPPRESSION.is_match(l)).map(|(i, _)| i as u64 + 1));
+    } else {
+        let mut new_line = 0u64;
+        for l in patch.lines() {
+            if let Some(cap) = HUNK_HEADER.captures(l) {
+                new_line = cap[1].parse().unwrap_or(0);
+            } else if l.starts_with('-') || l.starts_with('\\') {
+                continue;
+            } else {
+                if SUPPRESSION.is_match(l) {
+                    marked.push(new_line);
+                }
+                new_line += 1;
+            }
+        }
+    }
+    marked.into_iter().flat_map(|l| [l, l + 1]).collect()
+}
+
+//  Prefix the added and context lines of a patch with their line numbers in the new file
+fn number_patch_lines(patch: &str) -> String {
+    let mut out = String::new();
+    let mut new_line = 0u64;
+    for l in patch.lines() {
+        if let Some(cap) = HUNK_HEADER.captures(l) {
+            new_line = cap[1].parse().unwrap_or(0);
+            out.push_str(l);
+        } else if l.starts_with('-') || l.starts_with('\\') {
+            out.push_str(&format!("{:>6} {}", "", l));
+        } else {
+            out.push_str(&format!("{:>6} {}", new_line, l));
+            new_line += 1;
+        }
+        out.push('\n');
+    }
+    out
+}
+
+//  The changed files of a PR with their line counts and patches, cut to the budget, for the
+//  prompts about the whole PR
+fn changes_digest(files: &[FileDiff], budget: usize) -> String {
+    let mut changes = String::new();
+    for f in files {
+        changes.push_str(&format!("\n### {} (+{} -{})\n", f.filename, f.additions, f.deletions));
+        if let Some(patch) = &f.patch {
+            changes.push_str(&format!("```diff\n{}\n```\n", patch));
+        }
+    }
+    truncate_tokens(&changes, budget)
+}
+
+//  Prefix each line with its line number, starting from `first`
+fn number_lines(s: &str, first: usize) -> String {
+    s.lines()
+        .enumerate()
+        .map(|(i, l)| format!("{:>6} {}\n", first + i, l))
+        .collect()
+}
+
+//  Split text into chunks of about max_tokens, cutting before a block boundary where possible,
+//  else after a blank line, else at a line break.
+fn split_chunks(text: &str, max_tokens: usize, is_boundary: fn(&str, &str) -> bool) -> Vec<&str> {
+    if max_tokens == 0 {
+        return vec![text];
+    }
+    let mut chunks = Vec::new();
+    // Byte offset and token count of the current chunk
+    let (mut start, mut tokens) = (0, 0);
+    // Cut points in the current chunk: (byte offset, tokens before the cut)
+    let mut boundary: Option<(usize, usize)> = None;
+    let mut blank: Option<(usize, usize)> = None;
+    let (mut offset, mut prev) = (0, "");
+    for line in text.split_inclusive('\n') {
+        let len = count_tokens(line);
+        if tokens > 0 && tokens + len > max_tokens {
+            let (cut, cut_tokens) = boundary.or(blank).unwrap_or((offset, tokens));
+            chunks.push(&text[start..cut]);
+            (start, tokens) = (cut, tokens - cut_tokens);
+            (boundary, blank) = (None, None);
+        }
+        if offset > start && is_boundary(prev, line) {
+            boundary = Some((offset, tokens));
+        } else if line.trim().is_empty() {
+            blank = Some((offset + line.len(), tokens + len));
+        }
+        tokens += len;
+        offset += line.len();
+        prev = line;
+    }
+    if start < text.len() {
+        chunks.push(&text[start..]);
+    }
+    chunks
+}
+
+//  A top-level item (function, type, class ...) that starts after a blank line or a closed block
+fn is_block_start(prev: &str, line: &str) -> bool {
+    let top_level = !line.trim().is_empty() && !line.starts_with(char::is_whitespace);
+    top_level && !line.starts_with(['}', ')', ']']) && (prev.trim().is_empty() || prev.starts_with('}'))
+}
+
+//  is_block_start() for text prefixed by number_lines()
+fn is_numbered_block_start(prev: &str, line: &str) -> bool {
+    is_block_start(prev.get(7..).unwrap_or(""), line.get(7..).unwrap_or(""))
+}
+
+fn is_hunk_start(_prev: &str, line: &str) -> bool {
+    line.starts_with("@@")
+}
+
+//  Whether two findings of the ensemble are about the same issue: at nearby lines, or without a line
+//  and of the same severity
+fn same_issue(a: &Finding, b: &Finding) -> bool {
+    match (a.line, b.line) {
+        (Some(x), Some(y)) => x.abs_diff(y) <= ENSEMBLE_LINE_SLACK,
+        (None, None) => a.severity == b.severity,
+        _ => false,
+    }
+}
+
+//  Extract the "[<severity>] LINE <n>: <message>" findings from an LLM response, with the
+//  ```suggestion block that may follow each of them.
+//  Either the severity or the line number may be missing; untagged findings are minor.
+fn parse_findings(path: &str, text: &str) -> Vec<Finding> {
+    let mut findings: Vec<Finding> = Vec::new();
+    let mut lines = text.lines();
+    while let Some(l) = lines.next() {
+        if l.trim_start().starts_with("```suggestion") {
+            let code: Vec<&str> = lines.by_ref().take_while(|l| !l.trim_start().starts_with("```")).collect();
+            if let Some(finding) = findings.last_mut().filter(|f| f.line.is_some() && f.suggestion.is_none()) {
+                finding.suggestion = Some(code.join("\n"));
+            }
+            continue;
+        }
+        let Some(cap) = FINDING_LINE.captures(l) else {
+            continue;
+        };
+        if cap.get(1).is_none() && cap.get(2).is_none() {
+            continue;
+        }
+        let mut message = cap[4].trim().to_string();
+        let confidence = CONFIDENCE.captures(&message).and_then(|c| Some((c.get(0)?.start(), c[1].parse::<u8>().ok()?.min(100))));
+        let confidence = confidence.map(|(start, confidence)| {
+            message.truncate(start);
+            confidence
+        });
+        findings.push(Finding {
+            path: path.to_string(),
+            line: cap.get(2).and_then(|m| m.as_str().parse().ok()),
+            end_line: cap.get(3).and_then(|m| m.as_str().parse().ok()),
+            severity: cap.get(1).and_then(|m| Severity::parse(m.as_str())).unwrap_or(Severity::Minor),
+            message,
+            suggestion: None,
+            confidence,
+        });
+    }
+    findings
+}
+
+//  Flag the added lines of a patch that look like they contain credentials: well-known token
+//  formats, private keys, and random-looking values assigned to secret-sounding names.
+fn scan_secrets(path: &str, patch: &str) -> Vec<Finding> {
+    let mut secrets = Vec::new();
+    let mut new_line = 0u64;
+    for l in patch.lines() {
+        if let Some(cap) = HUNK_HEADER.captures(l) {
+            new_line = cap[1].parse().unwrap_or(0);
+            continue;
+        } else if l.starts_with('-') || l.starts_with('\\') {
+            continue;
+        }
+        let line = new_line;
+        new_line += 1;
+        let Some(added) = l.strip_prefix('+') else {
+            continue;
+        };
+
+        let mut message = SECRET_PATTERNS
+            .iter()
+            .find_map(|(kind, re)| re.find(added).map(|m| format!("{} `{}`", kind, redact(m.as_str()))));
+        if message.is_none() {
+            message = SECRET_ASSIGNMENT.captures_iter(added).find_map(|cap| {
+                let value = &cap[2];
+                (shannon_entropy(value) >= SECRET_ENTROPY)
+                    .then(|| format!("Random-looking value `{}` assigned to `{}`", redact(value), &cap[1]))
+            });
+        }
+        if let Some(message) = message {
+            secrets.push(Finding {
+                path: path.to_string(),
+                line: Some(line),
+                end_line: None,
+                severity: Severity::Blocker,
+                message: format!("Possible secret: {}", message),
+                suggestion: None,
+                confidence: None,
+            });
+        }
+    }
+    secrets
+}
+
+//  Bits of entropy per character
+fn shannon_entropy(s: &str) -> f64 {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in s.chars() {
+        *counts.entry(c).or_default() += 1;
+    }
+    let len = s.chars().count() as f64;
+    counts
+        .values()
+        .map(|&n| {
+            let p = n as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+//  Only show the start of a secret, so that the review comment doesn't leak it again
+fn redact(secret: &str) -> String {
+    format!("{}…", secret.chars().take(4).collect::<String>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWO_HUNKS: &str = "@@ -1,3 +1,4 @@\n fn main() {\n-    old();\n+    new();\n+    more();\n }\n@@ -10,2 +11,3 @@\n fn tail() {\n+    added();\n }";
+    const NO_NEWLINE: &str = "@@ -1,2 +1,2 @@\n-a\n\\ No newline at end of file\n+b\n+c\n\\ No newline at end of file";
+    const DELETED_ONLY: &str = "@@ -1,3 +1,1 @@\n keep\n@@ -5,2 +4,0 @@\n-gone\n-also gone";
+
+    #[test]
+    fn commentable_lines_follow_each_hunk() {
+        assert_eq!(commentable_lines(TWO_HUNKS), HashSet::from([1, 2, 3, 4, 11, 12, 13]));
+        assert_eq!(commentable_lines(NO_NEWLINE), HashSet::from([1, 2]));
+        assert_eq!(commentable_lines(DELETED_ONLY), HashSet::from([1]));
+    }
+
+    #[test]
+    fn number_patch_lines_numbers_the_new_side() {
+        assert_eq!(
+            number_patch_lines(TWO_HUNKS),
+            "@@ -1,3 +1,4 @@\n     1  fn main() {\n       -    old();\n     2 +    new();\n     3 +    more();\n     4  }\n@@ -10,2 +11,3 @@\n    11  fn tail() {\n    12 +    added();\n    13  }\n"
+        );
+        assert_eq!(
+            number_patch_lines(NO_NEWLINE),
+            "@@ -1,2 +1,2 @@\n       -a\n       \\ No newline at end of file\n     1 +b\n     2 +c\n       \\ No newline at end of file\n"
+        );
+        assert_eq!(number_patch_lines(DELETED_ONLY), "@@ -1,3 +1,1 @@\n     1  keep\n@@ -5,2 +4,0 @@\n       -gone\n       -also gone\n");
+    }
+
+    #[test]
+    fn split_chunks_cuts_before_a_hunk() {
+        let first = (1..=20).map(|i| format!("+    let value_{} = compute({});\n", i, i)).collect::<String>();
+        let text = number_patch_lines(&format!("@@ -1,0 +1,20 @@\n{}@@ -40,1 +60,2 @@\n context();\n+    added();\n", first));
+        let max_tokens = count_tokens(&text[..text.rfind("@@ -40").unwrap()]) + 5;
+        let chunks = split_chunks(&text, max_tokens, is_hunk_start);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[1].starts_with("@@ -40,1 +60,2 @@\n"));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_chunks_inside_a_hunk_keeps_the_line_numbers() {
+        let lines = (1..=40).map(|i| format!("+    let value_{} = compute({});\n", i, i)).collect::<String>();
+        let text = number_patch_lines(&format!("@@ -1,0 +1,40 @@\n{}", lines));
+        let chunks = split_chunks(&text, 60, is_hunk_start);
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), text);
+        // Each chunk ends at a line break, and the next one goes on with the next line number
+        let number = |line: &str| line.get(..6).and_then(|n| n.trim().parse::<u64>().ok());
+        for pair in chunks.windows(2) {
+            assert!(pair[0].ends_with('\n'));
+            let last = pair[0].lines().last().and_then(number).unwrap();
+            assert_eq!(pair[1].lines().next().and_then(number), Some(last + 1));
+        }
+    }
+
+    #[test]
+    fn parse_findings_reads_each_form() {
+        type Parsed<'a> = (Option<u64>, Option<u64>, Severity, &'a str, Option<&'a str>, Option<u8>);
+        let cases: [(&str, Vec<Parsed>); 7] = [
+            ("[blocker] LINE 3: The lock is never released.", vec![(Some(3), None, Severity::Blocker, "The lock is never released.", None, None)]),
+            ("LINES 4-6: The branches are the same.", vec![(Some(4), Some(6), Severity::Minor, "The branches are the same.", None, None)]),
+            ("- **[nit]** The name is unclear.", vec![(None, None, Severity::Nit, "The name is unclear.", None, None)]),
+            ("The code looks fine.", vec![]),
+            ("[major] LINE 2: The index is not checked (confidence: 80)", vec![(Some(2), None, Severity::Major, "The index is not checked", None, Some(80))]),
+            (
+                "[minor] LINE 5: Use the helper.\n```suggestion\n    helper();\n```\n[nit] LINE 9: Typo.",
+                vec![
+                    (Some(5), None, Severity::Minor, "Use the helper.", Some("    helper();"), None),
+                    (Some(9), None, Severity::Nit, "Typo.", None, None),
+                ],
+            ),
+            // A suggestion needs the lines to replace
+            ("[minor] Rename it.\n```suggestion\nrenamed();\n```", vec![(None, None, Severity::Minor, "Rename it.", None, None)]),
+        ];
+        for (text, expected) in cases {
+            let findings = parse_findings("src/main.rs", text);
+            let parsed: Vec<Parsed> = findings
+                .iter()
+                .map(|f| (f.line, f.end_line, f.severity, f.message.as_str(), f.suggestion.as_deref(), f.confidence))
+                .collect();
+            assert_eq!(parsed, expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn scan_secrets_flags_known_token_formats() {
+        // Built at run time, so that the secret scanners of the repo don't flag the test itself
+        let token = format!("ghp_{}", "a1B2c3D4".repeat(5));
+        let secrets = scan_secrets("src/main.rs", &format!("@@ -1,1 +1,2 @@\n fn main() {{\n+    let auth = \"{}\";", token));
+        let found: Vec<(Option<u64>, Severity, &str)> = secrets.iter().map(|f| (f.line, f.severity, f.message.as_str())).collect();
+        assert_eq!(found, [(Some(2), Severity::Blocker, "Possible secret: GitHub token `ghp_…`")]);
+    }
+
+    #[test]
+    fn scan_secrets_weighs_the_entropy_of_assignments() {
+        let patch = "@@ -1,0 +1,2 @@\n+api_key = \"q8Zr2LmX9vTn4KbW7pYc\"\n+password = \"REPLACE_ME_REPLACE_ME\"";
+        let secrets = scan_secrets("config.toml", patch);
+        let found: Vec<(Option<u64>, &str)> = secrets.iter().map(|f| (f.line, f.message.as_str())).collect();
+        // The placeholder is too regular to be a secret
+        assert_eq!(found, [(Some(1), "Possible secret: Random-looking value `q8Zr…` assigned to `api_key`")]);
+    }
+
+    #[test]
+    fn scan_secrets_numbers_the_lines_of_each_hunk() {
+        let patch = TWO_HUNKS.replace("+    added();", "+    let token = \"q8Zr2LmX9vTn4KbW7pYc\";");
+        let lines: Vec<Option<u64>> = scan_secrets("src/main.rs", &patch).iter().map(|f| f.line).collect();
+        assert_eq!(lines, [Some(12)]);
+        assert!(scan_secrets("src/main.rs", "@@ -1,1 +1,0 @@\n-api_key = \"q8Zr2LmX9vTn4KbW7pYc\"").is_empty(), "deleted lines are not flagged");
+    }
+
+    #[test]
+    fn shannon_entropy_counts_bits_per_char() {
+        assert_eq!(shannon_entropy("aaaaaaaa"), 0.0);
+        assert_eq!(shannon_entropy("abcdabcd"), 2.0);
+        assert!(shannon_entropy("q8Zr2LmX9vTn4KbW7pYc") >= SECRET_ENTROPY);
+        assert!(shannon_entropy("REPLACE_ME_REPLACE_ME") < SECRET_ENTROPY);
+    }
+}
@@ -0,0 +1,217 @@
+//  Retrieval of related code for the file reviews. The definitions of the repo (function
+//  signatures, types and module docs) are indexed with embeddings at the head of the PR, and
+//  each patch gets the definitions closest to it, so that the LLM sees the callers and the callees
+//  of the changed code instead of reviewing each file in isolation.
+
+use crate::config::{Config, is_generated, is_test_file};
+use crate::github::{GithubApi, from_json, is_transient_github_error};
+use crate::llm::{Model, count_tokens, truncate_tokens};
+use crate::retry;
+use crate::symbols::{COMMENT, DEFINITION, TYPE_KINDS, block_end, doc_start};
+use crate::trace::{debug, error};
+use base64::Engine;
+use futures::{StreamExt, stream};
+use serde::Deserialize;
+
+//  Files larger than this are not indexed
+const MAX_FILE_BYTES: u64 = 100_000;
+
+//  The max number of definitions in the index, and of texts in one embeddings call
+const MAX_SYMBOLS: usize = 2000;
+const EMBED_BATCH: usize = 64;
+
+//  The lines kept of the file header, of the signature of a definition, and of the body of a type
+const HEADER_LINES: usize = 5;
+const SIGNATURE_LINES: usize = 3;
+const TYPE_LINES: usize = 30;
+
+//  The part of a patch that is embedded to find its related definitions
+const QUERY_TOKENS: usize = 1500;
+
+//  A definition of the repo, with its doc comment
+struct Symbol {
+    path: String,
+    line: usize,
+    //  The name of a type, whose text is its whole definition
+    type_name: Option<String>,
+    text: String,
+}
+
+//  The definitions of the repo, and their embeddings when they are used for retrieval
+pub(crate) struct RepoIndex {
+    symbols: Vec<Symbol>,
+    vectors: Vec<Vec<f32>>,
+}
+
+#[derive(Deserialize)]
+struct TreeEntry {
+    path: String,
+    #[serde(rename = "type")]
+    kind: String,
+    sha: String,
+    #[serde(default)]
+    size: u64,
+}
+
+impl RepoIndex {
+    //  Index the changed files, by path and blob SHA, and the source files of the repo at `head`
+    //  that are in their languages, the ones nearest to the changed files first. The definitions
+    //  are embedded for `related` when the rag setting is on.
+    pub(crate) async fn build(
+        github: &dyn GithubApi,
+        owner: &str,
+        repo: &str,
+        head: &str,
+        changed: &[(String, String)],
+        model: &Model,
+        config: &Config,
+    ) -> Result<RepoIndex, String> {
+        let route = format!("/repos/{}/{}/git/trees/{}?recursive=1", owner, repo, head);
+        let tree = retry("get_tree", config.max_attempts, is_transient_github_error, || github.get(&route)).await;
+        let entries: Vec<TreeEntry> = tree.and_then(|tree| from_json(tree["tree"].clone())).map_err(|e| e.to_string())?;
+        let extensions: Vec<&str> = changed.iter().filter_map(|(path, _)| extension(path)).collect();
+        let mut files: Vec<TreeEntry> = entries
+            .into_iter()
+            .filter(|e| {
+                e.kind == "blob"
+                    && e.size <= MAX_FILE_BYTES
+                    && extension(&e.path).is_some_and(|ext| extensions.contains(&ext))
+                    && !changed.iter().any(|(path, _)| *path == e.path)
+                    && !is_generated(&e.path, &[])
+                    && !is_test_file(&e.path)
+            })
+            .collect();
+        files.sort_by_key(|e| std::cmp::Reverse(changed.iter().map(|(path, _)| shared_dirs(path, &e.path)).max().unwrap_or(0)));
+        files.truncate(config.rag_max_files);
+
+        // The changed files are indexed too, for the definitions that the other changed files use
+        let sources = changed.iter().cloned().chain(files.into_iter().map(|e| (e.path, e.sha)));
+        let texts: Vec<Option<(String, String)>> = stream::iter(sources)
+            .map(|(path, sha)| async move { fetch_blob(github, owner, repo, &sha, config.max_attempts).await.map(|text| (path, text)) })
+            .buffered(config.max_concurrency.max(1))
+            .collect()
+            .await;
+        let mut symbols: Vec<Symbol> = texts.into_iter().flatten().flat_map(|(path, text)| extract_symbols(&path, &text)).collect();
+        symbols.truncate(MAX_SYMBOLS);
+
+        let mut vectors = Vec::new();
+        for batch in symbols.chunks(EMBED_BATCH).filter(|_| config.rag) {
+            let input: Vec<String> = batch.iter().map(|s| format!("{}\n{}", s.path, s.text)).collect();
+            let batch_vectors = model.llm.embed(&config.embedding_model, &input).await?;
+            if batch_vectors.len() != batch.len() {
+                return Err(format!("{} embeddings for {} texts", batch_vectors.len(), batch.len()));
+            }
+            vectors.extend(batch_vectors);
+        }
+        let index = RepoIndex { symbols, vectors };
+        debug!("rag", "Indexed {} definitions", index.symbols.len());
+        Ok(index)
+    }
+
+    //  The definitions outside of `path` that are the most similar to its patch, up to max_tokens
+    pub(crate) async fn related(&self, model: &Model, embedding_model: &str, path: &str, patch: &str, max_tokens: usize) -> String {
+        if self.vectors.is_empty() || patch.is_empty() {
+            return String::new();
+        }
+        let query = truncate_tokens(&format!("{}\n{}", path, patch), QUERY_TOKENS);
+        let vector = match model.llm.embed(embedding_model, &[query]).await {
+            Ok(mut vectors) if !vectors.is_empty() => vectors.swap_remove(0),
+            Ok(_) => return String::new(),
+            Err(e) => {
+                error!("rag", "Cannot embed the patch: {}", e);
+                return String::new();
+            }
+        };
+        let mut ranked: Vec<(f32, &Symbol)> =
+            self.symbols.iter().zip(&self.vectors).filter(|(s, _)| s.path != path).map(|(s, v)| (cosine(&vector, v), s)).collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let mut related = String::new();
+        let mut tokens = 0;
+        for (_, symbol) in ranked {
+            let text = render_symbol(symbol);
+            tokens += count_tokens(&text);
+            if tokens > max_tokens {
+                break;
+            }
+            related.push_str(&text);
+        }
+        related
+    }
+
+    //  The definitions of the types with these names outside of `path`, up to max_tokens
+    pub(crate) fn types(&self, names: &[String], path: &str, max_tokens: usize) -> String {
+        let mut types = String::new();
+        let mut tokens = 0;
+        for name in names {
+            let found = self.symbols.iter().find(|s| s.path != path && s.type_name.as_ref() == Some(name));
+            if let Some(symbol) = found {
+                let text = render_symbol(symbol);
+                tokens += count_tokens(&text);
+                if tokens > max_tokens {
+                    break;
+                }
+                types.push_str(&text);
+            }
+        }
+        types
+    }
+}
+
+//  The definitions of a file, each with its doc comment, and the doc comment at the top of the
+//  file. Types keep their whole definition, the others their signature.
+fn extract_symbols(path: &str, text: &str) -> Vec<Symbol> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut symbols = Vec::new();
+    let header: Vec<&str> = lines.iter().take_while(|l| COMMENT.is_match(l)).take(HEADER_LINES).copied().collect();
+    if !header.is_empty() {
+        symbols.push(Symbol { path: path.to_string(), line: 1, type_name: None, text: header.join("\n") });
+    }
+    for (i, line) in lines.iter().enumerate() {
+        let Some(cap) = DEFINITION.captures(line) else {
+            continue;
+        };
+        let type_name = cap.name("name").filter(|_| TYPE_KINDS.contains(&&cap["kind"])).map(|m| m.as_str().to_string());
+        let end = match type_name {
+            Some(_) => block_end(&lines, i).min(i + TYPE_LINES - 1),
+            None => {
+                // The signature ends with the body, or the colon of a Python definition
+                let ends = |l: &str| l.contains(['{', ';', '=']) || l.trim_end().ends_with(':');
+                (i..lines.len()).take(SIGNATURE_LINES).find(|&j| ends(lines[j])).unwrap_or((i + SIGNATURE_LINES - 1).min(lines.len() - 1))
+            }
+        };
+        let text = lines[doc_start(&lines, i)..=end].join("\n");
+        symbols.push(Symbol { path: path.to_string(), line: i + 1, type_name, text: truncate_tokens(&text, 400) });
+    }
+    symbols
+}
+
+fn render_symbol(symbol: &Symbol) -> String {
+    format!("`{}:{}`\n```\n{}\n```\n", symbol.path, symbol.line, symbol.text)
+}
+
+async fn fetch_blob(github: &dyn GithubApi, owner: &str, repo: &str, sha: &str, max_attempts: u32) -> Option<String> {
+    let route = format!("/repos/{}/{}/git/blobs/{}", owner, repo, sha);
+    let blob = retry("get_blob", max_attempts, is_transient_github_error, || github.get(&route)).await.ok()?;
+    let content: String = blob["content"].as_str()?.split_whitespace().collect();
+    let bytes = base64::engine::general_purpose::STANDARD.decode(content).ok()?;
+    Some(String::from_utf8_lossy(&bytes).into_owned()).filter(|text| !text.contains('\0'))
+}
+
+fn extension(path: &str) -> Option<&str> {
+    path.rsplit('/').next()?.rsplit_once('.').map(|(_, ext)| ext)
+}
+
+//  The number of leading directories that two paths share
+fn shared_dirs(a: &str, b: &str) -> usize {
+    let (a, b) = (a.rsplit_once('/').map_or("", |(dir, _)| dir), b.rsplit_once('/').map_or("", |(dir, _)| dir));
+    a.split('/').zip(b.split('/')).take_while(|(a, b)| !a.is_empty() && a == b).count()
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm(a) * norm(b) {
+        n if n > 0.0 => dot / n,
+        _ => 0.0,
+    }
+}
@@ -0,0 +1,561 @@
+//  The review comment, its hidden markers and sections, and the other report formats
+
+use crate::api::{ApiChange, BreakingChange};
+use crate::commits::CommitReview;
+use crate::deps::{Advisory, Change, DependencyFile, LicenseIssue};
+use crate::github::{Mergeability, RelatedPr};
+use crate::pipeline::{FileState, Finding, Severity};
+use lazy_static::lazy_static;
+use octocrab::models::pulls::FileDiff;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+lazy_static! {
+    pub(crate) static ref CWE_ID: Regex = Regex::new(r"\bCWE-(\d+)\b").unwrap();
+    static ref REVIEWED_HEAD: Regex = Regex::new(r"<!-- pr-review:head=([0-9a-f]{40}) -->").unwrap();
+}
+
+//  Hidden markers in the review comment, to find it again and to reuse its per-file sections
+pub const REVIEW_MARKER: &str = "<!-- pr-review:review -->";
+
+const FILE_MARKER: &str = "<!-- pr-review:file=";
+
+pub(crate) const SECTIONS_END_MARKER: &str = "<!-- pr-review:end -->";
+
+pub(crate) const PACKAGE_MARKER: &str = "<!-- pr-review:package=";
+
+//  GitHub rejects comments longer than this. The room reserved in the review comment for the
+//  notes and the footer that are added after the sections.
+pub(crate) const COMMENT_LIMIT: usize = 65536;
+
+pub(crate) const COMMENT_RESERVE: usize = 4096;
+
+//  The most related PRs listed in the review comment
+const MAX_RELATED_PRS: usize = 5;
+
+//  The name of the bot in the code scanning alerts
+pub(crate) const SARIF_TOOL: &str = "github-pr-review";
+
+pub(crate) fn render_reviewers(suggestions: &BTreeMap<String, Vec<String>>) -> String {
+    // One line per set of directories, e.g. "Changes touch `src/auth` — suggested reviewers: @alice"
+    let mut by_dirs: Vec<(&Vec<String>, Vec<&str>)> = Vec::new();
+    for (reviewer, dirs) in suggestions {
+        match by_dirs.iter_mut().find(|(d, _)| *d == dirs) {
+            Some((_, reviewers)) => reviewers.push(reviewer),
+            None => by_dirs.push((dirs, vec![reviewer])),
+        }
+    }
+    let mut out = String::from("### Suggested reviewers\n\n");
+    for (dirs, reviewers) in by_dirs {
+        let dirs: Vec<&str> = dirs.iter().map(|d| if d.is_empty() { "/" } else { d.as_str() }).collect();
+        out.push_str(&format!("* Changes touch {}: {}\n", format_paths(dirs.into_iter()), reviewers.join(", ")));
+    }
+    out.push('\n');
+    out
+}
+
+//  The open PRs that change the same files, so that conflicting work is caught early
+pub(crate) fn render_related_prs(related: &[RelatedPr]) -> String {
+    if related.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("### Possibly related PRs
+
+These open PRs change some of the same files:
+
+");
+    for pr in related.iter().take(MAX_RELATED_PRS) {
+        out.push_str(&format!(
+            "* #{} {} by @{}: {}\n",
+            pr.number,
+            pr.title.replace('\n', " "),
+            pr.author,
+            format_paths(pr.paths.iter().map(String::as_str))
+        ));
+    }
+    if related.len() > MAX_RELATED_PRS {
+        out.push_str(&format!("* and {} more\n", related.len() - MAX_RELATED_PRS));
+    }
+    out.push_str("\n------\n\n");
+    out
+}
+
+//  How far the branch is from the base branch, in one sentence
+fn merge_status(m: &Mergeability) -> String {
+    let behind = match m.behind_by {
+        0 => format!("is up to date with `{}`", m.base),
+        1 => format!("is 1 commit behind `{}`", m.base),
+        n => format!("is {} commits behind `{}`", n, m.base),
+    };
+    match m.conflicting {
+        Some(true) => format!("This branch has conflicts with `{}` that must be resolved, and {}.", m.base, behind),
+        Some(false) => format!("This branch has no conflicts, and {}.", behind),
+        None => format!("This branch {}. GitHub has not checked it for conflicts yet.", behind),
+    }
+}
+
+//  A note at the top of the review when the PR has conflicts or is far behind, since the reviewed
+//  diff may change a lot when the branch is brought up to date
+pub(crate) fn render_mergeability(m: &Mergeability, max_behind: u64, rebase_hint_phrase: &str) -> String {
+    if max_behind == 0 || (m.conflicting != Some(true) && m.behind_by <= max_behind) {
+        return String::new();
+    }
+    format!(
+        "### Mergeability\n\n{} The review may not hold after the branch is brought up to date. Say `{}` for how to update it.\n\n------\n\n",
+        merge_status(m),
+        rebase_hint_phrase
+    )
+}
+
+//  The git commands to rebase the branch of the PR on the base branch
+pub(crate) fn render_rebase_hint(m: &Mergeability, owner: &str, repo: &str) -> String {
+    let from_fork = !m.head_repo.eq_ignore_ascii_case(&format!("{}/{}", owner, repo));
+    let remote = if from_fork { "upstream" } else { "origin" };
+    let mut out = format!(
+        "Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). {}\n\n------\n\nTo rebase the branch on `{}`:\n\n```sh\n",
+        merge_status(m),
+        m.base
+    );
+    if from_fork {
+        out.push_str(&format!("# Once, in your clone of the fork\ngit remote add upstream https://github.com/{}/{}.git\n", owner, repo));
+    }
+    out.push_str(&format!(
+        "git fetch {remote}\ngit checkout {head}\ngit rebase {remote}/{base}\n# For each conflict that git reports, fix the files, then\ngit add <files>\ngit rebase --continue\n# When the rebase is done\ngit push --force-with-lease origin {head}\n```\n\n",
+        remote = remote,
+        head = m.head,
+        base = m.base
+    ));
+    out.push_str(&format!(
+        "To keep the history of the branch instead, run `git merge {}/{}` in place of the rebase, and push without `--force-with-lease`.\n",
+        remote, m.base
+    ));
+    out
+}
+
+//  The reply to the config command
+pub(crate) fn render_config(settings: &[(&str, String)], changed: &BTreeMap<String, String>) -> String {
+    let mut out = String::from("Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). Here are the settings in effect for this PR, from `.github/pr-review.yml`, the org-wide file and the flow config.\n\n| Setting | Value |\n| --- | --- |\n");
+    for (name, value) in settings {
+        out.push_str(&format!("| `{}` | {} |\n", name, value.replace('|', "\\|")));
+    }
+    if !changed.is_empty() {
+        out.push_str("\nChanged for this PR by command:\n\n");
+        for (name, value) in changed {
+            out.push_str(&format!("* `{}`: `{}`\n", name, value));
+        }
+    }
+    out
+}
+
+//  The head commit recorded in a review comment
+pub(crate) fn parse_reviewed_head(review: &str) -> Option<String> {
+    REVIEWED_HEAD.captures(review).map(|cap| cap[1].to_string())
+}
+
+//  Split the sections of a review into parts that fit in comments: the first part takes up to
+//  `first` bytes, the others up to `rest` bytes. A section too long for any comment is cut.
+pub(crate) fn split_sections(sections: &str, first: usize, rest: usize) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    for (start, end) in section_bounds(sections) {
+        let section = truncate_bytes(&sections[start..end], rest);
+        let limit = match parts.len() {
+            1 => first,
+            _ => rest,
+        };
+        if parts.last().is_some_and(|p| !p.is_empty() && p.len() + section.len() > limit)
+            || (parts.len() == 1 && section.len() > first)
+        {
+            parts.push(String::new());
+        }
+        parts.last_mut().unwrap().push_str(section);
+    }
+    parts
+}
+
+//  The per-file sections of a review comment, by file name
+pub(crate) fn parse_sections(review: &str) -> HashMap<String, String> {
+    let review = review.split(SECTIONS_END_MARKER).next().unwrap_or_default();
+    section_bounds(review)
+        .into_iter()
+        .filter_map(|(start, end)| {
+            let section = review[start..end].strip_prefix(FILE_MARKER)?;
+            let filename = section.split(" -->").next()?;
+            Some((filename.to_string(), review[start..end].to_string()))
+        })
+        .collect()
+}
+
+//  Where the file and package sections of a review start and end
+fn section_bounds(sections: &str) -> Vec<(usize, usize)> {
+    let mut starts: Vec<usize> = sections
+        .match_indices(FILE_MARKER)
+        .chain(sections.match_indices(PACKAGE_MARKER))
+        .map(|(start, _)| start)
+        .collect();
+    starts.sort_unstable();
+    let ends = starts.iter().skip(1).copied().chain([sections.len()]);
+    starts.iter().copied().zip(ends).collect()
+}
+
+pub(crate) fn render_failures(failures: &[(String, String)], retry_phrase: &str) -> String {
+    let mut out = String::from("### Failed reviews\n\n| File | Reason |\n| --- | --- |\n");
+    for (path, reason) in failures {
+        out.push_str(&format!("| `{}` | {} |\n", path, reason.replace('|', "\\|").replace('\n', " ")));
+    }
+    out.push_str(&format!("\nSay `{}` to review these files again.\n\n", retry_phrase));
+    out
+}
+
+//  The section of a file in the review comment. Long reviews stay readable because the section is
+//  collapsed, unless the file has blocker or major findings.
+pub(crate) fn render_section(f: &FileDiff, body: &str, findings: &[Finding]) -> String {
+    let verdict = findings.iter().map(|f| f.severity).min();
+    let open = match verdict {
+        Some(Severity::Blocker | Severity::Major) => " open",
+        _ => "",
+    };
+    format!(
+        "{}{} -->\n<a name=\"{}\"></a>\n<details{}>\n<summary><b>{}</b>: {}</summary>\n\n[View the file]({})\n\n{}</details>\n\n",
+        FILE_MARKER,
+        f.filename,
+        section_anchor(&f.filename),
+        open,
+        f.filename,
+        verdict_label(verdict, findings.len()),
+        f.blob_url.as_str(),
+        body
+    )
+}
+
+//  The anchor of the section of a file, for the links of the table of contents
+fn section_anchor(path: &str) -> String {
+    let slug: String = path.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' }).collect();
+    format!("pr-review-{}", slug)
+}
+
+fn verdict_label(verdict: Option<Severity>, findings: usize) -> String {
+    match (verdict, findings) {
+        (None, 0) => "no findings".to_string(),
+        (Some(severity), 1) => format!("1 finding ({})", severity.as_str()),
+        (Some(severity), n) => format!("{} findings (most serious: {})", n, severity.as_str()),
+        (None, n) => format!("{} findings", n),
+    }
+}
+
+//  A table of the reviewed files with links to their sections
+pub(crate) fn render_toc(files: &BTreeMap<String, FileState>) -> String {
+    let mut toc = String::from("### Files\n\n| File | Findings |\n| --- | --- |\n");
+    for (path, file) in files {
+        toc.push_str(&format!(
+            "| [{}](#{}) | {} |\n",
+            path.replace('|', "\\|"), section_anchor(path), verdict_label(file.verdict, file.findings)
+        ));
+    }
+    toc.push('\n');
+    toc
+}
+
+//  The inline review comment for a finding. GitHub only accepts review comments on lines that
+//  are part of the diff, and a suggestion replaces all the lines the comment spans.
+pub(crate) fn review_comment(finding: &Finding, commentable: &HashSet<u64>, suggestions: bool) -> Option<serde_json::Value> {
+    let line = finding.line?;
+    let end_line = finding.end_line.filter(|&end| end >= line).unwrap_or(line);
+    let mut body = format!("**{}**: {}", finding.severity.as_str(), finding.message);
+    if (line..=end_line).all(|l| commentable.contains(&l)) {
+        if let Some(code) = finding.suggestion.as_ref().filter(|_| suggestions) {
+            body.push_str(&format!("\n\n```suggestion\n{}\n```", code));
+        }
+        let mut comment = serde_json::json!({
+            "path": finding.path,
+            "line": end_line,
+            "side": "RIGHT",
+            "body": body,
+        });
+        if end_line > line {
+            comment["start_line"] = line.into();
+            comment["start_side"] = "RIGHT".into();
+        }
+        Some(comment)
+    } else if commentable.contains(&line) {
+        // Without all of its lines, the fix cannot be applied
+        Some(serde_json::json!({
+            "path": finding.path,
+            "line": line,
+            "side": "RIGHT",
+            "body": body,
+        }))
+    } else {
+        log::debug!("Finding at {}:{} is outside of the diff", finding.path, line);
+        None
+    }
+}
+
+//  The possible secrets, at the very top of the review comment
+pub(crate) fn render_secrets(secrets: &[Finding]) -> String {
+    if secrets.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("### :warning: Possible secrets\n\nThe following added lines look like they contain credentials. If they are real, remove them from the PR and revoke them, since they stay in the git history.\n\n");
+    for f in secrets {
+        out.push_str(&format!("- `{}` line {}: {}\n", f.path, f.line.unwrap_or_default(), f.message));
+    }
+    out.push_str("\n------\n\n");
+    out
+}
+
+//  The serious findings of the database migrations, in a banner under the possible secrets
+pub(crate) fn render_migration_risks(risks: &[&Finding]) -> String {
+    if risks.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("> [!WARNING]\n> **Risky database migrations.** The following changes may lose data, lock large tables or be impossible to roll back. Check them before deploying:\n>\n");
+    for f in risks {
+        match f.line {
+            Some(line) => out.push_str(&format!("> - `{}` line {}: **{}** {}\n", f.path, line, f.severity.as_str(), f.message)),
+            None => out.push_str(&format!("> - `{}`: **{}** {}\n", f.path, f.severity.as_str(), f.message)),
+        }
+    }
+    out.push_str("\n------\n\n");
+    out
+}
+
+//  The dependency changes of the manifests and lockfiles, after the possible secrets
+pub(crate) fn render_dependencies(files: &[DependencyFile]) -> String {
+    let files: Vec<&DependencyFile> = files.iter().filter(|f| f.too_large || !f.changes.is_empty()).collect();
+    if files.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("### Dependency changes\n\n");
+    for f in files {
+        if f.too_large {
+            out.push_str(&format!("`{}`: the diff is too large to list its changes.\n\n", f.path));
+            continue;
+        }
+        out.push_str(&format!("`{}`:\n", f.path));
+        for c in &f.changes {
+            let mut line = match &c.change {
+                Change::Added(version) => format!("- added `{}` {}", c.name, version),
+                Change::Updated(old, new) => format!("- updated `{}` {} → {}", c.name, old, new),
+                Change::Removed(version) => format!("- removed `{}` {}", c.name, version),
+                Change::Changed => format!("- changed the settings of `{}`", c.name),
+            };
+            if c.major {
+                line.push_str(" — **major version bump**, check the changelog for breaking changes");
+            }
+            if let Some(risk) = &c.risk {
+                line.push_str(&format!(" — :warning: {}", risk));
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out.push_str("------\n\n");
+    out
+}
+
+//  The known vulnerabilities of the added dependency versions, after the dependency changes
+pub(crate) fn render_advisories(advisories: &[Advisory]) -> String {
+    if advisories.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("### :warning: Dependency security\n\nThe following dependency versions that this PR adds have known vulnerabilities in the [OSV](https://osv.dev) database.\n\n");
+    for a in advisories {
+        let mut ids = vec![format!("[{}](https://osv.dev/vulnerability/{})", a.id, a.id)];
+        ids.extend(a.cves.iter().cloned());
+        out.push_str(&format!("- `{}` {} ({}): {}.", a.package, a.version, ids.join(", "), a.summary.trim_end_matches('.')));
+        if let Some(severity) = &a.severity {
+            out.push_str(&format!(" Severity: {}.", severity.to_lowercase()));
+        }
+        match &a.fixed {
+            Some(fixed) => out.push_str(&format!(" Fixed in {}.\n", fixed)),
+            None => out.push_str(" No fixed version yet.\n"),
+        }
+    }
+    out.push_str("\n------\n\n");
+    out
+}
+
+//  The new dependencies whose licenses are not in the license_allowlist
+pub(crate) fn render_license_issues(issues: &[LicenseIssue], allowlist: &[String]) -> String {
+    if issues.is_empty() {
+        return String::new();
+    }
+    let mut out = format!(
+        "### Dependency licenses\n\nThe following new dependencies have licenses that are not in the `license_allowlist` of this repo ({}).\n\n",
+        allowlist.join(", ")
+    );
+    for i in issues {
+        match i.licenses.is_empty() {
+            true => out.push_str(&format!("- `{}` {}: no license declared\n", i.package, i.version)),
+            false => out.push_str(&format!("- `{}` {}: {}\n", i.package, i.version, i.licenses.join(", "))),
+        }
+    }
+    out.push_str("\n------\n\n");
+    out
+}
+
+//  The changes to the public API of library crates that may break semver, after the dependencies
+pub(crate) fn render_api_changes(changes: &[BreakingChange]) -> String {
+    if changes.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("### Potential breaking API changes\n\nThese changes to the public items of library crates may break their users, and need a new major version.\n\n");
+    for c in changes {
+        let location = match c.line {
+            Some(line) => format!("{}:{}", c.path, line),
+            None => c.path.clone(),
+        };
+        match &c.change {
+            ApiChange::Removed => out.push_str(&format!("- `{}`: `{}` was removed\n", location, c.item)),
+            ApiChange::Changed(old, new) => out.push_str(&format!("- `{}`: `{}` changed from `{}` to `{}`\n", location, c.item, old, new)),
+            ApiChange::Added(reason) => out.push_str(&format!("- `{}`: `{}` is {}\n", location, c.item, reason)),
+        }
+    }
+    out.push_str("\n------\n\n");
+    out
+}
+
+//  The commit messages that break the conventions, with the suggested messages to copy, folded
+pub(crate) fn render_commit_hygiene(commits: &[CommitReview], total: usize) -> String {
+    let mut out = format!(
+        "<details>\n<summary><b>Commit hygiene</b>: {} of {} commit messages could be improved</summary>\n\n",
+        commits.len(), total
+    );
+    for c in commits {
+        let subject = c.message.lines().next().unwrap_or_default();
+        out.push_str(&format!("- `{}` {}: {}\n", c.sha.get(..7).unwrap_or(&c.sha), subject.replace('`', "'"), c.problems.join(", ")));
+        if let Some(suggestion) = &c.suggestion {
+            out.push_str(&format!("\n  ```\n{}\n  ```\n\n", suggestion.lines().map(|l| format!("  {}", l)).collect::<Vec<_>>().join("\n")));
+        }
+    }
+    if !out.ends_with("\n\n") {
+        out.push('\n');
+    }
+    out.push_str("</details>\n\n------\n\n");
+    out
+}
+
+//  Findings grouped by severity under a title, for the top of the review comment
+pub(crate) fn render_findings(title: &str, findings: &[&Finding]) -> String {
+    if findings.is_empty() {
+        return String::new();
+    }
+    let mut out = format!("### {}\n\n", title);
+    for severity in Severity::ALL {
+        let group: Vec<&Finding> = findings.iter().copied().filter(|f| f.severity == severity).collect();
+        if group.is_empty() {
+            continue;
+        }
+        out.push_str(&format!("#### {} ({})\n\n", severity.as_str(), group.len()));
+        for f in group {
+            match (f.line, f.end_line) {
+                (Some(line), Some(end)) if end > line => {
+                    out.push_str(&format!("- `{}` lines {}-{}: {}\n", f.path, line, end, f.message))
+                }
+                (Some(line), _) => out.push_str(&format!("- `{}` line {}: {}\n", f.path, line, f.message)),
+                (None, _) => out.push_str(&format!("- `{}`: {}\n", f.path, f.message)),
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+//  The longest prefix of at most `max` bytes that ends on a char boundary
+pub(crate) fn truncate_bytes(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+//  The findings as a SARIF 2.1.0 log, with one rule per severity
+pub(crate) fn to_sarif(findings: &[Finding]) -> serde_json::Value {
+    let rules: Vec<serde_json::Value> = Severity::ALL
+        .iter()
+        .map(|s| serde_json::json!({
+            "id": s.as_str(),
+            "shortDescription": { "text": format!("A {} issue found by the LLM code review", s.as_str()) },
+        }))
+        .collect();
+    let results: Vec<serde_json::Value> = findings
+        .iter()
+        .map(|f| {
+            let line = f.line.unwrap_or(1);
+            serde_json::json!({
+                "ruleId": f.severity.as_str(),
+                "level": match f.severity {
+                    Severity::Blocker => "error",
+                    Severity::Major => "warning",
+                    Severity::Minor | Severity::Nit => "note",
+                },
+                "message": { "text": f.message },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": f.path },
+                        "region": { "startLine": line, "endLine": f.end_line.unwrap_or(line).max(line) },
+                    },
+                }],
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": SARIF_TOOL,
+                    "informationUri": "https://github.com/flows-network/github-pr-review",
+                    "rules": rules,
+                },
+            },
+            "results": results,
+        }],
+    })
+}
+
+//  A gzip member (RFC 1952) around the deflated data, as the code scanning API expects
+pub(crate) fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    out.extend(miniz_oxide::deflate::compress_to_vec(data, 6));
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+//  Link the CWE identifiers in a text to their definitions
+pub(crate) fn link_cwes(text: &str) -> String {
+    CWE_ID.replace_all(text, "[CWE-$1](https://cwe.mitre.org/data/definitions/$1.html)").to_string()
+}
+
+//  A list of paths for the review comment, e.g. `a.rs`, `b.rs` and 3 more
+pub(crate) fn format_paths<'a>(paths: impl ExactSizeIterator<Item = &'a str>) -> String {
+    let total = paths.len();
+    let names: Vec<String> = paths.take(20).map(|p| format!("`{}`", p)).collect();
+    match total > names.len() {
+        true => format!("{} and {} more", names.join(", "), total - names.len()),
+        false => names.join(", "),
+    }
+}
+
+//  A rough token count for humans, e.g. 48k
+pub(crate) fn format_tokens(tokens: usize) -> String {
+    match tokens {
+        0..=999 => tokens.to_string(),
+        _ => format!("{}k", (tokens + 500) / 1000),
+    }
+}
@@ -0,0 +1,138 @@
+//  The code around a change at the level of symbols: the functions and blocks that enclose the
+//  changed lines, and the types that they use. This is a light parser of definitions, braces and
+//  indentation that works for the common languages, rather than tree-sitter, whose C grammars do
+//  not build for the wasm32-wasi target of the flow.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashSet;
+
+lazy_static! {
+    //  The first line of a definition in the common languages, with its kind and name
+    pub(crate) static ref DEFINITION: Regex = Regex::new(
+        r"^\s*(?:export\s+)?(?:default\s+)?(?:pub(?:\([^)]*\))?\s+)?(?:(?:public|private|protected|internal|static|abstract|final|async|unsafe|const|extern|data|sealed|open)\s+)*(?P<kind>fn|def|func|fun|function|class|struct|enum|union|trait|interface|type|impl|mod)\b(?:\s+(?:\([^)]*\)\s*)?(?P<name>[A-Za-z_][A-Za-z0-9_]*))?"
+    ).unwrap();
+    pub(crate) static ref COMMENT: Regex = Regex::new(r"^\s*(?://|#|/\*|\*|--)").unwrap();
+    //  The names of types, by the CamelCase convention of most languages
+    static ref TYPE_NAME: Regex = Regex::new(r"\b[A-Z][a-z0-9]+(?:[A-Z][A-Za-z0-9]*)?\b").unwrap();
+}
+
+//  The kinds of definitions that are types
+pub(crate) const TYPE_KINDS: &[&str] = &["class", "struct", "enum", "union", "trait", "interface", "type"];
+
+//  Blocks longer than this are cut to the lines around the change
+const MAX_BLOCK_LINES: usize = 150;
+
+//  The lines kept around a change that is outside of any definition, or in a long block
+const WINDOW_LINES: usize = 10;
+
+//  The max number of doc comment lines kept above a block
+const DOC_LINES: usize = 5;
+
+//  The ranges of lines, 1-based and inclusive, of the innermost definitions that enclose the
+//  `changed` lines of a file, with their doc comments. Ranges that overlap are merged.
+pub(crate) fn enclosing_blocks(text: &str, changed: &HashSet<u64>) -> Vec<(usize, usize)> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut changed: Vec<usize> = changed.iter().map(|&l| l as usize).filter(|&l| l >= 1 && l <= lines.len()).collect();
+    changed.sort();
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for line in changed {
+        if ranges.last().is_some_and(|&(start, end)| start <= line && line <= end) {
+            continue;
+        }
+        let i = line - 1;
+        let block = (0..=i)
+            .rev()
+            .filter(|&start| DEFINITION.is_match(lines[start]))
+            .map(|start| (start, block_end(&lines, start)))
+            .find(|&(_, end)| end >= i);
+        let (start, end) = match block {
+            Some((start, end)) if end - start < MAX_BLOCK_LINES => (doc_start(&lines, start), end),
+            _ => (i.saturating_sub(WINDOW_LINES), (i + WINDOW_LINES).min(lines.len() - 1)),
+        };
+        ranges.push((start + 1, end + 1));
+    }
+    ranges.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+//  The last line of the definition that starts at `start`: the matching closing brace, or the
+//  last line that is indented more than the definition when its body has no braces, e.g. Python
+pub(crate) fn block_end(lines: &[&str], start: usize) -> usize {
+    let mut depth = 0i32;
+    let mut opened = false;
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        let mut in_string: Option<char> = None;
+        let mut prev = ' ';
+        for c in line.chars() {
+            match (in_string, c) {
+                (Some(q), c) if c == q && prev != '\\' => in_string = None,
+                (Some(_), _) => {}
+                // Single quotes are left out, as they also start Rust lifetimes
+                (None, '"' | '`') => in_string = Some(c),
+                (None, '{') => {
+                    depth += 1;
+                    opened = true;
+                }
+                (None, '}') => depth -= 1,
+                _ => {}
+            }
+            prev = c;
+        }
+        if opened && depth <= 0 {
+            return i;
+        }
+        // A definition without a body, e.g. `struct A;`, or a signature followed by an indented body
+        match line.trim_end().chars().last() {
+            Some(';') if !opened => return i,
+            Some(':') if !opened => return indented_end(lines, start, i),
+            _ => {}
+        }
+    }
+    lines.len().saturating_sub(1)
+}
+
+//  The last line after `from` that is indented more than the line at `start`, blank lines aside
+fn indented_end(lines: &[&str], start: usize, from: usize) -> usize {
+    let indent = |l: &str| l.len() - l.trim_start().len();
+    let base = indent(lines[start]);
+    let mut end = from;
+    for (i, line) in lines.iter().enumerate().skip(from + 1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if indent(line) <= base {
+            break;
+        }
+        end = i;
+    }
+    end
+}
+
+//  The first line of the doc comment above a definition
+pub(crate) fn doc_start(lines: &[&str], start: usize) -> usize {
+    (0..start).rev().take_while(|&j| COMMENT.is_match(lines[j])).last().unwrap_or(start).max(start.saturating_sub(DOC_LINES))
+}
+
+//  The type names used in some code that it does not define itself
+pub(crate) fn referenced_types(code: &str) -> Vec<String> {
+    let defined: HashSet<&str> = code
+        .lines()
+        .filter_map(|l| DEFINITION.captures(l))
+        .filter_map(|cap| cap.name("name").map(|m| m.as_str()))
+        .collect();
+    let mut names: Vec<String> = Vec::new();
+    for m in TYPE_NAME.find_iter(code) {
+        if !defined.contains(m.as_str()) && !names.iter().any(|n| n == m.as_str()) {
+            names.push(m.as_str().to_string());
+        }
+    }
+    names
+}
@@ -0,0 +1,92 @@
+//  Structured logs. Each line is a JSON object with the delivery of the webhook event, the repo, the
+//  PR, the changed file and the stage of the review, so that the lines of one review can be picked
+//  out of the logs of concurrent events, and across the retries and the concurrent file reviews.
+
+use octocrab::models::webhook_events::{WebhookEvent, WebhookEventPayload};
+use std::fmt::Arguments;
+use std::future::Future;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+//  What a log line is about
+#[derive(Clone, Default)]
+pub(crate) struct Context {
+    //  The GUID of the webhook delivery, or a random ID when the caller has none
+    delivery: String,
+    repo: Option<String>,
+    pr: Option<u64>,
+    file: Option<String>,
+}
+
+impl Context {
+    pub(crate) fn of_event(payload: &WebhookEvent, delivery: Option<&str>) -> Context {
+        let pr = match &payload.specific {
+            WebhookEventPayload::PullRequest(e) => Some(e.number),
+            WebhookEventPayload::IssueComment(e) => Some(e.issue.number),
+            WebhookEventPayload::Issues(e) => Some(e.issue.number),
+            _ => None,
+        };
+        Context {
+            delivery: delivery.map_or_else(random_id, str::to_string),
+            repo: payload.repository.as_ref().and_then(|r| r.full_name.clone()),
+            pr,
+            file: None,
+        }
+    }
+}
+
+//  Run a future in a context: the lines it logs carry the context
+pub(crate) async fn scope<F: Future>(context: Context, f: F) -> F::Output {
+    CONTEXT.scope(context, f).await
+}
+
+//  Run a future in the current context, about one changed file
+pub(crate) async fn for_file<F: Future>(file: String, f: F) -> F::Output {
+    let context = CONTEXT.try_with(|c| c.clone()).unwrap_or_default();
+    CONTEXT.scope(Context { file: Some(file), ..context }, f).await
+}
+
+pub(crate) fn log(target: &str, level: log::Level, stage: &str, message: Arguments) {
+    if !log::log_enabled!(target: target, level) {
+        return;
+    }
+    let context = CONTEXT.try_with(|c| c.clone()).unwrap_or_default();
+    let mut line = serde_json::json!({ "stage": stage, "message": message.to_string() });
+    for (key, value) in [
+        ("delivery", Some(serde_json::json!(context.delivery)).filter(|_| !context.delivery.is_empty())),
+        ("repo", context.repo.map(serde_json::Value::from)),
+        ("pr", context.pr.map(serde_json::Value::from)),
+        ("file", context.file.map(serde_json::Value::from)),
+    ] {
+        if let Some(value) = value {
+            line[key] = value;
+        }
+    }
+    log::log!(target: target, level, "{}", line);
+}
+
+//  Like the macros of the log crate, with the stage of the review first, e.g.
+//  `error!("post", "Error posting comment: {}", error)`
+macro_rules! debug {
+    ($stage:expr, $($arg:tt)+) => { $crate::trace::log(module_path!(), log::Level::Debug, $stage, format_args!($($arg)+)) };
+}
+macro_rules! info {
+    ($stage:expr, $($arg:tt)+) => { $crate::trace::log(module_path!(), log::Level::Info, $stage, format_args!($($arg)+)) };
+}
+macro_rules! warning {
+    ($stage:expr, $($arg:tt)+) => { $crate::trace::log(module_path!(), log::Level::Warn, $stage, format_args!($($arg)+)) };
+}
+macro_rules! error {
+    ($stage:expr, $($arg:tt)+) => { $crate::trace::log(module_path!(), log::Level::Error, $stage, format_args!($($arg)+)) };
+}
+//  A macro named warn would be ambiguous with the warn attribute
+pub(crate) use {debug, error, info, warning as warn};
+
+//  The clock is random enough to tell apart the events of one flow, no need for a RNG
+fn random_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    format!("local-{:x}", nanos)
+}
@@ -0,0 +1,120 @@
+//  The triage of new issues: their kind, the labels of the repo that fit them, and the recent
+//  issues that may report the same thing. The LLM answers in `KEY: value` lines, which are checked
+//  against the labels and issues it was given.
+
+use std::collections::HashSet;
+
+//  The kinds of issues
+pub(crate) const KINDS: &[&str] = &["bug", "feature", "question"];
+
+//  The max number of recent issues that the LLM compares the new one with
+pub(crate) const MAX_CANDIDATES: usize = 10;
+
+//  Words too common to tell issues apart
+const STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "with", "this", "that", "not", "are", "was", "when", "from", "but", "have", "has", "can",
+    "does", "doesn", "don", "should", "would", "could", "there", "what", "how", "why", "use", "using", "get",
+    "issue", "error", "bug", "feature", "question", "please", "thanks", "any", "all", "into", "out", "after",
+];
+
+//  An issue of the repo, to compare a new one with
+pub(crate) struct RecentIssue {
+    pub(crate) number: u64,
+    pub(crate) title: String,
+    pub(crate) body: String,
+}
+
+//  The triage of an issue, as far as the answer of the LLM holds up
+#[derive(Default)]
+pub(crate) struct Triage {
+    pub(crate) kind: Option<&'static str>,
+    pub(crate) labels: Vec<String>,
+    pub(crate) duplicates: Vec<u64>,
+    pub(crate) summary: String,
+}
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.len() >= 3)
+        .map(str::to_lowercase)
+        .filter(|w| !STOP_WORDS.contains(&w.as_str()))
+        .collect()
+}
+
+//  The recent issues that share the most words with the new one, most similar first. The title
+//  of the new issue counts twice, since it says the most in the fewest words.
+pub(crate) fn similar_issues<'a>(title: &str, body: &str, issues: &'a [RecentIssue]) -> Vec<&'a RecentIssue> {
+    let title_words = words(title);
+    let all_words: HashSet<String> = title_words.union(&words(body)).cloned().collect();
+    let mut scored: Vec<(f64, &RecentIssue)> = issues
+        .iter()
+        .filter_map(|issue| {
+            let other: HashSet<String> = words(&issue.title).union(&words(&issue.body)).cloned().collect();
+            let shared = all_words.intersection(&other).count() + title_words.intersection(&other).count();
+            let score = shared as f64 / (all_words.union(&other).count().max(1) as f64);
+            (shared > 0).then_some((score, issue))
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().take(MAX_CANDIDATES).map(|(_, issue)| issue).collect()
+}
+
+//  The triage in an answer, with only the labels and the issues that were offered
+pub(crate) fn parse_triage(answer: &str, labels: &[String], candidates: &[u64]) -> Triage {
+    let mut triage = Triage::default();
+    for line in answer.lines() {
+        let line = line.trim().trim_start_matches(['-', '*', ' ']).replace("**", "");
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        let items = || value.split(',').map(|v| v.trim().trim_matches('`').trim()).filter(|v| !v.is_empty() && !v.eq_ignore_ascii_case("none"));
+        match key.trim().to_uppercase().as_str() {
+            "TYPE" => triage.kind = KINDS.iter().copied().find(|k| value.to_lowercase().contains(k)),
+            "LABELS" => {
+                for item in items() {
+                    if let Some(label) = labels.iter().find(|l| l.eq_ignore_ascii_case(item)) {
+                        if !triage.labels.contains(label) {
+                            triage.labels.push(label.clone());
+                        }
+                    }
+                }
+            }
+            "DUPLICATES" => {
+                for item in items() {
+                    match item.trim_start_matches('#').parse() {
+                        Ok(number) if candidates.contains(&number) && !triage.duplicates.contains(&number) => triage.duplicates.push(number),
+                        _ => {}
+                    }
+                }
+            }
+            "SUMMARY" => triage.summary = value.to_string(),
+            _ => {}
+        }
+    }
+    triage
+}
+
+//  The triage comment on the issue
+pub(crate) fn render_triage(triage: &Triage, candidates: &[&RecentIssue]) -> String {
+    let mut out = String::from("Hello, I am a [code reviewer](https://github.com/flows-network/github-pr-review/). Here is a first triage of this issue, for the maintainers to confirm.\n\n");
+    if let Some(kind) = triage.kind {
+        out.push_str(&format!("**Type:** {}\n\n", kind));
+    }
+    if !triage.labels.is_empty() {
+        let labels: Vec<String> = triage.labels.iter().map(|l| format!("`{}`", l)).collect();
+        out.push_str(&format!("**Suggested labels:** {}\n\n", labels.join(", ")));
+    }
+    if !triage.duplicates.is_empty() {
+        out.push_str("**Possible duplicates:**\n\n");
+        for number in &triage.duplicates {
+            let title = candidates.iter().find(|c| c.number == *number).map_or("", |c| c.title.as_str());
+            out.push_str(&format!("- #{} {}\n", number, title));
+        }
+        out.push('\n');
+    }
+    if !triage.summary.is_empty() {
+        out.push_str(&format!("{}\n", triage.summary));
+    }
+    out
+}
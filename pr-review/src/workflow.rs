@@ -0,0 +1,87 @@
+//  The security checks of GitHub Actions workflows, on the whole file after the change: script
+//  injection from the event payload in run steps, overly broad token permissions, and third-party
+//  actions that are not pinned to a commit SHA.
+
+use crate::pipeline::{Finding, Severity};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    //  A step that runs a script, and the first line of the script
+    static ref RUN: Regex = Regex::new(r"^(\s*)(?:-\s+)?(?:run|script):\s*(.*)$").unwrap();
+    //  The values that the author of a PR, an issue or a comment controls
+    static ref UNTRUSTED: Regex = Regex::new(r"\$\{\{\s*(github\.event\.[A-Za-z0-9_.*\[\]'-]+|github\.head_ref)\s*\}\}").unwrap();
+    static ref PERMISSIONS: Regex = Regex::new(r"^(\s*)permissions:\s*(\S*)").unwrap();
+    static ref USES: Regex = Regex::new(r##"^\s*(?:-\s+)?uses:\s*['"]?([^@\s'"]+)@([^\s'"#]+)"##).unwrap();
+    static ref COMMIT_SHA: Regex = Regex::new(r"^[0-9a-f]{40}$").unwrap();
+}
+
+//  The owners of the actions that GitHub maintains
+const FIRST_PARTY: &[&str] = &["actions", "github"];
+
+pub(crate) fn is_workflow(path: &str) -> bool {
+    path.starts_with(".github/workflows/") && (path.ends_with(".yml") || path.ends_with(".yaml"))
+}
+
+//  The issues of a workflow, as a list in the `[severity] LINE <n>:` form of the reviews, and
+//  their findings
+pub(crate) fn workflow_issues(path: &str, text: &str) -> (String, Vec<Finding>) {
+    let finding = |line: usize, severity: Severity, message: String| Finding {
+        path: path.to_string(),
+        line: Some(line as u64 + 1),
+        end_line: None,
+        severity,
+        message,
+        suggestion: None,
+        confidence: None,
+    };
+    let lines: Vec<&str> = text.lines().collect();
+    let indent = |l: &str| l.len() - l.trim_start().len();
+    let mut findings = Vec::new();
+    let mut permissions = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if let Some(cap) = RUN.captures(line) {
+            // A block scalar goes on while the lines are indented more than its key
+            let base = cap[1].len();
+            let mut end = i + 1;
+            if cap[2].starts_with(['|', '>']) {
+                while end < lines.len() && (lines[end].trim().is_empty() || indent(lines[end]) > base) {
+                    end += 1;
+                }
+            }
+            for (j, l) in lines.iter().enumerate().take(end).skip(i) {
+                for m in UNTRUSTED.captures_iter(l) {
+                    findings.push(finding(j, Severity::Major, format!("`${{{{ {} }}}}` is expanded into the script, so whoever controls it can inject commands. Pass it through an `env:` variable and quote it instead.", &m[1])));
+                }
+            }
+            i = end;
+            continue;
+        }
+        if let Some(cap) = PERMISSIONS.captures(line) {
+            permissions = true;
+            if &cap[2] == "write-all" {
+                findings.push(finding(i, Severity::Major, "`permissions: write-all` gives the `GITHUB_TOKEN` write access to everything. Grant only the scopes that the jobs need.".to_string()));
+            }
+        }
+        if let Some(cap) = USES.captures(line) {
+            let action = &cap[1];
+            let owner = action.split('/').next().unwrap_or_default();
+            if !action.starts_with("./") && !action.starts_with("docker://") && !FIRST_PARTY.contains(&owner) && !COMMIT_SHA.is_match(&cap[2]) {
+                findings.push(finding(i, Severity::Minor, format!("The third-party action `{}` is pinned to `{}`, which can be moved to other code. Pin it to a full commit SHA.", action, &cap[2])));
+            }
+        }
+        i += 1;
+    }
+    if !permissions && !lines.is_empty() {
+        findings.push(finding(0, Severity::Minor, "The workflow has no `permissions`, so its `GITHUB_TOKEN` gets the default permissions of the repo, which may allow writes. Set the least permissions that the jobs need.".to_string()));
+    }
+    findings.sort_by_key(|f| f.line);
+
+    let out = match findings.is_empty() {
+        true => "No workflow security issues found.".to_string(),
+        false => findings.iter().map(|f| format!("- [{}] LINE {}: {}\n", f.severity.as_str(), f.line.unwrap_or_default(), f.message)).collect(),
+    };
+    (out, findings)
+}